@@ -0,0 +1,62 @@
+#!/usr/bin/env python3
+"""pack guest images into a hypercrab bundle, layout is documented in src/bundle.rs
+
+usage: mkbundle.py -o bundle.bin \\
+           --image rcore:elf:guest.elf --image linux:linux:Image --image rootfs:initrd:rootfs.cpio \\
+           --guest name=rcore,cpus=1,mem=0x2000000,kernel=rcore \\
+           --guest name=linux,cpus=2,mem=0x8000000,kernel=linux,initrd=rootfs,bootargs="console=ttyS0"
+
+a guest can also be described by a text config packed as image of kind config, see src/config/text.rs
+"""
+import argparse
+import struct
+
+KINDS = {"flat": 0, "elf": 1, "linux": 2, "initrd": 3, "dtb": 4, "config": 5}
+NO_IMAGE = 0xFFFFFFFF
+HEADER = struct.Struct("<8sIIII")
+IMAGE_ENTRY = struct.Struct("<24sIIQQ")
+GUEST_ENTRY = struct.Struct("<24sIIQIIII128s")
+
+
+def align(n, a=4096):
+    return (n + a - 1) // a * a
+
+
+def main():
+    parser = argparse.ArgumentParser()
+    parser.add_argument("-o", "--output", required=True)
+    parser.add_argument("--image", action="append", default=[], help="name:kind:path")
+    parser.add_argument("--guest", action="append", default=[], help="key=value,...")
+    args = parser.parse_args()
+
+    images = []
+    for spec in args.image:
+        name, kind, path = spec.split(":", 2)
+        with open(path, "rb") as f:
+            images.append((name, KINDS[kind], f.read()))
+    index = {name: i for i, (name, _, _) in enumerate(images)}
+
+    guests = []
+    for spec in args.guest:
+        conf = dict(item.split("=", 1) for item in spec.split(","))
+        guests.append(GUEST_ENTRY.pack(
+            conf["name"].encode(), int(conf.get("cpus", "1"), 0), 0, int(conf["mem"], 0),
+            index[conf["kernel"]],
+            index[conf["initrd"]] if "initrd" in conf else NO_IMAGE,
+            index[conf["dtb"]] if "dtb" in conf else NO_IMAGE,
+            0, conf.get("bootargs", "").encode()))
+
+    offset = align(HEADER.size + IMAGE_ENTRY.size * len(images) + GUEST_ENTRY.size * len(guests))
+    table, payload = b"", b""
+    for name, kind, data in images:
+        table += IMAGE_ENTRY.pack(name.encode(), kind, 0, offset + len(payload), len(data))
+        payload += data + b"\0" * (align(len(data)) - len(data))
+
+    blob = HEADER.pack(b"HCBUNDLE", 1, len(images), len(guests), 0) + table + b"".join(guests)
+    blob += b"\0" * (offset - len(blob)) + payload
+    with open(args.output, "wb") as f:
+        f.write(blob)
+
+
+if __name__ == "__main__":
+    main()
@@ -1,8 +1,14 @@
+use super::intc::imsic::{host_imsic, sgei_hie};
+use crate::device_tree::{host_has_extension, host_has_multi_extension};
+use alloc::vec;
+use alloc::vec::Vec;
+use core::mem::{offset_of, size_of};
 use riscv::register::hgatp::Hgatp;
 use riscv::register::{
     hstatus::{self, Hstatus},
     sstatus::{self, Sstatus, SPP},
 };
+use spin::Once;
 
 // register type used in riscv::register is not ffi safe,so we just use usize
 #[repr(C)]
@@ -22,6 +28,143 @@ pub struct TrapContext {
     pub guest_hyp_stack: usize,
     // address of trap_handler
     pub trap_handler: usize,
+    // tp of hypervisor on the hart running vcpu,points to per hart area
+    pub hyp_tp: usize,
+    // loaded by __vm_entry,saved by __vm_exit if guest may change them
+    pub csrs: VcpuCsrs,
+    // moved by hypervisor only when vcpu switches harts,see `save_fp`
+    pub fp: FpState,
+}
+
+/// vs level and hypervisor csrs of a vcpu
+#[repr(C)]
+#[derive(Copy, Clone, Default)]
+pub struct VcpuCsrs {
+    pub vsstatus: usize,
+    pub vsie: usize,
+    pub vstvec: usize,
+    pub vsscratch: usize,
+    pub vsepc: usize,
+    pub vscause: usize,
+    pub vstval: usize,
+    pub vsatp: usize,
+    // virtual interrupts pending for vcpu,guest clears vssip through vsip
+    pub hvip: usize,
+    // guest sets vs interrupt enables through vsie
+    pub hie: usize,
+    // those below are only written by hypervisor
+    pub hcounteren: usize,
+    pub henvcfg: usize,
+    pub htimedelta: usize,
+    // guest timer compare with sstc,moved by hypervisor only when vcpu switches harts
+    pub vstimecmp: usize,
+    // interrupt file register guest selected with aia,moved like vstimecmp
+    pub vsiselect: usize,
+}
+
+/// f and d registers of a vcpu
+#[repr(C)]
+#[derive(Copy, Clone, Default)]
+pub struct FpState {
+    pub f: [u64; 32],
+    pub fcsr: usize,
+}
+
+pub const SSTATUS_FS: usize = 3 << 13;
+const FS_OFF: usize = 0;
+const FS_INITIAL: usize = 1 << 13;
+const FS_CLEAN: usize = 2 << 13;
+const FS_DIRTY: usize = 3 << 13;
+pub const SSTATUS_VS: usize = 3 << 9;
+const VS_OFF: usize = 0;
+const VS_INITIAL: usize = 1 << 9;
+const VS_CLEAN: usize = 2 << 9;
+const VS_DIRTY: usize = 3 << 9;
+
+// guest wfi traps as virtual instruction
+const HSTATUS_VTW: usize = 1 << 21;
+// guest file of hart vcpu takes vs external interrupts from,0 for none
+const HSTATUS_VGEIN_SHIFT: usize = 12;
+const HSTATUS_VGEIN: usize = 0x3f << HSTATUS_VGEIN_SHIFT;
+// vs software,timer and external interrupts in hvip
+const HVIP_VS_INTERRUPTS: usize = 1 << 2 | 1 << 6 | 1 << 10;
+
+const HVIP_VSTIP: usize = 1 << 6;
+const HVIP_VSEIP: usize = 1 << 10;
+// guest programs its timer by stimecmp
+const HENVCFG_STCE: usize = 1 << 63;
+
+// guest reads cycle,time and instret without trapping
+const HCOUNTEREN_CY_TM_IR: usize = 0b111;
+
+// trap.S stores xn at n*8 and fn at n*8,and reaches every field by a 12 bit immediate
+const _: () = {
+    assert!(offset_of!(TrapContext, regs) == 0);
+    assert!(offset_of!(FpState, f) == 0);
+    assert!(size_of::<VectorCsrs>() < 2048);
+    assert!(size_of::<usize>() == 8);
+    assert!(size_of::<TrapContext>() < 2048);
+};
+
+extern "C" {
+    fn __fp_save(fp: *mut FpState);
+    fn __fp_restore(fp: *const FpState);
+    fn __vlenb() -> usize;
+    fn __vector_save(csrs: *mut VectorCsrs, regs: *mut u8);
+    fn __vector_restore(csrs: *const VectorCsrs, regs: *const u8);
+}
+
+/// interrupts hypervisor injects into vs mode,by their supervisor interrupt number
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VirtIrq {
+    Software = 1,
+    Timer = 5,
+    External = 9,
+}
+
+impl VirtIrq {
+    pub const ALL: [Self; 3] = [Self::Software, Self::Timer, Self::External];
+
+    /// vs interrupt sits one bit above its supervisor one in hvip
+    #[inline]
+    pub fn hvip_bit(self) -> usize {
+        1 << (self as usize + 1)
+    }
+}
+
+/// vector csrs of a vcpu
+#[repr(C)]
+#[derive(Copy, Clone, Default)]
+pub struct VectorCsrs {
+    pub vstart: usize,
+    pub vl: usize,
+    pub vtype: usize,
+    pub vcsr: usize,
+}
+
+/// v registers of a vcpu,32 of vlenb bytes each
+#[derive(Clone)]
+pub struct VectorState {
+    csrs: VectorCsrs,
+    regs: Vec<u8>,
+}
+
+static HOST_VLENB: Once<Option<usize>> = Once::new();
+
+/// bytes in a v register of host harts,none if they have no vector extension
+pub fn host_vlenb() -> Option<usize> {
+    *HOST_VLENB.call_once(|| host_has_extension('v').then(|| unsafe { __vlenb() }))
+}
+
+impl VectorState {
+    /// zeroed registers,none if host harts have no vector extension
+    pub fn new() -> Option<Self> {
+        let vlenb = host_vlenb()?;
+        Some(Self {
+            csrs: VectorCsrs::default(),
+            regs: vec![0; 32 * vlenb],
+        })
+    }
 }
 
 impl TrapContext {
@@ -30,23 +173,170 @@ impl TrapContext {
         self.regs[2] = sp;
     }
 
+    /// set argument registers a0,a1... in order
+    pub fn set_args(&mut self, args: &[usize]) {
+        assert!(
+            args.len() <= 8,
+            "[TrapContext] only a0-a7 are argument registers"
+        );
+        self.regs[10..10 + args.len()].copy_from_slice(args);
+    }
+
     /// set init context, include stack in hyp address space hgatp for the vcpu,return pl and address
     /// in guest address space
     pub fn init_context(entry: usize, stack_ptr: usize, hgatp: usize, trap_handler: usize) -> Self {
         let mut sstatus = sstatus::read();
         // return to s mode
         sstatus.set_spp(SPP::Supervisor);
+        // f registers start zeroed,they are loaded on first run of vcpu
+        // and v registers are off unless vcpu gets vector state
+        let sstatus = sstatus.bits() & !SSTATUS_FS & !SSTATUS_VS | FS_INITIAL;
         let mut hstatus = hstatus::read();
         // return to virtual pl(VS or VU)
         hstatus.set_spv(true);
         Self {
             regs: [0; 32],
-            sstatus: sstatus.bits(),
-            hstatus: hstatus.bits(),
+            sstatus,
+            hstatus: hstatus.bits() & !HSTATUS_VGEIN | HSTATUS_VTW,
             sepc: entry,
             guest_hyp_stack: stack_ptr,
             trap_handler,
             hgatp,
+            hyp_tp: 0,
+            csrs: VcpuCsrs {
+                hcounteren: HCOUNTEREN_CY_TM_IR,
+                henvcfg: if host_has_multi_extension("sstc") {
+                    HENVCFG_STCE
+                } else {
+                    0
+                },
+                // no timer until guest sets one
+                vstimecmp: usize::MAX,
+                hie: sgei_hie(),
+                ..VcpuCsrs::default()
+            },
+            fp: FpState::default(),
+        }
+    }
+
+    /// save f registers of vcpu leaving this hart if guest wrote them since they were loaded
+    ///
+    /// hypervisor has no float code,so f registers of a hart keep what the vcpu on it left there
+    /// across vm exits
+    pub fn save_fp(&mut self) {
+        if self.sstatus & SSTATUS_FS == FS_DIRTY {
+            unsafe { __fp_save(&mut self.fp) };
+            self.sstatus = self.sstatus & !SSTATUS_FS | FS_CLEAN;
+        }
+    }
+
+    /// load f registers of vcpu going to run on this hart,unless guest has fp off
+    pub fn restore_fp(&mut self) {
+        if self.sstatus & SSTATUS_FS != FS_OFF {
+            unsafe { __fp_restore(&self.fp) };
+            self.sstatus = self.sstatus & !SSTATUS_FS | FS_CLEAN;
+        }
+    }
+
+    /// hypervisor raised a virtual interrupt for vcpu
+    #[inline]
+    pub fn has_virtual_irq(&self) -> bool {
+        self.csrs.hvip & HVIP_VS_INTERRUPTS != 0
+    }
+
+    /// guest sets its timer by stimecmp,not by sbi
+    #[inline]
+    pub fn has_sstc(&self) -> bool {
+        self.csrs.henvcfg & HENVCFG_STCE != 0
+    }
+
+    #[inline]
+    fn set_hvip(&mut self, bit: usize, pending: bool) {
+        if pending {
+            self.csrs.hvip |= bit;
+        } else {
+            self.csrs.hvip &= !bit;
+        }
+    }
+
+    /// timer interrupt of sbi timer is pending for vcpu,or not
+    #[inline]
+    pub fn set_timer_pending(&mut self, pending: bool) {
+        self.set_hvip(HVIP_VSTIP, pending);
+    }
+
+    /// external interrupt of interrupt file hypervisor emulates is pending for vcpu,or not
+    #[inline]
+    pub fn set_external_pending(&mut self, pending: bool) {
+        self.set_hvip(HVIP_VSEIP, pending);
+    }
+
+    /// injected interrupt is pending for vcpu,or not
+    #[inline]
+    pub fn set_virtual_irq(&mut self, irq: VirtIrq, pending: bool) {
+        self.set_hvip(irq.hvip_bit(), pending);
+    }
+
+    /// vcpu takes vs external interrupts from guest file index of the hart it runs on,0 for none
+    pub fn set_guest_file(&mut self, index: usize) {
+        self.hstatus = self.hstatus & !HSTATUS_VGEIN | index << HSTATUS_VGEIN_SHIFT;
+    }
+
+    #[inline]
+    pub fn guest_file(&self) -> usize {
+        (self.hstatus & HSTATUS_VGEIN) >> HSTATUS_VGEIN_SHIFT
+    }
+
+    /// read vsiselect of vcpu leaving this hart,harts without aia have none
+    pub fn save_vsiselect(&mut self) {
+        if host_imsic().is_some() {
+            // vsiselect
+            unsafe { core::arch::asm!("csrr {}, 0x250", out(reg) self.csrs.vsiselect) };
+        }
+    }
+
+    /// load vsiselect of vcpu going to run on this hart
+    pub fn restore_vsiselect(&self) {
+        if host_imsic().is_some() {
+            unsafe { core::arch::asm!("csrw 0x250, {}", in(reg) self.csrs.vsiselect) };
+        }
+    }
+
+    /// read vstimecmp of vcpu leaving this hart
+    pub fn save_vstimecmp(&mut self) {
+        if self.has_sstc() {
+            unsafe { core::arch::asm!("csrr {}, vstimecmp", out(reg) self.csrs.vstimecmp) };
+        }
+    }
+
+    /// load vstimecmp of vcpu going to run on this hart
+    pub fn restore_vstimecmp(&self) {
+        if self.has_sstc() {
+            unsafe { core::arch::asm!("csrw vstimecmp, {}", in(reg) self.csrs.vstimecmp) };
+        }
+    }
+
+    /// let guest use v registers,they start zeroed
+    pub fn enable_vector(&mut self) {
+        self.sstatus = self.sstatus & !SSTATUS_VS | VS_INITIAL;
+    }
+
+    /// save v registers of vcpu leaving this hart if guest wrote them since they were loaded
+    ///
+    /// a vector write sets vs of both sstatus and vsstatus dirty,we go by sstatus as guest can't
+    /// turn it off. hypervisor has no vector code either
+    pub fn save_vector(&mut self, vector: &mut VectorState) {
+        if self.sstatus & SSTATUS_VS == VS_DIRTY {
+            unsafe { __vector_save(&mut vector.csrs, vector.regs.as_mut_ptr()) };
+            self.sstatus = self.sstatus & !SSTATUS_VS | VS_CLEAN;
+        }
+    }
+
+    /// load v registers of vcpu going to run on this hart,unless it has vector off
+    pub fn restore_vector(&mut self, vector: &VectorState) {
+        if self.sstatus & SSTATUS_VS != VS_OFF {
+            unsafe { __vector_restore(&vector.csrs, vector.regs.as_ptr()) };
+            self.sstatus = self.sstatus & !SSTATUS_VS | VS_CLEAN;
         }
     }
 }
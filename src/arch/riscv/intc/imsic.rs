@@ -0,0 +1,434 @@
+//! incoming msi controller of host harts,from aia
+//!
+//! every hart has a supervisor interrupt file and guest interrupt files in the pages after it. a
+//! vcpu running with hstatus.vgein = n takes vs external interrupts from guest file n of its hart
+//! directly,and guest writes to a g stage page mapped to that file raise msis with no exit. while
+//! the vcpu is off its hart,an msi in its file raises supervisor guest external interrupt on that
+//! hart instead,if the hgeie bit of the file is set
+//!
+//! registers of a file are reached through siselect and sireg. hypervisor reads and writes those of
+//! guest file n on this hart through vsiselect and vsireg with hstatus.vgein = n
+
+use super::hart_of_intc;
+use crate::constants::{MAX_HARTS, PAGE_SIZE};
+use crate::device_tree::host_fdt;
+use crate::mm::{hpm_guard, MapPermission};
+use crate::println;
+use riscv::register::hstatus;
+use spin::{Mutex, Once};
+
+// file registers behind siselect
+pub const EIDELIVERY: usize = 0x70;
+pub const EITHRESHOLD: usize = 0x72;
+pub const EIP_BASE: usize = 0x80;
+pub const EIE_BASE: usize = 0xc0;
+// csrs guest reaches its file through,they trap as virtual instruction without a guest file
+pub const CSR_SIREG: usize = 0x151;
+pub const CSR_STOPEI: usize = 0x15c;
+// interrupt ids of a file,id 0 is never used
+pub const MAX_IDS: usize = 256;
+const WORDS: usize = MAX_IDS / 64;
+
+// supervisor external interrupt in cpu local interrupt controller
+const IRQ_S_EXT: usize = 9;
+const HSTATUS_VGEIN_SHIFT: usize = 12;
+const HSTATUS_VGEIN: usize = 0x3f << HSTATUS_VGEIN_SHIFT;
+const HIE_SGEIE: usize = 1 << 12;
+
+/// guest interrupt file index of a hart,index starts at 1
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GuestFile {
+    pub hart: usize,
+    pub index: usize,
+}
+
+/// registers of an interrupt file,for files emulated by hypervisor and guest files moved off a hart
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FileState {
+    pub eidelivery: usize,
+    pub eithreshold: usize,
+    pub eip: [u64; WORDS],
+    pub eie: [u64; WORDS],
+}
+
+impl FileState {
+    /// register siselect selects,none if there is no such register. eip and eie are 64 bit,odd
+    /// indexes don't exist on rv64
+    pub fn read(&self, select: usize) -> Option<usize> {
+        match select {
+            EIDELIVERY => Some(self.eidelivery),
+            EITHRESHOLD => Some(self.eithreshold),
+            EIP_BASE..=0xbf if select % 2 == 0 => {
+                Some(self.eip.get((select - EIP_BASE) / 2).copied().unwrap_or(0) as usize)
+            }
+            EIE_BASE..=0xff if select % 2 == 0 => {
+                Some(self.eie.get((select - EIE_BASE) / 2).copied().unwrap_or(0) as usize)
+            }
+            _ => None,
+        }
+    }
+
+    /// return false if there is no such register
+    pub fn write(&mut self, select: usize, value: usize) -> bool {
+        match select {
+            EIDELIVERY => self.eidelivery = value & 0x1,
+            EITHRESHOLD => self.eithreshold = value & (MAX_IDS - 1),
+            EIP_BASE..=0xbf if select % 2 == 0 => {
+                if let Some(eip) = self.eip.get_mut((select - EIP_BASE) / 2) {
+                    *eip = value as u64;
+                }
+            }
+            EIE_BASE..=0xff if select % 2 == 0 => {
+                if let Some(eie) = self.eie.get_mut((select - EIE_BASE) / 2) {
+                    *eie = value as u64;
+                }
+            }
+            _ => return false,
+        }
+        self.eip[0] &= !1;
+        self.eie[0] &= !1;
+        true
+    }
+
+    /// msi with id arrived
+    pub fn set_pending(&mut self, id: usize) {
+        if id != 0 && id < MAX_IDS {
+            self.eip[id / 64] |= 1 << (id % 64);
+        }
+    }
+
+    /// lowest id pending and enabled under threshold,what topei reads
+    pub fn top(&self) -> Option<usize> {
+        let limit = match self.eithreshold {
+            0 => MAX_IDS,
+            threshold => threshold,
+        };
+        (0..WORDS)
+            .map(|word| (word, self.eip[word] & self.eie[word]))
+            .find(|&(_, bits)| bits != 0)
+            .map(|(word, bits)| word * 64 + bits.trailing_zeros() as usize)
+            .filter(|&id| id < limit)
+    }
+
+    /// interrupt of file is asserted to its hart
+    #[inline]
+    pub fn pending(&self) -> bool {
+        self.eidelivery == 1 && self.top().is_some()
+    }
+
+    /// topei is written,top interrupt is claimed
+    pub fn claim(&mut self) {
+        if let Some(id) = self.top() {
+            self.eip[id / 64] &= !(1 << (id % 64));
+        }
+    }
+}
+
+pub struct HostImsic {
+    // supervisor file of each hart,0 if hart has none
+    files: [usize; MAX_HARTS],
+    // guest file indexes hgeie implements
+    usable: usize,
+    // guest files not handed out,index mask of each hart
+    free: Mutex<[usize; MAX_HARTS]>,
+    num_ids: usize,
+}
+
+static HOST_IMSIC: Once<Option<HostImsic>> = Once::new();
+
+/// guest file indexes this hart implements in hgeie
+fn probe_guest_files() -> usize {
+    let usable: usize;
+    unsafe {
+        // hgeie
+        core::arch::asm!(
+            "csrw 0x607, {all}",
+            "csrr {usable}, 0x607",
+            "csrw 0x607, zero",
+            all = in(reg) usize::MAX,
+            usable = out(reg) usable,
+        );
+    }
+    usable & !1
+}
+
+/// find supervisor imsic of host harts in host device tree and map its pages,called once by boot
+/// hart. a host without one runs guests with emulated interrupt controllers only
+pub fn init_imsic() {
+    HOST_IMSIC.call_once(|| {
+        let fdt = host_fdt();
+        let node = fdt.all_nodes().find(|node| {
+            let compatible = node.compatible().map_or(false, |compatible| {
+                compatible.all().any(|c| c == "riscv,imsics")
+            });
+            // m mode imsic is wired to machine external interrupt
+            let supervisor = node.property("interrupts-extended").map_or(false, |prop| {
+                prop.value.chunks_exact(8).all(|pair| {
+                    u32::from_be_bytes(pair[4..].try_into().unwrap()) as usize == IRQ_S_EXT
+                })
+            });
+            compatible && supervisor
+        })?;
+        let base = node.reg()?.next()?.starting_address as usize;
+        let guest_bits = node
+            .property("riscv,guest-index-bits")
+            .and_then(|prop| prop.as_usize())
+            .unwrap_or(0);
+        let stride = PAGE_SIZE << guest_bits;
+        let mut files = [0; MAX_HARTS];
+        // file of ith hart in interrupts-extended is ith stride from base
+        let intcs = node.property("interrupts-extended")?.value.chunks_exact(8);
+        let window = intcs.len() * stride;
+        for (i, pair) in intcs.enumerate() {
+            let phandle = u32::from_be_bytes(pair[..4].try_into().unwrap()) as usize;
+            match hart_of_intc(phandle) {
+                Some(hart) if hart < MAX_HARTS => files[hart] = base + i * stride,
+                _ => {}
+            }
+        }
+        hpm_guard().map_physical(base, window, MapPermission::R | MapPermission::W);
+        // guest files of a hart are the pages after its supervisor file
+        let pages = 1usize << guest_bits;
+        let usable = probe_guest_files() & usize::MAX >> (usize::BITS as usize - pages);
+        let num_ids = node
+            .property("riscv,num-ids")
+            .and_then(|prop| prop.as_usize())
+            .map_or(MAX_IDS - 1, |ids| ids.min(MAX_IDS - 1));
+        println!(
+            "[hypervisor] imsic at {:#x},{} guest files per hart",
+            base,
+            usable.count_ones()
+        );
+        let free = files.map(|file| if file != 0 { usable } else { 0 });
+        Some(HostImsic {
+            files,
+            usable,
+            free: Mutex::new(free),
+            num_ids,
+        })
+    });
+}
+
+/// imsic of host harts,none without aia
+#[inline]
+pub fn host_imsic() -> Option<&'static HostImsic> {
+    HOST_IMSIC.get().and_then(|imsic| imsic.as_ref())
+}
+
+impl HostImsic {
+    /// largest interrupt id of a file
+    #[inline]
+    pub fn num_ids(&self) -> usize {
+        self.num_ids
+    }
+
+    /// guest files every hart has
+    #[inline]
+    pub fn guest_files(&self) -> usize {
+        self.usable.count_ones() as usize
+    }
+
+    /// free guest file on a hart of affinity,from the one with most of them left
+    pub fn alloc(&self, affinity: usize) -> Option<GuestFile> {
+        let mut free = self.free.lock();
+        let hart = (0..MAX_HARTS)
+            .filter(|&hart| affinity & 1 << hart != 0 && free[hart] != 0)
+            .max_by_key(|&hart| free[hart].count_ones())?;
+        let index = free[hart].trailing_zeros() as usize;
+        free[hart] &= !(1 << index);
+        Some(GuestFile { hart, index })
+    }
+
+    pub fn free(&self, file: GuestFile) {
+        self.free.lock()[file.hart] |= 1 << file.index;
+    }
+
+    /// physical page of guest file
+    #[inline]
+    pub fn file_pa(&self, file: GuestFile) -> usize {
+        self.files[file.hart] + file.index * PAGE_SIZE
+    }
+
+    /// raise msi with id in guest file,from any hart
+    pub fn send(&self, file: GuestFile, id: usize) {
+        // seteipnum_le at offset 0
+        unsafe { core::ptr::write_volatile(self.file_pa(file) as *mut u32, id as u32) };
+    }
+}
+
+/// let supervisor guest external interrupts through on this hart,vcpu contexts keep the bit too
+pub fn enable_sgei() {
+    unsafe { core::arch::asm!("csrs hie, {}", in(reg) HIE_SGEIE) };
+}
+
+/// hie bit vcpus carry so supervisor guest external interrupts stay enabled while they run
+#[inline]
+pub fn sgei_hie() -> usize {
+    host_imsic().map_or(0, |_| HIE_SGEIE)
+}
+
+/// msi in guest file index of this hart raises supervisor guest external interrupt,or not
+pub fn set_guest_irq(index: usize, enable: bool) {
+    unsafe {
+        // hgeie
+        if enable {
+            core::arch::asm!("csrs 0x607, {}", in(reg) 1usize << index);
+        } else {
+            core::arch::asm!("csrc 0x607, {}", in(reg) 1usize << index);
+        }
+    }
+}
+
+/// guest files of this hart with an interrupt pending
+#[inline]
+pub fn pending_guest_irqs() -> usize {
+    let pending: usize;
+    // hgeip
+    unsafe { core::arch::asm!("csrr {}, 0xe12", out(reg) pending) };
+    pending
+}
+
+/// guest files of this hart which raised supervisor guest external interrupt,their hgeie bits are
+/// cleared so a level interrupt doesn't fire again before their vcpus run
+pub fn take_guest_irqs() -> usize {
+    let enabled: usize;
+    unsafe { core::arch::asm!("csrr {}, 0x607", out(reg) enabled) };
+    let taken = pending_guest_irqs() & enabled;
+    unsafe { core::arch::asm!("csrc 0x607, {}", in(reg) taken) };
+    taken
+}
+
+/// file register vcpu running on this hart selected
+#[inline]
+pub fn read_vsiselect() -> usize {
+    let select: usize;
+    // vsiselect
+    unsafe { core::arch::asm!("csrr {}, 0x250", out(reg) select) };
+    select
+}
+
+/// run f with vsiselect and vsireg reaching guest file index of this hart
+unsafe fn with_guest_file<R>(index: usize, f: impl FnOnce() -> R) -> R {
+    let hstatus = hstatus::read().bits();
+    let vsiselect: usize;
+    // vsiselect
+    core::arch::asm!("csrr {}, 0x250", out(reg) vsiselect);
+    let vgein = hstatus & !HSTATUS_VGEIN | index << HSTATUS_VGEIN_SHIFT;
+    core::arch::asm!("csrw hstatus, {}", in(reg) vgein);
+    let ret = f();
+    core::arch::asm!("csrw 0x250, {}", in(reg) vsiselect);
+    core::arch::asm!("csrw hstatus, {}", in(reg) hstatus);
+    ret
+}
+
+#[inline]
+unsafe fn read_vsireg(select: usize) -> usize {
+    let value: usize;
+    // vsiselect,vsireg
+    core::arch::asm!("csrw 0x250, {}", "csrr {}, 0x251", in(reg) select, out(reg) value);
+    value
+}
+
+#[inline]
+unsafe fn write_vsireg(select: usize, value: usize) {
+    core::arch::asm!("csrw 0x250, {}", "csrw 0x251, {}", in(reg) select, in(reg) value);
+}
+
+/// registers of guest file index of this hart
+pub fn save_guest_file(index: usize) -> FileState {
+    unsafe {
+        with_guest_file(index, || {
+            let mut state = FileState {
+                eidelivery: read_vsireg(EIDELIVERY),
+                eithreshold: read_vsireg(EITHRESHOLD),
+                ..FileState::default()
+            };
+            for word in 0..WORDS {
+                state.eip[word] = read_vsireg(EIP_BASE + word * 2) as u64;
+                state.eie[word] = read_vsireg(EIE_BASE + word * 2) as u64;
+            }
+            state
+        })
+    }
+}
+
+/// load registers of guest file index of this hart from state
+pub fn restore_guest_file(index: usize, state: &FileState) {
+    unsafe {
+        with_guest_file(index, || {
+            write_vsireg(EIDELIVERY, state.eidelivery);
+            write_vsireg(EITHRESHOLD, state.eithreshold);
+            for word in 0..WORDS {
+                write_vsireg(EIE_BASE + word * 2, state.eie[word] as usize);
+                write_vsireg(EIP_BASE + word * 2, state.eip[word] as usize);
+            }
+        })
+    }
+}
+
+/// guest file index of this hart back to reset state,nothing pending or enabled
+pub fn clear_guest_file(index: usize) {
+    unsafe {
+        with_guest_file(index, || {
+            write_vsireg(EIDELIVERY, 0);
+            write_vsireg(EITHRESHOLD, 0);
+            for word in 0..WORDS {
+                write_vsireg(EIE_BASE + word * 2, 0);
+                write_vsireg(EIP_BASE + word * 2, 0);
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn file_registers() {
+        let mut file = FileState::default();
+        assert!(file.write(EIDELIVERY, 0x3));
+        assert_eq!(file.read(EIDELIVERY), Some(1));
+        assert!(file.write(EITHRESHOLD, MAX_IDS + 5));
+        assert_eq!(file.read(EITHRESHOLD), Some(5));
+        // id 0 never pends nor enables
+        assert!(file.write(EIP_BASE, usize::MAX));
+        assert!(file.write(EIE_BASE + 2, 0x10));
+        assert_eq!(file.read(EIP_BASE), Some(usize::MAX - 1));
+        assert_eq!(file.read(EIE_BASE + 2), Some(0x10));
+        // odd indexes and registers beyond the file don't exist
+        assert!(!file.write(EIP_BASE + 1, 1));
+        assert_eq!(file.read(EIE_BASE + 1), None);
+        assert_eq!(file.read(0x71), None);
+        assert_eq!(file.read(EIP_BASE + 2 * WORDS), Some(0));
+    }
+
+    #[test]
+    fn top_pending_and_claim() {
+        let mut file = FileState::default();
+        file.set_pending(0);
+        file.set_pending(MAX_IDS);
+        assert_eq!(file.eip, [0; WORDS]);
+        file.set_pending(70);
+        file.set_pending(9);
+        assert_eq!(file.top(), None);
+        file.write(EIE_BASE + 2, 1 << 6);
+        assert_eq!(file.top(), Some(70));
+        // not delivered until eidelivery is set
+        assert!(!file.pending());
+        file.write(EIDELIVERY, 1);
+        assert!(file.pending());
+        file.write(EIE_BASE, 1 << 9);
+        assert_eq!(file.top(), Some(9));
+        // threshold masks ids from it up
+        file.write(EITHRESHOLD, 9);
+        assert_eq!(file.top(), None);
+        assert!(!file.pending());
+        file.write(EITHRESHOLD, 0);
+        file.claim();
+        assert_eq!(file.top(), Some(70));
+        file.claim();
+        assert_eq!(file.top(), None);
+        assert_eq!(file.eip, [0; WORDS]);
+    }
+}
@@ -1 +1,24 @@
-mod plic;
\ No newline at end of file
+//! interrupt controllers of host harts
+
+pub mod imsic;
+pub mod plic;
+
+use crate::device_tree::host_fdt;
+
+/// hart whose cpu interrupt controller has phandle
+fn hart_of_intc(phandle: usize) -> Option<usize> {
+    host_fdt()
+        .find_node("/cpus")?
+        .children()
+        .find(|cpu| {
+            cpu.children()
+                .filter(|child| child.name.starts_with("interrupt-controller"))
+                .any(|intc| {
+                    intc.property("phandle")
+                        .and_then(|prop| prop.as_usize())
+                        .map_or(false, |handle| handle == phandle)
+                })
+        })?
+        .property("reg")?
+        .as_usize()
+}
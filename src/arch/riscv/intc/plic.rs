@@ -1,20 +1,169 @@
+//! platform level interrupt controller of host
+//!
+//! only supervisor contexts are used,context of a hart is the one wired to its supervisor external
+//! interrupt. a source is enabled on every hart with priority 1 and threshold 0,whichever hart
+//! claims it first handles it. any hart may complete it then,see `hypervisor::irq`
+
+use super::hart_of_intc;
+use crate::constants::MAX_HARTS;
+use crate::device_tree::host_fdt;
+use crate::mm::{hpm_guard, MapPermission};
+use crate::percpu;
+use crate::println;
+use spin::{Mutex, Once};
+
 const MAX_INT_SOURCE_ID: usize = 1023;
 const MAX_CONTEXT: usize = 15872;
-const PENDING_BASE: usize = 0x1000;
 const ENABLE_BASE: usize = 0x2000;
+const ENABLE_STRIDE: usize = 0x80;
+const CONTEXT_BASE: usize = 0x20_0000;
+const CONTEXT_STRIDE: usize = 0x1000;
+const CONTEXT_THRESHOLD: usize = 0x0;
+const CONTEXT_CLAIM: usize = 0x4;
+
+// supervisor external interrupt in cpu local interrupt controller
+const IRQ_S_EXT: usize = 9;
 
 pub struct Plic {
     base_addr: usize,
+    // sources are 1..=ndev
+    ndev: usize,
+    // supervisor context of each hart
+    contexts: [Option<usize>; MAX_HARTS],
+    // enable words are shared by harts,read modify write under it
+    enable_lock: Mutex<()>,
 }
 
+static HOST_PLIC: Once<Option<Plic>> = Once::new();
+
 impl Plic {
     #[inline(always)]
     pub unsafe fn new(base_addr: usize) -> Self {
-        Self { base_addr }
+        Self {
+            base_addr,
+            ndev: MAX_INT_SOURCE_ID,
+            contexts: [None; MAX_HARTS],
+            enable_lock: Mutex::new(()),
+        }
     }
 
     pub unsafe fn priority_ptr(&self, int_source_id: usize) -> *mut u32 {
         assert!(int_source_id > 0 && int_source_id <= MAX_INT_SOURCE_ID);
         (self.base_addr + int_source_id * 4) as *mut u32
     }
+
+    unsafe fn enable_ptr(&self, context: usize, int_source_id: usize) -> *mut u32 {
+        assert!(context < MAX_CONTEXT);
+        (self.base_addr + ENABLE_BASE + context * ENABLE_STRIDE + int_source_id / 32 * 4)
+            as *mut u32
+    }
+
+    unsafe fn context_ptr(&self, context: usize, reg: usize) -> *mut u32 {
+        assert!(context < MAX_CONTEXT);
+        (self.base_addr + CONTEXT_BASE + context * CONTEXT_STRIDE + reg) as *mut u32
+    }
+
+    #[inline]
+    pub fn has_source(&self, source: u32) -> bool {
+        source != 0 && source as usize <= self.ndev
+    }
+
+    /// priority 0 never interrupts
+    pub fn set_priority(&self, source: u32, priority: u32) {
+        if self.has_source(source) {
+            unsafe { self.priority_ptr(source as usize).write_volatile(priority) };
+        }
+    }
+
+    /// let source interrupt supervisor context of every hart,or none of them
+    pub fn set_enabled(&self, source: u32, enable: bool) {
+        if !self.has_source(source) {
+            return;
+        }
+        let _guard = self.enable_lock.lock();
+        for context in self.contexts.iter().flatten() {
+            unsafe {
+                let ptr = self.enable_ptr(*context, source as usize);
+                let bits = ptr.read_volatile();
+                let bit = 1 << (source % 32);
+                ptr.write_volatile(if enable { bits | bit } else { bits & !bit });
+            }
+        }
+    }
+
+    /// context of this hart,none if it isn't wired to the plic
+    #[inline]
+    fn this_context(&self) -> Option<usize> {
+        self.contexts[percpu::hart_id()]
+    }
+
+    /// highest priority source pending for this hart,it's not offered to any hart until completed
+    pub fn claim(&self) -> Option<u32> {
+        let context = self.this_context()?;
+        let source = unsafe { self.context_ptr(context, CONTEXT_CLAIM).read_volatile() };
+        Some(source).filter(|&source| source != 0)
+    }
+
+    /// source claimed by some hart is handled,it may interrupt again. it must still be enabled
+    pub fn complete(&self, source: u32) {
+        if let Some(context) = self.this_context() {
+            unsafe {
+                self.context_ptr(context, CONTEXT_CLAIM)
+                    .write_volatile(source)
+            };
+        }
+    }
+}
+
+/// find supervisor plic of host in host device tree,map its registers and mask every source,called
+/// once by boot hart. a host without one takes no external interrupts
+pub fn init_plic() {
+    HOST_PLIC.call_once(|| {
+        let fdt = host_fdt();
+        let node = fdt.all_nodes().find(|node| {
+            node.compatible().map_or(false, |compatible| {
+                compatible
+                    .all()
+                    .any(|c| c == "riscv,plic0" || c == "sifive,plic-1.0.0")
+            })
+        })?;
+        let reg = node.reg()?.next()?;
+        let base = reg.starting_address as usize;
+        hpm_guard().map_physical(base, reg.size?, MapPermission::R | MapPermission::W);
+        let mut plic = unsafe { Plic::new(base) };
+        plic.ndev = node
+            .property("riscv,ndev")
+            .and_then(|prop| prop.as_usize())
+            .map_or(MAX_INT_SOURCE_ID, |ndev| ndev.min(MAX_INT_SOURCE_ID));
+        // ith pair of interrupts-extended is context i,m mode ones are left alone
+        let intcs = node.property("interrupts-extended")?.value.chunks_exact(8);
+        for (context, pair) in intcs.enumerate() {
+            let phandle = u32::from_be_bytes(pair[..4].try_into().unwrap()) as usize;
+            let irq = u32::from_be_bytes(pair[4..].try_into().unwrap()) as usize;
+            match hart_of_intc(phandle) {
+                Some(hart) if hart < MAX_HARTS && irq == IRQ_S_EXT => {
+                    plic.contexts[hart] = Some(context)
+                }
+                _ => {}
+            }
+        }
+        for source in 1..=plic.ndev as u32 {
+            plic.set_priority(source, 0);
+            plic.set_enabled(source, false);
+        }
+        for context in plic.contexts.iter().flatten() {
+            unsafe {
+                plic.context_ptr(*context, CONTEXT_THRESHOLD)
+                    .write_volatile(0)
+            };
+        }
+        println!("[hypervisor] plic at {:#x},{} sources", base, plic.ndev);
+        Some(plic)
+    });
+}
+
+/// plic of host,none if host has no plic
+#[inline]
+pub fn host_plic() -> Option<&'static Plic> {
+    HOST_PLIC.get().and_then(|plic| plic.as_ref())
 }
@@ -0,0 +1,230 @@
+//! decode load/store instruction which traps on guest mmio access,and csr instruction which traps
+//! as virtual instruction
+
+use riscv::register::htinst;
+
+/// what a trapped load/store does
+#[derive(Debug, Clone, Copy)]
+pub struct MmioInsn {
+    pub is_store: bool,
+    // access width in bytes
+    pub width: usize,
+    // rd of load or rs2 of store
+    pub reg: usize,
+    // load should sign extend value to xlen
+    pub signed: bool,
+    // instruction length,sepc moves forward it after emulation
+    pub len: usize,
+}
+
+const OPCODE_LOAD: usize = 0x03;
+const OPCODE_STORE: usize = 0x23;
+const OPCODE_SYSTEM: usize = 0x73;
+
+/// csrrw,csrrs,csrrc and their immediate forms
+#[derive(Debug, Clone, Copy)]
+pub struct CsrInsn {
+    pub csr: usize,
+    pub rd: usize,
+    // rs1,or zero extended immediate
+    pub src: usize,
+    pub imm: bool,
+    // funct3 & 0x3,1 write,2 set,3 clear
+    op: usize,
+}
+
+impl CsrInsn {
+    /// csr value after instruction given old value and value of source,none if it doesn't write
+    pub fn new_value(&self, old: usize, operand: usize) -> Option<usize> {
+        match self.op {
+            1 => Some(operand),
+            // set and clear with x0 or 0 only read
+            _ if self.src == 0 => None,
+            2 => Some(old | operand),
+            _ => Some(old & !operand),
+        }
+    }
+}
+
+/// decode csr instruction guest trapped on,stval of virtual instruction holds it
+pub fn decode_csr_insn(insn: usize) -> Option<CsrInsn> {
+    let funct3 = (insn >> 12) & 0x7;
+    if insn & 0x7f != OPCODE_SYSTEM || funct3 & 0x3 == 0 {
+        return None;
+    }
+    Some(CsrInsn {
+        csr: insn >> 20 & 0xfff,
+        rd: (insn >> 7) & 0x1f,
+        src: (insn >> 15) & 0x1f,
+        imm: funct3 & 0x4 != 0,
+        op: funct3 & 0x3,
+    })
+}
+
+fn decode_standard(insn: usize, len: usize) -> Option<MmioInsn> {
+    let funct3 = (insn >> 12) & 0x7;
+    match insn & 0x7f {
+        OPCODE_LOAD => {
+            let (width, signed) = match funct3 {
+                0 => (1, true),
+                1 => (2, true),
+                2 => (4, true),
+                3 => (8, false),
+                4 => (1, false),
+                5 => (2, false),
+                6 => (4, false),
+                _ => return None,
+            };
+            Some(MmioInsn {
+                is_store: false,
+                width,
+                reg: (insn >> 7) & 0x1f,
+                signed,
+                len,
+            })
+        }
+        OPCODE_STORE if funct3 <= 3 => Some(MmioInsn {
+            is_store: true,
+            width: 1 << funct3,
+            reg: (insn >> 20) & 0x1f,
+            signed: false,
+            len,
+        }),
+        _ => None,
+    }
+}
+
+/// c.lw c.ld c.sw c.sd in quadrant 0
+fn decode_compressed(insn: usize) -> Option<MmioInsn> {
+    if insn & 0x3 != 0 {
+        return None;
+    }
+    // rd' or rs2' is x8 - x15
+    let reg = ((insn >> 2) & 0x7) + 8;
+    let (is_store, width) = match (insn >> 13) & 0x7 {
+        0b010 => (false, 4),
+        0b011 => (false, 8),
+        0b110 => (true, 4),
+        0b111 => (true, 8),
+        _ => return None,
+    };
+    Some(MmioInsn {
+        is_store,
+        width,
+        reg,
+        signed: !is_store && width == 4,
+        len: 2,
+    })
+}
+
+/// read 16 bits instruction parcel from guest virtual address with vs stage translation
+unsafe fn read_guest_insn_parcel(va: usize) -> usize {
+    let parcel: usize;
+    core::arch::asm!("hlvx.hu {0}, ({1})", out(reg) parcel, in(reg) va);
+    parcel
+}
+
+/// fetch compressed or standard guest instruction at guest virtual address va
+pub unsafe fn fetch_guest_insn(va: usize) -> usize {
+    let low = read_guest_insn_parcel(va);
+    if low & 0x3 != 0x3 {
+        low
+    } else {
+        low | read_guest_insn_parcel(va + 2) << 16
+    }
+}
+
+/// decode the load/store which causes current guest page fault
+///
+/// use transformed instruction in htinst if hardware provides it,or fetch it from guest memory
+pub unsafe fn decode_trapped_insn(sepc: usize) -> Option<MmioInsn> {
+    let transformed = htinst::read();
+    if transformed & 0x1 != 0 {
+        // bit 1 is cleared when trapped instruction is compressed
+        let len = if transformed & 0x2 != 0 { 4 } else { 2 };
+        return decode_standard(transformed | 0x2, len);
+    }
+    if transformed != 0 {
+        // pseudo instruction for implicit access of vs stage page table
+        return None;
+    }
+    let insn = fetch_guest_insn(sepc);
+    if insn & 0x3 != 0x3 {
+        decode_compressed(insn)
+    } else {
+        decode_standard(insn, 4)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn csr_insn(csr: usize, src: usize, funct3: usize, rd: usize) -> usize {
+        csr << 20 | src << 15 | funct3 << 12 | rd << 7 | OPCODE_SYSTEM
+    }
+
+    #[test]
+    fn csr_forms() {
+        // csrrw a0,stopei,a1
+        let insn = decode_csr_insn(csr_insn(0x15c, 11, 1, 10)).unwrap();
+        assert_eq!(
+            (insn.csr, insn.rd, insn.src, insn.imm),
+            (0x15c, 10, 11, false)
+        );
+        assert_eq!(insn.new_value(0xf0, 0x0f), Some(0x0f));
+        // csrrsi zero,sireg,5
+        let insn = decode_csr_insn(csr_insn(0x151, 5, 6, 0)).unwrap();
+        assert_eq!((insn.csr, insn.rd, insn.src, insn.imm), (0x151, 0, 5, true));
+        assert_eq!(insn.new_value(0xf0, 5), Some(0xf5));
+        // csrrc t0,0xfff,t1
+        let insn = decode_csr_insn(csr_insn(0xfff, 6, 3, 5)).unwrap();
+        assert_eq!(insn.csr, 0xfff);
+        assert_eq!(insn.new_value(0xff, 0x0f), Some(0xf0));
+    }
+
+    #[test]
+    fn csr_read_only_forms() {
+        // csrr a0,stopei is csrrs a0,stopei,zero
+        let insn = decode_csr_insn(csr_insn(0x15c, 0, 2, 10)).unwrap();
+        assert_eq!(insn.new_value(0xf0, 0), None);
+        let insn = decode_csr_insn(csr_insn(0x15c, 0, 7, 10)).unwrap();
+        assert_eq!(insn.new_value(0xf0, 0), None);
+        // csrrw with x0 still writes
+        let insn = decode_csr_insn(csr_insn(0x15c, 0, 1, 10)).unwrap();
+        assert_eq!(insn.new_value(0xf0, 0), Some(0));
+    }
+
+    #[test]
+    fn not_csr() {
+        // ecall,wfi and the reserved funct3 4
+        assert!(decode_csr_insn(0x0000_0073).is_none());
+        assert!(decode_csr_insn(0x1050_0073).is_none());
+        assert!(decode_csr_insn(csr_insn(0x15c, 1, 4, 1)).is_none());
+        // lw a0,0(a1)
+        assert!(decode_csr_insn(0x0005_a503).is_none());
+    }
+
+    #[test]
+    fn loads_and_stores() {
+        // lbu a0,0(a1)
+        let insn = decode_standard(0x0005_c503, 4).unwrap();
+        assert!(!insn.is_store && !insn.signed);
+        assert_eq!((insn.width, insn.reg), (1, 10));
+        // sd a2,8(a1)
+        let insn = decode_standard(0x00c5_b423, 4).unwrap();
+        assert!(insn.is_store);
+        assert_eq!((insn.width, insn.reg), (8, 12));
+        // c.lw a0,0(a1)
+        let insn = decode_compressed(0x4188).unwrap();
+        assert!(!insn.is_store && insn.signed);
+        assert_eq!((insn.width, insn.reg, insn.len), (4, 10, 2));
+        // c.sd a0,0(a1)
+        let insn = decode_compressed(0xe188).unwrap();
+        assert!(insn.is_store);
+        assert_eq!((insn.width, insn.reg), (8, 10));
+        // c.addi4spn is no access
+        assert!(decode_compressed(0x0028).is_none());
+        assert!(decode_standard(0x0000_0073, 4).is_none());
+    }
+}
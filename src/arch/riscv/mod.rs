@@ -1,15 +1,48 @@
 pub mod context;
 pub mod mm;
+pub mod mmio;
 pub mod page_table;
 pub mod vm_exit;
-mod intc;
+pub mod intc;
 
 pub use context::*;
 use core::arch::global_asm;
+use core::mem::offset_of;
 pub use vm_exit::*;
 
+// offsets trap.S uses are taken from TrapContext,see checks next to it
 #[cfg(target_arch = "riscv64")]
-global_asm!(include_str!("trap.S"));
+global_asm!(
+    include_str!("trap.S"),
+    CTX_SSTATUS = const offset_of!(TrapContext, sstatus),
+    CTX_SEPC = const offset_of!(TrapContext, sepc),
+    CTX_HSTATUS = const offset_of!(TrapContext, hstatus),
+    CTX_HGATP = const offset_of!(TrapContext, hgatp),
+    CTX_HYP_STACK = const offset_of!(TrapContext, guest_hyp_stack),
+    CTX_TRAP_HANDLER = const offset_of!(TrapContext, trap_handler),
+    CTX_HYP_TP = const offset_of!(TrapContext, hyp_tp),
+    CTX_VSSTATUS = const offset_of!(TrapContext, csrs.vsstatus),
+    CTX_VSIE = const offset_of!(TrapContext, csrs.vsie),
+    CTX_VSTVEC = const offset_of!(TrapContext, csrs.vstvec),
+    CTX_VSSCRATCH = const offset_of!(TrapContext, csrs.vsscratch),
+    CTX_VSEPC = const offset_of!(TrapContext, csrs.vsepc),
+    CTX_VSCAUSE = const offset_of!(TrapContext, csrs.vscause),
+    CTX_VSTVAL = const offset_of!(TrapContext, csrs.vstval),
+    CTX_VSATP = const offset_of!(TrapContext, csrs.vsatp),
+    CTX_HVIP = const offset_of!(TrapContext, csrs.hvip),
+    CTX_HIE = const offset_of!(TrapContext, csrs.hie),
+    CTX_HCOUNTEREN = const offset_of!(TrapContext, csrs.hcounteren),
+    CTX_HENVCFG = const offset_of!(TrapContext, csrs.henvcfg),
+    CTX_HTIMEDELTA = const offset_of!(TrapContext, csrs.htimedelta),
+    HYP_FRAME_SIZE = const offset_of!(TrapContext, sepc) + 8,
+    FP_FCSR = const offset_of!(FpState, fcsr),
+    SSTATUS_FS = const SSTATUS_FS,
+    VEC_VSTART = const offset_of!(VectorCsrs, vstart),
+    VEC_VL = const offset_of!(VectorCsrs, vl),
+    VEC_VTYPE = const offset_of!(VectorCsrs, vtype),
+    VEC_VCSR = const offset_of!(VectorCsrs, vcsr),
+    SSTATUS_VS = const SSTATUS_VS,
+);
 
 pub fn is_cpu_support() -> bool {
     use crate::constants::HYPERVISOR_EXTENSION;
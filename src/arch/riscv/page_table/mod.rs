@@ -34,3 +34,24 @@ pub unsafe fn active_page_table(satp: usize) {
         )
     }
 }
+
+/// flush all g stage tlb entries of current hart
+#[inline(always)]
+pub unsafe fn flush_guest_tlb() {
+    use core::arch::asm;
+    asm!("hfence.gvma zero, zero")
+}
+
+/// flush all vs stage tlb entries of current vmid on current hart
+#[inline(always)]
+pub unsafe fn flush_vs_tlb() {
+    use core::arch::asm;
+    asm!("hfence.vvma zero, zero")
+}
+
+/// flush all tlb entries of hypervisor address space
+#[inline(always)]
+pub unsafe fn flush_host_tlb() {
+    use core::arch::asm;
+    asm!("sfence.vma")
+}
@@ -42,6 +42,15 @@ impl PageTableEntry {
         (self.entry & 0xff) as u8
     }
 
+    pub fn pte_flags(&self) -> PTEFlags {
+        PTEFlags::from_bits_truncate(self.flags())
+    }
+
+    /// keep ppn and replace flags of the pte
+    pub fn set_flags(&mut self, flags: PTEFlags) {
+        self.entry = (self.entry & !0xff) | flags.bits as usize;
+    }
+
     pub fn is_valid(&self) -> bool {
         self.flags() & PTEFlags::V.bits != 0
     }
@@ -1,11 +1,26 @@
+use crate::arch::mmio::fetch_guest_insn;
 use crate::arch::TrapContext;
 use crate::constants::TRAMPOLINE;
+use crate::guest::StopReason;
+use crate::hypervisor::irq::handle_external_irqs;
+use crate::hypervisor::sbi::handle_sbi_call;
+use crate::hypervisor::smp::clear_kick;
+use crate::hypervisor::{
+    handle_cow_fault, handle_imsic_csr, handle_mmio, handle_timer_tick, handle_wfi, resume_vcpu,
+    stop_guest, take_guest_interrupts,
+};
+use crate::percpu;
 use crate::println;
 use crate::sbi::sbi_shutdown;
 use riscv::register::mtvec::TrapMode;
-use riscv::register::scause::{Exception, Trap};
+use riscv::register::scause::{Exception, Interrupt, Trap};
 use riscv::register::{htinst, htval, scause, sepc, sscratch, stval, stvec, vsatp};
 
+// guest instruction trapped as virtual instruction,stval holds it unless hart leaves it zero
+const WFI: usize = 0x1050_0073;
+// supervisor guest external interrupt,a guest file of this hart got an msi
+const IRQ_SGEI: usize = 12;
+
 extern "C" {
     pub fn __vm_exit();
     pub fn __vm_entry(context: *mut TrapContext) -> !;
@@ -39,10 +54,28 @@ pub fn set_guest_trap_handler() {
 
 /// handle trap from V mode(VS or VU?)
 #[no_mangle]
-pub unsafe fn vm_exit() {
+pub unsafe extern "C" fn vm_exit(ctx: *mut TrapContext) -> ! {
     set_hyp_trap_handler();
+    percpu::count(|stats| &stats.vm_exits);
     let scause = scause::read().cause();
     match scause {
+        // another hart wants this vcpu to exit
+        Trap::Interrupt(Interrupt::SupervisorSoft) => {
+            clear_kick();
+            percpu::count(|stats| &stats.kicks);
+            resume_vcpu(ctx)
+        }
+        // time slice may be over
+        Trap::Interrupt(Interrupt::SupervisorTimer) => handle_timer_tick(ctx),
+        // a device of host or of a guest it's passed through to
+        Trap::Interrupt(Interrupt::SupervisorExternal) => {
+            handle_external_irqs();
+            resume_vcpu(ctx)
+        }
+        Trap::Interrupt(_) if scause::read().code() == IRQ_SGEI => {
+            take_guest_interrupts();
+            resume_vcpu(ctx)
+        }
         Trap::Interrupt(_) => {}
         Trap::Exception(Exception::InstructionGuestPageFault) => {
             let stval = stval::read();
@@ -53,6 +86,37 @@ pub unsafe fn vm_exit() {
                 scause, sepc, stval, htval
             );
         }
+        Trap::Exception(Exception::VirtualSupervisorEnvCall) => {
+            if let Some(reason) = handle_sbi_call(ctx) {
+                stop_guest(ctx, reason)
+            }
+            resume_vcpu(ctx)
+        }
+        Trap::Exception(Exception::VirtualInstruction) => {
+            // hart may not report trapped instruction,it's fetched from guest then
+            let insn = match stval::read() {
+                0 => fetch_guest_insn((*ctx).sepc),
+                insn => insn,
+            };
+            if insn == WFI {
+                handle_wfi(ctx)
+            }
+            if handle_imsic_csr(ctx, insn) {
+                resume_vcpu(ctx)
+            }
+        }
+        Trap::Exception(Exception::LoadGuestPageFault) => {
+            let gpa = htval::read() << 2 | stval::read() & 0x3;
+            if handle_mmio(ctx, gpa) {
+                resume_vcpu(ctx)
+            }
+        }
+        Trap::Exception(Exception::StoreGuestPageFault) => {
+            let gpa = htval::read() << 2 | stval::read() & 0x3;
+            if handle_cow_fault(ctx, gpa) || handle_mmio(ctx, gpa) {
+                resume_vcpu(ctx)
+            }
+        }
         _ => (),
     }
     let stval = stval::read();
@@ -67,7 +131,8 @@ pub unsafe fn vm_exit() {
         vsatp::read().bits()
     );
 
-    sbi_shutdown()
+    // only this guest goes down,host and other guests keep running
+    stop_guest(ctx, StopReason::Crash)
 }
 
 #[no_mangle]
@@ -0,0 +1,231 @@
+//! guest image bundle
+//!
+//! one blob carrying several named images and the guests built from them,so one hypervisor binary
+//! can start a mix of guests. all integers are little endian
+//!
+//! ```text
+//! header      magic "HCBUNDLE" | version u32 | image count u32 | guest count u32 | reserved u32
+//! image entry name [u8; 24] | kind u32 | reserved u32 | offset u64 | size u64
+//! guest entry name [u8; 24] | cpu nums u32 | reserved u32 | mem size u64 |
+//!             kernel u32 | initrd u32 | dtb u32 | reserved u32 | bootargs [u8; 128]
+//! ```
+//!
+//! image offset is counted from the start of bundle,absent initrd or dtb index is `u32::MAX`
+
+use crate::device_tree::host_fdt;
+use crate::guest::ImageKind;
+
+pub const BUNDLE_MAGIC: &[u8; 8] = b"HCBUNDLE";
+const BUNDLE_VERSION: u32 = 1;
+const HEADER_SIZE: usize = 24;
+const IMAGE_ENTRY_SIZE: usize = 48;
+const GUEST_ENTRY_SIZE: usize = 184;
+const NAME_LEN: usize = 24;
+const BOOTARGS_LEN: usize = 128;
+const NO_IMAGE: u32 = u32::MAX;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BundleError {
+    BadMagic,
+    BadVersion(u32),
+    Truncated,
+    /// image index of guest is out of image table
+    BadImageIndex(u32),
+    /// image is used as something it isn't,like an initrd as kernel
+    WrongImageKind(u32),
+}
+
+/// what an image of bundle holds
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EntryKind {
+    /// kernel in declared format,loader refuses it if it's something else
+    Kernel(ImageKind),
+    Initrd,
+    Dtb,
+    /// text guest config,see `config::parse_config`
+    Config,
+}
+
+impl EntryKind {
+    fn from_raw(kind: u32) -> Option<Self> {
+        match kind {
+            0 => Some(Self::Kernel(ImageKind::Flat)),
+            1 => Some(Self::Kernel(ImageKind::Elf)),
+            2 => Some(Self::Kernel(ImageKind::Linux)),
+            3 => Some(Self::Initrd),
+            4 => Some(Self::Dtb),
+            5 => Some(Self::Config),
+            _ => None,
+        }
+    }
+
+    /// format of kernel image,none if image is not a kernel
+    #[inline]
+    pub fn kernel_kind(&self) -> Option<ImageKind> {
+        match self {
+            Self::Kernel(kind) => Some(*kind),
+            _ => None,
+        }
+    }
+
+    #[inline]
+    pub fn is_kernel(&self) -> bool {
+        self.kernel_kind().is_some()
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct BundleImage<'a> {
+    pub name: &'a str,
+    pub kind: EntryKind,
+    pub data: &'a [u8],
+}
+
+pub struct BundleGuest<'a> {
+    pub name: &'a str,
+    pub cpu_nums: usize,
+    pub mem_size: usize,
+    pub kernel: BundleImage<'a>,
+    pub initrd: Option<BundleImage<'a>>,
+    pub dtb: Option<BundleImage<'a>>,
+    pub bootargs: &'a str,
+}
+
+pub struct Bundle<'a> {
+    data: &'a [u8],
+    image_nums: usize,
+    guest_nums: usize,
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Result<u32, BundleError> {
+    data.get(offset..offset + 4)
+        .map(|bytes| u32::from_le_bytes(bytes.try_into().unwrap()))
+        .ok_or(BundleError::Truncated)
+}
+
+fn read_u64(data: &[u8], offset: usize) -> Result<usize, BundleError> {
+    data.get(offset..offset + 8)
+        .map(|bytes| u64::from_le_bytes(bytes.try_into().unwrap()) as usize)
+        .ok_or(BundleError::Truncated)
+}
+
+/// nul padded string field
+fn read_str(data: &[u8], offset: usize, len: usize) -> Result<&str, BundleError> {
+    let bytes = data
+        .get(offset..offset + len)
+        .ok_or(BundleError::Truncated)?;
+    let end = bytes.iter().position(|&b| b == 0).unwrap_or(len);
+    core::str::from_utf8(&bytes[..end]).map_err(|_| BundleError::Truncated)
+}
+
+impl<'a> Bundle<'a> {
+    pub fn is_bundle(data: &[u8]) -> bool {
+        data.starts_with(BUNDLE_MAGIC)
+    }
+
+    pub fn parse(data: &'a [u8]) -> Result<Self, BundleError> {
+        if !Self::is_bundle(data) {
+            return Err(BundleError::BadMagic);
+        }
+        let version = read_u32(data, 8)?;
+        if version != BUNDLE_VERSION {
+            return Err(BundleError::BadVersion(version));
+        }
+        let bundle = Self {
+            data,
+            image_nums: read_u32(data, 12)? as usize,
+            guest_nums: read_u32(data, 16)? as usize,
+        };
+        // check every entry once,so iterators below never fail
+        for i in 0..bundle.image_nums {
+            bundle.image(i as u32)?;
+        }
+        for i in 0..bundle.guest_nums {
+            bundle.guest(i)?;
+        }
+        Ok(bundle)
+    }
+
+    pub fn image(&self, index: u32) -> Result<BundleImage<'a>, BundleError> {
+        if index as usize >= self.image_nums {
+            return Err(BundleError::BadImageIndex(index));
+        }
+        let entry = HEADER_SIZE + index as usize * IMAGE_ENTRY_SIZE;
+        let kind = read_u32(self.data, entry + NAME_LEN)?;
+        let offset = read_u64(self.data, entry + NAME_LEN + 8)?;
+        let size = read_u64(self.data, entry + NAME_LEN + 16)?;
+        Ok(BundleImage {
+            name: read_str(self.data, entry, NAME_LEN)?,
+            kind: EntryKind::from_raw(kind).ok_or(BundleError::WrongImageKind(index))?,
+            data: offset
+                .checked_add(size)
+                .and_then(|end| self.data.get(offset..end))
+                .ok_or(BundleError::Truncated)?,
+        })
+    }
+
+    fn optional_image(
+        &self,
+        index: u32,
+        kind: EntryKind,
+    ) -> Result<Option<BundleImage<'a>>, BundleError> {
+        if index == NO_IMAGE {
+            return Ok(None);
+        }
+        let image = self.image(index)?;
+        if image.kind != kind {
+            return Err(BundleError::WrongImageKind(index));
+        }
+        Ok(Some(image))
+    }
+
+    pub fn guest(&self, index: usize) -> Result<BundleGuest<'a>, BundleError> {
+        let entry = HEADER_SIZE + self.image_nums * IMAGE_ENTRY_SIZE + index * GUEST_ENTRY_SIZE;
+        let kernel_index = read_u32(self.data, entry + 40)?;
+        let kernel = self.image(kernel_index)?;
+        if !kernel.kind.is_kernel() {
+            return Err(BundleError::WrongImageKind(kernel_index));
+        }
+        Ok(BundleGuest {
+            name: read_str(self.data, entry, NAME_LEN)?,
+            cpu_nums: read_u32(self.data, entry + 24)? as usize,
+            mem_size: read_u64(self.data, entry + 32)?,
+            kernel,
+            initrd: self.optional_image(read_u32(self.data, entry + 44)?, EntryKind::Initrd)?,
+            dtb: self.optional_image(read_u32(self.data, entry + 48)?, EntryKind::Dtb)?,
+            bootargs: read_str(self.data, entry + 56, BOOTARGS_LEN)?,
+        })
+    }
+
+    pub fn images(&self) -> impl Iterator<Item = BundleImage<'a>> + '_ {
+        (0..self.image_nums).map(|i| self.image(i as u32).unwrap())
+    }
+
+    pub fn find_image(&self, name: &str) -> Option<BundleImage<'a>> {
+        self.images().find(|image| image.name == name)
+    }
+
+    pub fn guests(&self) -> impl Iterator<Item = BundleGuest<'a>> + '_ {
+        (0..self.guest_nums).map(|i| self.guest(i).unwrap())
+    }
+}
+
+/// [start,end) of bundle loaded by qemu,recorded in host /chosen
+///
+/// `-initrd` fills `linux,initrd-start` and `linux,initrd-end`,blob from `-device loader` can be
+/// pointed out by `hypercrab,bundle` = <address size>
+pub fn find_host_bundle() -> Option<(usize, usize)> {
+    let fdt = host_fdt();
+    let chosen = fdt.find_node("/chosen")?;
+    if let Some(bundle) = chosen.property("hypercrab,bundle") {
+        let cells = bundle.value;
+        if cells.len() == 16 {
+            let start = u64::from_be_bytes(cells[..8].try_into().unwrap()) as usize;
+            let size = u64::from_be_bytes(cells[8..].try_into().unwrap()) as usize;
+            return Some((start, start + size));
+        }
+    }
+    let start = chosen.property("linux,initrd-start")?.as_usize()?;
+    let end = chosen.property("linux,initrd-end")?.as_usize()?;
+    Some((start, end))
+}
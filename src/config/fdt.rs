@@ -0,0 +1,209 @@
+//! guest configs described by host device tree
+//!
+//! each child of `/chosen/hypercrab` is a guest,images are named ones in the bundle
+//!
+//! ```text
+//! chosen {
+//!     hypercrab {
+//!         guest@0 {
+//!             name = "linux";
+//!             vcpus = <2>;
+//!             memory = <0x0 0x80200000 0x0 0x4000000>;      // (gpa size) pairs of u64
+//!             reserved-memory = <0x0 0x90000000 0x0 0x100000>;
+//!             kernel = "linux-image";
+//!             initrd = "rootfs";
+//!             bootargs = "console=ttyS0";
+//!             console = "host";
+//!             restart = "on-crash";
+//!             timeslice = <10>;                              // ms
+//!             weight = <512>;
+//!             cap = <50>;                                    // percent of one hart
+//!             affinity = <0x4 0xd>;                          // hart mask of each vcpu
+//!             vector = "off";                                // on|off
+//!             freezeclock = "on";                            // guest time stops while paused
+//!             uart@10000000 {                                // plic,aplic,imsic,uart or virtio-mmio
+//!                 reg = <0x0 0x10000000>;
+//!                 interrupts = <10>;
+//!             };
+//!             passthrough@10008000 {
+//!                 compatible = "virtio,mmio";
+//!                 reg = <0x0 0x10008000 0x0 0x1000>;         // host window
+//!                 guest-address = <0x0 0x10008000>;
+//!                 interrupts = <8 8>;                        // host irq,guest irq
+//!             };
+//!         };
+//!     };
+//! };
+//! ```
+
+use super::text::{find_image, parse_switch};
+use super::{
+    ConfigError, ConsoleRoute, DeviceConfig, DeviceKind, MemoryBank, MemoryKind, Passthrough,
+    RestartPolicy, VmConfig,
+};
+use crate::bundle::{Bundle, EntryKind};
+use crate::device_tree::host_fdt;
+use crate::println;
+use alloc::vec::Vec;
+use fdt::node::FdtNode;
+
+/// big endian u64 cells of property
+fn u64_cells(value: &[u8]) -> Option<Vec<usize>> {
+    if value.is_empty() || value.len() % 8 != 0 {
+        return None;
+    }
+    Some(
+        value
+            .chunks_exact(8)
+            .map(|cell| u64::from_be_bytes(cell.try_into().unwrap()) as usize)
+            .collect(),
+    )
+}
+
+fn u32_cells(value: &[u8]) -> Option<Vec<u32>> {
+    if value.is_empty() || value.len() % 4 != 0 {
+        return None;
+    }
+    Some(
+        value
+            .chunks_exact(4)
+            .map(|cell| u32::from_be_bytes(cell.try_into().unwrap()))
+            .collect(),
+    )
+}
+
+fn banks(node: &FdtNode, name: &str, kind: MemoryKind) -> Option<Vec<MemoryBank>> {
+    let cells = u64_cells(node.property(name)?.value)?;
+    Some(
+        cells
+            .chunks(2)
+            .filter(|pair| pair.len() == 2)
+            .map(|pair| MemoryBank {
+                gpa: pair[0],
+                size: pair[1],
+                kind,
+            })
+            .collect(),
+    )
+}
+
+fn parse_guest<'a>(node: &FdtNode, bundle: &Bundle<'a>) -> Result<VmConfig<'a>, ConfigError> {
+    let err = ConfigError::Syntax { line: 0 };
+    let string = |name: &str| node.property(name).and_then(|prop| prop.as_str());
+    let mut config = VmConfig::new(string("name").unwrap_or(node.name), &[]);
+
+    if let Some(vcpus) = node.property("vcpus") {
+        config.vcpu_nums = vcpus.as_usize().ok_or(err.clone())?;
+    }
+    let ram = banks(node, "memory", MemoryKind::Ram);
+    let reserved = banks(node, "reserved-memory", MemoryKind::Reserved);
+    if ram.is_some() || reserved.is_some() {
+        config.memory = ram.unwrap_or_default();
+        config.memory.extend(reserved.unwrap_or_default());
+    }
+    let kernel = string("kernel").ok_or(ConfigError::NoKernel)?;
+    config.set_kernel(find_image(bundle, kernel, |kind| kind.is_kernel())?);
+    if let Some(initrd) = string("initrd") {
+        config.initrd = Some(find_image(bundle, initrd, |kind| kind == EntryKind::Initrd)?.data);
+    }
+    if let Some(dtb) = string("dtb") {
+        config.dtb = Some(find_image(bundle, dtb, |kind| kind == EntryKind::Dtb)?.data);
+    }
+    if let Some(entry) = node.property("entry") {
+        config.entry = Some(entry.as_usize().ok_or(err.clone())?);
+    }
+    if let Some(bootargs) = string("bootargs") {
+        config.bootargs = bootargs.into();
+    }
+    if let Some(console) = string("console") {
+        config.console = ConsoleRoute::from_name(console).ok_or(err.clone())?;
+    }
+    if let Some(restart) = string("restart") {
+        config.restart = RestartPolicy::from_name(restart).ok_or(err.clone())?;
+    }
+    if let Some(time_slice) = node.property("timeslice") {
+        config.time_slice_ms = time_slice.as_usize().ok_or(err.clone())?;
+    }
+    if let Some(weight) = node.property("weight") {
+        config.weight = weight.as_usize().ok_or(err.clone())?;
+    }
+    if let Some(cap) = node.property("cap") {
+        config.cap = Some(cap.as_usize().ok_or(err.clone())?);
+    }
+    if let Some(affinity) = node.property("affinity") {
+        let masks = u32_cells(affinity.value).ok_or(err.clone())?;
+        for (vcpu_id, &mask) in masks.iter().enumerate() {
+            config.affinity.push((Some(vcpu_id), mask as usize));
+        }
+    }
+    if let Some(vector) = string("vector") {
+        config.vector = parse_switch(vector).ok_or(err.clone())?;
+    }
+    if let Some(freeze_clock) = string("freezeclock") {
+        config.freeze_clock = parse_switch(freeze_clock).ok_or(err.clone())?;
+    }
+
+    let mut devices = Vec::new();
+    for child in node.children() {
+        let kind = child.name.split('@').next().unwrap();
+        let reg = child
+            .property("reg")
+            .and_then(|prop| u64_cells(prop.value))
+            .ok_or(err.clone())?;
+        let irqs = child
+            .property("interrupts")
+            .and_then(|prop| u32_cells(prop.value));
+        if kind == "passthrough" {
+            let gpa = child
+                .property("guest-address")
+                .and_then(|prop| u64_cells(prop.value))
+                .map_or(reg[0], |cells| cells[0]);
+            config.passthrough.push(Passthrough {
+                host_pa: reg[0],
+                size: *reg.get(1).ok_or(err.clone())?,
+                gpa,
+                compatible: child
+                    .property("compatible")
+                    .and_then(|prop| prop.as_str())
+                    .ok_or(err.clone())?
+                    .into(),
+                irq: match irqs.as_deref() {
+                    None => None,
+                    Some(&[host, guest]) => Some((host, guest)),
+                    Some(_) => return Err(err),
+                },
+            });
+        } else {
+            devices.push(DeviceConfig {
+                kind: DeviceKind::from_name(kind).ok_or(err.clone())?,
+                base: reg[0],
+                irq: irqs.map(|irqs| irqs[0]),
+            });
+        }
+    }
+    if !devices.is_empty() {
+        config.devices = devices;
+    }
+    config.validate()?;
+    Ok(config)
+}
+
+/// guest configs under host `/chosen/hypercrab`,empty if there is none
+///
+/// a broken config is reported and skipped,the other guests still boot
+pub fn host_configs<'a>(bundle: &Bundle<'a>) -> Vec<VmConfig<'a>> {
+    let fdt = host_fdt();
+    let node = match fdt.find_node("/chosen/hypercrab") {
+        Some(node) => node,
+        None => return Vec::new(),
+    };
+    node.children()
+        .filter_map(|guest| match parse_guest(&guest, bundle) {
+            Ok(config) => Some(config),
+            Err(err) => {
+                println!("[hypervisor] bad guest config {}: {:?}", guest.name, err);
+                None
+            }
+        })
+        .collect()
+}
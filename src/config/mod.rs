@@ -0,0 +1,587 @@
+//! declarative description of a guest
+//!
+//! everything a guest looks like,vcpus,memory banks,boot images,devices,console and passthrough
+//! windows,lives in one `VmConfig`. it comes from a text config packed in the bundle,a node under
+//! host `/chosen/hypercrab`,or defaults for a bare kernel image. a config is validated before any
+//! guest memory is allocated for it
+
+mod fdt;
+mod text;
+
+pub use self::fdt::host_configs;
+use crate::arch::intc::imsic::host_imsic;
+use crate::arch::mm::KERNEL_START_PA;
+use crate::bundle::{find_host_bundle, Bundle, BundleGuest, BundleImage, EntryKind};
+use crate::constants::{ALL_HARTS, GUEST_STACK_SIZE, MAX_HARTS, MEMORY_END, PAGE_SIZE};
+use crate::device_tree::{host_harts, host_owned_windows};
+use crate::guest::device::{
+    PLIC_SOURCE_NUMS, VIRT_APLIC_BASE, VIRT_APLIC_SIZE, VIRT_IMSIC_BASE, VIRT_IMSIC_SIZE,
+    VIRT_PLIC_BASE, VIRT_PLIC_SIZE, VIRT_UART_BASE, VIRT_UART_IRQ, VIRT_UART_SIZE,
+    VIRT_VIRTIO_BASE, VIRT_VIRTIO_IRQ_BASE, VIRT_VIRTIO_NUMS, VIRT_VIRTIO_SIZE,
+};
+use crate::guest::{GuestImages, ImageKind, DEFAULT_BOOTARGS};
+use crate::mm::free_frames;
+use crate::println;
+use crate::schedule::{DEFAULT_WEIGHT, MAX_WEIGHT};
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+pub use text::parse_config;
+
+/// guest ram of a bare kernel image
+pub const DEFAULT_RAM_SIZE: usize = 0x200_0000;
+pub const MAX_VCPUS: usize = 8;
+/// how long a vcpu runs before others waiting for its hart get a turn
+pub const DEFAULT_TIME_SLICE_MS: usize = 10;
+pub const MAX_TIME_SLICE_MS: usize = 1000;
+
+// host physical memory owned by hypervisor,never passed through
+const HOST_RAM_START: usize = 0x8000_0000;
+// g stage and hypervisor page tables take about this many frames for every 512 frames of ram
+const PAGE_TABLE_FRAMES_PER_512: usize = 2;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConfigError {
+    /// line of text config can't be understood
+    Syntax {
+        line: usize,
+    },
+    /// image is not in bundle or is not the kind wanted
+    MissingImage(String),
+    NoKernel,
+    BadVcpuNums(usize),
+    NoMemory,
+    /// first bank is where images are loaded,it must be ram
+    BadBootBank,
+    /// bank is empty or not page aligned
+    BadBank {
+        gpa: usize,
+        size: usize,
+    },
+    /// two windows starting at these gpas overlap
+    Overlap(usize, usize),
+    /// guest needs exactly one irqchip,a plic or an aplic
+    NoIrqChip,
+    /// guest has more than one imsic,or host harts have none
+    BadImsic,
+    BadIrq(u32),
+    /// two devices raise the same interrupt
+    IrqConflict(u32),
+    /// boot entry is outside guest ram
+    BadEntry(usize),
+    /// host window is not page aligned or overlaps host ram,host dtb,guest bundle or devices
+    /// hypervisor keeps
+    BadPassthrough {
+        host_pa: usize,
+    },
+    /// host has fewer free frames than memory banks and vcpu stacks of guest take
+    OutOfMemory {
+        size: usize,
+    },
+    BadTimeSlice(usize),
+    BadWeight(usize),
+    /// cap is percent of one hart
+    BadCap(usize),
+    /// affinity of vcpu has none of harts host has or a hart beyond `MAX_HARTS`
+    BadAffinity(usize),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemoryKind {
+    Ram,
+    /// backed and described to guest,but kept out of its allocator by `/reserved-memory`
+    Reserved,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct MemoryBank {
+    pub gpa: usize,
+    pub size: usize,
+    pub kind: MemoryKind,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeviceKind {
+    Plic,
+    Aplic,
+    Imsic,
+    Uart,
+    VirtioMmio,
+}
+
+impl DeviceKind {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "plic" => Some(Self::Plic),
+            "aplic" => Some(Self::Aplic),
+            "imsic" => Some(Self::Imsic),
+            "uart" => Some(Self::Uart),
+            "virtio-mmio" => Some(Self::VirtioMmio),
+            _ => None,
+        }
+    }
+
+    /// size of mmio window
+    pub fn window_size(&self) -> usize {
+        match self {
+            Self::Plic => VIRT_PLIC_SIZE,
+            Self::Aplic => VIRT_APLIC_SIZE,
+            Self::Imsic => VIRT_IMSIC_SIZE,
+            Self::Uart => VIRT_UART_SIZE,
+            Self::VirtioMmio => VIRT_VIRTIO_SIZE,
+        }
+    }
+}
+
+/// emulated device at gpa,irq is the source number on irqchip
+#[derive(Debug, Clone, Copy)]
+pub struct DeviceConfig {
+    pub kind: DeviceKind,
+    pub base: usize,
+    pub irq: Option<u32>,
+}
+
+/// where emulated uart of guest is connected
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConsoleRoute {
+    /// host sbi console,input and output
+    Host,
+    /// output is dropped and there is never input
+    Null,
+}
+
+impl ConsoleRoute {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "host" => Some(Self::Host),
+            "none" => Some(Self::Null),
+            _ => None,
+        }
+    }
+}
+
+/// hart set like `0,2-3` as a mask,none if it's malformed or names a hart beyond `MAX_HARTS`
+pub fn parse_harts(s: &str) -> Option<usize> {
+    let mut mask = 0;
+    for part in s.split(',') {
+        let (first, last) = match part.split_once('-') {
+            Some((first, last)) => (text::parse_num(first)?, text::parse_num(last)?),
+            None => {
+                let hart = text::parse_num(part)?;
+                (hart, hart)
+            }
+        };
+        if first > last || last >= MAX_HARTS {
+            return None;
+        }
+        for hart in first..=last {
+            mask |= 1 << hart;
+        }
+    }
+    Some(mask)
+}
+
+/// what to do when guest asks for reboot or shutdown,or crashes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RestartPolicy {
+    /// guest stops on reboot,shutdown and crash
+    Never,
+    /// restart on reboot and crash,stop on shutdown
+    OnCrash,
+    /// restart whatever happens,even on shutdown
+    Always,
+    /// like `OnCrash`,but at most n times
+    Max(usize),
+}
+
+impl RestartPolicy {
+    /// `never`,`on-crash`,`always` or `max n`
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "never" => Some(Self::Never),
+            "on-crash" => Some(Self::OnCrash),
+            "always" => Some(Self::Always),
+            _ => {
+                let times = name.strip_prefix("max")?.trim();
+                text::parse_num(times).map(Self::Max)
+            }
+        }
+    }
+}
+
+/// host device window handed to guest as is
+#[derive(Debug, Clone)]
+pub struct Passthrough {
+    pub host_pa: usize,
+    pub size: usize,
+    pub gpa: usize,
+    // compatible string of device node in guest device tree
+    pub compatible: String,
+    // (host irq,guest irq),host interrupt is forwarded to guest irqchip
+    pub irq: Option<(u32, u32)>,
+}
+
+pub struct VmConfig<'a> {
+    pub name: String,
+    pub vcpu_nums: usize,
+    // first bank is boot bank
+    pub memory: Vec<MemoryBank>,
+    pub kernel: &'a [u8],
+    // format bundle declares for kernel,none lets loader find it out
+    pub kernel_kind: Option<ImageKind>,
+    pub initrd: Option<&'a [u8]>,
+    pub dtb: Option<&'a [u8]>,
+    // start boot vcpu here instead of entry of kernel image
+    pub entry: Option<usize>,
+    pub bootargs: String,
+    pub devices: Vec<DeviceConfig>,
+    pub console: ConsoleRoute,
+    pub passthrough: Vec<Passthrough>,
+    pub restart: RestartPolicy,
+    pub time_slice_ms: usize,
+    // share of cpu time against other guests
+    pub weight: usize,
+    // at most this percent of one hart
+    pub cap: Option<usize>,
+    // (vcpu or every vcpu,hart mask) in order,later ones win. vcpus none names run on any hart
+    pub affinity: Vec<(Option<usize>, usize)>,
+    // vcpus get vector extension if host has it
+    pub vector: bool,
+    // guest time stops while guest is paused
+    pub freeze_clock: bool,
+}
+
+/// devices of qemu virt machine we emulate,an aplic with an imsic if host harts have one like
+/// with aia=aplic-imsic,a plic otherwise
+pub fn default_devices() -> Vec<DeviceConfig> {
+    let mut devices = Vec::new();
+    if host_imsic().is_some() {
+        devices.push(DeviceConfig {
+            kind: DeviceKind::Aplic,
+            base: VIRT_APLIC_BASE,
+            irq: None,
+        });
+        devices.push(DeviceConfig {
+            kind: DeviceKind::Imsic,
+            base: VIRT_IMSIC_BASE,
+            irq: None,
+        });
+    } else {
+        devices.push(DeviceConfig {
+            kind: DeviceKind::Plic,
+            base: VIRT_PLIC_BASE,
+            irq: None,
+        });
+    }
+    devices.push(DeviceConfig {
+        kind: DeviceKind::Uart,
+        base: VIRT_UART_BASE,
+        irq: Some(VIRT_UART_IRQ),
+    });
+    for i in 0..VIRT_VIRTIO_NUMS {
+        devices.push(DeviceConfig {
+            kind: DeviceKind::VirtioMmio,
+            base: VIRT_VIRTIO_BASE + i * VIRT_VIRTIO_SIZE,
+            irq: Some(VIRT_VIRTIO_IRQ_BASE + i as u32),
+        });
+    }
+    devices
+}
+
+#[inline]
+fn overlaps(a: (usize, usize), b: (usize, usize)) -> bool {
+    a.0 < b.0 + b.1 && b.0 < a.0 + a.1
+}
+
+impl<'a> VmConfig<'a> {
+    /// one vcpu,default ram and qemu virt devices
+    pub fn new(name: &str, kernel: &'a [u8]) -> Self {
+        Self {
+            name: name.into(),
+            vcpu_nums: 1,
+            memory: vec![MemoryBank {
+                gpa: KERNEL_START_PA,
+                size: DEFAULT_RAM_SIZE,
+                kind: MemoryKind::Ram,
+            }],
+            kernel,
+            kernel_kind: None,
+            initrd: None,
+            dtb: None,
+            entry: None,
+            bootargs: DEFAULT_BOOTARGS.into(),
+            devices: default_devices(),
+            console: ConsoleRoute::Host,
+            passthrough: Vec::new(),
+            restart: RestartPolicy::OnCrash,
+            time_slice_ms: DEFAULT_TIME_SLICE_MS,
+            weight: DEFAULT_WEIGHT,
+            cap: None,
+            affinity: Vec::new(),
+            vector: true,
+            freeze_clock: false,
+        }
+    }
+
+    /// guest from guest table of bundle,which only says vcpus,ram size and images
+    pub fn from_bundle_guest(guest: &BundleGuest<'a>) -> Self {
+        let mut config = Self::new(guest.name, &[]);
+        config.set_kernel(guest.kernel);
+        config.vcpu_nums = guest.cpu_nums;
+        config.memory[0].size = guest.mem_size;
+        config.initrd = guest.initrd.map(|image| image.data);
+        config.dtb = guest.dtb.map(|image| image.data);
+        if !guest.bootargs.is_empty() {
+            config.bootargs = guest.bootargs.into();
+        }
+        config
+    }
+
+    /// boot from kernel image of bundle,in format it declares
+    pub fn set_kernel(&mut self, image: BundleImage<'a>) {
+        self.kernel = image.data;
+        self.kernel_kind = image.kind.kernel_kind();
+    }
+
+    #[inline]
+    pub fn boot_bank(&self) -> &MemoryBank {
+        &self.memory[0]
+    }
+
+    /// frames guest takes from host: memory banks,hypervisor stacks of vcpus and page tables
+    pub fn host_frames(&self) -> usize {
+        let ram: usize = self.memory.iter().map(|bank| bank.size / PAGE_SIZE).sum();
+        let stacks = self.vcpu_nums * GUEST_STACK_SIZE / PAGE_SIZE;
+        ram + stacks + (ram + stacks) / 512 * PAGE_TABLE_FRAMES_PER_512
+    }
+
+    /// harts vcpu may run on
+    pub fn vcpu_affinity(&self, vcpu_id: usize) -> usize {
+        self.affinity
+            .iter()
+            .rev()
+            .find(|(id, _)| id.map_or(true, |id| id == vcpu_id))
+            .map_or(ALL_HARTS, |&(_, mask)| mask)
+    }
+
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if self.kernel.is_empty() {
+            return Err(ConfigError::NoKernel);
+        }
+        if self.vcpu_nums == 0 || self.vcpu_nums > MAX_VCPUS {
+            return Err(ConfigError::BadVcpuNums(self.vcpu_nums));
+        }
+        if self.time_slice_ms == 0 || self.time_slice_ms > MAX_TIME_SLICE_MS {
+            return Err(ConfigError::BadTimeSlice(self.time_slice_ms));
+        }
+        if self.weight == 0 || self.weight > MAX_WEIGHT {
+            return Err(ConfigError::BadWeight(self.weight));
+        }
+        match self.cap {
+            Some(cap) if cap == 0 || cap > 100 => return Err(ConfigError::BadCap(cap)),
+            _ => {}
+        }
+        for &(vcpu_id, mask) in self.affinity.iter() {
+            let vcpu_id = vcpu_id.unwrap_or(0);
+            if vcpu_id >= self.vcpu_nums || mask & host_harts() == 0 || mask & !ALL_HARTS != 0 {
+                return Err(ConfigError::BadAffinity(vcpu_id));
+            }
+        }
+        match self.memory.first() {
+            None => return Err(ConfigError::NoMemory),
+            Some(bank) if bank.kind != MemoryKind::Ram => return Err(ConfigError::BadBootBank),
+            _ => {}
+        }
+
+        // every window in guest physical address space: banks,devices,passthrough
+        let mut windows: Vec<(usize, usize)> = Vec::new();
+        for bank in self.memory.iter() {
+            if bank.size == 0 || bank.gpa % PAGE_SIZE != 0 || bank.size % PAGE_SIZE != 0 {
+                return Err(ConfigError::BadBank {
+                    gpa: bank.gpa,
+                    size: bank.size,
+                });
+            }
+            windows.push((bank.gpa, bank.size));
+        }
+        windows.extend(
+            self.devices
+                .iter()
+                .map(|dev| (dev.base, dev.kind.window_size())),
+        );
+        let frames = self.host_frames();
+        if frames > free_frames() {
+            return Err(ConfigError::OutOfMemory {
+                size: frames * PAGE_SIZE,
+            });
+        }
+        let mut host_owned = host_owned_windows();
+        host_owned.push((HOST_RAM_START, MEMORY_END - HOST_RAM_START));
+        if let Some((start, end)) = find_host_bundle() {
+            host_owned.push((start, end - start));
+        }
+        for pt in self.passthrough.iter() {
+            if pt.size == 0
+                || pt.host_pa % PAGE_SIZE != 0
+                || pt.gpa % PAGE_SIZE != 0
+                || pt.size % PAGE_SIZE != 0
+                || pt.host_pa.checked_add(pt.size).is_none()
+                || host_owned
+                    .iter()
+                    .any(|&window| overlaps((pt.host_pa, pt.size), window))
+            {
+                return Err(ConfigError::BadPassthrough {
+                    host_pa: pt.host_pa,
+                });
+            }
+            windows.push((pt.gpa, pt.size));
+        }
+        for (i, &a) in windows.iter().enumerate() {
+            if a.0.checked_add(a.1).is_none() {
+                return Err(ConfigError::Overlap(a.0, a.0));
+            }
+            if let Some(&b) = windows[..i].iter().find(|&&b| overlaps(a, b)) {
+                return Err(ConfigError::Overlap(b.0, a.0));
+            }
+        }
+
+        let irqchips = self
+            .devices
+            .iter()
+            .filter(|dev| matches!(dev.kind, DeviceKind::Plic | DeviceKind::Aplic))
+            .count();
+        if irqchips != 1 {
+            return Err(ConfigError::NoIrqChip);
+        }
+        // guest reaches imsic through csrs only harts with aia have
+        let imsics = self
+            .devices
+            .iter()
+            .filter(|dev| dev.kind == DeviceKind::Imsic)
+            .count();
+        if imsics > 1 || imsics == 1 && host_imsic().is_none() {
+            return Err(ConfigError::BadImsic);
+        }
+        let mut irqs: Vec<u32> = Vec::new();
+        let guest_irqs = self.devices.iter().filter_map(|dev| dev.irq).chain(
+            self.passthrough
+                .iter()
+                .filter_map(|pt| pt.irq.map(|irq| irq.1)),
+        );
+        for irq in guest_irqs {
+            if irq == 0 || irq as usize >= PLIC_SOURCE_NUMS {
+                return Err(ConfigError::BadIrq(irq));
+            }
+            if irqs.contains(&irq) {
+                return Err(ConfigError::IrqConflict(irq));
+            }
+            irqs.push(irq);
+        }
+
+        if let Some(entry) = self.entry {
+            let in_ram = self
+                .memory
+                .iter()
+                .any(|bank| (bank.gpa..bank.gpa + bank.size).contains(&entry));
+            if !in_ram {
+                return Err(ConfigError::BadEntry(entry));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl VmConfig<'static> {
+    /// images guest boots from,they live as long as hypervisor
+    pub fn guest_images(&self) -> GuestImages {
+        GuestImages {
+            kernel: self.kernel,
+            kernel_kind: self.kernel_kind,
+            initrd: self.initrd,
+            dtb: self.dtb,
+            bootargs: self.bootargs.clone(),
+            entry: self.entry,
+        }
+    }
+}
+
+/// validated configs of every guest bundle describes
+///
+/// guests under host `/chosen/hypercrab` win over guest table and config images of bundle. a broken
+/// config is reported and skipped,the other guests still boot
+pub fn bundle_configs<'a>(bundle: &Bundle<'a>) -> Vec<VmConfig<'a>> {
+    let configs = host_configs(bundle);
+    if !configs.is_empty() {
+        return configs;
+    }
+    let mut configs = Vec::new();
+    for guest in bundle.guests() {
+        let config = VmConfig::from_bundle_guest(&guest);
+        match config.validate() {
+            Ok(()) => configs.push(config),
+            Err(err) => println!("[hypervisor] bad guest config {}: {:?}", guest.name, err),
+        }
+    }
+    for image in bundle
+        .images()
+        .filter(|image| image.kind == EntryKind::Config)
+    {
+        let config = core::str::from_utf8(image.data)
+            .map_err(|_| ConfigError::Syntax { line: 0 })
+            .and_then(|text| parse_config(text, bundle));
+        match config {
+            Ok(config) => configs.push(config),
+            Err(err) => println!("[hypervisor] bad guest config {}: {:?}", image.name, err),
+        }
+    }
+    configs
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hart_sets() {
+        assert_eq!(parse_harts("0"), Some(0b1));
+        assert_eq!(parse_harts("0,2-3"), Some(0b1101));
+        assert_eq!(parse_harts("1-1,1"), Some(0b10));
+        assert_eq!(parse_harts("0-7"), Some(ALL_HARTS));
+        for bad in ["", "8", "3-1", "0,", "0-", "a", "0-8"] {
+            assert_eq!(parse_harts(bad), None, "{}", bad);
+        }
+    }
+
+    #[test]
+    fn later_affinity_wins() {
+        let mut config = VmConfig::new("test", &[]);
+        assert_eq!(config.vcpu_affinity(0), ALL_HARTS);
+        config.affinity.push((Some(1), 0b100));
+        config.affinity.push((None, 0b11));
+        config.affinity.push((Some(0), 0b1000));
+        assert_eq!(config.vcpu_affinity(0), 0b1000);
+        assert_eq!(config.vcpu_affinity(1), 0b11);
+    }
+
+    #[test]
+    fn restart_names() {
+        assert_eq!(
+            RestartPolicy::from_name("never"),
+            Some(RestartPolicy::Never)
+        );
+        assert_eq!(
+            RestartPolicy::from_name("on-crash"),
+            Some(RestartPolicy::OnCrash)
+        );
+        assert_eq!(
+            RestartPolicy::from_name("always"),
+            Some(RestartPolicy::Always)
+        );
+        assert_eq!(
+            RestartPolicy::from_name("max 3"),
+            Some(RestartPolicy::Max(3))
+        );
+        assert_eq!(RestartPolicy::from_name("max"), None);
+        assert_eq!(RestartPolicy::from_name("sometimes"), None);
+    }
+}
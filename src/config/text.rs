@@ -0,0 +1,372 @@
+//! text config packed in bundle as an image of kind `Config`
+//!
+//! one `key = value` per line,`#` starts a comment. list keys repeat,the first `memory` or `device`
+//! line drops the default banks or devices
+//!
+//! ```text
+//! name = linux
+//! vcpus = 2
+//! memory = 0x80200000 64M              # gpa size [ram|reserved]
+//! kernel = linux-image                 # image names in bundle
+//! initrd = rootfs
+//! entry = 0x80200000                   # optional,override entry of kernel
+//! bootargs = console=ttyS0 earlycon=sbi
+//! device = plic 0x0c000000             # kind gpa [irq],or aplic 0x0d000000
+//! device = uart 0x10000000 10
+//! device = imsic 0x28000000            # needs a host with aia
+//! console = host                       # host|none
+//! restart = max 3                      # never|on-crash|always|max n
+//! timeslice = 10                       # ms a vcpu runs while others wait for its hart
+//! weight = 512                         # share of cpu time against other guests,256 by default
+//! cap = 50                             # optional,at most this percent of one hart
+//! affinity = 0 2                       # vcpu|* harts,pin vcpu 0 to hart 2
+//! affinity = 1 0,2-3
+//! vector = off                         # on|off,hide vector extension from guest
+//! freezeclock = on                     # on|off,guest time stops while guest is paused
+//! passthrough = 0x10008000 0x1000 0x10008000 virtio,mmio 8 8  # hpa size gpa compatible [host irq guest irq]
+//! ```
+
+use super::{
+    parse_harts, ConfigError, ConsoleRoute, DeviceConfig, DeviceKind, MemoryBank, MemoryKind,
+    Passthrough, RestartPolicy, VmConfig,
+};
+use crate::bundle::{Bundle, BundleImage, EntryKind};
+
+/// decimal or 0x prefixed hex,with optional K/M/G suffix
+pub(super) fn parse_num(s: &str) -> Option<usize> {
+    let (digits, shift) = match s.as_bytes().last()? {
+        b'K' | b'k' => (&s[..s.len() - 1], 10),
+        b'M' | b'm' => (&s[..s.len() - 1], 20),
+        b'G' | b'g' => (&s[..s.len() - 1], 30),
+        _ => (s, 0),
+    };
+    let value = match digits.strip_prefix("0x") {
+        Some(hex) => usize::from_str_radix(hex, 16).ok()?,
+        None => digits.parse().ok()?,
+    };
+    value.checked_mul(1 << shift)
+}
+
+/// `on` or `off`
+pub(super) fn parse_switch(s: &str) -> Option<bool> {
+    match s {
+        "on" => Some(true),
+        "off" => Some(false),
+        _ => None,
+    }
+}
+
+/// image of bundle by name,kind is checked against what it's used as
+pub(super) fn find_image<'a>(
+    bundle: &Bundle<'a>,
+    name: &str,
+    is_wanted: fn(EntryKind) -> bool,
+) -> Result<BundleImage<'a>, ConfigError> {
+    bundle
+        .find_image(name)
+        .filter(|image| is_wanted(image.kind))
+        .ok_or_else(|| ConfigError::MissingImage(name.into()))
+}
+
+/// parse text config,images are looked up in bundle by name
+pub fn parse_config<'a>(text: &str, bundle: &Bundle<'a>) -> Result<VmConfig<'a>, ConfigError> {
+    let config = parse_lines(text, bundle)?;
+    config.validate()?;
+    Ok(config)
+}
+
+/// config as text says,not validated against host yet
+fn parse_lines<'a>(text: &str, bundle: &Bundle<'a>) -> Result<VmConfig<'a>, ConfigError> {
+    let mut config = VmConfig::new("", &[]);
+    let mut default_memory = true;
+    let mut default_devices = true;
+
+    for (i, line) in text.lines().enumerate() {
+        let err = ConfigError::Syntax { line: i + 1 };
+        let line = line.split('#').next().unwrap().trim();
+        if line.is_empty() {
+            continue;
+        }
+        let (key, value) = line.split_once('=').ok_or(err.clone())?;
+        let value = value.trim();
+        let mut args = value.split_whitespace();
+        let mut next_num = || args.next().and_then(parse_num).ok_or(err.clone());
+        match key.trim() {
+            "name" => config.name = value.into(),
+            "vcpus" => config.vcpu_nums = next_num()?,
+            "memory" => {
+                let (gpa, size) = (next_num()?, next_num()?);
+                let kind = match value.split_whitespace().nth(2) {
+                    None | Some("ram") => MemoryKind::Ram,
+                    Some("reserved") => MemoryKind::Reserved,
+                    Some(_) => return Err(err),
+                };
+                if default_memory {
+                    config.memory.clear();
+                    default_memory = false;
+                }
+                config.memory.push(MemoryBank { gpa, size, kind });
+            }
+            "kernel" => config.set_kernel(find_image(bundle, value, |kind| kind.is_kernel())?),
+            "initrd" => {
+                config.initrd =
+                    Some(find_image(bundle, value, |kind| kind == EntryKind::Initrd)?.data)
+            }
+            "dtb" => {
+                config.dtb = Some(find_image(bundle, value, |kind| kind == EntryKind::Dtb)?.data)
+            }
+            "entry" => config.entry = Some(next_num()?),
+            "bootargs" => config.bootargs = value.into(),
+            "device" => {
+                let mut args = value.split_whitespace();
+                let kind = args
+                    .next()
+                    .and_then(DeviceKind::from_name)
+                    .ok_or(err.clone())?;
+                let base = args.next().and_then(parse_num).ok_or(err.clone())?;
+                let irq = match args.next() {
+                    Some(irq) => Some(parse_num(irq).ok_or(err)? as u32),
+                    None => None,
+                };
+                if default_devices {
+                    config.devices.clear();
+                    default_devices = false;
+                }
+                config.devices.push(DeviceConfig { kind, base, irq });
+            }
+            "console" => config.console = ConsoleRoute::from_name(value).ok_or(err)?,
+            "restart" => config.restart = RestartPolicy::from_name(value).ok_or(err)?,
+            "timeslice" => config.time_slice_ms = next_num()?,
+            "weight" => config.weight = next_num()?,
+            "cap" => config.cap = Some(next_num()?),
+            "affinity" => {
+                let mut args = value.split_whitespace();
+                let vcpu_id = match args.next().ok_or(err.clone())? {
+                    "*" => None,
+                    vcpu_id => Some(parse_num(vcpu_id).ok_or(err.clone())?),
+                };
+                let mask = args.next().and_then(parse_harts).ok_or(err)?;
+                config.affinity.push((vcpu_id, mask));
+            }
+            "vector" => config.vector = parse_switch(value).ok_or(err)?,
+            "freezeclock" => config.freeze_clock = parse_switch(value).ok_or(err)?,
+            "passthrough" => {
+                let (host_pa, size, gpa) = (next_num()?, next_num()?, next_num()?);
+                let mut args = value.split_whitespace().skip(3);
+                let compatible = args.next().ok_or(err.clone())?.into();
+                let irq = match (args.next(), args.next()) {
+                    (None, None) => None,
+                    (Some(host), Some(guest)) => Some((
+                        parse_num(host).ok_or(err.clone())? as u32,
+                        parse_num(guest).ok_or(err)? as u32,
+                    )),
+                    _ => return Err(err),
+                };
+                config.passthrough.push(Passthrough {
+                    host_pa,
+                    size,
+                    gpa,
+                    compatible,
+                    irq,
+                });
+            }
+            _ => return Err(err),
+        }
+    }
+    Ok(config)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bundle::BUNDLE_MAGIC;
+    use crate::guest::ImageKind;
+    use alloc::vec::Vec;
+
+    const KERNEL: &[u8] = b"linux kernel";
+    const INITRD: &[u8] = b"rootfs";
+
+    /// bundle with a linux kernel named linux and an initrd named rootfs
+    fn bundle_data() -> Vec<u8> {
+        let images: [(&str, u32, &[u8]); 2] = [("linux", 2, KERNEL), ("rootfs", 3, INITRD)];
+        let mut data = BUNDLE_MAGIC.to_vec();
+        for word in [1, images.len() as u32, 0, 0] {
+            data.extend(word.to_le_bytes());
+        }
+        let mut offset = data.len() + images.len() * 48;
+        for (name, kind, image) in images {
+            let mut field = [0; 24];
+            field[..name.len()].copy_from_slice(name.as_bytes());
+            data.extend(field);
+            data.extend(kind.to_le_bytes());
+            data.extend(0u32.to_le_bytes());
+            data.extend((offset as u64).to_le_bytes());
+            data.extend((image.len() as u64).to_le_bytes());
+            offset += image.len();
+        }
+        for (_, _, image) in images {
+            data.extend(image);
+        }
+        data
+    }
+
+    fn parse(text: &str) -> Result<VmConfig<'static>, ConfigError> {
+        let data = Vec::leak(bundle_data());
+        parse_lines(text, &Bundle::parse(data).unwrap())
+    }
+
+    #[test]
+    fn numbers() {
+        assert_eq!(parse_num("16"), Some(16));
+        assert_eq!(parse_num("0x80200000"), Some(0x8020_0000));
+        assert_eq!(parse_num("4k"), Some(0x1000));
+        assert_eq!(parse_num("64M"), Some(64 << 20));
+        assert_eq!(parse_num("0x2G"), Some(2 << 30));
+        assert_eq!(parse_num("0xffffffffffffffffK"), None);
+        for bad in ["", "M", "0x", "12x", "-1", "0X10"] {
+            assert_eq!(parse_num(bad), None, "{}", bad);
+        }
+    }
+
+    #[test]
+    fn full_config() {
+        let config = parse(
+            "# guest of the example
+            name = linux
+            vcpus = 2
+            memory = 0x80200000 64M
+            memory = 0x90000000 1M reserved
+            kernel = linux
+            initrd = rootfs                  # comment after value
+            entry = 0x80200000
+            bootargs = console=ttyS0 earlycon=sbi
+            device = plic 0x0c000000
+            device = uart 0x10000000 10
+            console = none
+            passthrough = 0x10008000 0x1000 0x10008000 virtio,mmio 8 9",
+        )
+        .unwrap();
+        assert_eq!(config.name, "linux");
+        assert_eq!(config.vcpu_nums, 2);
+        let banks: Vec<_> = config
+            .memory
+            .iter()
+            .map(|bank| (bank.gpa, bank.size, bank.kind))
+            .collect();
+        assert_eq!(
+            banks,
+            [
+                (0x8020_0000, 64 << 20, MemoryKind::Ram),
+                (0x9000_0000, 1 << 20, MemoryKind::Reserved),
+            ]
+        );
+        assert_eq!(config.kernel, KERNEL);
+        assert_eq!(config.kernel_kind, Some(ImageKind::Linux));
+        assert_eq!(config.initrd, Some(INITRD));
+        assert_eq!(config.dtb, None);
+        assert_eq!(config.entry, Some(0x8020_0000));
+        assert_eq!(config.bootargs, "console=ttyS0 earlycon=sbi");
+        let devices: Vec<_> = config
+            .devices
+            .iter()
+            .map(|device| (device.kind, device.base, device.irq))
+            .collect();
+        assert_eq!(
+            devices,
+            [
+                (DeviceKind::Plic, 0x0c00_0000, None),
+                (DeviceKind::Uart, 0x1000_0000, Some(10)),
+            ]
+        );
+        assert_eq!(config.console, ConsoleRoute::Null);
+        let passthrough = &config.passthrough[0];
+        assert_eq!(
+            (passthrough.host_pa, passthrough.size, passthrough.gpa),
+            (0x1000_8000, 0x1000, 0x1000_8000)
+        );
+        assert_eq!(passthrough.compatible, "virtio,mmio");
+        assert_eq!(passthrough.irq, Some((8, 9)));
+    }
+
+    #[test]
+    fn defaults_stay_without_lines() {
+        let config = parse("kernel = linux\n\n   # nothing else\n").unwrap();
+        assert_eq!(config.vcpu_nums, 1);
+        assert_eq!(config.memory.len(), 1);
+        assert_eq!(config.devices.len(), crate::config::default_devices().len());
+        assert!(config.passthrough.is_empty());
+    }
+
+    #[test]
+    fn syntax_errors_name_their_line() {
+        for (text, line) in [
+            ("name = a\nvcpus 2", 2),
+            ("vcpus = two", 1),
+            ("\nmemory = 0x80000000", 2),
+            ("memory = 0x80000000 1M rom", 1),
+            ("device = gpu 0x1000", 1),
+            ("device = uart 0x1000 x", 1),
+            ("console = serial", 1),
+            ("passthrough = 0x1000 0x1000 0x1000 virtio,mmio 8", 1),
+            ("colour = blue", 1),
+        ] {
+            assert_eq!(
+                parse(text).err(),
+                Some(ConfigError::Syntax { line }),
+                "{}",
+                text
+            );
+        }
+    }
+
+    #[test]
+    fn restart_policy() {
+        let config = parse("restart = max 3").unwrap();
+        assert_eq!(config.restart, RestartPolicy::Max(3));
+        let config = parse("kernel = linux").unwrap();
+        assert_eq!(config.restart, RestartPolicy::OnCrash);
+        for bad in ["restart = sometimes", "restart = max", "restart = max -1"] {
+            assert_eq!(
+                parse(bad).err(),
+                Some(ConfigError::Syntax { line: 1 }),
+                "{}",
+                bad
+            );
+        }
+    }
+
+    #[test]
+    fn affinity_lines() {
+        let config = parse("affinity = * 0-1\naffinity = 1 2,3").unwrap();
+        assert_eq!(config.affinity, [(None, 0b0011), (Some(1), 0b1100)]);
+        for bad in [
+            "affinity = 0",
+            "affinity = 0 9",
+            "affinity = x 0",
+            "affinity = 0 1-",
+        ] {
+            assert_eq!(
+                parse(bad).err(),
+                Some(ConfigError::Syntax { line: 1 }),
+                "{}",
+                bad
+            );
+        }
+    }
+
+    #[test]
+    fn images_must_exist_as_their_kind() {
+        assert_eq!(
+            parse("kernel = bzImage").err(),
+            Some(ConfigError::MissingImage("bzImage".into()))
+        );
+        assert_eq!(
+            parse("kernel = rootfs").err(),
+            Some(ConfigError::MissingImage("rootfs".into()))
+        );
+        assert_eq!(
+            parse("initrd = linux").err(),
+            Some(ConfigError::MissingImage("linux".into()))
+        );
+    }
+}
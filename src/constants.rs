@@ -3,16 +3,21 @@ pub const HYPERVISOR_EXTENSION: usize = 0x7;
 pub const PAGE_SIZE: usize = 0x1 << 12;
 pub const PAGE_SIZE_BITS: usize = 12;
 pub const BOOT_STACK_SIZE: usize = PAGE_SIZE * 32;
+
+// harts entry.S reserves a stack for,stack of hart n is the nth HART_STACK_SIZE below boot_stack_top
+pub const MAX_HARTS: usize = 8;
+// affinity mask letting a vcpu run on any hart
+pub const ALL_HARTS: usize = (1 << MAX_HARTS) - 1;
+pub const HART_STACK_SIZE: usize = PAGE_SIZE * 16;
 pub const KERNEL_HEAP_SIZE: usize = 0x40_0000;
 
 pub const MEMORY_END: usize = 0x8020_0000 + 0xF00_0000;
-pub const CPU_NUMS: usize = 1;
 
 pub const GUEST_STACK_SIZE: usize = PAGE_SIZE * 16;
 
-pub const GUEST_STACK_TOP: usize = TRAMPOLINE - PAGE_SIZE;
+// stack in guest ram for boot vcpu,placed below device tree
+pub const GUEST_BOOT_STACK_SIZE: usize = PAGE_SIZE * 16;
 
-// 2 ^ 22 = 4M
-pub const GUEST_MEM_SIZE: usize = 0x200_0000;
+pub const GUEST_STACK_TOP: usize = TRAMPOLINE - PAGE_SIZE;
 
 pub const TRAMPOLINE: usize = usize::MAX - PAGE_SIZE + 1;
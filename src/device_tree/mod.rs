@@ -0,0 +1,100 @@
+//! device tree of host machine and writer for guest device trees
+
+mod writer;
+
+use crate::constants::MAX_HARTS;
+use crate::mm::{hpm_guard, MapPermission};
+use alloc::vec::Vec;
+use fdt::Fdt;
+use spin::Once;
+pub use writer::{node_name, FdtWriter};
+
+static HOST_DTB: Once<usize> = Once::new();
+
+// devices hypervisor or sbi drive,never handed to a guest
+const HOST_OWNED_COMPATIBLES: &[&str] = &[
+    "riscv,plic0",
+    "sifive,plic-1.0.0",
+    "riscv,imsics",
+    "riscv,aplic",
+    "riscv,clint0",
+    "sifive,clint0",
+];
+
+/// record host dtb passed by sbi and map it to hypervisor address space
+///
+/// size is read before paging is on,header of dtb isn't mapped until here
+pub fn init_host_fdt(dtb_paddress: usize, size: usize) {
+    hpm_guard().map_physical(dtb_paddress, size, MapPermission::R);
+    HOST_DTB.call_once(|| dtb_paddress);
+}
+
+pub fn host_fdt() -> Fdt<'static> {
+    let dtb = *HOST_DTB
+        .get()
+        .expect("[hypervisor] host fdt is not initialized");
+    unsafe { Fdt::from_ptr(dtb as *const u8).unwrap() }
+}
+
+/// (start,size) of host physical windows guests must not see: ram,host dtb and registers of
+/// interrupt controllers and timers
+pub fn host_owned_windows() -> Vec<(usize, usize)> {
+    let fdt = host_fdt();
+    let mut windows: Vec<(usize, usize)> = fdt
+        .memory()
+        .regions()
+        .filter_map(|region| Some((region.starting_address as usize, region.size?)))
+        .collect();
+    windows.push((*HOST_DTB.get().unwrap(), fdt.total_size()));
+    let owned = fdt.all_nodes().filter(|node| {
+        node.compatible().map_or(false, |compatible| {
+            compatible
+                .all()
+                .any(|c| HOST_OWNED_COMPATIBLES.contains(&c))
+        })
+    });
+    for node in owned {
+        let regs = node.reg().into_iter().flatten();
+        windows.extend(regs.filter_map(|reg| Some((reg.starting_address as usize, reg.size?))));
+    }
+    windows
+}
+
+/// mask of harts host device tree lists that have a hypervisor stack,see `start_secondary_harts`
+pub fn host_harts() -> usize {
+    host_fdt()
+        .cpus()
+        .map(|cpu| cpu.ids().first())
+        .filter(|&hart| hart < MAX_HARTS)
+        .fold(0, |mask, hart| mask | 1 << hart)
+}
+
+/// isa string of boot hart,like `rv64imafdch_zicsr_zifencei`
+pub fn host_isa() -> &'static str {
+    host_fdt()
+        .cpus()
+        .next()
+        .and_then(|cpu| cpu.property("riscv,isa"))
+        .and_then(|prop| prop.as_str())
+        .unwrap_or("rv64imafdc")
+}
+
+/// single letter extension is in isa string of boot hart
+pub fn host_has_extension(letter: char) -> bool {
+    let base = host_isa().split('_').next().unwrap_or("");
+    base.get(4..)
+        .map_or(false, |letters| letters.contains(letter))
+}
+
+/// multi letter extension like `sstc` is in isa string of boot hart
+pub fn host_has_multi_extension(name: &str) -> bool {
+    host_isa().split('_').skip(1).any(|ext| ext == name)
+}
+
+pub fn host_timebase_frequency() -> usize {
+    host_fdt()
+        .cpus()
+        .next()
+        .map(|cpu| cpu.timebase_frequency())
+        .unwrap_or(10_000_000)
+}
@@ -0,0 +1,153 @@
+//! flattened device tree writer
+//!
+//! produce dtb version 17,see devicetree specification chapter 5
+
+use alloc::string::String;
+use alloc::vec::Vec;
+
+const FDT_MAGIC: u32 = 0xd00d_feed;
+const FDT_VERSION: u32 = 17;
+const FDT_LAST_COMP_VERSION: u32 = 16;
+const FDT_HEADER_SIZE: usize = 40;
+// one empty reserve entry terminates the memory reservation block
+const FDT_RSVMAP_SIZE: usize = 16;
+
+const FDT_BEGIN_NODE: u32 = 0x1;
+const FDT_END_NODE: u32 = 0x2;
+const FDT_PROP: u32 = 0x3;
+const FDT_END: u32 = 0x9;
+
+pub struct FdtWriter {
+    structure: Vec<u8>,
+    strings: Vec<u8>,
+    depth: usize,
+}
+
+impl FdtWriter {
+    pub fn new() -> Self {
+        Self {
+            structure: Vec::new(),
+            strings: Vec::new(),
+            depth: 0,
+        }
+    }
+
+    #[inline]
+    fn push_u32(&mut self, value: u32) {
+        self.structure.extend_from_slice(&value.to_be_bytes());
+    }
+
+    /// pad structure block to 4 bytes boundary
+    fn align(&mut self) {
+        while self.structure.len() % 4 != 0 {
+            self.structure.push(0);
+        }
+    }
+
+    /// offset of name in strings block,reuse the same name
+    fn string_offset(&mut self, name: &str) -> u32 {
+        let mut offset = 0;
+        for s in self.strings.split(|&b| b == 0) {
+            if s == name.as_bytes() && offset < self.strings.len() {
+                return offset as u32;
+            }
+            offset += s.len() + 1;
+        }
+        let offset = self.strings.len();
+        self.strings.extend_from_slice(name.as_bytes());
+        self.strings.push(0);
+        offset as u32
+    }
+
+    pub fn begin_node(&mut self, name: &str) {
+        self.push_u32(FDT_BEGIN_NODE);
+        self.structure.extend_from_slice(name.as_bytes());
+        self.structure.push(0);
+        self.align();
+        self.depth += 1;
+    }
+
+    pub fn end_node(&mut self) {
+        assert!(self.depth > 0, "[FdtWriter] end node without begin");
+        self.push_u32(FDT_END_NODE);
+        self.depth -= 1;
+    }
+
+    pub fn property(&mut self, name: &str, value: &[u8]) {
+        let name_offset = self.string_offset(name);
+        self.push_u32(FDT_PROP);
+        self.push_u32(value.len() as u32);
+        self.push_u32(name_offset);
+        self.structure.extend_from_slice(value);
+        self.align();
+    }
+
+    /// property without value,like `interrupt-controller`
+    pub fn property_null(&mut self, name: &str) {
+        self.property(name, &[]);
+    }
+
+    pub fn property_u32(&mut self, name: &str, value: u32) {
+        self.property(name, &value.to_be_bytes());
+    }
+
+    pub fn property_u32s(&mut self, name: &str, values: &[u32]) {
+        let bytes: Vec<u8> = values.iter().flat_map(|v| v.to_be_bytes()).collect();
+        self.property(name, &bytes);
+    }
+
+    /// each value takes two cells,used by `reg` when #address-cells and #size-cells are 2
+    pub fn property_u64s(&mut self, name: &str, values: &[u64]) {
+        let bytes: Vec<u8> = values.iter().flat_map(|v| v.to_be_bytes()).collect();
+        self.property(name, &bytes);
+    }
+
+    pub fn property_string(&mut self, name: &str, value: &str) {
+        self.property_strings(name, &[value]);
+    }
+
+    pub fn property_strings(&mut self, name: &str, values: &[&str]) {
+        let mut bytes = Vec::new();
+        for value in values {
+            bytes.extend_from_slice(value.as_bytes());
+            bytes.push(0);
+        }
+        self.property(name, &bytes);
+    }
+
+    /// finish the tree and return dtb blob
+    pub fn finish(mut self, boot_cpuid: u32) -> Vec<u8> {
+        assert_eq!(self.depth, 0, "[FdtWriter] unclosed node");
+        self.push_u32(FDT_END);
+
+        let off_mem_rsvmap = FDT_HEADER_SIZE;
+        let off_dt_struct = off_mem_rsvmap + FDT_RSVMAP_SIZE;
+        let off_dt_strings = off_dt_struct + self.structure.len();
+        let total_size = off_dt_strings + self.strings.len();
+
+        let mut blob = Vec::with_capacity(total_size);
+        for field in [
+            FDT_MAGIC,
+            total_size as u32,
+            off_dt_struct as u32,
+            off_dt_strings as u32,
+            off_mem_rsvmap as u32,
+            FDT_VERSION,
+            FDT_LAST_COMP_VERSION,
+            boot_cpuid,
+            self.strings.len() as u32,
+            self.structure.len() as u32,
+        ] {
+            blob.extend_from_slice(&field.to_be_bytes());
+        }
+        blob.extend_from_slice(&[0; FDT_RSVMAP_SIZE]);
+        blob.extend_from_slice(&self.structure);
+        blob.extend_from_slice(&self.strings);
+        blob
+    }
+}
+
+/// node name with unit address,like `memory@80200000`
+pub fn node_name(name: &str, unit_address: usize) -> String {
+    alloc::format!("{}@{:x}", name, unit_address)
+}
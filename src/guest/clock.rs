@@ -0,0 +1,77 @@
+//! time a guest sees
+//!
+//! guest reads host time csr plus htimedelta of its vcpus,so guest time starts at zero when guest is
+//! created or restarts. a clock which freezes on pause gives the paused time back by moving delta,
+//! guest sees no jump when resumed. sbi timers of vcpus and wakeups of blocked vcpus convert guest
+//! deadlines through the same delta,see `Guest::set_vcpu_timer`
+
+use crate::schedule::now;
+
+#[derive(Debug, Clone, Copy)]
+pub struct GuestClock {
+    // guest time minus host time,wrapping
+    delta: usize,
+    // guest time doesn't advance while paused
+    freeze_on_pause: bool,
+    // host time guest was paused at
+    paused_at: Option<usize>,
+}
+
+impl GuestClock {
+    /// clock reading zero now
+    pub fn new(freeze_on_pause: bool) -> Self {
+        Self {
+            delta: 0usize.wrapping_sub(now()),
+            freeze_on_pause,
+            paused_at: None,
+        }
+    }
+
+    /// htimedelta of vcpus
+    #[inline]
+    pub fn delta(&self) -> usize {
+        self.delta
+    }
+
+    /// guest time now,a frozen clock reads what it read when guest was paused
+    pub fn now(&self) -> usize {
+        let host = match self.paused_at {
+            Some(paused_at) if self.freeze_on_pause => paused_at,
+            _ => now(),
+        };
+        host.wrapping_add(self.delta)
+    }
+
+    /// host time when guest time reaches guest_time,usize::MAX stays never and so does a deadline
+    /// past the end of host time
+    pub fn to_host(&self, guest_time: usize) -> usize {
+        if guest_time == usize::MAX {
+            return usize::MAX;
+        }
+        let host_now = now();
+        let guest_now = host_now.wrapping_add(self.delta);
+        match guest_time.checked_sub(guest_now) {
+            Some(ahead) => host_now.saturating_add(ahead),
+            // passed already
+            None => host_now.saturating_sub(guest_now - guest_time),
+        }
+    }
+
+    /// back to zero,guest restarts
+    pub fn reset(&mut self) {
+        *self = Self::new(self.freeze_on_pause);
+    }
+
+    pub fn pause(&mut self) {
+        self.paused_at.get_or_insert(now());
+    }
+
+    /// a frozen clock goes on from where it stopped
+    pub fn resume(&mut self) {
+        if let Some(paused_at) = self.paused_at.take() {
+            if self.freeze_on_pause {
+                self.delta = self.delta.wrapping_sub(now() - paused_at);
+            }
+        }
+    }
+}
@@ -0,0 +1,799 @@
+//! advanced platform level interrupt controller of guest,a single supervisor domain
+//!
+//! sources are numbered as on plic. in direct mode interrupt delivery control n is the supervisor
+//! external interrupt of vcpu n. in msi mode a pending enabled source is forwarded as an msi to
+//! imsic file of its target vcpu,`VirtDevices::sync_irqs` hands those to `VirtImsic`
+//!
+//! aplic sees no completion. a line held up for a host interrupt is released,completed on host,when
+//! guest re-arms its source by setip or clrip,when guest configures it so its rectified input drops,
+//! or in direct mode when idc which claimed it claims again after its handler returned. line reads
+//! low from then on until hypervisor raises it again,as it does while device still asserts
+
+use super::{IrqChip, MmioDevice, Phandles, PLIC_SOURCE_NUMS, VIRT_APLIC_SIZE};
+use crate::device_tree::{node_name, FdtWriter};
+use alloc::vec;
+use alloc::vec::Vec;
+
+const WORDS: usize = PLIC_SOURCE_NUMS / 32;
+
+const DOMAINCFG: usize = 0x0;
+const SOURCECFG_BASE: usize = 0x4;
+const MMSIADDRCFG: usize = 0x1bc0;
+const MMSIADDRCFGH: usize = 0x1bc4;
+const SMSIADDRCFG: usize = 0x1bc8;
+const SMSIADDRCFGH: usize = 0x1bcc;
+const SETIP_BASE: usize = 0x1c00;
+const SETIPNUM: usize = 0x1cdc;
+const IN_CLRIP_BASE: usize = 0x1d00;
+const CLRIPNUM: usize = 0x1ddc;
+const SETIE_BASE: usize = 0x1e00;
+const SETIENUM: usize = 0x1edc;
+const CLRIE_BASE: usize = 0x1f00;
+const CLRIENUM: usize = 0x1fdc;
+const SETIPNUM_LE: usize = 0x2000;
+const SETIPNUM_BE: usize = 0x2004;
+const GENMSI: usize = 0x3000;
+const TARGET_BASE: usize = 0x3004;
+const IDC_BASE: usize = 0x4000;
+const IDC_STRIDE: usize = 0x20;
+const IDELIVERY: usize = 0x0;
+const IFORCE: usize = 0x4;
+const ITHRESHOLD: usize = 0x8;
+const TOPI: usize = 0x18;
+const CLAIMI: usize = 0x1c;
+
+// domaincfg reads with bit 31 set
+const DOMAINCFG_RO: u32 = 1 << 31;
+const DOMAINCFG_IE: u32 = 1 << 8;
+const DOMAINCFG_DM: u32 = 1 << 2;
+// source is delegated to a child domain,we have none
+const SOURCECFG_D: u32 = 1 << 10;
+const SOURCECFG_SM: u32 = 0x7;
+// hart index of target in both delivery modes
+const TARGET_HART_SHIFT: u32 = 18;
+const TARGET_HART: u32 = 0x3fff << TARGET_HART_SHIFT;
+const TARGET_GUEST_SHIFT: u32 = 12;
+const TARGET_GUEST: u32 = 0x3f;
+const TARGET_EIID: u32 = 0x7ff;
+const TARGET_IPRIO: u32 = 0xff;
+// lock bit of mmsiaddrcfgh,machine level msi addresses are not ours to give
+const MSIADDRCFGH_L: u32 = 1 << 31;
+// supervisor external interrupt in cpu local interrupt controller
+const IRQ_S_EXT: u32 = 9;
+
+/// source mode of sourcecfg
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SourceMode {
+    Inactive,
+    Detached,
+    EdgeRise,
+    EdgeFall,
+    LevelHigh,
+    LevelLow,
+}
+
+impl SourceMode {
+    fn from_cfg(cfg: u32) -> Self {
+        match cfg & SOURCECFG_SM {
+            1 => Self::Detached,
+            4 => Self::EdgeRise,
+            5 => Self::EdgeFall,
+            6 => Self::LevelHigh,
+            7 => Self::LevelLow,
+            _ => Self::Inactive,
+        }
+    }
+
+    /// input is inverted before it's seen
+    #[inline]
+    fn inverted(self) -> bool {
+        matches!(self, Self::EdgeFall | Self::LevelLow)
+    }
+
+    #[inline]
+    fn is_level(self) -> bool {
+        matches!(self, Self::LevelHigh | Self::LevelLow)
+    }
+}
+
+#[derive(Clone, Copy, Default)]
+struct Idc {
+    idelivery: u32,
+    iforce: u32,
+    ithreshold: u32,
+    // source claimed last,0 once released
+    claimed: u32,
+}
+
+pub struct VirtAplic {
+    base: usize,
+    // base of imsic msis go to,none leaves domain in direct mode
+    msi_base: Option<usize>,
+    domaincfg: u32,
+    sourcecfg: [u32; PLIC_SOURCE_NUMS],
+    target: [u32; PLIC_SOURCE_NUMS],
+    pending: [u32; WORDS],
+    enabled: [u32; WORDS],
+    // current line level of each source,before inversion
+    level: [u32; WORDS],
+    idcs: Vec<Idc>,
+    genmsi: u32,
+    // (vcpu,interrupt id) of msis not handed to imsic yet
+    msis: Vec<(usize, u32)>,
+    // sources released since taken
+    eois: Vec<u32>,
+}
+
+#[inline]
+fn test_bit(bits: &[u32], n: usize) -> bool {
+    bits[n / 32] & (1 << (n % 32)) != 0
+}
+
+#[inline]
+fn set_bit(bits: &mut [u32], n: usize, value: bool) {
+    if value {
+        bits[n / 32] |= 1 << (n % 32);
+    } else {
+        bits[n / 32] &= !(1 << (n % 32));
+    }
+}
+
+impl VirtAplic {
+    /// aplic with an idc for every vcpu,msi_base is where guest imsic lives if it has one
+    pub fn new(base: usize, vcpu_nums: usize, msi_base: Option<usize>) -> Self {
+        Self {
+            base,
+            msi_base,
+            domaincfg: 0,
+            sourcecfg: [0; PLIC_SOURCE_NUMS],
+            target: [0; PLIC_SOURCE_NUMS],
+            pending: [0; WORDS],
+            enabled: [0; WORDS],
+            level: [0; WORDS],
+            idcs: vec![Idc::default(); vcpu_nums],
+            genmsi: 0,
+            msis: Vec::new(),
+            eois: Vec::new(),
+        }
+    }
+
+    #[inline]
+    fn msi_mode(&self) -> bool {
+        self.domaincfg & DOMAINCFG_DM != 0
+    }
+
+    #[inline]
+    fn enabled_domain(&self) -> bool {
+        self.domaincfg & DOMAINCFG_IE != 0
+    }
+
+    #[inline]
+    fn mode(&self, source: usize) -> SourceMode {
+        SourceMode::from_cfg(self.sourcecfg[source])
+    }
+
+    /// line level of source as aplic sees it,after inversion
+    fn rectified(&self, source: usize) -> bool {
+        let mode = self.mode(source);
+        match mode {
+            SourceMode::Inactive | SourceMode::Detached => false,
+            _ => test_bit(&self.level, source) != mode.inverted(),
+        }
+    }
+
+    /// guest is done with interrupt of source,its line drops if it's up
+    fn release(&mut self, source: usize) {
+        if !self.rectified(source) {
+            return;
+        }
+        self.eois.push(source as u32);
+        let mode = self.mode(source);
+        set_bit(&mut self.level, source, mode.inverted());
+        if mode.is_level() {
+            set_bit(&mut self.pending, source, false);
+        }
+    }
+
+    /// software sets or clears pending bit of source,level sources in direct mode follow their
+    /// line only
+    ///
+    /// either re-arms source,a level source in msi mode is pending again once hypervisor raises
+    /// its line again
+    fn write_pending(&mut self, source: usize, pending: bool) {
+        if source == 0 || source >= PLIC_SOURCE_NUMS {
+            return;
+        }
+        self.release(source);
+        let mode = self.mode(source);
+        let allowed = match mode {
+            SourceMode::Inactive => false,
+            SourceMode::Detached | SourceMode::EdgeRise | SourceMode::EdgeFall => true,
+            // a level source in msi mode is only set while its line is asserted
+            SourceMode::LevelHigh | SourceMode::LevelLow => {
+                self.msi_mode() && (!pending || self.rectified(source))
+            }
+        };
+        if allowed {
+            set_bit(&mut self.pending, source, pending);
+        }
+    }
+
+    fn write_enabled(&mut self, source: usize, enabled: bool) {
+        if source == 0 || source >= PLIC_SOURCE_NUMS {
+            return;
+        }
+        if self.mode(source) != SourceMode::Inactive {
+            set_bit(&mut self.enabled, source, enabled);
+        }
+    }
+
+    fn write_sourcecfg(&mut self, source: usize, value: u32) {
+        let was = self.rectified(source);
+        // no child domain to delegate to,reserved modes read as inactive
+        let value = if value & SOURCECFG_D != 0 {
+            0
+        } else {
+            value & SOURCECFG_SM
+        };
+        self.sourcecfg[source] = match SourceMode::from_cfg(value) {
+            SourceMode::Inactive => 0,
+            _ => value,
+        };
+        match self.mode(source) {
+            SourceMode::Inactive => {
+                set_bit(&mut self.pending, source, false);
+                set_bit(&mut self.enabled, source, false);
+            }
+            mode if mode.is_level() && !self.msi_mode() => {
+                let rectified = self.rectified(source);
+                set_bit(&mut self.pending, source, rectified);
+            }
+            _ => {}
+        }
+        if was && !self.rectified(source) {
+            self.eois.push(source as u32);
+        }
+    }
+
+    fn write_target(&mut self, source: usize, value: u32) {
+        self.target[source] = if self.msi_mode() {
+            value & (TARGET_HART | TARGET_GUEST << TARGET_GUEST_SHIFT | TARGET_EIID)
+        } else {
+            // priority 0 reads as 1
+            value & TARGET_HART | (value & TARGET_IPRIO).max(1)
+        };
+    }
+
+    /// highest priority source pending and enabled for idc under its threshold,as topi reads it
+    fn topi(&self, idc: usize) -> u32 {
+        let threshold = match self.idcs.get(idc) {
+            Some(idc) => idc.ithreshold,
+            None => return 0,
+        };
+        if self.msi_mode() {
+            return 0;
+        }
+        let mut best: Option<(u32, usize)> = None;
+        for source in 1..PLIC_SOURCE_NUMS {
+            let target = self.target[source];
+            let priority = target & TARGET_IPRIO;
+            if test_bit(&self.pending, source)
+                && test_bit(&self.enabled, source)
+                && (target >> TARGET_HART_SHIFT) as usize == idc
+                && (threshold == 0 || priority < threshold)
+                && best.map_or(true, |(p, _)| priority < p)
+            {
+                best = Some((priority, source));
+            }
+        }
+        best.map_or(0, |(priority, source)| (source as u32) << 16 | priority)
+    }
+
+    /// read of claimi,topi is claimed and its pending bit cleared
+    ///
+    /// source claimed before is released,handler of idc returned from it
+    fn claim(&mut self, idc: usize) -> u32 {
+        let claimed = match self.idcs.get_mut(idc) {
+            Some(idc) => core::mem::take(&mut idc.claimed),
+            None => 0,
+        };
+        if claimed != 0 {
+            self.release(claimed as usize);
+        }
+        let topi = self.topi(idc);
+        let source = (topi >> 16) as usize;
+        if source == 0 {
+            // a forced interrupt is taken by claiming nothing
+            if let Some(idc) = self.idcs.get_mut(idc) {
+                idc.iforce = 0;
+            }
+            return 0;
+        }
+        // level source still asserted stays pending
+        let still = self.mode(source).is_level() && self.rectified(source);
+        set_bit(&mut self.pending, source, still);
+        self.idcs[idc].claimed = source as u32;
+        topi
+    }
+
+    /// sources pending and enabled in msi mode are sent to their targets
+    fn forward_msis(&mut self) {
+        if !self.msi_mode() || !self.enabled_domain() {
+            return;
+        }
+        for source in 1..PLIC_SOURCE_NUMS {
+            if !test_bit(&self.pending, source) || !test_bit(&self.enabled, source) {
+                continue;
+            }
+            set_bit(&mut self.pending, source, false);
+            let target = self.target[source];
+            // vcpus have no guest files of their own
+            if target >> TARGET_GUEST_SHIFT & TARGET_GUEST != 0 {
+                continue;
+            }
+            self.msis
+                .push(((target >> TARGET_HART_SHIFT) as usize, target & TARGET_EIID));
+        }
+    }
+
+    /// msiaddrcfg registers describe guest imsic,one page for every hart index
+    fn msiaddrcfg(&self, offset: usize) -> u32 {
+        let ppn = self.msi_base.map_or(0, |base| base >> 12);
+        match offset {
+            MMSIADDRCFGH => MSIADDRCFGH_L,
+            SMSIADDRCFG => ppn as u32,
+            SMSIADDRCFGH => (ppn >> 32) as u32 & 0xfff,
+            _ => 0,
+        }
+    }
+
+    fn read_idc(&mut self, offset: usize) -> u32 {
+        let idc = (offset - IDC_BASE) / IDC_STRIDE;
+        let regs = match self.idcs.get(idc) {
+            Some(regs) => *regs,
+            None => return 0,
+        };
+        match (offset - IDC_BASE) % IDC_STRIDE {
+            IDELIVERY => regs.idelivery,
+            IFORCE => regs.iforce,
+            ITHRESHOLD => regs.ithreshold,
+            TOPI => self.topi(idc),
+            CLAIMI => self.claim(idc),
+            _ => 0,
+        }
+    }
+
+    fn write_idc(&mut self, offset: usize, value: u32) {
+        let idc = (offset - IDC_BASE) / IDC_STRIDE;
+        if let Some(regs) = self.idcs.get_mut(idc) {
+            match (offset - IDC_BASE) % IDC_STRIDE {
+                IDELIVERY => regs.idelivery = value & 1,
+                IFORCE => regs.iforce = value & 1,
+                ITHRESHOLD => regs.ithreshold = value & TARGET_IPRIO,
+                _ => {}
+            }
+        }
+    }
+}
+
+impl MmioDevice for VirtAplic {
+    fn base(&self) -> usize {
+        self.base
+    }
+
+    fn size(&self) -> usize {
+        VIRT_APLIC_SIZE
+    }
+
+    fn read(&mut self, offset: usize, _width: usize) -> usize {
+        let word = |base: usize| (offset - base) / 4;
+        let value = match offset {
+            DOMAINCFG => self.domaincfg | DOMAINCFG_RO,
+            SOURCECFG_BASE..=0xffc => self
+                .sourcecfg
+                .get(word(SOURCECFG_BASE) + 1)
+                .copied()
+                .unwrap_or(0),
+            MMSIADDRCFG..=SMSIADDRCFGH => self.msiaddrcfg(offset),
+            SETIP_BASE..=0x1c7c => self.pending.get(word(SETIP_BASE)).copied().unwrap_or(0),
+            // in_clrip reads rectified inputs
+            IN_CLRIP_BASE..=0x1d7c => {
+                let first = word(IN_CLRIP_BASE) * 32;
+                (0..32)
+                    .filter(|bit| {
+                        let source = first + bit;
+                        source < PLIC_SOURCE_NUMS && self.rectified(source)
+                    })
+                    .fold(0, |value, bit| value | 1 << bit)
+            }
+            SETIE_BASE..=0x1e7c => self.enabled.get(word(SETIE_BASE)).copied().unwrap_or(0),
+            GENMSI => self.genmsi,
+            TARGET_BASE..=0x3ffc => self.target.get(word(TARGET_BASE) + 1).copied().unwrap_or(0),
+            IDC_BASE..=usize::MAX => self.read_idc(offset),
+            _ => 0,
+        };
+        value as usize
+    }
+
+    fn write(&mut self, offset: usize, _width: usize, value: usize) {
+        let value = value as u32;
+        let word = |base: usize| (offset - base) / 4;
+        match offset {
+            // big endian is not supported,msi mode only if guest has an imsic
+            DOMAINCFG => {
+                let mut modes = DOMAINCFG_IE;
+                if self.msi_base.is_some() {
+                    modes |= DOMAINCFG_DM;
+                }
+                self.domaincfg = value & modes;
+            }
+            SOURCECFG_BASE..=0xffc => {
+                let source = word(SOURCECFG_BASE) + 1;
+                if source < PLIC_SOURCE_NUMS {
+                    self.write_sourcecfg(source, value);
+                }
+            }
+            SETIP_BASE..=0x1c7c | IN_CLRIP_BASE..=0x1d7c => {
+                let set = offset < IN_CLRIP_BASE;
+                let first = (offset & 0x7f) / 4 * 32;
+                for bit in (0..32).filter(|bit| value & 1 << bit != 0) {
+                    self.write_pending(first + bit, set);
+                }
+            }
+            SETIPNUM | SETIPNUM_LE => self.write_pending(value as usize, true),
+            SETIPNUM_BE => self.write_pending(value.swap_bytes() as usize, true),
+            CLRIPNUM => self.write_pending(value as usize, false),
+            SETIE_BASE..=0x1e7c | CLRIE_BASE..=0x1f7c => {
+                let set = offset < CLRIE_BASE;
+                let first = (offset & 0x7f) / 4 * 32;
+                for bit in (0..32).filter(|bit| value & 1 << bit != 0) {
+                    self.write_enabled(first + bit, set);
+                }
+            }
+            SETIENUM => self.write_enabled(value as usize, true),
+            CLRIENUM => self.write_enabled(value as usize, false),
+            // sent at once,busy bit never reads set
+            GENMSI => {
+                self.genmsi = value & (TARGET_HART | TARGET_EIID);
+                if self.msi_mode() {
+                    self.msis
+                        .push(((value >> TARGET_HART_SHIFT) as usize, value & TARGET_EIID));
+                }
+            }
+            TARGET_BASE..=0x3ffc => {
+                let source = word(TARGET_BASE) + 1;
+                if source < PLIC_SOURCE_NUMS {
+                    self.write_target(source, value);
+                }
+            }
+            IDC_BASE..=usize::MAX => self.write_idc(offset, value),
+            _ => {}
+        }
+        self.forward_msis();
+    }
+
+    fn reset(&mut self) {
+        *self = Self::new(self.base, self.idcs.len(), self.msi_base);
+    }
+
+    fn describe(&self, fdt: &mut FdtWriter, phandles: &Phandles) {
+        fdt.begin_node(&node_name("aplic", self.base));
+        fdt.property_string("compatible", "riscv,aplic");
+        fdt.property_u64s(
+            "reg",
+            &[
+                self.base as u64,
+                (IDC_BASE + self.idcs.len() * IDC_STRIDE) as u64,
+            ],
+        );
+        fdt.property_u32("#address-cells", 0);
+        fdt.property_u32("#interrupt-cells", 2);
+        fdt.property_null("interrupt-controller");
+        fdt.property_u32("riscv,num-sources", PLIC_SOURCE_NUMS as u32 - 1);
+        // guest driver picks msi mode when it sees an msi parent
+        match self.msi_base {
+            Some(_) => fdt.property_u32("msi-parent", phandles.msi),
+            None => {
+                let interrupts: Vec<u32> = phandles
+                    .cpu_intc
+                    .iter()
+                    .flat_map(|&intc| [intc, IRQ_S_EXT])
+                    .collect();
+                fdt.property_u32s("interrupts-extended", &interrupts);
+            }
+        }
+        fdt.property_u32("phandle", phandles.irqchip);
+        fdt.end_node();
+    }
+}
+
+impl IrqChip for VirtAplic {
+    fn set_irq(&mut self, source: u32, level: bool) {
+        let source = source as usize;
+        if source == 0 || source >= PLIC_SOURCE_NUMS {
+            return;
+        }
+        let was = self.rectified(source);
+        set_bit(&mut self.level, source, level);
+        let now = self.rectified(source);
+        match self.mode(source) {
+            SourceMode::Inactive | SourceMode::Detached => {}
+            mode if mode.is_level() && !self.msi_mode() => {
+                set_bit(&mut self.pending, source, now);
+            }
+            mode if mode.is_level() && !now => set_bit(&mut self.pending, source, false),
+            _ => {
+                if !was && now {
+                    set_bit(&mut self.pending, source, true);
+                }
+            }
+        }
+        self.forward_msis();
+    }
+
+    /// only in direct mode,msis reach vcpus through imsic
+    fn vcpu_pending(&self, vcpu_id: usize) -> bool {
+        let idc = match self.idcs.get(vcpu_id) {
+            Some(idc) => idc,
+            None => return false,
+        };
+        !self.msi_mode()
+            && self.enabled_domain()
+            && idc.idelivery != 0
+            && (idc.iforce != 0 || self.topi(vcpu_id) != 0)
+    }
+
+    #[inline]
+    fn take_msis(&mut self) -> Vec<(usize, u32)> {
+        core::mem::take(&mut self.msis)
+    }
+
+    #[inline]
+    fn take_eois(&mut self) -> Vec<u32> {
+        core::mem::take(&mut self.eois)
+    }
+
+    #[inline]
+    fn interrupt_cells(&self) -> u32 {
+        2
+    }
+
+    fn as_mmio(&mut self) -> &mut dyn MmioDevice {
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const IMSIC_BASE: usize = 0x2800_0000;
+
+    fn read(aplic: &mut VirtAplic, offset: usize) -> u32 {
+        aplic.read(offset, 4) as u32
+    }
+
+    fn write(aplic: &mut VirtAplic, offset: usize, value: u32) {
+        aplic.write(offset, 4, value as usize);
+    }
+
+    fn idc(n: usize, reg: usize) -> usize {
+        IDC_BASE + n * IDC_STRIDE + reg
+    }
+
+    /// direct mode aplic with source on idc 0 at priority,enabled and delivered
+    fn direct(source: usize, mode: u32, priority: u32) -> VirtAplic {
+        let mut aplic = VirtAplic::new(0xc00_0000, 2, None);
+        write(&mut aplic, DOMAINCFG, DOMAINCFG_IE);
+        write(&mut aplic, SOURCECFG_BASE * source, mode);
+        write(&mut aplic, TARGET_BASE + 4 * (source - 1), priority);
+        write(&mut aplic, SETIENUM, source as u32);
+        write(&mut aplic, idc(0, IDELIVERY), 1);
+        aplic
+    }
+
+    /// msi mode aplic with source sent to vcpu as eiid,enabled
+    fn msi(source: usize, mode: u32, vcpu: u32, eiid: u32) -> VirtAplic {
+        let mut aplic = VirtAplic::new(0xc00_0000, 2, Some(IMSIC_BASE));
+        write(&mut aplic, DOMAINCFG, DOMAINCFG_IE | DOMAINCFG_DM);
+        write(&mut aplic, SOURCECFG_BASE * source, mode);
+        write(
+            &mut aplic,
+            TARGET_BASE + 4 * (source - 1),
+            vcpu << TARGET_HART_SHIFT | eiid,
+        );
+        write(&mut aplic, SETIENUM, source as u32);
+        aplic
+    }
+
+    #[test]
+    fn sourcecfg_modes() {
+        let mut aplic = VirtAplic::new(0, 1, None);
+        for mode in [1, 4, 5, 6, 7] {
+            write(&mut aplic, SOURCECFG_BASE * 3, mode);
+            assert_eq!(read(&mut aplic, SOURCECFG_BASE * 3), mode);
+        }
+        // reserved modes and delegation read as inactive
+        for cfg in [2, 3, SOURCECFG_D | 4] {
+            write(&mut aplic, SOURCECFG_BASE * 3, cfg);
+            assert_eq!(read(&mut aplic, SOURCECFG_BASE * 3), 0);
+        }
+        // inactive source can't be enabled or pending
+        write(&mut aplic, SETIENUM, 3);
+        write(&mut aplic, SETIPNUM, 3);
+        assert_eq!(read(&mut aplic, SETIE_BASE), 0);
+        assert_eq!(read(&mut aplic, SETIP_BASE), 0);
+        // going inactive drops both
+        write(&mut aplic, SOURCECFG_BASE * 3, 4);
+        write(&mut aplic, SETIENUM, 3);
+        write(&mut aplic, SETIPNUM, 3);
+        assert_eq!(read(&mut aplic, SETIE_BASE), 1 << 3);
+        assert_eq!(read(&mut aplic, SETIP_BASE), 1 << 3);
+        write(&mut aplic, SOURCECFG_BASE * 3, 0);
+        assert_eq!(read(&mut aplic, SETIE_BASE), 0);
+        assert_eq!(read(&mut aplic, SETIP_BASE), 0);
+    }
+
+    #[test]
+    fn domaincfg_msi_mode_needs_imsic() {
+        let mut aplic = VirtAplic::new(0, 1, None);
+        write(&mut aplic, DOMAINCFG, DOMAINCFG_IE | DOMAINCFG_DM);
+        assert_eq!(read(&mut aplic, DOMAINCFG), DOMAINCFG_RO | DOMAINCFG_IE);
+        let mut aplic = VirtAplic::new(0, 1, Some(IMSIC_BASE));
+        write(&mut aplic, DOMAINCFG, DOMAINCFG_IE | DOMAINCFG_DM);
+        assert_eq!(
+            read(&mut aplic, DOMAINCFG),
+            DOMAINCFG_RO | DOMAINCFG_IE | DOMAINCFG_DM
+        );
+        assert_eq!(read(&mut aplic, SMSIADDRCFG), (IMSIC_BASE >> 12) as u32);
+    }
+
+    #[test]
+    fn in_clrip_reads_rectified_input() {
+        let mut aplic = VirtAplic::new(0, 1, None);
+        write(&mut aplic, SOURCECFG_BASE * 2, 6);
+        write(&mut aplic, SOURCECFG_BASE * 3, 7);
+        assert_eq!(read(&mut aplic, IN_CLRIP_BASE), 1 << 3);
+        aplic.set_irq(2, true);
+        aplic.set_irq(3, true);
+        assert_eq!(read(&mut aplic, IN_CLRIP_BASE), 1 << 2);
+    }
+
+    #[test]
+    fn edge_topi_and_claimi() {
+        let mut aplic = direct(3, 4, 2);
+        assert_eq!(read(&mut aplic, idc(0, TOPI)), 0);
+        assert!(!aplic.vcpu_pending(0));
+        aplic.set_irq(3, true);
+        assert_eq!(read(&mut aplic, idc(0, TOPI)), 3 << 16 | 2);
+        assert!(aplic.vcpu_pending(0));
+        assert!(!aplic.vcpu_pending(1));
+        assert_eq!(read(&mut aplic, idc(0, CLAIMI)), 3 << 16 | 2);
+        assert_eq!(read(&mut aplic, idc(0, TOPI)), 0);
+        assert!(!aplic.vcpu_pending(0));
+        // line held high raises no second edge
+        aplic.set_irq(3, true);
+        assert_eq!(read(&mut aplic, idc(0, TOPI)), 0);
+        assert!(aplic.take_eois().is_empty());
+        // claiming again releases source claimed before
+        assert_eq!(read(&mut aplic, idc(0, CLAIMI)), 0);
+        assert_eq!(aplic.take_eois(), [3]);
+        assert_eq!(read(&mut aplic, IN_CLRIP_BASE), 0);
+        aplic.set_irq(3, true);
+        assert_eq!(read(&mut aplic, idc(0, TOPI)), 3 << 16 | 2);
+    }
+
+    #[test]
+    fn falling_edge() {
+        let mut aplic = direct(4, 5, 1);
+        aplic.set_irq(4, true);
+        assert_eq!(read(&mut aplic, idc(0, TOPI)), 0);
+        aplic.set_irq(4, false);
+        assert_eq!(read(&mut aplic, idc(0, TOPI)), 4 << 16 | 1);
+    }
+
+    #[test]
+    fn level_stays_pending_while_asserted() {
+        let mut aplic = direct(5, 6, 1);
+        aplic.set_irq(5, true);
+        assert_eq!(read(&mut aplic, idc(0, CLAIMI)), 5 << 16 | 1);
+        assert_eq!(read(&mut aplic, SETIP_BASE), 1 << 5);
+        // released by next claim,line drops until hypervisor raises it again
+        assert_eq!(read(&mut aplic, idc(0, CLAIMI)), 0);
+        assert_eq!(aplic.take_eois(), [5]);
+        assert_eq!(read(&mut aplic, SETIP_BASE), 0);
+        aplic.set_irq(5, true);
+        aplic.set_irq(5, false);
+        assert_eq!(read(&mut aplic, SETIP_BASE), 0);
+    }
+
+    #[test]
+    fn priority_and_threshold() {
+        let mut aplic = direct(3, 4, 5);
+        write(&mut aplic, SOURCECFG_BASE * 7, 4);
+        write(&mut aplic, TARGET_BASE + 4 * 6, 2);
+        write(&mut aplic, SETIENUM, 7);
+        aplic.set_irq(3, true);
+        aplic.set_irq(7, true);
+        assert_eq!(read(&mut aplic, idc(0, TOPI)), 7 << 16 | 2);
+        write(&mut aplic, idc(0, ITHRESHOLD), 2);
+        assert_eq!(read(&mut aplic, idc(0, TOPI)), 0);
+        write(&mut aplic, idc(0, ITHRESHOLD), 6);
+        assert_eq!(read(&mut aplic, idc(0, TOPI)), 7 << 16 | 2);
+        // priority 0 reads as 1
+        write(&mut aplic, TARGET_BASE + 4 * 6, 0);
+        assert_eq!(read(&mut aplic, TARGET_BASE + 4 * 6), 1);
+    }
+
+    #[test]
+    fn setip_and_clrip() {
+        let mut aplic = direct(3, 4, 1);
+        write(&mut aplic, SETIPNUM, 3);
+        assert_eq!(read(&mut aplic, SETIP_BASE), 1 << 3);
+        write(&mut aplic, CLRIPNUM, 3);
+        assert_eq!(read(&mut aplic, SETIP_BASE), 0);
+        write(&mut aplic, SETIP_BASE, 1 << 3);
+        assert_eq!(read(&mut aplic, SETIP_BASE), 1 << 3);
+        write(&mut aplic, IN_CLRIP_BASE, 1 << 3);
+        assert_eq!(read(&mut aplic, SETIP_BASE), 0);
+        // level source in direct mode follows its line only
+        write(&mut aplic, SOURCECFG_BASE * 5, 6);
+        write(&mut aplic, SETIPNUM, 5);
+        assert_eq!(read(&mut aplic, SETIP_BASE), 0);
+        // source 0 doesn't exist
+        write(&mut aplic, SETIPNUM, 0);
+        assert_eq!(read(&mut aplic, SETIP_BASE), 0);
+    }
+
+    #[test]
+    fn setip_releases_raised_line() {
+        let mut aplic = direct(3, 4, 1);
+        aplic.set_irq(3, true);
+        write(&mut aplic, CLRIPNUM, 3);
+        assert_eq!(aplic.take_eois(), [3]);
+        assert_eq!(read(&mut aplic, IN_CLRIP_BASE), 0);
+        write(&mut aplic, SETIPNUM, 3);
+        assert!(aplic.take_eois().is_empty());
+    }
+
+    #[test]
+    fn sourcecfg_dropping_line_releases_it() {
+        let mut aplic = direct(3, 6, 1);
+        aplic.set_irq(3, true);
+        write(&mut aplic, SOURCECFG_BASE * 3, 7);
+        assert_eq!(aplic.take_eois(), [3]);
+        assert_eq!(read(&mut aplic, SETIP_BASE), 0);
+    }
+
+    #[test]
+    fn msi_mode_forwards_to_target() {
+        let mut aplic = msi(5, 4, 1, 7);
+        assert_eq!(read(&mut aplic, idc(1, TOPI)), 0);
+        aplic.set_irq(5, true);
+        assert_eq!(aplic.take_msis(), [(1, 7)]);
+        assert_eq!(read(&mut aplic, SETIP_BASE), 0);
+        assert!(!aplic.vcpu_pending(1));
+        // sent once domain is enabled
+        write(&mut aplic, DOMAINCFG, DOMAINCFG_DM);
+        write(&mut aplic, SETIPNUM, 5);
+        assert!(aplic.take_msis().is_empty());
+        write(&mut aplic, DOMAINCFG, DOMAINCFG_IE | DOMAINCFG_DM);
+        assert_eq!(aplic.take_msis(), [(1, 7)]);
+        write(&mut aplic, GENMSI, 1 << TARGET_HART_SHIFT | 9);
+        assert_eq!(aplic.take_msis(), [(1, 9)]);
+    }
+
+    #[test]
+    fn msi_mode_level_is_rearmed_by_setip() {
+        let mut aplic = msi(5, 6, 0, 3);
+        aplic.set_irq(5, true);
+        assert_eq!(aplic.take_msis(), [(0, 3)]);
+        assert!(aplic.take_eois().is_empty());
+        // guest re-arms source,line is released and pending again only once it's raised
+        write(&mut aplic, SETIPNUM, 5);
+        assert_eq!(aplic.take_eois(), [5]);
+        assert!(aplic.take_msis().is_empty());
+        aplic.set_irq(5, true);
+        assert_eq!(aplic.take_msis(), [(0, 3)]);
+    }
+}
@@ -0,0 +1,156 @@
+//! incoming msi controller of guest,an interrupt file page for every vcpu
+//!
+//! page of a vcpu owning a guest interrupt file of a host hart maps to that file in g stage,guest
+//! raises msis there without exits and the vcpu reaches its file through sireg directly. other
+//! vcpus get a file emulated here: writes to their page trap as mmio and set pending bits,their
+//! sireg and stopei accesses trap as virtual instruction,see `Guest::emulate_imsic_csr`. a vcpu
+//! moves between the two as it gives up its guest file on repin and takes a free one again
+
+use super::{MmioDevice, Phandles};
+use crate::arch::intc::imsic::{host_imsic, FileState, GuestFile, MAX_IDS};
+use crate::constants::PAGE_SIZE;
+use crate::device_tree::{node_name, FdtWriter};
+use alloc::vec;
+use alloc::vec::Vec;
+
+const SETEIPNUM_LE: usize = 0x0;
+const SETEIPNUM_BE: usize = 0x4;
+// supervisor external interrupt in cpu local interrupt controller
+const IRQ_S_EXT: u32 = 9;
+
+/// where interrupt file of a vcpu lives
+#[derive(Debug, Clone, Copy)]
+pub enum ImsicFile {
+    Hardware(GuestFile),
+    Software(FileState),
+}
+
+pub struct VirtImsic {
+    base: usize,
+    files: Vec<ImsicFile>,
+    // mask of vcpus an msi landed in emulated file of,since last taken
+    notified: usize,
+}
+
+impl VirtImsic {
+    /// every vcpu starts with an emulated file
+    pub fn new(base: usize, vcpu_nums: usize) -> Self {
+        Self {
+            base,
+            files: vec![ImsicFile::Software(FileState::default()); vcpu_nums],
+            notified: 0,
+        }
+    }
+
+    /// gpa of interrupt file page of vcpu
+    #[inline]
+    pub fn page(&self, vcpu_id: usize) -> usize {
+        self.base + vcpu_id * PAGE_SIZE
+    }
+
+    #[inline]
+    pub fn set_file(&mut self, vcpu_id: usize, file: ImsicFile) {
+        self.files[vcpu_id] = file;
+    }
+
+    /// guest file of host hart vcpu owns
+    pub fn guest_file(&self, vcpu_id: usize) -> Option<GuestFile> {
+        match self.files.get(vcpu_id)? {
+            ImsicFile::Hardware(file) => Some(*file),
+            ImsicFile::Software(_) => None,
+        }
+    }
+
+    /// emulated file of vcpu
+    pub fn software(&mut self, vcpu_id: usize) -> Option<&mut FileState> {
+        match self.files.get_mut(vcpu_id)? {
+            ImsicFile::Software(state) => Some(state),
+            ImsicFile::Hardware(_) => None,
+        }
+    }
+
+    /// emulated file of vcpu asserts its external interrupt
+    pub fn software_pending(&self, vcpu_id: usize) -> bool {
+        match self.files.get(vcpu_id) {
+            Some(ImsicFile::Software(state)) => state.pending(),
+            _ => false,
+        }
+    }
+
+    /// msi with interrupt id to file of vcpu
+    pub fn send(&mut self, vcpu_id: usize, id: usize) {
+        match self.files.get_mut(vcpu_id) {
+            Some(ImsicFile::Software(state)) => {
+                state.set_pending(id);
+                self.notified |= 1 << vcpu_id;
+            }
+            // from aplic,or a guest write racing with the mapping of the page
+            Some(ImsicFile::Hardware(file)) => {
+                if let Some(imsic) = host_imsic() {
+                    imsic.send(*file, id);
+                }
+            }
+            None => {}
+        }
+    }
+
+    /// vcpus an msi landed for since last call,as a mask
+    #[inline]
+    pub fn take_notified(&mut self) -> usize {
+        core::mem::take(&mut self.notified)
+    }
+}
+
+impl MmioDevice for VirtImsic {
+    fn base(&self) -> usize {
+        self.base
+    }
+
+    fn size(&self) -> usize {
+        self.files.len() * PAGE_SIZE
+    }
+
+    fn read(&mut self, _offset: usize, _width: usize) -> usize {
+        0
+    }
+
+    fn write(&mut self, offset: usize, _width: usize, value: usize) {
+        let vcpu_id = offset / PAGE_SIZE;
+        let id = match offset % PAGE_SIZE {
+            SETEIPNUM_LE => value as u32,
+            SETEIPNUM_BE => (value as u32).swap_bytes(),
+            _ => return,
+        } as usize;
+        self.send(vcpu_id, id);
+    }
+
+    /// emulated files are cleared,guest files are cleared by their vcpus before they run again
+    fn reset(&mut self) {
+        for file in self.files.iter_mut() {
+            if let ImsicFile::Software(state) = file {
+                *state = FileState::default();
+            }
+        }
+        self.notified = 0;
+    }
+
+    fn describe(&self, fdt: &mut FdtWriter, phandles: &Phandles) {
+        let interrupts: Vec<u32> = phandles
+            .cpu_intc
+            .iter()
+            .flat_map(|&intc| [intc, IRQ_S_EXT])
+            .collect();
+        let num_ids = host_imsic().map_or(MAX_IDS - 1, |imsic| imsic.num_ids());
+        fdt.begin_node(&node_name("imsics", self.base));
+        fdt.property_string("compatible", "riscv,imsics");
+        fdt.property_u64s("reg", &[self.base as u64, self.size() as u64]);
+        fdt.property_u32("#interrupt-cells", 0);
+        fdt.property_null("interrupt-controller");
+        fdt.property_null("msi-controller");
+        fdt.property_u32("#msi-cells", 0);
+        fdt.property_u32("riscv,num-ids", num_ids as u32);
+        fdt.property_u32s("interrupts-extended", &interrupts);
+        fdt.property_u32("phandle", phandles.msi);
+        fdt.end_node();
+    }
+}
@@ -0,0 +1,277 @@
+//! emulated devices of virt machine
+//!
+//! guest g stage page table never maps device windows,every access traps as guest page fault and is
+//! forwarded to the device covering the faulting gpa. imsic pages of vcpus owning a guest interrupt
+//! file of a host hart are the exception,see `imsic`
+//!
+//! irqchip is a plic or an aplic,devices raise interrupts on it the same way. an aplic in msi mode
+//! turns them into msis to imsic. interrupts of passthrough devices come from host plic,their line
+//! is up from host claim until guest is done with the interrupt
+
+mod aplic;
+mod imsic;
+mod plic;
+mod uart;
+mod virtio;
+
+use crate::arch::intc::plic::host_plic;
+use crate::config::{ConsoleRoute, DeviceConfig, DeviceKind, Passthrough, MAX_VCPUS};
+use crate::constants::PAGE_SIZE;
+use crate::device_tree::FdtWriter;
+use alloc::boxed::Box;
+use alloc::vec;
+use alloc::vec::Vec;
+pub use aplic::VirtAplic;
+pub use imsic::{ImsicFile, VirtImsic};
+pub use plic::{VirtPlic, PLIC_SOURCE_NUMS};
+pub use uart::VirtUart;
+pub use virtio::VirtioMmioSlot;
+
+// platform layout follows qemu virt machine,so guest kernels built for it run without change
+pub const VIRT_PLIC_BASE: usize = 0x0c00_0000;
+pub const VIRT_PLIC_SIZE: usize = 0x0400_0000;
+pub const VIRT_APLIC_BASE: usize = 0x0d00_0000;
+// interrupt delivery controls of vcpus follow domain registers
+pub const VIRT_APLIC_SIZE: usize = 0x4000 + MAX_VCPUS * 0x20;
+pub const VIRT_IMSIC_BASE: usize = 0x2800_0000;
+// a page for every vcpu
+pub const VIRT_IMSIC_SIZE: usize = MAX_VCPUS * PAGE_SIZE;
+pub const VIRT_UART_BASE: usize = 0x1000_0000;
+pub const VIRT_UART_SIZE: usize = 0x100;
+pub const VIRT_UART_IRQ: u32 = 10;
+pub const VIRT_VIRTIO_BASE: usize = 0x1000_1000;
+pub const VIRT_VIRTIO_SIZE: usize = 0x1000;
+pub const VIRT_VIRTIO_IRQ_BASE: u32 = 1;
+pub const VIRT_VIRTIO_NUMS: usize = 8;
+
+/// phandles referenced by device nodes
+pub struct Phandles {
+    // interrupt controller node of each vcpu
+    pub cpu_intc: Vec<u32>,
+    // phandle of irqchip,interrupt parent of other devices
+    pub irqchip: u32,
+    // phandle of imsic,msi parent
+    pub msi: u32,
+    // cells of interrupt specifier on irqchip
+    pub irq_cells: u32,
+}
+
+// level triggered,active high,second cell of interrupt specifier
+const IRQ_TYPE_LEVEL_HIGH: u32 = 4;
+
+impl Phandles {
+    /// interrupt specifier of source on irqchip,sources of devices are level triggered
+    pub fn interrupts(&self, source: u32) -> Vec<u32> {
+        match self.irq_cells {
+            1 => vec![source],
+            _ => vec![source, IRQ_TYPE_LEVEL_HIGH],
+        }
+    }
+}
+
+pub trait MmioDevice {
+    fn base(&self) -> usize;
+
+    fn size(&self) -> usize;
+
+    fn read(&mut self, offset: usize, width: usize) -> usize;
+
+    fn write(&mut self, offset: usize, width: usize, value: usize);
+
+    /// back to power on state
+    fn reset(&mut self) {}
+
+    /// interrupt source number and current line level,if device is wired to irqchip
+    fn irq_line(&self) -> Option<(u32, bool)> {
+        None
+    }
+
+    /// append device node to guest device tree
+    fn describe(&self, fdt: &mut FdtWriter, phandles: &Phandles);
+
+    #[inline]
+    fn contains(&self, gpa: usize) -> bool {
+        gpa >= self.base() && gpa < self.base() + self.size()
+    }
+}
+
+/// interrupt controller which other devices raise interrupts to
+pub trait IrqChip: MmioDevice {
+    fn set_irq(&mut self, source: u32, level: bool);
+
+    /// external interrupt of vcpu is pending
+    fn vcpu_pending(&self, vcpu_id: usize) -> bool;
+
+    /// (vcpu,interrupt id) of msis sent since last call
+    fn take_msis(&mut self) -> Vec<(usize, u32)> {
+        Vec::new()
+    }
+
+    /// sources guest finished an interrupt of since last call
+    fn take_eois(&mut self) -> Vec<u32> {
+        Vec::new()
+    }
+
+    /// cells of interrupt specifier devices use
+    fn interrupt_cells(&self) -> u32 {
+        1
+    }
+
+    fn as_mmio(&mut self) -> &mut dyn MmioDevice;
+}
+
+/// interrupt of host plic passed through to guest irqchip
+struct ForwardedIrq {
+    host: u32,
+    guest: u32,
+    // claimed on host,guest isn't done with it yet
+    asserted: bool,
+}
+
+pub struct VirtDevices {
+    pub irqchip: Box<dyn IrqChip>,
+    pub imsic: Option<VirtImsic>,
+    pub devices: Vec<Box<dyn MmioDevice>>,
+    forwarded: Vec<ForwardedIrq>,
+    // gpa of uart used as guest console
+    pub stdout: Option<usize>,
+}
+
+impl VirtDevices {
+    /// devices of validated config,which has exactly one irqchip,with interrupts of passthrough
+    /// devices
+    pub fn from_config(
+        configs: &[DeviceConfig],
+        passthrough: &[Passthrough],
+        vcpu_nums: usize,
+        console: ConsoleRoute,
+    ) -> Self {
+        let mut irqchip: Option<Box<dyn IrqChip>> = None;
+        let mut imsic = None;
+        let mut devices: Vec<Box<dyn MmioDevice>> = Vec::new();
+        // aplic sends msis to imsic if there is one
+        let msi_base = configs
+            .iter()
+            .find(|config| config.kind == DeviceKind::Imsic)
+            .map(|config| config.base);
+        let mut stdout = None;
+        for config in configs.iter() {
+            let irq = config.irq.unwrap_or(0);
+            match config.kind {
+                DeviceKind::Plic => irqchip = Some(Box::new(VirtPlic::new(config.base, vcpu_nums))),
+                DeviceKind::Aplic => {
+                    irqchip = Some(Box::new(VirtAplic::new(config.base, vcpu_nums, msi_base)))
+                }
+                DeviceKind::Imsic => imsic = Some(VirtImsic::new(config.base, vcpu_nums)),
+                DeviceKind::Uart => {
+                    stdout.get_or_insert(config.base);
+                    devices.push(Box::new(VirtUart::new(config.base, irq, console)));
+                }
+                DeviceKind::VirtioMmio => {
+                    devices.push(Box::new(VirtioMmioSlot::new(config.base, irq)))
+                }
+            }
+        }
+        Self {
+            irqchip: irqchip.expect("[hypervisor] guest has no irqchip"),
+            imsic,
+            devices,
+            forwarded: passthrough
+                .iter()
+                .filter_map(|pt| pt.irq)
+                .map(|(host, guest)| ForwardedIrq {
+                    host,
+                    guest,
+                    asserted: false,
+                })
+                .collect(),
+            stdout,
+        }
+    }
+
+    /// interrupt of host came for a passthrough device,return false if none has it
+    pub fn forward_irq(&mut self, host: u32) -> bool {
+        match self.forwarded.iter_mut().find(|line| line.host == host) {
+            Some(line) => {
+                line.asserted = true;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// device whose window covers gpa
+    pub fn find(&mut self, gpa: usize) -> Option<&mut dyn MmioDevice> {
+        if self.irqchip.contains(gpa) {
+            return Some(self.irqchip.as_mmio());
+        }
+        if let Some(imsic) = self.imsic.as_mut().filter(|imsic| imsic.contains(gpa)) {
+            return Some(imsic);
+        }
+        self.devices
+            .iter_mut()
+            .find(|dev| dev.contains(gpa))
+            .map(|dev| dev.as_mut())
+    }
+
+    /// every device back to power on state,interrupts guest didn't finish are completed on host
+    pub fn reset(&mut self) {
+        for line in self.forwarded.iter_mut().filter(|line| line.asserted) {
+            line.asserted = false;
+            if let Some(plic) = host_plic() {
+                plic.complete(line.host);
+            }
+        }
+        self.irqchip.reset();
+        if let Some(imsic) = self.imsic.as_mut() {
+            imsic.reset();
+        }
+        for dev in self.devices.iter_mut() {
+            dev.reset();
+        }
+    }
+
+    /// propagate interrupt lines of devices to irqchip,and msis irqchip sent to imsic
+    ///
+    /// a passthrough interrupt guest is done with is completed on host,its device may raise it
+    /// again then
+    pub fn sync_irqs(&mut self) {
+        for dev in self.devices.iter() {
+            if let Some((source, level)) = dev.irq_line() {
+                self.irqchip.set_irq(source, level);
+            }
+        }
+        for line in self.forwarded.iter() {
+            self.irqchip.set_irq(line.guest, line.asserted);
+        }
+        let msis = self.irqchip.take_msis();
+        if let Some(imsic) = self.imsic.as_mut() {
+            for (vcpu_id, id) in msis {
+                imsic.send(vcpu_id, id as usize);
+            }
+        }
+        for source in self.irqchip.take_eois() {
+            if let Some(line) = self
+                .forwarded
+                .iter_mut()
+                .find(|line| line.asserted && line.guest == source)
+            {
+                line.asserted = false;
+                self.irqchip.set_irq(source, false);
+                if let Some(plic) = host_plic() {
+                    plic.complete(line.host);
+                }
+            }
+        }
+    }
+
+    pub fn describe(&self, fdt: &mut FdtWriter, phandles: &Phandles) {
+        self.irqchip.describe(fdt, phandles);
+        if let Some(imsic) = self.imsic.as_ref() {
+            imsic.describe(fdt, phandles);
+        }
+        for dev in self.devices.iter() {
+            dev.describe(fdt, phandles);
+        }
+    }
+}
@@ -0,0 +1,252 @@
+//! platform level interrupt controller of guest
+//!
+//! context n is the supervisor external interrupt of vcpu n,sources are level triggered
+
+use super::{IrqChip, MmioDevice, Phandles, VIRT_PLIC_SIZE};
+use crate::device_tree::{node_name, FdtWriter};
+use alloc::vec::Vec;
+
+// source 0 is reserved by plic spec
+pub const PLIC_SOURCE_NUMS: usize = 96;
+const WORDS: usize = PLIC_SOURCE_NUMS / 32;
+
+const PRIORITY_BASE: usize = 0x0;
+const PENDING_BASE: usize = 0x1000;
+const ENABLE_BASE: usize = 0x2000;
+const ENABLE_STRIDE: usize = 0x80;
+const CONTEXT_BASE: usize = 0x20_0000;
+const CONTEXT_STRIDE: usize = 0x1000;
+const CONTEXT_THRESHOLD: usize = 0x0;
+const CONTEXT_CLAIM: usize = 0x4;
+// supervisor external interrupt in cpu local interrupt controller
+const IRQ_S_EXT: u32 = 9;
+
+struct PlicContext {
+    enable: [u32; WORDS],
+    threshold: u32,
+}
+
+pub struct VirtPlic {
+    base: usize,
+    priority: [u32; PLIC_SOURCE_NUMS],
+    pending: [u32; WORDS],
+    // claimed by some context but not completed
+    in_service: [u32; WORDS],
+    // current line level of each source
+    level: [u32; WORDS],
+    contexts: Vec<PlicContext>,
+    // sources completed since taken
+    eois: Vec<u32>,
+}
+
+#[inline]
+fn test_bit(bits: &[u32], n: usize) -> bool {
+    bits[n / 32] & (1 << (n % 32)) != 0
+}
+
+#[inline]
+fn set_bit(bits: &mut [u32], n: usize, value: bool) {
+    if value {
+        bits[n / 32] |= 1 << (n % 32);
+    } else {
+        bits[n / 32] &= !(1 << (n % 32));
+    }
+}
+
+impl VirtPlic {
+    pub fn new(base: usize, context_nums: usize) -> Self {
+        let contexts = (0..context_nums)
+            .map(|_| PlicContext {
+                enable: [0; WORDS],
+                threshold: 0,
+            })
+            .collect();
+        Self {
+            base,
+            priority: [0; PLIC_SOURCE_NUMS],
+            pending: [0; WORDS],
+            in_service: [0; WORDS],
+            level: [0; WORDS],
+            contexts,
+            eois: Vec::new(),
+        }
+    }
+
+    /// highest priority source pending and enabled for context above its threshold
+    pub fn best_pending(&self, context: usize) -> Option<u32> {
+        let ctx = self.contexts.get(context)?;
+        let mut best: Option<(u32, usize)> = None;
+        for source in 1..PLIC_SOURCE_NUMS {
+            let priority = self.priority[source];
+            if test_bit(&self.pending, source)
+                && test_bit(&ctx.enable, source)
+                && priority > ctx.threshold
+                && best.map_or(true, |(p, _)| priority > p)
+            {
+                best = Some((priority, source));
+            }
+        }
+        best.map(|(_, source)| source as u32)
+    }
+
+    /// whether external interrupt of context should be asserted
+    pub fn context_pending(&self, context: usize) -> bool {
+        self.best_pending(context).is_some()
+    }
+
+    fn claim(&mut self, context: usize) -> u32 {
+        match self.best_pending(context) {
+            Some(source) => {
+                set_bit(&mut self.pending, source as usize, false);
+                set_bit(&mut self.in_service, source as usize, true);
+                source
+            }
+            None => 0,
+        }
+    }
+
+    fn complete(&mut self, source: usize) {
+        if source == 0 || source >= PLIC_SOURCE_NUMS {
+            return;
+        }
+        if test_bit(&self.in_service, source) {
+            self.eois.push(source as u32);
+        }
+        set_bit(&mut self.in_service, source, false);
+        // level triggered source still asserted becomes pending again
+        if test_bit(&self.level, source) {
+            set_bit(&mut self.pending, source, true);
+        }
+    }
+}
+
+impl MmioDevice for VirtPlic {
+    fn base(&self) -> usize {
+        self.base
+    }
+
+    fn size(&self) -> usize {
+        VIRT_PLIC_SIZE
+    }
+
+    fn read(&mut self, offset: usize, _width: usize) -> usize {
+        let value = match offset {
+            PRIORITY_BASE..=0xffc => self
+                .priority
+                .get((offset - PRIORITY_BASE) / 4)
+                .copied()
+                .unwrap_or(0),
+            PENDING_BASE..=0x107f => self
+                .pending
+                .get((offset - PENDING_BASE) / 4)
+                .copied()
+                .unwrap_or(0),
+            ENABLE_BASE..=0x1f_ffff => {
+                let context = (offset - ENABLE_BASE) / ENABLE_STRIDE;
+                let word = (offset - ENABLE_BASE) % ENABLE_STRIDE / 4;
+                self.contexts
+                    .get(context)
+                    .and_then(|ctx| ctx.enable.get(word))
+                    .copied()
+                    .unwrap_or(0)
+            }
+            CONTEXT_BASE..=usize::MAX => {
+                let context = (offset - CONTEXT_BASE) / CONTEXT_STRIDE;
+                match (offset - CONTEXT_BASE) % CONTEXT_STRIDE {
+                    CONTEXT_THRESHOLD => self.contexts.get(context).map_or(0, |ctx| ctx.threshold),
+                    CONTEXT_CLAIM if context < self.contexts.len() => self.claim(context),
+                    _ => 0,
+                }
+            }
+            _ => 0,
+        };
+        value as usize
+    }
+
+    fn write(&mut self, offset: usize, _width: usize, value: usize) {
+        let value = value as u32;
+        match offset {
+            PRIORITY_BASE..=0xffc => {
+                if let Some(priority) = self.priority.get_mut((offset - PRIORITY_BASE) / 4) {
+                    *priority = value & 0x7;
+                }
+            }
+            ENABLE_BASE..=0x1f_ffff => {
+                let context = (offset - ENABLE_BASE) / ENABLE_STRIDE;
+                let word = (offset - ENABLE_BASE) % ENABLE_STRIDE / 4;
+                if let Some(enable) = self
+                    .contexts
+                    .get_mut(context)
+                    .and_then(|ctx| ctx.enable.get_mut(word))
+                {
+                    // source 0 does not exist
+                    *enable = if word == 0 { value & !0x1 } else { value };
+                }
+            }
+            CONTEXT_BASE..=usize::MAX => {
+                let context = (offset - CONTEXT_BASE) / CONTEXT_STRIDE;
+                match (offset - CONTEXT_BASE) % CONTEXT_STRIDE {
+                    CONTEXT_THRESHOLD => {
+                        if let Some(ctx) = self.contexts.get_mut(context) {
+                            ctx.threshold = value & 0x7;
+                        }
+                    }
+                    CONTEXT_CLAIM => self.complete(value as usize),
+                    _ => {}
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn reset(&mut self) {
+        *self = Self::new(self.base, self.contexts.len());
+    }
+
+    fn describe(&self, fdt: &mut FdtWriter, phandles: &Phandles) {
+        let interrupts: Vec<u32> = phandles
+            .cpu_intc
+            .iter()
+            .flat_map(|&intc| [intc, IRQ_S_EXT])
+            .collect();
+        fdt.begin_node(&node_name("plic", self.base));
+        fdt.property_strings("compatible", &["sifive,plic-1.0.0", "riscv,plic0"]);
+        fdt.property_u64s("reg", &[self.base as u64, VIRT_PLIC_SIZE as u64]);
+        fdt.property_u32("#address-cells", 0);
+        fdt.property_u32("#interrupt-cells", 1);
+        fdt.property_null("interrupt-controller");
+        fdt.property_u32("riscv,ndev", PLIC_SOURCE_NUMS as u32 - 1);
+        fdt.property_u32s("interrupts-extended", &interrupts);
+        fdt.property_u32("phandle", phandles.irqchip);
+        fdt.end_node();
+    }
+}
+
+impl IrqChip for VirtPlic {
+    fn set_irq(&mut self, source: u32, level: bool) {
+        let source = source as usize;
+        if source == 0 || source >= PLIC_SOURCE_NUMS {
+            return;
+        }
+        set_bit(&mut self.level, source, level);
+        if level && !test_bit(&self.in_service, source) {
+            set_bit(&mut self.pending, source, true);
+        } else if !level {
+            set_bit(&mut self.pending, source, false);
+        }
+    }
+
+    #[inline]
+    fn vcpu_pending(&self, vcpu_id: usize) -> bool {
+        self.context_pending(vcpu_id)
+    }
+
+    #[inline]
+    fn take_eois(&mut self) -> Vec<u32> {
+        core::mem::take(&mut self.eois)
+    }
+
+    fn as_mmio(&mut self) -> &mut dyn MmioDevice {
+        self
+    }
+}
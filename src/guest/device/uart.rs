@@ -0,0 +1,158 @@
+//! ns16550a uart backed by sbi console
+
+use super::{MmioDevice, Phandles, VIRT_UART_SIZE};
+use crate::config::ConsoleRoute;
+use crate::device_tree::{node_name, FdtWriter};
+use crate::monitor;
+use crate::sbi::sbi_put_char;
+
+// register offsets
+const RBR_THR_DLL: usize = 0;
+const IER_DLM: usize = 1;
+const IIR_FCR: usize = 2;
+const LCR: usize = 3;
+const MCR: usize = 4;
+const LSR: usize = 5;
+const MSR: usize = 6;
+const SCR: usize = 7;
+
+const LCR_DLAB: u8 = 1 << 7;
+const LSR_DR: u8 = 1 << 0;
+const LSR_THRE: u8 = 1 << 5;
+const LSR_TEMT: u8 = 1 << 6;
+const IER_RDI: u8 = 1 << 0;
+const IER_THRI: u8 = 1 << 1;
+const IIR_NO_INT: u8 = 0x1;
+const IIR_THRI: u8 = 0x2;
+const IIR_RDI: u8 = 0x4;
+// 16550 fifo enabled bits in iir
+const IIR_FIFO_ENABLED: u8 = 0xc0;
+
+// same clock as qemu virt uart
+const UART_CLOCK_FREQUENCY: u32 = 0x38_4000;
+
+pub struct VirtUart {
+    base: usize,
+    irq: u32,
+    console: ConsoleRoute,
+    ier: u8,
+    lcr: u8,
+    mcr: u8,
+    scr: u8,
+    fcr: u8,
+    dll: u8,
+    dlm: u8,
+    // char read from sbi console but not consumed by guest yet
+    rx: Option<u8>,
+}
+
+impl VirtUart {
+    pub fn new(base: usize, irq: u32, console: ConsoleRoute) -> Self {
+        Self {
+            base,
+            irq,
+            console,
+            ier: 0,
+            lcr: 0,
+            mcr: 0,
+            scr: 0,
+            fcr: 0,
+            dll: 0,
+            dlm: 0,
+            rx: None,
+        }
+    }
+
+    fn poll_rx(&mut self) {
+        if self.rx.is_none() && self.console == ConsoleRoute::Host {
+            self.rx = monitor::getchar();
+        }
+    }
+
+    fn iir(&self) -> u8 {
+        let fifo = if self.fcr & 0x1 != 0 {
+            IIR_FIFO_ENABLED
+        } else {
+            0
+        };
+        if self.ier & IER_RDI != 0 && self.rx.is_some() {
+            fifo | IIR_RDI
+        } else if self.ier & IER_THRI != 0 {
+            fifo | IIR_THRI
+        } else {
+            fifo | IIR_NO_INT
+        }
+    }
+}
+
+impl MmioDevice for VirtUart {
+    fn base(&self) -> usize {
+        self.base
+    }
+
+    fn size(&self) -> usize {
+        VIRT_UART_SIZE
+    }
+
+    fn read(&mut self, offset: usize, _width: usize) -> usize {
+        let dlab = self.lcr & LCR_DLAB != 0;
+        let value = match offset {
+            RBR_THR_DLL if dlab => self.dll,
+            RBR_THR_DLL => {
+                self.poll_rx();
+                self.rx.take().unwrap_or(0)
+            }
+            IER_DLM if dlab => self.dlm,
+            IER_DLM => self.ier,
+            IIR_FCR => {
+                self.poll_rx();
+                self.iir()
+            }
+            LCR => self.lcr,
+            MCR => self.mcr,
+            LSR => {
+                self.poll_rx();
+                let ready = if self.rx.is_some() { LSR_DR } else { 0 };
+                LSR_THRE | LSR_TEMT | ready
+            }
+            MSR => 0,
+            SCR => self.scr,
+            _ => 0,
+        };
+        value as usize
+    }
+
+    fn write(&mut self, offset: usize, _width: usize, value: usize) {
+        let value = value as u8;
+        let dlab = self.lcr & LCR_DLAB != 0;
+        match offset {
+            RBR_THR_DLL if dlab => self.dll = value,
+            RBR_THR_DLL if self.console == ConsoleRoute::Host => sbi_put_char(value as usize),
+            IER_DLM if dlab => self.dlm = value,
+            IER_DLM => self.ier = value & 0xf,
+            IIR_FCR => self.fcr = value,
+            LCR => self.lcr = value,
+            MCR => self.mcr = value,
+            SCR => self.scr = value,
+            _ => {}
+        }
+    }
+
+    fn reset(&mut self) {
+        *self = Self::new(self.base, self.irq, self.console);
+    }
+
+    fn irq_line(&self) -> Option<(u32, bool)> {
+        Some((self.irq, self.iir() & IIR_NO_INT == 0))
+    }
+
+    fn describe(&self, fdt: &mut FdtWriter, phandles: &Phandles) {
+        fdt.begin_node(&node_name("serial", self.base));
+        fdt.property_string("compatible", "ns16550a");
+        fdt.property_u64s("reg", &[self.base as u64, VIRT_UART_SIZE as u64]);
+        fdt.property_u32("clock-frequency", UART_CLOCK_FREQUENCY);
+        fdt.property_u32s("interrupts", &phandles.interrupts(self.irq));
+        fdt.property_u32("interrupt-parent", phandles.irqchip);
+        fdt.end_node();
+    }
+}
@@ -0,0 +1,57 @@
+//! virtio mmio transport without backend device
+//!
+//! device id 0 tells guest driver the slot is empty,just like unused transports of qemu virt
+
+use super::{MmioDevice, Phandles, VIRT_VIRTIO_SIZE};
+use crate::device_tree::{node_name, FdtWriter};
+
+const VIRTIO_MMIO_MAGIC_VALUE: usize = 0x000;
+const VIRTIO_MMIO_VERSION: usize = 0x004;
+const VIRTIO_MMIO_DEVICE_ID: usize = 0x008;
+const VIRTIO_MMIO_VENDOR_ID: usize = 0x00c;
+
+// "virt" in little endian
+const VIRTIO_MAGIC: usize = 0x7472_6976;
+const VIRTIO_VENDOR: usize = 0x554d_4551;
+
+pub struct VirtioMmioSlot {
+    base: usize,
+    irq: u32,
+}
+
+impl VirtioMmioSlot {
+    pub fn new(base: usize, irq: u32) -> Self {
+        Self { base, irq }
+    }
+}
+
+impl MmioDevice for VirtioMmioSlot {
+    fn base(&self) -> usize {
+        self.base
+    }
+
+    fn size(&self) -> usize {
+        VIRT_VIRTIO_SIZE
+    }
+
+    fn read(&mut self, offset: usize, _width: usize) -> usize {
+        match offset {
+            VIRTIO_MMIO_MAGIC_VALUE => VIRTIO_MAGIC,
+            VIRTIO_MMIO_VERSION => 2,
+            VIRTIO_MMIO_DEVICE_ID => 0,
+            VIRTIO_MMIO_VENDOR_ID => VIRTIO_VENDOR,
+            _ => 0,
+        }
+    }
+
+    fn write(&mut self, _offset: usize, _width: usize, _value: usize) {}
+
+    fn describe(&self, fdt: &mut FdtWriter, phandles: &Phandles) {
+        fdt.begin_node(&node_name("virtio_mmio", self.base));
+        fdt.property_string("compatible", "virtio,mmio");
+        fdt.property_u64s("reg", &[self.base as u64, VIRT_VIRTIO_SIZE as u64]);
+        fdt.property_u32s("interrupts", &phandles.interrupts(self.irq));
+        fdt.property_u32("interrupt-parent", phandles.irqchip);
+        fdt.end_node();
+    }
+}
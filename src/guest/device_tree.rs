@@ -0,0 +1,167 @@
+//! device tree generated for each guest from its memory,vcpus and emulated devices
+
+use crate::config::Passthrough;
+use crate::device_tree::{host_isa, host_timebase_frequency, node_name, FdtWriter};
+use crate::guest::device::{Phandles, VirtDevices};
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+pub const DEFAULT_BOOTARGS: &str = "console=ttyS0 earlycon=sbi";
+
+/// like qemu,place device tree at 2M aligned address at the top of guest ram
+pub const DTB_ALIGN: usize = 0x20_0000;
+
+// single letter extensions we virtualize,h is never exposed to guest and v only if guest has it
+const GUEST_BASE_EXTENSIONS: &str = "imafdcv";
+// multi letter extensions guest can use without help of hypervisor
+const GUEST_MULTI_LETTER_EXTENSIONS: &[&str] = &[
+    "zicsr",
+    "zifencei",
+    "zihintpause",
+    "zba",
+    "zbb",
+    "zbc",
+    "zbs",
+    "zca",
+    "zcd",
+    "zfa",
+    // guest stimecmp is vstimecmp,see `TrapContext::has_sstc`
+    "sstc",
+];
+
+/// filter host isa string to what guest can see,like `rv64imafdc_zicsr_zifencei`
+///
+/// hiding vector drops v and every zv extension built on it. ssaia is only seen by guests with an
+/// imsic
+pub fn guest_isa(host_isa: &str, vector: bool, aia: bool) -> String {
+    let mut extensions = host_isa.split('_');
+    let base = extensions.next().unwrap_or("rv64");
+    let (xlen, letters) = base.split_at(base.len().min(4));
+    let mut isa: String = xlen.into();
+    isa.extend(
+        letters
+            .chars()
+            .filter(|c| GUEST_BASE_EXTENSIONS.contains(*c) && (vector || *c != 'v')),
+    );
+    let visible = |ext: &&str| {
+        GUEST_MULTI_LETTER_EXTENSIONS.contains(ext)
+            || vector && ext.starts_with("zv")
+            || aia && *ext == "ssaia"
+    };
+    for ext in extensions.filter(visible) {
+        isa.push('_');
+        isa.push_str(ext);
+    }
+    isa
+}
+
+/// what guest device tree describes
+pub struct GuestFdtInfo<'a> {
+    // (gpa,size) of each memory bank
+    pub memory: &'a [(usize, usize)],
+    // banks guest should leave alone,subset of memory
+    pub reserved: &'a [(usize, usize)],
+    pub vcpu_nums: usize,
+    pub devices: &'a VirtDevices,
+    pub bootargs: &'a str,
+    // [start,end) of initrd in guest physical address space
+    pub initrd: Option<(usize, usize)>,
+    pub passthrough: &'a [Passthrough],
+    // v is in isa string of vcpus
+    pub vector: bool,
+}
+
+pub fn build_guest_fdt(info: &GuestFdtInfo) -> Vec<u8> {
+    let phandles = Phandles {
+        cpu_intc: (1..=info.vcpu_nums as u32).collect(),
+        irqchip: info.vcpu_nums as u32 + 1,
+        msi: info.vcpu_nums as u32 + 2,
+        irq_cells: info.devices.irqchip.interrupt_cells(),
+    };
+    let mut fdt = FdtWriter::new();
+    fdt.begin_node("");
+    fdt.property_u32("#address-cells", 2);
+    fdt.property_u32("#size-cells", 2);
+    fdt.property_string("compatible", "riscv-virtio");
+    fdt.property_string("model", "hypercrab,virt");
+
+    fdt.begin_node("chosen");
+    fdt.property_string("bootargs", info.bootargs);
+    if let Some((start, end)) = info.initrd {
+        fdt.property_u64s("linux,initrd-start", &[start as u64]);
+        fdt.property_u64s("linux,initrd-end", &[end as u64]);
+    }
+    if let Some(uart) = info.devices.stdout {
+        fdt.property_string(
+            "stdout-path",
+            &format!("/soc/{}", node_name("serial", uart)),
+        );
+    }
+    fdt.end_node();
+
+    for &(gpa, size) in info.memory {
+        fdt.begin_node(&node_name("memory", gpa));
+        fdt.property_string("device_type", "memory");
+        fdt.property_u64s("reg", &[gpa as u64, size as u64]);
+        fdt.end_node();
+    }
+
+    if !info.reserved.is_empty() {
+        fdt.begin_node("reserved-memory");
+        fdt.property_u32("#address-cells", 2);
+        fdt.property_u32("#size-cells", 2);
+        fdt.property_null("ranges");
+        for &(gpa, size) in info.reserved {
+            fdt.begin_node(&node_name("reserved", gpa));
+            fdt.property_u64s("reg", &[gpa as u64, size as u64]);
+            fdt.property_null("no-map");
+            fdt.end_node();
+        }
+        fdt.end_node();
+    }
+
+    let isa = guest_isa(host_isa(), info.vector, info.devices.imsic.is_some());
+    fdt.begin_node("cpus");
+    fdt.property_u32("#address-cells", 1);
+    fdt.property_u32("#size-cells", 0);
+    fdt.property_u32("timebase-frequency", host_timebase_frequency() as u32);
+    for (vcpu_id, &intc) in phandles.cpu_intc.iter().enumerate() {
+        fdt.begin_node(&node_name("cpu", vcpu_id));
+        fdt.property_string("device_type", "cpu");
+        fdt.property_u32("reg", vcpu_id as u32);
+        fdt.property_string("status", "okay");
+        fdt.property_string("compatible", "riscv");
+        fdt.property_string("riscv,isa", &isa);
+        fdt.property_string("mmu-type", "riscv,sv39");
+        fdt.begin_node("interrupt-controller");
+        fdt.property_u32("#interrupt-cells", 1);
+        fdt.property_null("interrupt-controller");
+        fdt.property_string("compatible", "riscv,cpu-intc");
+        fdt.property_u32("phandle", intc);
+        fdt.end_node();
+        fdt.end_node();
+    }
+    fdt.end_node();
+
+    fdt.begin_node("soc");
+    fdt.property_u32("#address-cells", 2);
+    fdt.property_u32("#size-cells", 2);
+    fdt.property_string("compatible", "simple-bus");
+    fdt.property_null("ranges");
+    info.devices.describe(&mut fdt, &phandles);
+    for pt in info.passthrough.iter() {
+        fdt.begin_node(&node_name("passthrough", pt.gpa));
+        fdt.property_string("compatible", &pt.compatible);
+        fdt.property_u64s("reg", &[pt.gpa as u64, pt.size as u64]);
+        if let Some((_, guest_irq)) = pt.irq {
+            fdt.property_u32s("interrupts", &phandles.interrupts(guest_irq));
+            fdt.property_u32("interrupt-parent", phandles.irqchip);
+        }
+        fdt.end_node();
+    }
+    fdt.end_node();
+
+    fdt.end_node();
+    fdt.finish(0)
+}
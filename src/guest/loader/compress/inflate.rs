@@ -0,0 +1,308 @@
+//! deflate (rfc 1951) in gzip wrapper (rfc 1952)
+
+use super::{Crc32, Sink, Window};
+use crate::guest::loader::LoadError;
+
+const WINDOW_SIZE: usize = 1 << 15;
+const MAX_BITS: usize = 15;
+const MAX_LITLEN_CODES: usize = 288;
+const MAX_DIST_CODES: usize = 30;
+
+// gzip header flags
+const FHCRC: u8 = 1 << 1;
+const FEXTRA: u8 = 1 << 2;
+const FNAME: u8 = 1 << 3;
+const FCOMMENT: u8 = 1 << 4;
+const CM_DEFLATE: u8 = 8;
+
+const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131,
+    163, 195, 227, 258,
+];
+const LENGTH_EXTRA: [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+const DIST_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537,
+    2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+const DIST_EXTRA: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13,
+    13,
+];
+// order of code length code lengths in dynamic block header
+const CLEN_ORDER: [usize; 19] = [
+    16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15,
+];
+
+struct BitReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+    bit_buf: u32,
+    bit_cnt: u32,
+}
+
+impl<'a> BitReader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self {
+            data,
+            pos: 0,
+            bit_buf: 0,
+            bit_cnt: 0,
+        }
+    }
+
+    fn bits(&mut self, n: u32) -> Result<u32, LoadError> {
+        while self.bit_cnt < n {
+            let byte = *self.data.get(self.pos).ok_or(LoadError::Truncated)?;
+            self.pos += 1;
+            self.bit_buf |= (byte as u32) << self.bit_cnt;
+            self.bit_cnt += 8;
+        }
+        let value = self.bit_buf & ((1u64 << n) - 1) as u32;
+        self.bit_buf >>= n;
+        self.bit_cnt -= n;
+        Ok(value)
+    }
+
+    /// drop bits left in current byte
+    fn align(&mut self) {
+        self.bit_buf = 0;
+        self.bit_cnt = 0;
+    }
+
+    fn bytes(&mut self, n: usize) -> Result<&'a [u8], LoadError> {
+        let bytes = self
+            .pos
+            .checked_add(n)
+            .and_then(|end| self.data.get(self.pos..end))
+            .ok_or(LoadError::Truncated)?;
+        self.pos += n;
+        Ok(bytes)
+    }
+}
+
+/// canonical huffman code as count of codes of each length and symbols sorted by code
+struct Huffman<const N: usize> {
+    counts: [u16; MAX_BITS + 1],
+    symbols: [u16; N],
+}
+
+impl<const N: usize> Huffman<N> {
+    fn new(lengths: &[u8]) -> Result<Self, LoadError> {
+        let mut huffman = Self {
+            counts: [0; MAX_BITS + 1],
+            symbols: [0; N],
+        };
+        for &len in lengths {
+            huffman.counts[len as usize] += 1;
+        }
+        // over subscribed code can't be decoded
+        let mut left: i32 = 1;
+        for len in 1..=MAX_BITS {
+            left = (left << 1) - huffman.counts[len] as i32;
+            if left < 0 {
+                return Err(LoadError::BadCompressedImage);
+            }
+        }
+        let mut offsets = [0u16; MAX_BITS + 1];
+        for len in 1..MAX_BITS {
+            offsets[len + 1] = offsets[len] + huffman.counts[len];
+        }
+        for (symbol, &len) in lengths.iter().enumerate() {
+            if len != 0 {
+                huffman.symbols[offsets[len as usize] as usize] = symbol as u16;
+                offsets[len as usize] += 1;
+            }
+        }
+        Ok(huffman)
+    }
+
+    fn decode(&self, input: &mut BitReader) -> Result<u16, LoadError> {
+        // codes are packed msb first,read them one bit at a time
+        let (mut code, mut first, mut index) = (0i32, 0i32, 0i32);
+        for len in 1..=MAX_BITS {
+            code |= input.bits(1)? as i32;
+            let count = self.counts[len] as i32;
+            if code - count < first {
+                return Ok(self.symbols[(index + code - first) as usize]);
+            }
+            index += count;
+            first = (first + count) << 1;
+            code <<= 1;
+        }
+        Err(LoadError::BadCompressedImage)
+    }
+}
+
+fn fixed_tables() -> Result<(Huffman<MAX_LITLEN_CODES>, Huffman<MAX_DIST_CODES>), LoadError> {
+    let mut lengths = [0u8; MAX_LITLEN_CODES];
+    lengths[..144].fill(8);
+    lengths[144..256].fill(9);
+    lengths[256..280].fill(7);
+    lengths[280..].fill(8);
+    Ok((Huffman::new(&lengths)?, Huffman::new(&[5; MAX_DIST_CODES])?))
+}
+
+fn dynamic_tables(
+    input: &mut BitReader,
+) -> Result<(Huffman<MAX_LITLEN_CODES>, Huffman<MAX_DIST_CODES>), LoadError> {
+    let nlen = input.bits(5)? as usize + 257;
+    let ndist = input.bits(5)? as usize + 1;
+    let ncode = input.bits(4)? as usize + 4;
+    if nlen > 286 || ndist > MAX_DIST_CODES {
+        return Err(LoadError::BadCompressedImage);
+    }
+    let mut clens = [0u8; 19];
+    for &index in CLEN_ORDER[..ncode].iter() {
+        clens[index] = input.bits(3)? as u8;
+    }
+    let clen_code = Huffman::<19>::new(&clens)?;
+
+    let mut lengths = [0u8; MAX_LITLEN_CODES + MAX_DIST_CODES];
+    let mut i = 0;
+    while i < nlen + ndist {
+        let symbol = clen_code.decode(input)?;
+        let (value, repeat) = match symbol {
+            0..=15 => (symbol as u8, 1),
+            16 if i > 0 => (lengths[i - 1], 3 + input.bits(2)? as usize),
+            17 => (0, 3 + input.bits(3)? as usize),
+            18 => (0, 11 + input.bits(7)? as usize),
+            _ => return Err(LoadError::BadCompressedImage),
+        };
+        if i + repeat > nlen + ndist {
+            return Err(LoadError::BadCompressedImage);
+        }
+        lengths[i..i + repeat].fill(value);
+        i += repeat;
+    }
+    // block without end of block code can't end
+    if lengths[256] == 0 {
+        return Err(LoadError::BadCompressedImage);
+    }
+    Ok((
+        Huffman::new(&lengths[..nlen])?,
+        Huffman::new(&lengths[nlen..nlen + ndist])?,
+    ))
+}
+
+fn inflate_block(
+    input: &mut BitReader,
+    out: &mut Window,
+    litlen: &Huffman<MAX_LITLEN_CODES>,
+    dist: &Huffman<MAX_DIST_CODES>,
+) -> Result<(), LoadError> {
+    loop {
+        let symbol = litlen.decode(input)? as usize;
+        match symbol {
+            0..=255 => out.push(symbol as u8)?,
+            256 => return Ok(()),
+            257..=285 => {
+                let index = symbol - 257;
+                let len =
+                    LENGTH_BASE[index] as usize + input.bits(LENGTH_EXTRA[index] as u32)? as usize;
+                let index = dist.decode(input)? as usize;
+                if index >= MAX_DIST_CODES {
+                    return Err(LoadError::BadCompressedImage);
+                }
+                let distance =
+                    DIST_BASE[index] as usize + input.bits(DIST_EXTRA[index] as u32)? as usize;
+                out.copy_match(distance, len)?;
+            }
+            _ => return Err(LoadError::BadCompressedImage),
+        }
+        if out.stopped {
+            return Ok(());
+        }
+    }
+}
+
+/// raw deflate stream,return bytes read from input
+fn inflate(data: &[u8], out: &mut Window) -> Result<usize, LoadError> {
+    let mut input = BitReader::new(data);
+    loop {
+        let last = input.bits(1)? == 1;
+        match input.bits(2)? {
+            0 => {
+                input.align();
+                let header = input.bytes(4)?;
+                let len = u16::from_le_bytes([header[0], header[1]]);
+                let nlen = u16::from_le_bytes([header[2], header[3]]);
+                if len != !nlen {
+                    return Err(LoadError::BadCompressedImage);
+                }
+                out.extend(input.bytes(len as usize)?)?;
+            }
+            1 => {
+                let (litlen, dist) = fixed_tables()?;
+                inflate_block(&mut input, out, &litlen, &dist)?;
+            }
+            2 => {
+                let (litlen, dist) = dynamic_tables(&mut input)?;
+                inflate_block(&mut input, out, &litlen, &dist)?;
+            }
+            _ => return Err(LoadError::BadCompressedImage),
+        }
+        if last || out.stopped {
+            return Ok(input.pos);
+        }
+    }
+}
+
+/// length of gzip member header
+fn gzip_header_len(data: &[u8]) -> Result<usize, LoadError> {
+    let header = data.get(..10).ok_or(LoadError::Truncated)?;
+    if header[2] != CM_DEFLATE {
+        return Err(LoadError::BadCompressedImage);
+    }
+    let flags = header[3];
+    let mut pos = 10;
+    if flags & FEXTRA != 0 {
+        let xlen = data.get(pos..pos + 2).ok_or(LoadError::Truncated)?;
+        pos += 2 + u16::from_le_bytes([xlen[0], xlen[1]]) as usize;
+    }
+    for flag in [FNAME, FCOMMENT] {
+        if flags & flag != 0 {
+            let rest = data.get(pos..).ok_or(LoadError::Truncated)?;
+            pos += rest
+                .iter()
+                .position(|&b| b == 0)
+                .ok_or(LoadError::Truncated)?
+                + 1;
+        }
+    }
+    if flags & FHCRC != 0 {
+        pos += 2;
+    }
+    if pos > data.len() {
+        return Err(LoadError::Truncated);
+    }
+    Ok(pos)
+}
+
+/// decompress first member of gzip file,crc and size in trailer are checked
+pub fn gunzip(data: &[u8], sink: &mut Sink) -> Result<usize, LoadError> {
+    let header_len = gzip_header_len(data)?;
+    let mut crc = Crc32::new();
+    let mut checked_sink = |chunk: &[u8]| {
+        crc.update(chunk);
+        sink(chunk)
+    };
+    let mut out = Window::new(WINDOW_SIZE, &mut checked_sink);
+    let used = inflate(&data[header_len..], &mut out)?;
+    out.flush()?;
+    let (size, stopped) = (out.pos, out.stopped);
+    drop(out);
+    if stopped {
+        return Ok(size);
+    }
+    let trailer = data
+        .get(header_len + used..header_len + used + 8)
+        .ok_or(LoadError::Truncated)?;
+    let expect_crc = u32::from_le_bytes(trailer[..4].try_into().unwrap());
+    let expect_size = u32::from_le_bytes(trailer[4..].try_into().unwrap());
+    if expect_crc != crc.finish() || expect_size != size as u32 {
+        return Err(LoadError::BadCompressedImage);
+    }
+    Ok(size)
+}
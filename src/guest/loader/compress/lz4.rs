@@ -0,0 +1,199 @@
+//! lz4 frame format and legacy format of `lz4 -l`
+
+use super::{Sink, Window, LZ4_FRAME_MAGIC, LZ4_LEGACY_MAGIC};
+use crate::guest::loader::LoadError;
+
+// offset of a match is u16
+const WINDOW_SIZE: usize = 1 << 16;
+const MIN_MATCH: usize = 4;
+const LEGACY_BLOCK_SIZE: usize = 8 << 20;
+
+// frame descriptor flags
+const FLG_VERSION_MASK: u8 = 0b1100_0000;
+const FLG_VERSION: u8 = 0b0100_0000;
+const FLG_BLOCK_CHECKSUM: u8 = 1 << 4;
+const FLG_CONTENT_SIZE: u8 = 1 << 3;
+const FLG_CONTENT_CHECKSUM: u8 = 1 << 2;
+const FLG_DICT_ID: u8 = 1 << 0;
+const BLOCK_UNCOMPRESSED: u32 = 1 << 31;
+
+struct Input<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Input<'a> {
+    fn byte(&mut self) -> Result<u8, LoadError> {
+        let byte = *self.data.get(self.pos).ok_or(LoadError::Truncated)?;
+        self.pos += 1;
+        Ok(byte)
+    }
+
+    fn bytes(&mut self, n: usize) -> Result<&'a [u8], LoadError> {
+        let bytes = self
+            .pos
+            .checked_add(n)
+            .and_then(|end| self.data.get(self.pos..end))
+            .ok_or(LoadError::Truncated)?;
+        self.pos += n;
+        Ok(bytes)
+    }
+
+    fn u32(&mut self) -> Result<u32, LoadError> {
+        Ok(u32::from_le_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+
+    #[inline]
+    fn remaining(&self) -> usize {
+        self.data.len() - self.pos
+    }
+
+    /// length continued in following bytes while they are 255
+    fn extended_len(&mut self, mut len: usize) -> Result<usize, LoadError> {
+        loop {
+            let byte = self.byte()?;
+            len = len
+                .checked_add(byte as usize)
+                .ok_or(LoadError::BadCompressedImage)?;
+            if byte != 255 {
+                return Ok(len);
+            }
+        }
+    }
+}
+
+/// one compressed block,output of block must not exceed max
+fn decompress_block(block: &[u8], out: &mut Window, max: usize) -> Result<(), LoadError> {
+    let mut input = Input {
+        data: block,
+        pos: 0,
+    };
+    let start = out.pos;
+    loop {
+        let token = input.byte()?;
+        let mut literal_len = (token >> 4) as usize;
+        if literal_len == 15 {
+            literal_len = input.extended_len(literal_len)?;
+        }
+        if out.pos - start + literal_len > max {
+            return Err(LoadError::BadCompressedImage);
+        }
+        out.extend(input.bytes(literal_len)?)?;
+        // last sequence has literals only
+        if input.remaining() == 0 || out.stopped {
+            return Ok(());
+        }
+        let offset = u16::from_le_bytes(input.bytes(2)?.try_into().unwrap()) as usize;
+        let mut match_len = (token & 0xf) as usize;
+        if match_len == 15 {
+            match_len = input.extended_len(match_len)?;
+        }
+        match_len += MIN_MATCH;
+        if out.pos - start + match_len > max {
+            return Err(LoadError::BadCompressedImage);
+        }
+        out.copy_match(offset, match_len)?;
+        if out.stopped {
+            return Ok(());
+        }
+    }
+}
+
+fn max_block_size(bd: u8) -> Result<usize, LoadError> {
+    match (bd >> 4) & 0x7 {
+        4 => Ok(64 << 10),
+        5 => Ok(256 << 10),
+        6 => Ok(1 << 20),
+        7 => Ok(4 << 20),
+        _ => Err(LoadError::BadCompressedImage),
+    }
+}
+
+fn decompress_frame(input: &mut Input, out: &mut Window) -> Result<(), LoadError> {
+    let flg = input.byte()?;
+    let bd = input.byte()?;
+    if flg & FLG_VERSION_MASK != FLG_VERSION {
+        return Err(LoadError::BadCompressedImage);
+    }
+    let max = max_block_size(bd)?;
+    if flg & FLG_CONTENT_SIZE != 0 {
+        input.bytes(8)?;
+    }
+    if flg & FLG_DICT_ID != 0 {
+        // preset dictionary is not known to us
+        return Err(LoadError::BadCompressedImage);
+    }
+    // header checksum
+    input.byte()?;
+    loop {
+        let size = input.u32()?;
+        if size == 0 {
+            break;
+        }
+        let len = (size & !BLOCK_UNCOMPRESSED) as usize;
+        if len > max {
+            return Err(LoadError::BadCompressedImage);
+        }
+        let block = input.bytes(len)?;
+        if size & BLOCK_UNCOMPRESSED != 0 {
+            out.extend(block)?;
+        } else {
+            decompress_block(block, out, max)?;
+        }
+        if out.stopped {
+            return Ok(());
+        }
+        if flg & FLG_BLOCK_CHECKSUM != 0 {
+            input.bytes(4)?;
+        }
+    }
+    if flg & FLG_CONTENT_CHECKSUM != 0 {
+        input.bytes(4)?;
+    }
+    Ok(())
+}
+
+fn decompress_legacy(input: &mut Input, out: &mut Window) -> Result<(), LoadError> {
+    // linux appends decompressed size after last block
+    while input.remaining() > 4 {
+        let size = input.u32()?;
+        // another file concatenated
+        if size.to_le_bytes() == LZ4_LEGACY_MAGIC {
+            continue;
+        }
+        let block = input.bytes(size as usize)?;
+        decompress_block(block, out, LEGACY_BLOCK_SIZE)?;
+        if out.stopped {
+            break;
+        }
+    }
+    Ok(())
+}
+
+pub fn decompress(data: &[u8], sink: &mut Sink) -> Result<usize, LoadError> {
+    let mut input = Input { data, pos: 0 };
+    let magic = input.bytes(4)?;
+    let mut out = Window::new(WINDOW_SIZE, sink);
+    if magic == LZ4_FRAME_MAGIC {
+        decompress_frame(&mut input, &mut out)?;
+    } else if magic == LZ4_LEGACY_MAGIC {
+        decompress_legacy(&mut input, &mut out)?;
+    } else {
+        return Err(LoadError::BadCompressedImage);
+    }
+    out.flush()?;
+    Ok(out.pos)
+}
+
+/// content size recorded in frame header
+pub fn content_size(data: &[u8]) -> Option<usize> {
+    if !data.starts_with(&LZ4_FRAME_MAGIC) {
+        return None;
+    }
+    let flg = *data.get(4)?;
+    if flg & FLG_CONTENT_SIZE == 0 {
+        return None;
+    }
+    let raw = data.get(6..14)?;
+    usize::try_from(u64::from_le_bytes(raw.try_into().unwrap())).ok()
+}
@@ -0,0 +1,367 @@
+//! gzip and lz4 compressed guest images
+//!
+//! decompressors are streaming,output goes through a small window of back references to a sink
+//! which writes it straight to guest ram. malformed input fails with `BadCompressedImage`,output
+//! never goes beyond what sink accepts
+
+mod inflate;
+mod lz4;
+
+use super::LoadError;
+use alloc::vec;
+use alloc::vec::Vec;
+
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+const LZ4_FRAME_MAGIC: [u8; 4] = [0x04, 0x22, 0x4d, 0x18];
+// `lz4 -l`,used by linux for Image.lz4
+const LZ4_LEGACY_MAGIC: [u8; 4] = [0x02, 0x21, 0x4c, 0x18];
+
+/// receives decompressed bytes in order,return false to stop decompressing
+pub type Sink<'s> = dyn FnMut(&[u8]) -> Result<bool, LoadError> + 's;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    Gzip,
+    Lz4,
+}
+
+impl Compression {
+    pub fn detect(data: &[u8]) -> Option<Self> {
+        if data.starts_with(&GZIP_MAGIC) {
+            Some(Self::Gzip)
+        } else if data.starts_with(&LZ4_FRAME_MAGIC) || data.starts_with(&LZ4_LEGACY_MAGIC) {
+            Some(Self::Lz4)
+        } else {
+            None
+        }
+    }
+
+    /// decompress data into sink,return decompressed size or where sink stopped
+    pub fn decompress(&self, data: &[u8], sink: &mut Sink) -> Result<usize, LoadError> {
+        match self {
+            Self::Gzip => inflate::gunzip(data, sink),
+            Self::Lz4 => lz4::decompress(data, sink),
+        }
+    }
+
+    /// decompress data into guest memory dst at gpa,fail if it doesn't fit,return bytes written
+    pub fn decompress_into(
+        &self,
+        data: &[u8],
+        gpa: usize,
+        dst: &mut [u8],
+    ) -> Result<usize, LoadError> {
+        let mut written = 0;
+        self.decompress(data, &mut |chunk: &[u8]| {
+            let end = written + chunk.len();
+            if end > dst.len() {
+                return Err(LoadError::OutOfGuestMemory {
+                    start: gpa,
+                    end: gpa + end,
+                });
+            }
+            dst[written..end].copy_from_slice(chunk);
+            written = end;
+            Ok(true)
+        })?;
+        Ok(written)
+    }
+
+    /// size of decompressed data if format records it,not to be trusted until decoding produces
+    /// as much
+    ///
+    /// gzip keeps it modulo 2^32 in its trailer,an lz4 frame only if its content size flag is set
+    pub fn recorded_size(&self, data: &[u8]) -> Option<usize> {
+        match self {
+            Self::Gzip => {
+                let trailer = data.len().checked_sub(4).map(|start| &data[start..])?;
+                Some(u32::from_le_bytes(trailer.try_into().unwrap()) as usize)
+            }
+            Self::Lz4 => lz4::content_size(data),
+        }
+    }
+}
+
+/// history of decompressed data,matches copy from here
+struct Window<'s, 'f> {
+    buf: Vec<u8>,
+    // bytes produced so far
+    pos: usize,
+    // bytes handed to sink
+    flushed: usize,
+    sink: &'s mut Sink<'f>,
+    // sink wants no more
+    stopped: bool,
+}
+
+impl<'s, 'f> Window<'s, 'f> {
+    /// size must be power of 2
+    fn new(size: usize, sink: &'s mut Sink<'f>) -> Self {
+        Self {
+            buf: vec![0; size],
+            pos: 0,
+            flushed: 0,
+            sink,
+            stopped: false,
+        }
+    }
+
+    #[inline]
+    fn push(&mut self, byte: u8) -> Result<(), LoadError> {
+        let mask = self.buf.len() - 1;
+        self.buf[self.pos & mask] = byte;
+        self.pos += 1;
+        if self.pos - self.flushed == self.buf.len() {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    fn extend(&mut self, bytes: &[u8]) -> Result<(), LoadError> {
+        for &byte in bytes {
+            self.push(byte)?;
+        }
+        Ok(())
+    }
+
+    /// repeat len bytes starting distance bytes back
+    fn copy_match(&mut self, distance: usize, len: usize) -> Result<(), LoadError> {
+        if distance == 0 || distance > self.pos || distance > self.buf.len() {
+            return Err(LoadError::BadCompressedImage);
+        }
+        let mask = self.buf.len() - 1;
+        for _ in 0..len {
+            let byte = self.buf[(self.pos - distance) & mask];
+            self.push(byte)?;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), LoadError> {
+        if self.stopped || self.pos == self.flushed {
+            self.flushed = self.pos;
+            return Ok(());
+        }
+        let mask = self.buf.len() - 1;
+        let start = self.flushed & mask;
+        let len = self.pos - self.flushed;
+        let first = len.min(self.buf.len() - start);
+        let mut more = (self.sink)(&self.buf[start..start + first])?;
+        if more && first < len {
+            more = (self.sink)(&self.buf[..len - first])?;
+        }
+        self.flushed = self.pos;
+        self.stopped = !more;
+        Ok(())
+    }
+}
+
+/// crc-32 of gzip trailer
+struct Crc32(u32);
+
+const CRC32_TABLE: [u32; 256] = {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 {
+                0xedb8_8320 ^ (crc >> 1)
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+impl Crc32 {
+    fn new() -> Self {
+        Self(!0)
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        for &byte in data {
+            self.0 = CRC32_TABLE[((self.0 ^ byte as u32) & 0xff) as usize] ^ (self.0 >> 8);
+        }
+    }
+
+    fn finish(&self) -> u32 {
+        !self.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::format;
+
+    // `gzip -0` of b"hello hypervisor",a stored block
+    const GZIP_STORED: [u8; 39] = [
+        0x1f, 0x8b, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x04, 0x03, 0x01, 0x10, 0x00, 0xef, 0xff,
+        0x68, 0x65, 0x6c, 0x6c, 0x6f, 0x20, 0x68, 0x79, 0x70, 0x65, 0x72, 0x76, 0x69, 0x73, 0x6f,
+        0x72, 0x7f, 0xd0, 0x0a, 0xae, 0x10, 0x00, 0x00, 0x00,
+    ];
+    // `gzip -9` of b"abcabcabcabcabcabc hello hello hello",fixed huffman codes
+    const GZIP_FIXED: [u8; 33] = [
+        0x1f, 0x8b, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02, 0x03, 0x4b, 0x4c, 0x4a, 0x4e, 0x44,
+        0x45, 0x0a, 0x19, 0xa9, 0x39, 0x39, 0xf9, 0xc8, 0x24, 0x00, 0xae, 0xd8, 0x6c, 0x3b, 0x24,
+        0x00, 0x00, 0x00,
+    ];
+    // `gzip -9` of `dynamic_text`,dynamic huffman codes
+    const GZIP_DYNAMIC: [u8; 103] = [
+        0x1f, 0x8b, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02, 0x03, 0x6d, 0xd0, 0xb9, 0x0d, 0x80,
+        0x30, 0x10, 0x05, 0xd1, 0x9c, 0x2a, 0xb6, 0x84, 0x3d, 0x6c, 0x8e, 0x72, 0x10, 0x09, 0x91,
+        0x8d, 0x0c, 0x76, 0xfd, 0x08, 0x6d, 0xb4, 0xe8, 0xa7, 0x13, 0xbc, 0x60, 0xc6, 0x71, 0x75,
+        0x62, 0x6a, 0xbd, 0xdc, 0x54, 0x0b, 0x9d, 0x7b, 0x7b, 0x88, 0xa7, 0xf1, 0x55, 0x89, 0x55,
+        0xbc, 0x6a, 0xac, 0xea, 0xd5, 0x62, 0x35, 0xaf, 0x09, 0xba, 0x19, 0xba, 0x33, 0x74, 0x17,
+        0xe8, 0xae, 0xd0, 0xdd, 0xa0, 0x2b, 0x0c, 0x61, 0x11, 0x28, 0x8b, 0xe2, 0x15, 0x86, 0xed,
+        0x84, 0xed, 0xfc, 0xb7, 0x5f, 0x1e, 0x64, 0x39, 0xe7, 0x66, 0x01, 0x00, 0x00,
+    ];
+    // literals "abc",match of 9 bytes 3 back,then literals "hello"
+    const LZ4_BLOCK: [u8; 12] = [
+        0x35, 0x61, 0x62, 0x63, 0x03, 0x00, 0x50, 0x68, 0x65, 0x6c, 0x6c, 0x6f,
+    ];
+    const LZ4_TEXT: &[u8] = b"abcabcabcabchello";
+
+    fn dynamic_text() -> Vec<u8> {
+        (0..16)
+            .flat_map(|i| format!("vcpu {} runs on hart {}\n", i, i % 4).into_bytes())
+            .collect()
+    }
+
+    /// lz4 frame of LZ4_BLOCK with its content size
+    fn lz4_frame() -> Vec<u8> {
+        let mut frame = LZ4_FRAME_MAGIC.to_vec();
+        frame.extend([0x48, 0x40]);
+        frame.extend((LZ4_TEXT.len() as u64).to_le_bytes());
+        frame.push(0);
+        frame.extend((LZ4_BLOCK.len() as u32).to_le_bytes());
+        frame.extend(LZ4_BLOCK);
+        frame.extend([0; 4]);
+        frame
+    }
+
+    fn decompress(data: &[u8]) -> Result<Vec<u8>, LoadError> {
+        let mut out = Vec::new();
+        let size = Compression::detect(data)
+            .unwrap()
+            .decompress(data, &mut |chunk: &[u8]| {
+                out.extend_from_slice(chunk);
+                Ok(true)
+            })?;
+        assert_eq!(size, out.len());
+        Ok(out)
+    }
+
+    #[test]
+    fn detect() {
+        assert_eq!(Compression::detect(&GZIP_STORED), Some(Compression::Gzip));
+        assert_eq!(Compression::detect(&lz4_frame()), Some(Compression::Lz4));
+        assert_eq!(
+            Compression::detect(&LZ4_LEGACY_MAGIC),
+            Some(Compression::Lz4)
+        );
+        assert_eq!(Compression::detect(b"\x7fELF"), None);
+        assert_eq!(Compression::detect(&[0x1f]), None);
+    }
+
+    #[test]
+    fn gunzip() {
+        assert_eq!(decompress(&GZIP_STORED).unwrap(), b"hello hypervisor");
+        assert_eq!(
+            decompress(&GZIP_FIXED).unwrap(),
+            b"abcabcabcabcabcabc hello hello hello"
+        );
+        assert_eq!(decompress(&GZIP_DYNAMIC).unwrap(), dynamic_text());
+    }
+
+    #[test]
+    fn gunzip_checks_trailer() {
+        let mut data = GZIP_FIXED;
+        data[25] ^= 1;
+        assert_eq!(decompress(&data), Err(LoadError::BadCompressedImage));
+        let mut data = GZIP_FIXED;
+        data[29] ^= 1;
+        assert_eq!(decompress(&data), Err(LoadError::BadCompressedImage));
+        assert_eq!(decompress(&GZIP_FIXED[..27]), Err(LoadError::Truncated));
+    }
+
+    #[test]
+    fn lz4() {
+        assert_eq!(decompress(&lz4_frame()).unwrap(), LZ4_TEXT);
+        // uncompressed block in a frame without content size
+        let mut frame = LZ4_FRAME_MAGIC.to_vec();
+        frame.extend([0x40, 0x40, 0x00, 0x05, 0x00, 0x00, 0x80]);
+        frame.extend(b"hello");
+        frame.extend([0; 4]);
+        assert_eq!(decompress(&frame).unwrap(), b"hello");
+        // `lz4 -l` followed by decompressed size as linux appends it
+        let mut legacy = LZ4_LEGACY_MAGIC.to_vec();
+        legacy.extend((LZ4_BLOCK.len() as u32).to_le_bytes());
+        legacy.extend(LZ4_BLOCK);
+        legacy.extend((LZ4_TEXT.len() as u32).to_le_bytes());
+        assert_eq!(decompress(&legacy).unwrap(), LZ4_TEXT);
+    }
+
+    #[test]
+    fn lz4_rejects_bad_streams() {
+        // match reaching before output start
+        let mut frame = lz4_frame();
+        frame[23] = 0x04;
+        assert_eq!(decompress(&frame), Err(LoadError::BadCompressedImage));
+        // version bits of descriptor
+        let mut frame = lz4_frame();
+        frame[4] = 0x08;
+        assert_eq!(decompress(&frame), Err(LoadError::BadCompressedImage));
+        let frame = lz4_frame();
+        assert_eq!(
+            decompress(&frame[..frame.len() - 8]),
+            Err(LoadError::Truncated)
+        );
+    }
+
+    #[test]
+    fn recorded_size() {
+        assert_eq!(Compression::Gzip.recorded_size(&GZIP_STORED), Some(16));
+        assert_eq!(Compression::Lz4.recorded_size(&lz4_frame()), Some(17));
+        let mut frame = lz4_frame();
+        frame[4] = 0x40;
+        assert_eq!(Compression::Lz4.recorded_size(&frame), None);
+        assert_eq!(Compression::Lz4.recorded_size(&LZ4_LEGACY_MAGIC), None);
+    }
+
+    #[test]
+    fn decompress_into_stays_in_dst() {
+        let mut dst = [0; 32];
+        let written = Compression::Gzip
+            .decompress_into(&GZIP_STORED, 0x8000_0000, &mut dst)
+            .unwrap();
+        assert_eq!(&dst[..written], b"hello hypervisor");
+        let mut dst = [0; 8];
+        assert_eq!(
+            Compression::Gzip.decompress_into(&GZIP_STORED, 0x8000_0000, &mut dst),
+            Err(LoadError::OutOfGuestMemory {
+                start: 0x8000_0000,
+                end: 0x8000_0010,
+            })
+        );
+    }
+
+    #[test]
+    fn sink_stops_decompression() {
+        let mut chunks = 0;
+        let size = Compression::Gzip
+            .decompress(&GZIP_DYNAMIC, &mut |_: &[u8]| {
+                chunks += 1;
+                Ok(false)
+            })
+            .unwrap();
+        assert_eq!(chunks, 1);
+        assert!(size > 0);
+    }
+}
@@ -0,0 +1,206 @@
+//! minimal elf64 parser,only what we need to place PT_LOAD segments into guest ram
+
+use super::{GuestRam, LoadError};
+use alloc::vec::Vec;
+
+const ELF_MAGIC: [u8; 4] = [0x7f, b'E', b'L', b'F'];
+const ELFCLASS64: u8 = 2;
+const ELFDATA2LSB: u8 = 1;
+const ET_EXEC: u16 = 2;
+const EM_RISCV: u16 = 0xf3;
+const PT_LOAD: u32 = 1;
+
+const EHDR_SIZE: usize = 64;
+const PHDR_SIZE: usize = 56;
+
+#[inline]
+fn read_bytes<const N: usize>(data: &[u8], offset: usize) -> Result<[u8; N], LoadError> {
+    offset
+        .checked_add(N)
+        .and_then(|end| data.get(offset..end))
+        .and_then(|bytes| bytes.try_into().ok())
+        .ok_or(LoadError::Truncated)
+}
+
+fn read_u16(data: &[u8], offset: usize) -> Result<u16, LoadError> {
+    read_bytes(data, offset).map(u16::from_le_bytes)
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Result<u32, LoadError> {
+    read_bytes(data, offset).map(u32::from_le_bytes)
+}
+
+fn read_u64(data: &[u8], offset: usize) -> Result<usize, LoadError> {
+    read_bytes(data, offset).map(|bytes| u64::from_le_bytes(bytes) as usize)
+}
+
+pub fn is_elf(image: &[u8]) -> bool {
+    image.starts_with(&ELF_MAGIC)
+}
+
+/// program header fields used by loader
+struct ProgramHeader {
+    p_type: u32,
+    offset: usize,
+    paddr: usize,
+    filesz: usize,
+    memsz: usize,
+}
+
+impl ProgramHeader {
+    fn parse(image: &[u8], phoff: usize, index: usize) -> Result<Self, LoadError> {
+        let offset = index
+            .checked_mul(PHDR_SIZE)
+            .and_then(|start| phoff.checked_add(start))
+            .ok_or(LoadError::Truncated)?;
+        // fields are read from a copy of the whole header,offsets into it can't overflow
+        let phdr: [u8; PHDR_SIZE] = read_bytes(image, offset)?;
+        Ok(Self {
+            p_type: read_u32(&phdr, 0)?,
+            offset: read_u64(&phdr, 8)?,
+            paddr: read_u64(&phdr, 24)?,
+            filesz: read_u64(&phdr, 32)?,
+            memsz: read_u64(&phdr, 40)?,
+        })
+    }
+
+    /// segment content in image
+    fn file_bytes<'a>(&self, image: &'a [u8]) -> Result<&'a [u8], LoadError> {
+        self.offset
+            .checked_add(self.filesz)
+            .and_then(|end| image.get(self.offset..end))
+            .ok_or(LoadError::Truncated)
+    }
+}
+
+/// read elf header and PT_LOAD program headers,return (e_entry,segments)
+fn parse_elf(image: &[u8]) -> Result<(usize, Vec<ProgramHeader>), LoadError> {
+    let ident: [u8; 16] = read_bytes(image, 0)?;
+    if ident[4] != ELFCLASS64
+        || ident[5] != ELFDATA2LSB
+        || read_u16(image, 16)? != ET_EXEC
+        || read_u16(image, 18)? != EM_RISCV
+        || read_u16(image, 52)? as usize != EHDR_SIZE
+    {
+        return Err(LoadError::BadElfHeader);
+    }
+    let entry = read_u64(image, 24)?;
+    let phoff = read_u64(image, 32)?;
+    let phentsize = read_u16(image, 54)? as usize;
+    let phnum = read_u16(image, 56)? as usize;
+    if phentsize != PHDR_SIZE {
+        return Err(LoadError::BadElfHeader);
+    }
+    let mut segments = Vec::new();
+    for i in 0..phnum {
+        let phdr = ProgramHeader::parse(image, phoff, i)?;
+        if phdr.p_type != PT_LOAD {
+            continue;
+        }
+        if phdr.filesz > phdr.memsz || phdr.offset.checked_add(phdr.filesz).is_none() {
+            return Err(LoadError::BadElfHeader);
+        }
+        segments.push(phdr);
+    }
+    if segments.is_empty() {
+        return Err(LoadError::BadElfHeader);
+    }
+    Ok((entry, segments))
+}
+
+/// check segments and entry are inside guest ram,return (lowest segment start,highest segment end)
+fn check_segments(
+    ram: &mut GuestRam,
+    entry: usize,
+    segments: &[ProgramHeader],
+) -> Result<(usize, usize), LoadError> {
+    let (mut start, mut end) = (usize::MAX, 0);
+    for phdr in segments {
+        ram.slice_mut(phdr.paddr, phdr.memsz)?;
+        start = start.min(phdr.paddr);
+        end = end.max(phdr.paddr + phdr.memsz);
+    }
+    if !ram.contains(entry) {
+        return Err(LoadError::OutOfGuestMemory {
+            start: entry,
+            end: entry,
+        });
+    }
+    Ok((start, end))
+}
+
+/// copy PT_LOAD segments to their physical address and zero fill bss
+///
+/// return (e_entry,lowest segment start,highest segment end)
+pub fn load_elf(ram: &mut GuestRam, image: &[u8]) -> Result<(usize, usize, usize), LoadError> {
+    let (entry, segments) = parse_elf(image)?;
+    // check all segments before touching guest ram
+    for phdr in segments.iter() {
+        phdr.file_bytes(image)?;
+    }
+    let (start, end) = check_segments(ram, entry, &segments)?;
+
+    for phdr in segments.iter() {
+        let dst = ram.slice_mut(phdr.paddr, phdr.memsz)?;
+        let (file, bss) = dst.split_at_mut(phdr.filesz);
+        file.copy_from_slice(phdr.file_bytes(image)?);
+        bss.fill(0);
+    }
+    Ok((entry, start, end))
+}
+
+/// `load_elf` for an image being decompressed,segments are copied out of it as it streams by
+pub struct ElfStream {
+    entry: usize,
+    segments: Vec<ProgramHeader>,
+    start: usize,
+    end: usize,
+    // end of last segment content in image
+    file_end: usize,
+}
+
+impl ElfStream {
+    /// head is start of decompressed image,program headers must be inside it. segments are
+    /// checked and zeroed before any content arrives
+    pub fn new(ram: &mut GuestRam, head: &[u8]) -> Result<Self, LoadError> {
+        let (entry, segments) = parse_elf(head)?;
+        let (start, end) = check_segments(ram, entry, &segments)?;
+        for phdr in segments.iter() {
+            ram.slice_mut(phdr.paddr, phdr.memsz)?.fill(0);
+        }
+        let file_end = segments
+            .iter()
+            .map(|phdr| phdr.offset + phdr.filesz)
+            .max()
+            .unwrap();
+        Ok(Self {
+            entry,
+            segments,
+            start,
+            end,
+            file_end,
+        })
+    }
+
+    /// chunk at offset pos of decompressed image,return false once every segment is complete
+    pub fn write(&self, ram: &mut GuestRam, pos: usize, chunk: &[u8]) -> Result<bool, LoadError> {
+        let chunk_end = pos + chunk.len();
+        for phdr in self.segments.iter() {
+            let from = phdr.offset.max(pos);
+            let to = (phdr.offset + phdr.filesz).min(chunk_end);
+            if from < to {
+                ram.slice_mut(phdr.paddr + from - phdr.offset, to - from)?
+                    .copy_from_slice(&chunk[from - pos..to - pos]);
+            }
+        }
+        Ok(chunk_end < self.file_end)
+    }
+
+    /// size bytes were streamed,return (e_entry,lowest segment start,highest segment end)
+    pub fn finish(self, size: usize) -> Result<(usize, usize, usize), LoadError> {
+        if size < self.file_end {
+            return Err(LoadError::Truncated);
+        }
+        Ok((self.entry, self.start, self.end))
+    }
+}
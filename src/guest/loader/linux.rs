@@ -0,0 +1,102 @@
+//! riscv linux `Image` boot protocol
+//!
+//! see Documentation/arch/riscv/boot-image-header.rst in linux tree
+
+use super::{GuestRam, LoadError};
+
+const RISCV_IMAGE_MAGIC: &[u8; 8] = b"RISCV\0\0\0";
+const RISCV_IMAGE_MAGIC2: &[u8; 4] = b"RSC\x05";
+const HEADER_SIZE: usize = 64;
+// flags bit 0: kernel endianness,0 is little endian
+const FLAG_BIG_ENDIAN: u64 = 0x1;
+
+/// rv64 kernel must be placed at 2M aligned address
+pub const KERNEL_ALIGN: usize = 0x20_0000;
+
+pub struct ImageHeader {
+    pub text_offset: usize,
+    pub image_size: usize,
+    pub flags: u64,
+}
+
+fn read_u64(image: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(image[offset..offset + 8].try_into().unwrap())
+}
+
+pub fn is_linux_image(image: &[u8]) -> bool {
+    image.len() >= HEADER_SIZE
+        && &image[48..56] == RISCV_IMAGE_MAGIC
+        && &image[56..60] == RISCV_IMAGE_MAGIC2
+}
+
+impl ImageHeader {
+    pub fn parse(image: &[u8]) -> Result<Self, LoadError> {
+        if !is_linux_image(image) {
+            return Err(LoadError::BadLinuxHeader);
+        }
+        let header = Self {
+            text_offset: read_u64(image, 8) as usize,
+            image_size: read_u64(image, 16) as usize,
+            flags: read_u64(image, 24),
+        };
+        if header.flags & FLAG_BIG_ENDIAN != 0 {
+            return Err(LoadError::BadLinuxHeader);
+        }
+        Ok(header)
+    }
+
+    /// memory kernel occupies after loaded,include bss
+    ///
+    /// header version before 0.2 may leave image_size as 0
+    pub fn effective_size(&self, file_size: usize) -> usize {
+        self.image_size.max(file_size)
+    }
+}
+
+/// 2M aligned base of boot bank plus text_offset,an offset that overflows is a broken header
+fn load_address(ram: &GuestRam, header: &ImageHeader) -> Result<usize, LoadError> {
+    ((ram.base_gpa() + KERNEL_ALIGN - 1) / KERNEL_ALIGN * KERNEL_ALIGN)
+        .checked_add(header.text_offset)
+        .ok_or(LoadError::BadLinuxHeader)
+}
+
+/// place kernel at 2M aligned offset of guest ram,return its load address
+pub fn load_linux_image(ram: &mut GuestRam, image: &[u8]) -> Result<usize, LoadError> {
+    let header = ImageHeader::parse(image)?;
+    let load_gpa = load_address(ram, &header)?;
+    let dst = ram.slice_mut(load_gpa, header.effective_size(image.len()))?;
+    let (file, bss) = dst.split_at_mut(image.len());
+    file.copy_from_slice(image);
+    bss.fill(0);
+    Ok(load_gpa)
+}
+
+/// `load_linux_image` for a kernel being decompressed,it streams to its load address
+pub struct LinuxStream {
+    header: ImageHeader,
+    load_gpa: usize,
+}
+
+impl LinuxStream {
+    /// head is start of decompressed kernel
+    pub fn new(ram: &GuestRam, head: &[u8]) -> Result<Self, LoadError> {
+        let header = ImageHeader::parse(head)?;
+        let load_gpa = load_address(ram, &header)?;
+        Ok(Self { header, load_gpa })
+    }
+
+    /// chunk at offset pos of decompressed kernel,it may take up to the end of its bank
+    pub fn write(&self, ram: &mut GuestRam, pos: usize, chunk: &[u8]) -> Result<bool, LoadError> {
+        ram.slice_mut(self.load_gpa + pos, chunk.len())?
+            .copy_from_slice(chunk);
+        Ok(true)
+    }
+
+    /// kernel of size bytes was streamed,zero its bss. return [start,end) of kernel
+    pub fn finish(self, ram: &mut GuestRam, size: usize) -> Result<(usize, usize), LoadError> {
+        let end = self.load_gpa + self.header.effective_size(size);
+        ram.slice_mut(self.load_gpa + size, end - self.load_gpa - size)?
+            .fill(0);
+        Ok((self.load_gpa, end))
+    }
+}
@@ -0,0 +1,294 @@
+//! guest image loader
+//!
+//! an image is an elf64 riscv executable,a riscv linux `Image` or a flat binary copied to the start
+//! of guest ram. any of them may be gzip or lz4 compressed,then it is decompressed straight into
+//! guest ram
+
+mod compress;
+mod elf;
+mod linux;
+
+pub use compress::Compression;
+
+use crate::measure::MeasureEvent;
+use crate::println;
+use alloc::vec::Vec;
+
+// decompressed bytes held back to find out format of a compressed image,enough for elf program
+// headers
+const HEAD_SIZE: usize = 0x1000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LoadError {
+    /// image is empty or truncated
+    Truncated,
+    /// elf header is not a riscv64 little endian executable
+    BadElfHeader,
+    /// linux image header is broken or kernel is big endian
+    BadLinuxHeader,
+    /// segment [start,end) in guest physical address space is not inside guest ram
+    OutOfGuestMemory { start: usize, end: usize },
+    /// no free guest ram left for a blob of size bytes
+    NoSpace { size: usize },
+    /// host has too few free frames to back size bytes of guest memory
+    OutOfHostMemory { size: usize },
+    /// digest of image is not in allowlist
+    NotAllowed(MeasureEvent),
+    /// gzip or lz4 stream is corrupted
+    BadCompressedImage,
+    /// image is not in the format it was declared to be
+    WrongImageKind {
+        declared: ImageKind,
+        found: ImageKind,
+    },
+}
+
+/// guest ram viewed from hypervisor,each bank maps gpa [gpa,gpa + mem.len()) to mem
+///
+/// the first bank is boot bank,images without fixed load address are placed there
+pub struct GuestRam<'a> {
+    pub banks: Vec<(usize, &'a mut [u8])>,
+}
+
+impl<'a> GuestRam<'a> {
+    /// bytes of guest ram in gpa [gpa,gpa + len),range can't cross banks
+    pub fn slice_mut(&mut self, gpa: usize, len: usize) -> Result<&mut [u8], LoadError> {
+        let err = LoadError::OutOfGuestMemory {
+            start: gpa,
+            end: gpa.wrapping_add(len),
+        };
+        let end = gpa.checked_add(len).ok_or(err)?;
+        let (base, mem) = self
+            .banks
+            .iter_mut()
+            .find(|(base, mem)| *base <= gpa && end <= *base + mem.len())
+            .ok_or(err)?;
+        Ok(&mut mem[gpa - *base..end - *base])
+    }
+
+    #[inline]
+    pub fn contains(&self, gpa: usize) -> bool {
+        self.banks
+            .iter()
+            .any(|(base, mem)| (*base..*base + mem.len()).contains(&gpa))
+    }
+
+    #[inline]
+    pub fn base_gpa(&self) -> usize {
+        self.banks[0].0
+    }
+
+    /// end of boot bank
+    #[inline]
+    pub fn end_gpa(&self) -> usize {
+        self.banks[0].0 + self.banks[0].1.len()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImageKind {
+    Flat,
+    Elf,
+    Linux,
+}
+
+impl ImageKind {
+    /// format of an uncompressed image,told by its first bytes
+    fn detect(head: &[u8]) -> Self {
+        if elf::is_elf(head) {
+            Self::Elf
+        } else if linux::is_linux_image(head) {
+            Self::Linux
+        } else {
+            Self::Flat
+        }
+    }
+}
+
+/// image found must be of declared kind if there is one,checked before anything is loaded
+fn check_kind(declared: Option<ImageKind>, found: ImageKind) -> Result<(), LoadError> {
+    match declared {
+        Some(declared) if declared != found => Err(LoadError::WrongImageKind { declared, found }),
+        _ => Ok(()),
+    }
+}
+
+/// where the image is placed in guest physical address space
+#[derive(Debug, Clone, Copy)]
+pub struct LoadedImage {
+    pub kind: ImageKind,
+    pub entry: usize,
+    // [start,end) used by image,include bss
+    pub start: usize,
+    pub end: usize,
+}
+
+/// load image to guest ram according to its format,which must be declared one if there is one
+pub fn load_image(
+    ram: &mut GuestRam,
+    image: &[u8],
+    declared: Option<ImageKind>,
+) -> Result<LoadedImage, LoadError> {
+    if image.is_empty() {
+        return Err(LoadError::Truncated);
+    }
+    let loaded = if let Some(compression) = Compression::detect(image) {
+        load_compressed_image(ram, compression, image, declared)?
+    } else {
+        let kind = ImageKind::detect(image);
+        check_kind(declared, kind)?;
+        match kind {
+            ImageKind::Elf => {
+                let (entry, start, end) = elf::load_elf(ram, image)?;
+                LoadedImage {
+                    kind,
+                    entry,
+                    start,
+                    end,
+                }
+            }
+            ImageKind::Linux => {
+                let start = linux::load_linux_image(ram, image)?;
+                let size = linux::ImageHeader::parse(image)?.effective_size(image.len());
+                LoadedImage {
+                    kind,
+                    entry: start,
+                    start,
+                    end: start + size,
+                }
+            }
+            ImageKind::Flat => {
+                let base = ram.base_gpa();
+                ram.slice_mut(base, image.len())?.copy_from_slice(image);
+                LoadedImage {
+                    kind,
+                    entry: base,
+                    start: base,
+                    end: base + image.len(),
+                }
+            }
+        }
+    };
+    println!(
+        "[hypervisor] load {:?} guest image at [{:#x},{:#x}),entry:{:#x}",
+        loaded.kind, loaded.start, loaded.end, loaded.entry
+    );
+    Ok(loaded)
+}
+
+/// where a compressed image streams to,picked by its first decompressed bytes
+enum Stream {
+    Elf(elf::ElfStream),
+    Linux(linux::LinuxStream),
+    // flat binary at start of boot bank
+    Flat(usize),
+}
+
+impl Stream {
+    fn new(
+        ram: &mut GuestRam,
+        head: &[u8],
+        declared: Option<ImageKind>,
+    ) -> Result<Self, LoadError> {
+        let kind = ImageKind::detect(head);
+        check_kind(declared, kind)?;
+        Ok(match kind {
+            ImageKind::Elf => Self::Elf(elf::ElfStream::new(ram, head)?),
+            ImageKind::Linux => Self::Linux(linux::LinuxStream::new(ram, head)?),
+            ImageKind::Flat => Self::Flat(ram.base_gpa()),
+        })
+    }
+
+    /// chunk at offset pos of decompressed image,return false if nothing after it is needed
+    fn write(&self, ram: &mut GuestRam, pos: usize, chunk: &[u8]) -> Result<bool, LoadError> {
+        match self {
+            Self::Elf(elf) => elf.write(ram, pos, chunk),
+            Self::Linux(linux) => linux.write(ram, pos, chunk),
+            Self::Flat(base) => {
+                ram.slice_mut(base + pos, chunk.len())?
+                    .copy_from_slice(chunk);
+                Ok(true)
+            }
+        }
+    }
+
+    /// size bytes were streamed
+    fn finish(self, ram: &mut GuestRam, size: usize) -> Result<LoadedImage, LoadError> {
+        Ok(match self {
+            Self::Elf(elf) => {
+                let (entry, start, end) = elf.finish(size)?;
+                LoadedImage {
+                    kind: ImageKind::Elf,
+                    entry,
+                    start,
+                    end,
+                }
+            }
+            Self::Linux(linux) => {
+                let (start, end) = linux.finish(ram, size)?;
+                LoadedImage {
+                    kind: ImageKind::Linux,
+                    entry: start,
+                    start,
+                    end,
+                }
+            }
+            Self::Flat(base) => LoadedImage {
+                kind: ImageKind::Flat,
+                entry: base,
+                start: base,
+                end: base + size,
+            },
+        })
+    }
+}
+
+/// decompress image into guest ram in a single pass
+///
+/// first `HEAD_SIZE` decompressed bytes are held back until they tell the format,then they and
+/// everything after them go straight to where that format is loaded
+fn load_compressed_image(
+    ram: &mut GuestRam,
+    compression: Compression,
+    image: &[u8],
+    declared: Option<ImageKind>,
+) -> Result<LoadedImage, LoadError> {
+    let mut head = Vec::with_capacity(HEAD_SIZE);
+    let mut stream: Option<Stream> = None;
+    // decompressed bytes handed to stream
+    let mut pos = 0;
+    compression.decompress(image, &mut |mut chunk: &[u8]| {
+        if stream.is_none() {
+            let n = chunk.len().min(HEAD_SIZE - head.len());
+            head.extend_from_slice(&chunk[..n]);
+            chunk = &chunk[n..];
+            if head.len() < HEAD_SIZE {
+                return Ok(true);
+            }
+            let picked = Stream::new(ram, &head, declared)?;
+            let more = picked.write(ram, 0, &head)?;
+            pos = head.len();
+            stream = Some(picked);
+            if !more {
+                return Ok(false);
+            }
+        }
+        let more = stream.as_ref().unwrap().write(ram, pos, chunk)?;
+        pos += chunk.len();
+        Ok(more)
+    })?;
+    let stream = match stream {
+        Some(stream) => stream,
+        // whole image is shorter than head
+        None => {
+            if head.is_empty() {
+                return Err(LoadError::Truncated);
+            }
+            let stream = Stream::new(ram, &head, declared)?;
+            stream.write(ram, 0, &head)?;
+            pos = head.len();
+            stream
+        }
+    };
+    stream.finish(ram, pos)
+}
@@ -1,23 +1,83 @@
+mod clock;
+pub mod device;
+mod device_tree;
+mod loader;
+mod state;
 mod vcpu;
 mod virt_machine;
 
 use crate::mm::{MemRegion, PageTable};
+use alloc::string::String;
 use alloc::vec::Vec;
-pub use virt_machine::Guest;
+pub use clock::GuestClock;
+pub use device_tree::DEFAULT_BOOTARGS;
+pub use loader::{ImageKind, LoadError, LoadedImage};
+pub use state::{RunState, StateError};
+pub use vcpu::{Fence, HsmState};
+pub use virt_machine::{CowFault, Guest, StopReason};
 
 // virt machine = gpa address space + device + vcpus
 // guest = virt machine + resource(mem region(region represent gpm space)+stack for each vcpu ) in host machine
 
+/// images a guest boots from
+#[derive(Clone, Copy)]
+pub struct BootImages<'a> {
+    pub kernel: &'a [u8],
+    // declared format of kernel,none lets loader find it out
+    pub kernel_kind: Option<ImageKind>,
+    pub initrd: Option<&'a [u8]>,
+    // use this device tree instead of generating one
+    pub dtb: Option<&'a [u8]>,
+    pub bootargs: &'a str,
+}
+
+impl<'a> BootImages<'a> {
+    pub fn kernel_only(kernel: &'a [u8]) -> Self {
+        Self {
+            kernel,
+            kernel_kind: None,
+            initrd: None,
+            dtb: None,
+            bootargs: DEFAULT_BOOTARGS,
+        }
+    }
+}
+
+/// images of a guest kept for its whole life,so it can boot again on reset
+#[derive(Clone)]
+pub struct GuestImages {
+    pub kernel: &'static [u8],
+    pub kernel_kind: Option<ImageKind>,
+    pub initrd: Option<&'static [u8]>,
+    pub dtb: Option<&'static [u8]>,
+    pub bootargs: String,
+    // start boot vcpu here instead of entry of kernel image
+    pub entry: Option<usize>,
+}
+
+impl GuestImages {
+    pub fn boot_images(&self) -> BootImages<'_> {
+        BootImages {
+            kernel: self.kernel,
+            kernel_kind: self.kernel_kind,
+            initrd: self.initrd,
+            dtb: self.dtb,
+            bootargs: &self.bootargs,
+        }
+    }
+}
+
 /// struct represent mem resource used by guest
 pub struct GuestResource<P: PageTable> {
-    pub normal_mem: MemRegion<P>,
+    // one host region for each memory bank
+    pub normal_mem: Vec<MemRegion<P>>,
     pub stack: Vec<MemRegion<P>>,
 }
 
 impl<P: PageTable> GuestResource<P> {
-    pub fn new(mem: MemRegion<P>) -> Self {
+    pub fn new() -> Self {
         Self {
-            normal_mem: mem,
+            normal_mem: Vec::new(),
             stack: Vec::new(),
         }
     }
@@ -27,15 +87,6 @@ impl<P: PageTable> GuestResource<P> {
             self.stack.len() > vcpu_id,
             "[GuestResource] guest has no vcpu:{vcpu_id}"
         );
-        self.stack[vcpu_id].start_vpn.into()
-    }
-
-    #[inline(always)]
-    pub fn get_start_ptr(&mut self) -> *mut u8 {
-        self.normal_mem.start_vpn.page_base_va().0 as *mut u8
-    }
-
-    pub fn get_mem_size(&self) -> usize {
-        self.normal_mem.get_size()
+        self.stack[vcpu_id].end_vpn().page_base_va().0
     }
 }
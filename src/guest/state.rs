@@ -0,0 +1,78 @@
+//! lifecycle of guests and vcpus
+//!
+//! a vcpu is created stopped,it becomes runnable when guest boots or starts it by sbi hsm. it's
+//! running while a hart runs it and blocked while it waits in wfi,a paused vcpu doesn't run until
+//! resumed. halted and crashed vcpus never run again,a new vcpu replaces them when guest restarts
+//! or starts the hart again
+//!
+//! a guest is created,runnable,paused,halted or crashed,runnable covers running and blocked vcpus.
+//! a halted or crashed guest is created again by a restart
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RunState {
+    Created,
+    Runnable,
+    Running,
+    Blocked,
+    Paused,
+    Halted,
+    Crashed,
+}
+
+/// why a lifecycle operation failed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StateError {
+    NoGuest(usize),
+    /// lifecycle has no such transition
+    BadTransition {
+        from: RunState,
+        to: RunState,
+    },
+}
+
+impl RunState {
+    pub fn can_become(self, next: Self) -> bool {
+        use RunState::*;
+        matches!(
+            (self, next),
+            (Created, Runnable)
+                | (Runnable, Running)
+                | (Running, Runnable | Blocked)
+                | (Blocked, Runnable)
+                | (Runnable | Running | Blocked, Paused)
+                | (Paused, Runnable)
+                | (Created | Runnable | Running | Blocked | Paused, Halted)
+                | (Runnable | Running | Blocked | Paused, Crashed)
+                | (Halted | Crashed, Created)
+        )
+    }
+
+    /// move to next if lifecycle allows it
+    pub fn transition(&mut self, next: Self) -> Result<(), StateError> {
+        if !self.can_become(next) {
+            return Err(StateError::BadTransition {
+                from: *self,
+                to: next,
+            });
+        }
+        *self = next;
+        Ok(())
+    }
+
+    /// move to next,hypervisor itself never asks for a transition lifecycle doesn't allow
+    pub fn advance(&mut self, next: Self) {
+        assert!(
+            self.can_become(next),
+            "[hypervisor] {:?} can't become {:?}",
+            self,
+            next
+        );
+        *self = next;
+    }
+
+    /// stopped for good,until a restart
+    #[inline]
+    pub fn is_stopped(self) -> bool {
+        matches!(self, Self::Halted | Self::Crashed)
+    }
+}
@@ -1,16 +1,473 @@
-use crate::arch::TrapContext;
-#[derive(Clone, Copy)]
+use crate::arch::intc::imsic::{clear_guest_file, set_guest_irq, GuestFile};
+use crate::arch::page_table::flush_vs_tlb;
+use crate::arch::{TrapContext, VectorState, VirtIrq};
+use crate::constants::ALL_HARTS;
+use crate::guest::state::{RunState, StateError};
+use crate::percpu;
+use crate::schedule::{now, VcpuAccount};
+
+/// hart state guest sees through sbi hsm
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HsmState {
+    Stopped,
+    Started,
+}
+
+/// fence guest asks remote harts for by sbi rfence
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fence {
+    // sfence.vma,with or without asid
+    Tlb,
+    // fence.i
+    Icache,
+}
+
+/// virtual interrupts injected into a vcpu and how long they took to reach it,in ticks of time csr
+#[derive(Debug, Clone, Copy, Default)]
+pub struct IrqLatency {
+    pub injected: usize,
+    pub delivered: usize,
+    pub total: usize,
+    pub max: usize,
+}
+
+impl IrqLatency {
+    fn record(&mut self, latency: usize) {
+        self.delivered += 1;
+        self.total += latency;
+        self.max = self.max.max(latency);
+    }
+
+    /// mean latency of delivered interrupts
+    #[inline]
+    pub fn average(&self) -> usize {
+        self.total / self.delivered.max(1)
+    }
+}
+
+/// interrupts injected into vcpu,applied to its hvip before it enters guest on a hart
+#[derive(Debug, Clone, Copy, Default)]
+struct InjectedIrqs {
+    // hvip bits injected and not cleared
+    asserted: usize,
+    // hvip bits cleared since last delivery
+    cleared: usize,
+    // host time each of `VirtIrq::ALL` was injected,until delivered
+    since: [Option<usize>; 3],
+}
+
+#[derive(Clone)]
 pub struct VCpu {
     context: TrapContext,
+    // v registers,none if vcpu has no vector extension
+    vector: Option<VectorState>,
+    state: RunState,
+    // physical hart running this vcpu,it stays on the hart after leaving running state until it
+    // exits
+    hart: Option<usize>,
+    // hart which ran it last
+    last_hart: Option<usize>,
+    // mask of harts it may run on
+    affinity: usize,
+    // on a run queue,see `schedule`
+    queued: bool,
+    // deadline of sbi timer in guest time,vcpus with sstc keep it in vstimecmp
+    timer: Option<usize>,
+    // guest interrupt file of a host hart,vcpu runs only on that hart while it owns the file
+    guest_file: Option<GuestFile>,
+    // file still holds state of a guest run before,it's cleared when vcpu runs next
+    stale_file: bool,
+    // vcpu gives its file up at next release,its affinity left the hart out
+    moving_file: bool,
+    // guest asked for remote sfence.vma or fence.i while vcpu was off harts,done when it runs next
+    stale_tlb: bool,
+    stale_icache: bool,
+    injected: InjectedIrqs,
+    irq_latency: IrqLatency,
+    account: VcpuAccount,
 }
 
 impl VCpu {
-    pub fn new(context: TrapContext) -> Self {
-        Self { context }
+    /// vcpu is stopped until guest starts it by sbi hsm,except boot vcpu
+    ///
+    /// vector gives it v registers if host harts have them
+    pub fn new(mut context: TrapContext, vector: bool) -> Self {
+        let vector = if vector { VectorState::new() } else { None };
+        if vector.is_some() {
+            context.enable_vector();
+        }
+        Self {
+            context,
+            vector,
+            state: RunState::Created,
+            hart: None,
+            last_hart: None,
+            affinity: ALL_HARTS,
+            queued: false,
+            timer: None,
+            guest_file: None,
+            stale_file: false,
+            moving_file: false,
+            stale_tlb: false,
+            stale_icache: false,
+            injected: InjectedIrqs::default(),
+            irq_latency: IrqLatency::default(),
+            account: VcpuAccount::default(),
+        }
+    }
+
+    /// set pc where vcpu starts to run in guest
+    pub fn set_entry(&mut self, entry: usize) {
+        self.context.sepc = entry;
+    }
+
+    pub fn set_sp(&mut self, sp: usize) {
+        self.context.set_sp(sp);
+    }
+
+    pub fn set_args(&mut self, args: &[usize]) {
+        self.context.set_args(args);
     }
 
     #[inline(always)]
     pub fn get_ctx_ptr(&mut self) -> *mut TrapContext {
         &mut self.context
     }
+
+    #[inline]
+    pub fn state(&self) -> RunState {
+        self.state
+    }
+
+    /// hsm sees a vcpu started until it halts or crashes
+    pub fn hsm_state(&self) -> HsmState {
+        match self.state {
+            RunState::Created | RunState::Halted | RunState::Crashed => HsmState::Stopped,
+            _ => HsmState::Started,
+        }
+    }
+
+    /// created vcpu may run now
+    pub fn start(&mut self) {
+        self.state.advance(RunState::Runnable);
+    }
+
+    /// running vcpu stopped itself by sbi hsm
+    pub fn stop(&mut self) {
+        self.state.advance(RunState::Halted);
+    }
+
+    /// vcpu stops for good,unless it is already
+    pub fn halt(&mut self) {
+        if !self.state.is_stopped() {
+            self.state.advance(RunState::Halted);
+        }
+    }
+
+    /// vcpu took a trap hypervisor can't handle,unless it's stopped already
+    pub fn crash(&mut self) {
+        if !self.state.is_stopped() {
+            self.state.advance(RunState::Crashed);
+        }
+    }
+
+    /// vcpu doesn't run until resumed,it leaves its hart at next exit. a blocked vcpu wakes when
+    /// resumed,as if an interrupt came
+    pub fn pause(&mut self) -> Result<(), StateError> {
+        if self.state == RunState::Blocked {
+            self.account.wake(now());
+        }
+        self.state.transition(RunState::Paused)
+    }
+
+    pub fn resume(&mut self) -> Result<(), StateError> {
+        self.state.transition(RunState::Runnable)
+    }
+
+    #[inline]
+    pub fn hart(&self) -> Option<usize> {
+        self.hart
+    }
+
+    #[inline]
+    pub fn last_hart(&self) -> Option<usize> {
+        self.last_hart
+    }
+
+    #[inline]
+    pub fn affinity(&self) -> usize {
+        self.affinity
+    }
+
+    /// harts vcpu may run on,it moves off its hart at next exit if that one is left out
+    #[inline]
+    pub fn set_affinity(&mut self, affinity: usize) {
+        self.affinity = affinity;
+    }
+
+    /// harts vcpu may run on now,only the hart of its guest file if it owns one
+    #[inline]
+    pub fn placement(&self) -> usize {
+        self.guest_file.map_or(self.affinity, |file| 1 << file.hart)
+    }
+
+    #[inline]
+    pub fn allows(&self, hart: usize) -> bool {
+        self.placement() & 1 << hart != 0
+    }
+
+    /// vcpu takes vs external interrupts from guest file of a host hart,or from one emulated by
+    /// hypervisor
+    pub fn set_guest_file(&mut self, file: Option<GuestFile>) {
+        self.guest_file = file;
+        self.moving_file = false;
+        self.context
+            .set_guest_file(file.map_or(0, |file| file.index));
+    }
+
+    /// guest restarts,its guest file is cleared before vcpu runs again
+    #[inline]
+    pub fn discard_guest_file(&mut self) {
+        self.stale_file = self.guest_file.is_some();
+    }
+
+    #[inline]
+    pub fn guest_file(&self) -> Option<GuestFile> {
+        self.guest_file
+    }
+
+    /// vcpu gives up its guest file once it leaves the hart of file
+    #[inline]
+    pub fn move_guest_file(&mut self) {
+        self.moving_file = self.guest_file.is_some();
+    }
+
+    #[inline]
+    pub fn is_moving_file(&self) -> bool {
+        self.moving_file
+    }
+
+    /// vcpu off harts does fence before it runs next
+    #[inline]
+    pub fn fence_on_entry(&mut self, fence: Fence) {
+        match fence {
+            Fence::Tlb => self.stale_tlb = true,
+            Fence::Icache => self.stale_icache = true,
+        }
+    }
+
+    /// emulated interrupt file asserts external interrupt of vcpu,or not
+    #[inline]
+    pub fn set_external_pending(&mut self, pending: bool) {
+        self.context.set_external_pending(pending);
+    }
+
+    /// runnable and off harts
+    #[inline]
+    pub fn is_runnable(&self) -> bool {
+        self.state == RunState::Runnable && self.hart.is_none()
+    }
+
+    /// vcpu waits for an interrupt,it leaves its hart and doesn't queue until woken
+    ///
+    /// an msi in its guest file raises supervisor guest external interrupt on this hart meanwhile
+    pub fn block(&mut self) {
+        self.state.advance(RunState::Blocked);
+        self.account.block(now());
+        if let Some(file) = self.guest_file {
+            set_guest_irq(file.index, true);
+        }
+    }
+
+    /// interrupt came for blocked vcpu
+    pub fn wake(&mut self) {
+        if self.state == RunState::Blocked {
+            self.state.advance(RunState::Runnable);
+            self.account.wake(now());
+        }
+    }
+
+    /// hypervisor raised a virtual interrupt for vcpu,or injected one not delivered yet
+    #[inline]
+    pub fn has_virtual_irq(&self) -> bool {
+        self.context.has_virtual_irq() || self.injected.asserted != 0
+    }
+
+    /// irq is pending for vcpu until cleared,a software interrupt only until delivered since guest
+    /// clears it itself
+    pub fn inject_irq(&mut self, irq: VirtIrq) {
+        let slot = VirtIrq::ALL.iter().position(|&i| i == irq).unwrap();
+        self.injected.asserted |= irq.hvip_bit();
+        self.injected.cleared &= !irq.hvip_bit();
+        self.injected.since[slot].get_or_insert(now());
+        self.irq_latency.injected += 1;
+    }
+
+    /// irq is no longer pending for vcpu
+    pub fn clear_irq(&mut self, irq: VirtIrq) {
+        let slot = VirtIrq::ALL.iter().position(|&i| i == irq).unwrap();
+        self.injected.asserted &= !irq.hvip_bit();
+        self.injected.cleared |= irq.hvip_bit();
+        self.injected.since[slot] = None;
+    }
+
+    /// apply injected interrupts to hvip of vcpu,it's about to enter guest on this hart
+    ///
+    /// hvip of a vcpu running on another hart is only touched after it exits,`__vm_exit` saves
+    /// hvip over what we'd write
+    pub fn deliver_irqs(&mut self) {
+        let now = now();
+        for (slot, irq) in VirtIrq::ALL.into_iter().enumerate() {
+            if self.injected.cleared & irq.hvip_bit() != 0 {
+                self.context.set_virtual_irq(irq, false);
+            }
+            if self.injected.asserted & irq.hvip_bit() == 0 {
+                continue;
+            }
+            self.context.set_virtual_irq(irq, true);
+            if let Some(since) = self.injected.since[slot].take() {
+                self.irq_latency.record(now - since);
+            }
+        }
+        self.injected.asserted &= !VirtIrq::Software.hvip_bit();
+        self.injected.cleared = 0;
+    }
+
+    #[inline]
+    pub fn irq_latency(&self) -> &IrqLatency {
+        &self.irq_latency
+    }
+
+    #[inline]
+    pub fn is_queued(&self) -> bool {
+        self.queued
+    }
+
+    #[inline]
+    pub fn set_queued(&mut self, queued: bool) {
+        self.queued = queued;
+        if queued {
+            self.account.ready(now());
+        }
+    }
+
+    #[inline]
+    pub fn account(&self) -> &VcpuAccount {
+        &self.account
+    }
+
+    /// guest running on this hart sets its timer to fire at guest time,pending timer interrupt is
+    /// cleared
+    pub fn set_timer(&mut self, deadline: usize) {
+        if self.context.has_sstc() {
+            self.context.csrs.vstimecmp = deadline;
+            self.context.restore_vstimecmp();
+        } else {
+            self.timer = Some(deadline);
+            self.context.set_timer_pending(false);
+        }
+    }
+
+    /// pick up vstimecmp guest wrote while running on this hart
+    #[inline]
+    pub fn sync_timer(&mut self) {
+        self.context.save_vstimecmp();
+    }
+
+    /// guest time timer of vcpu fires at,none if it isn't set
+    pub fn timer_deadline(&self) -> Option<usize> {
+        if self.context.has_sstc() {
+            Some(self.context.csrs.vstimecmp).filter(|&deadline| deadline != usize::MAX)
+        } else {
+            self.timer
+        }
+    }
+
+    /// sbi timer hypervisor fires for vcpu,none with sstc where hart fires it
+    #[inline]
+    pub fn sbi_timer(&self) -> Option<usize> {
+        self.timer
+    }
+
+    /// sbi timer reached its deadline,timer interrupt is pending for guest now
+    pub fn fire_timer(&mut self) {
+        if self.timer.take().is_some() {
+            self.context.set_timer_pending(true);
+        }
+    }
+
+    /// guest time minus host time,guest reads it from time csr
+    #[inline]
+    pub fn set_time_delta(&mut self, delta: usize) {
+        self.context.csrs.htimedelta = delta;
+    }
+
+    /// this hart is going to run vcpu,it's off run queues now
+    ///
+    /// unless vcpu ran last here and nothing else ran since,vs stage tlb of hart is flushed,all
+    /// guests share vmid 0,and f and v registers of vcpu are loaded. fences guest asked for while
+    /// vcpu was off harts are done here
+    pub fn run_on(&mut self, hart: usize) {
+        let ctx = self.get_ctx_ptr();
+        if self.last_hart != Some(hart) || percpu::with(|cpu| cpu.last()) != ctx {
+            unsafe { flush_vs_tlb() };
+            self.context.restore_fp();
+            if let Some(vector) = &self.vector {
+                self.context.restore_vector(vector);
+            }
+        } else if core::mem::take(&mut self.stale_tlb) {
+            unsafe { flush_vs_tlb() };
+        }
+        self.stale_tlb = false;
+        if core::mem::take(&mut self.stale_icache) {
+            unsafe { core::arch::asm!("fence.i") };
+        }
+        self.context.restore_vstimecmp();
+        self.context.restore_vsiselect();
+        if let Some(file) = self.guest_file {
+            // vcpu takes interrupts of its file directly now
+            set_guest_irq(file.index, false);
+            if core::mem::take(&mut self.stale_file) {
+                clear_guest_file(file.index);
+            }
+        }
+        self.state.advance(RunState::Running);
+        self.account.run(now());
+        self.hart = Some(hart);
+        self.last_hart = Some(hart);
+        self.queued = false;
+    }
+
+    /// burn credits for time vcpu ran since last charge,return the time
+    #[inline]
+    pub fn charge(&mut self) -> usize {
+        self.account.charge(now())
+    }
+
+    /// vcpu left this hart,return time it ran since last charge
+    ///
+    /// it's runnable again unless it blocked,paused or stopped while on the hart
+    pub fn release(&mut self) -> usize {
+        if self.state == RunState::Running {
+            self.state.advance(RunState::Runnable);
+        }
+        self.context.save_vstimecmp();
+        self.context.save_vsiselect();
+        self.context.save_fp();
+        if let Some(vector) = &mut self.vector {
+            self.context.save_vector(vector);
+        }
+        // migrating away,drop what this hart caches of its vs translations
+        let hart = self.hart.take();
+        if hart.map_or(false, |hart| !self.allows(hart)) {
+            unsafe { flush_vs_tlb() };
+        }
+        self.charge()
+    }
+
+    /// credits of a new period
+    #[inline]
+    pub fn refill(&mut self, credits: usize) {
+        self.account.refill(credits);
+    }
 }
@@ -1,65 +1,1349 @@
-use crate::arch::mm::KERNEL_START_PA;
-use crate::arch::page_table::PageTableAdapter;
-use crate::arch::{vm_exit, TrapContext};
-use crate::guest::vcpu::VCpu;
-use crate::guest::GuestResource;
-use crate::mm::{hpm_guard, AddressSpace, GStagePageTable, GuestAddressSpace, PageTable};
+use crate::arch::intc::imsic::{
+    host_imsic, pending_guest_irqs, read_vsiselect, restore_guest_file, save_guest_file,
+    set_guest_irq, GuestFile, CSR_SIREG, CSR_STOPEI,
+};
+use crate::arch::mmio::{decode_csr_insn, MmioInsn};
+use crate::arch::page_table::{
+    PTEFlags, PageTableAdapter, PageTableEntry, PhysAddress, PhysPageNum, VirtPageNum,
+};
+use crate::arch::{host_vlenb, vm_exit, TrapContext, VirtIrq};
+use crate::config::{ConsoleRoute, MemoryBank, MemoryKind, Passthrough, RestartPolicy, VmConfig};
+use crate::constants::{GUEST_BOOT_STACK_SIZE, PAGE_SIZE};
+use crate::device_tree::host_timebase_frequency;
+use crate::guest::device::{ImsicFile, VirtDevices};
+use crate::guest::device_tree::{build_guest_fdt, GuestFdtInfo, DTB_ALIGN};
+use crate::guest::loader::{load_image, Compression, GuestRam, ImageKind, LoadError, LoadedImage};
+use crate::guest::state::{RunState, StateError};
+use crate::guest::vcpu::{Fence, HsmState, VCpu};
+use crate::guest::{BootImages, GuestClock, GuestImages, GuestResource};
+use crate::hypervisor::smp::{flush_guest_tlb_all, kick};
+use crate::measure::{is_allowed, MeasureEvent, Measurement, MeasurementLog};
+use crate::mm::{
+    frame_alloc, free_frames, hpm_guard, AddressSpace, FrameTracker, GStagePageTable,
+    GuestAddressSpace, MapPermission, PageTable,
+};
+use crate::percpu;
 use crate::println;
+use crate::sbi::{sbi_remote_fence_i, sbi_remote_hfence_vvma, SBI_SUCCESS};
+use crate::schedule::{self, GuestShare, VcpuRef};
+use alloc::string::String;
+use alloc::sync::Arc;
 use alloc::vec::Vec;
 
 pub struct Guest<P: PageTable, G: GStagePageTable> {
     guest_id: usize,
+    name: String,
     vcpus: Vec<VCpu>,
+    // host regions of banks,in the same order as banks and address_space.regions
     resources: GuestResource<P>,
     address_space: GuestAddressSpace<G>,
+    banks: Vec<MemoryBank>,
+    devices: VirtDevices,
+    console: ConsoleRoute,
+    passthrough: Vec<Passthrough>,
+    // device tree passed to boot vcpu in a1
+    dtb_gpa: Option<usize>,
+    // [start,end) of initrd in guest physical address space
+    initrd: Option<(usize, usize)>,
+    measurements: MeasurementLog,
+    images: GuestImages,
+    restart: RestartPolicy,
+    restarts: usize,
+    state: RunState,
+    // guest is going to stop,vcpus must leave their harts first
+    stop_request: Option<StopReason>,
+    // in ticks of time csr
+    time_slice: usize,
+    share: GuestShare,
+    // vcpus have vector extension
+    vector: bool,
+    clock: GuestClock,
+}
+
+/// why guest stops running
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StopReason {
+    /// guest asked for shutdown
+    Shutdown,
+    /// guest asked for cold or warm reboot
+    Reboot,
+    /// guest took a trap hypervisor can't handle
+    Crash,
+}
+
+/// outcome of a store guest page fault on memory banks
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CowFault {
+    /// page isn't write protected normal memory
+    NotCow,
+    /// page is writable again
+    Handled,
+    /// page is shared and host has no free frame to copy it to
+    OutOfMemory,
 }
 
 impl Guest<PageTableAdapter, PageTableAdapter> {
-    pub fn new(guest_id: usize, cpu_nums: usize, mem_size: usize) -> Self {
+    /// build guest from validated config,images are not loaded yet
+    pub fn new(guest_id: usize, config: &VmConfig<'static>) -> Result<Self, LoadError> {
+        // other guests may have taken frames since config was validated
+        let frames = config.host_frames();
+        if frames > free_frames() {
+            return Err(LoadError::OutOfHostMemory {
+                size: frames * PAGE_SIZE,
+            });
+        }
         let mut hpm_guard = hpm_guard();
-        let (gpm, host_region) = hpm_guard.alloc_gpm::<PageTableAdapter>(guest_id, mem_size);
-        let mut resources = GuestResource::new(host_region);
+        let mut gpm = GuestAddressSpace::<PageTableAdapter>::new_bare(guest_id);
+        let mut resources = GuestResource::new();
+        for bank in config.memory.iter() {
+            let host_region = hpm_guard.alloc_guest_ram(&mut gpm, bank.gpa, bank.size);
+            resources.normal_mem.push(host_region);
+        }
+        reserve_banks(&mut gpm, &config.memory);
+        for pt in config.passthrough.iter() {
+            gpm.map_passthrough(pt.gpa, pt.host_pa, pt.size);
+        }
 
-        let mut vcpus = Vec::with_capacity(cpu_nums);
+        let mut vcpus = Vec::with_capacity(config.vcpu_nums);
+        let vector = config.vector && host_vlenb().is_some();
 
         // init vcpus context
-        for vcpu_id in 0..cpu_nums {
+        for vcpu_id in 0..config.vcpu_nums {
             let stack_region = hpm_guard.alloc_vcpu_stack();
             resources.stack.push(stack_region);
             let context = TrapContext::init_context(
-                KERNEL_START_PA,
+                config.boot_bank().gpa,
                 resources.hart_stack_top(vcpu_id),
                 gpm.token(),
                 vm_exit as usize,
             );
-            vcpus.push(VCpu::new(context));
+            let mut vcpu = VCpu::new(context, vector);
+            vcpu.set_affinity(config.vcpu_affinity(vcpu_id));
+            vcpus.push(vcpu);
         }
+        vcpus[0].start();
 
-        Self {
+        let mut guest = Self {
             guest_id,
+            name: config.name.clone(),
             vcpus,
             resources,
             address_space: gpm,
+            banks: config.memory.clone(),
+            devices: VirtDevices::from_config(
+                &config.devices,
+                &config.passthrough,
+                config.vcpu_nums,
+                config.console,
+            ),
+            console: config.console,
+            passthrough: config.passthrough.clone(),
+            dtb_gpa: None,
+            initrd: None,
+            measurements: MeasurementLog::default(),
+            images: config.guest_images(),
+            restart: config.restart,
+            restarts: 0,
+            state: RunState::Created,
+            stop_request: None,
+            time_slice: config.time_slice_ms * host_timebase_frequency() / 1000,
+            share: GuestShare::new(config.weight, config.cap),
+            vector,
+            clock: GuestClock::new(config.freeze_clock),
+        };
+        guest.assign_guest_files();
+        Ok(guest)
+    }
+
+    /// give every vcpu a guest interrupt file on a host hart of its affinity and map its imsic page
+    /// there,vcpus left without one when files run out get a file emulated by hypervisor
+    fn assign_guest_files(&mut self) {
+        let (imsic, host) = match (self.devices.imsic.as_mut(), host_imsic()) {
+            (Some(imsic), Some(host)) => (imsic, host),
+            _ => return,
+        };
+        for (vcpu_id, vcpu) in self.vcpus.iter_mut().enumerate() {
+            match host.alloc(vcpu.affinity()) {
+                Some(file) => {
+                    self.address_space.map_passthrough(
+                        imsic.page(vcpu_id),
+                        host.file_pa(file),
+                        PAGE_SIZE,
+                    );
+                    imsic.set_file(vcpu_id, ImsicFile::Hardware(file));
+                    vcpu.set_guest_file(Some(file));
+                }
+                None => println!(
+                    "[hypervisor] no guest interrupt file left for vcpu {} of guest {},emulate it",
+                    vcpu_id, self.guest_id
+                ),
+            }
+        }
+    }
+
+    /// guest file of host hart vcpu owns
+    fn guest_file_of(&self, vcpu_id: usize) -> Option<GuestFile> {
+        self.devices
+            .imsic
+            .as_ref()
+            .and_then(|imsic| imsic.guest_file(vcpu_id))
+    }
+
+    /// vcpu leaving hart of its guest file gives the file up,file state moves to an emulated file
+    /// and its imsic page traps again. runs on hart of the file
+    ///
+    /// vcpu takes a guest file again once it runs on a hart with a free one,see
+    /// `reacquire_guest_file`
+    fn drop_guest_file(&mut self, vcpu_id: usize) {
+        let (file, host) = match (self.guest_file_of(vcpu_id), host_imsic()) {
+            (Some(file), Some(host)) => (file, host),
+            _ => return,
+        };
+        set_guest_irq(file.index, false);
+        let imsic = self.devices.imsic.as_mut().unwrap();
+        // no msi from other vcpus lands in file after it's saved,their writes trap and wait for
+        // guest queue until emulated file takes over
+        self.address_space.unmap_passthrough(imsic.page(vcpu_id));
+        flush_guest_tlb_all();
+        let state = save_guest_file(file.index);
+        imsic.set_file(vcpu_id, ImsicFile::Software(state));
+        host.free(file);
+        self.vcpus[vcpu_id].set_guest_file(None);
+    }
+
+    /// vcpu with an emulated file takes a free guest file of hart it's going to run on,file state
+    /// moves there and its imsic page maps to it again. runs on hart
+    fn reacquire_guest_file(&mut self, vcpu_id: usize, hart: usize) {
+        let (imsic, host) = match (self.devices.imsic.as_mut(), host_imsic()) {
+            (Some(imsic), Some(host)) => (imsic, host),
+            _ => return,
+        };
+        let state = match imsic.software(vcpu_id) {
+            Some(state) => *state,
+            None => return,
+        };
+        let file = match host.alloc(1 << hart) {
+            Some(file) => file,
+            None => return,
+        };
+        restore_guest_file(file.index, &state);
+        imsic.set_file(vcpu_id, ImsicFile::Hardware(file));
+        self.address_space
+            .map_passthrough(imsic.page(vcpu_id), host.file_pa(file), PAGE_SIZE);
+        self.vcpus[vcpu_id].set_guest_file(Some(file));
+    }
+
+    /// load images guest was created with and start boot vcpu at their entry,guest is runnable then
+    pub fn boot(&mut self) -> Result<(), LoadError> {
+        let images = self.images.clone();
+        self.load_boot_images(&images.boot_images())?;
+        if let Some(entry) = images.entry {
+            self.set_boot_entry(entry);
+        }
+        self.state.advance(RunState::Runnable);
+        Ok(())
+    }
+
+    /// whether guest should boot again after it stops for reason,count the restart if so
+    pub fn should_restart(&mut self, reason: StopReason) -> bool {
+        let restart = match (self.restart, reason) {
+            (RestartPolicy::Never, _) => false,
+            (RestartPolicy::Always, _) => true,
+            (_, StopReason::Shutdown) => false,
+            (RestartPolicy::OnCrash, _) => true,
+            (RestartPolicy::Max(times), _) => self.restarts < times,
+        };
+        if restart {
+            self.restarts += 1;
+        }
+        restart
+    }
+
+    /// bring guest back to power on state and boot it again
+    ///
+    /// ram banks are zeroed,reserved banks keep their content. every vcpu gets a fresh context,so no
+    /// vcpu may be running on any hart. guest must be halted or crashed
+    pub fn reset(&mut self) -> Result<(), LoadError> {
+        self.state.advance(RunState::Created);
+        self.clock.reset();
+        // hypervisor never writes to frames shared with other guests
+        let mut idx = 0;
+        while let Some(gppn) = self.ram_gppn(idx) {
+            let protected = self.gstage_pte(gppn).map_or(false, |pte| !pte.writable());
+            if protected && self.handle_cow_fault(gppn.0 * PAGE_SIZE) == CowFault::OutOfMemory {
+                return Err(LoadError::OutOfHostMemory { size: PAGE_SIZE });
+            }
+            idx += 1;
+        }
+        let banks = self.banks.clone();
+        for ((_, mem), bank) in self.guest_ram().banks.iter_mut().zip(banks.iter()) {
+            if bank.kind == MemoryKind::Ram {
+                mem.fill(0);
+            }
+        }
+        self.address_space.release_all();
+        reserve_banks(&mut self.address_space, &banks);
+
+        let token = self.address_space.token();
+        for (vcpu_id, vcpu) in self.vcpus.iter_mut().enumerate() {
+            let affinity = vcpu.affinity();
+            *vcpu = VCpu::new(
+                TrapContext::init_context(
+                    banks[0].gpa,
+                    self.resources.hart_stack_top(vcpu_id),
+                    token,
+                    vm_exit as usize,
+                ),
+                self.vector,
+            );
+            vcpu.set_affinity(affinity);
+            let file = self
+                .devices
+                .imsic
+                .as_ref()
+                .and_then(|imsic| imsic.guest_file(vcpu_id));
+            vcpu.set_guest_file(file);
+            vcpu.discard_guest_file();
+        }
+        self.vcpus[0].start();
+        self.devices.reset();
+        self.dtb_gpa = None;
+        self.initrd = None;
+        // images are measured again as they are loaded,after entries of earlier boots
+        self.measure(MeasureEvent::Reset, &[])?;
+        self.boot()
+    }
+
+    #[inline]
+    pub fn state(&self) -> RunState {
+        self.state
+    }
+
+    /// guest stops for good as halted or crashed,vcpus still on harts leave them at next exit
+    fn shut(&mut self, state: RunState) -> Result<(), StateError> {
+        self.state.transition(state)?;
+        self.stop_request = None;
+        for vcpu in self.vcpus.iter_mut() {
+            vcpu.halt();
+        }
+        Ok(())
+    }
+
+    /// guest stops for good,its running vcpus are kicked off their harts
+    pub fn halt(&mut self) -> Result<(), StateError> {
+        self.shut(RunState::Halted)?;
+        self.kick_running_vcpus();
+        Ok(())
+    }
+
+    /// halted or crashed
+    #[inline]
+    pub fn is_halted(&self) -> bool {
+        self.state.is_stopped()
+    }
+
+    /// freeze guest,running vcpus are kicked off their harts and stay off until guest is resumed
+    ///
+    /// guest is frozen once `running_harts` is empty,`pause_guest` waits for it
+    pub fn pause(&mut self) -> Result<(), StateError> {
+        self.state.transition(RunState::Paused)?;
+        self.clock.pause();
+        for vcpu in self.vcpus.iter_mut() {
+            if vcpu.state().can_become(RunState::Paused) {
+                vcpu.pause()?;
+            }
+        }
+        self.kick_running_vcpus();
+        Ok(())
+    }
+
+    /// paused guest goes on where it stopped
+    pub fn resume(&mut self) -> Result<(), StateError> {
+        self.state.transition(RunState::Runnable)?;
+        self.clock.resume();
+        for vcpu in self.vcpus.iter_mut() {
+            if vcpu.state() == RunState::Paused {
+                vcpu.resume()?;
+            }
+        }
+        self.queue_runnable_vcpus();
+        Ok(())
+    }
+
+    /// make vcpus on harts exit,they leave if they may not run any more
+    fn kick_running_vcpus(&self) {
+        for hart in self.running_harts() {
+            kick(hart);
+        }
+    }
+
+    /// vcpu of ctx took a trap hypervisor can't handle
+    pub fn crash_vcpu(&mut self, ctx: *mut TrapContext) {
+        if let Some(vcpu) = self.vcpu_of_ctx(ctx) {
+            vcpu.crash();
+        }
+    }
+
+    /// ask guest to stop for reason,return false if it's stopping or halted already
+    pub fn request_stop(&mut self, reason: StopReason) -> bool {
+        if !self.may_run() {
+            return false;
         }
+        self.stop_request = Some(reason);
+        true
     }
 
-    pub fn load_guest_image(&mut self, guest_data: &[u8]) {
-        unsafe {
-            let dst = core::slice::from_raw_parts_mut(
-                self.resources.get_start_ptr(),
-                self.resources.get_mem_size(),
+    /// restart or halt guest as its restart policy says,once no vcpu of it is on a hart
+    pub fn finish_stop(&mut self) {
+        let reason = match self.stop_request {
+            Some(reason) if self.running_harts().is_empty() => reason,
+            _ => return,
+        };
+        let stopped = match reason {
+            StopReason::Crash => RunState::Crashed,
+            _ => RunState::Halted,
+        };
+        // stop is only requested of a runnable guest,which may be paused since
+        let _ = self.shut(stopped);
+        if self.should_restart(reason) {
+            println!(
+                "[hypervisor] guest {} {:?},restart it",
+                self.guest_id, reason
             );
+            if let Err(err) = self.reset() {
+                println!(
+                    "[hypervisor] fail to restart guest {}: {:?}",
+                    self.guest_id, err
+                );
+                let _ = self.shut(RunState::Halted);
+            }
+        } else {
+            println!("[hypervisor] guest {} {:?},halt it", self.guest_id, reason);
+        }
+    }
+
+    /// vcpus may run,guest is runnable and not stopping
+    #[inline]
+    pub fn may_run(&self) -> bool {
+        self.state == RunState::Runnable && self.stop_request.is_none()
+    }
+
+    /// put runnable vcpus not queued yet on run queues,unless guest is over its cap
+    pub fn queue_runnable_vcpus(&mut self) {
+        if !self.may_run() || self.share.is_capped(schedule::period()) {
+            return;
+        }
+        for (vcpu_id, vcpu) in self.vcpus.iter_mut().enumerate() {
+            if vcpu.is_runnable() && !vcpu.is_queued() {
+                vcpu.set_queued(true);
+                let vcpu_ref = VcpuRef {
+                    guest_id: self.guest_id,
+                    vcpu_id,
+                };
+                schedule::enqueue(vcpu_ref, vcpu.account().is_under(), vcpu.placement());
+            }
+        }
+    }
+
+    /// mark vcpu popped from run queue running on hart,none if it can't run any more
+    ///
+    /// vcpus of a guest over its cap are parked,they queue again once credits are handed out
+    pub fn take_vcpu(&mut self, vcpu_id: usize, hart: usize) -> Option<*mut TrapContext> {
+        let may_run = self.may_run() && !self.share.is_capped(schedule::period());
+        let vcpu = self.vcpus.get_mut(vcpu_id)?;
+        // a stale entry,vcpu was queued again or reset since
+        if !vcpu.is_queued() {
+            return None;
+        }
+        if !may_run || !vcpu.is_runnable() {
+            vcpu.set_queued(false);
+            return None;
+        }
+        // affinity changed since it was queued
+        if !vcpu.allows(hart) {
+            vcpu.set_queued(false);
+            self.queue_runnable_vcpus();
+            return None;
+        }
+        vcpu.set_time_delta(self.clock.delta());
+        self.reacquire_guest_file(vcpu_id, hart);
+        self.sync_external_irq(vcpu_id);
+        let vcpu = &mut self.vcpus[vcpu_id];
+        vcpu.deliver_irqs();
+        vcpu.run_on(hart);
+        let timer = vcpu
+            .sbi_timer()
+            .map_or(usize::MAX, |deadline| self.clock.to_host(deadline));
+        schedule::set_vcpu_timer(timer);
+        Some(vcpu.get_ctx_ptr())
+    }
+
+    /// restrict vcpu to harts of affinity,return false if there is no such vcpu
+    ///
+    /// a running vcpu is kicked off a hart left out and moves at its next exit. a vcpu off harts gets
+    /// vs stage tlb of the hart it left fenced,and queues again if it waits on a wrong hart
+    pub fn set_affinity(&mut self, vcpu_id: usize, affinity: usize) -> bool {
+        let vcpu = match self.vcpus.get_mut(vcpu_id) {
+            Some(vcpu) => vcpu,
+            None => return false,
+        };
+        vcpu.set_affinity(affinity);
+        // vcpu stays on hart of its guest file until it leaves there once more
+        if vcpu
+            .guest_file()
+            .map_or(false, |file| affinity & 1 << file.hart == 0)
+        {
+            vcpu.move_guest_file();
+            if let Some(hart) = vcpu.hart() {
+                kick(hart);
+            }
+        }
+        match vcpu.hart() {
+            Some(hart) if !vcpu.allows(hart) => kick(hart),
+            Some(_) => {}
+            None => {
+                if let Some(last) = vcpu.last_hart().filter(|&last| !vcpu.allows(last)) {
+                    sbi_remote_hfence_vvma(1 << last, 0);
+                }
+                if vcpu.is_queued() {
+                    vcpu.set_queued(false);
+                    self.queue_runnable_vcpus();
+                }
+            }
+        }
+        true
+    }
+
+    /// vcpu of ctx left its hart,it queues again if it's still runnable
+    ///
+    /// a vcpu moving its guest file gives it up here,we are still on hart of the file
+    pub fn release_vcpu(&mut self, ctx: *mut TrapContext) {
+        if let Some(vcpu_id) = self.vcpu_id_of(ctx) {
+            let ran = self.vcpus[vcpu_id].release();
+            if self.vcpus[vcpu_id].is_moving_file() {
+                self.drop_guest_file(vcpu_id);
+            }
+            self.share.add_used(ran);
+            self.queue_runnable_vcpus();
+        }
+    }
+
+    /// timer of vcpu reached its deadline
+    fn vcpu_timer_due(&self, vcpu_id: usize) -> bool {
+        let now = self.clock.now();
+        self.vcpus[vcpu_id]
+            .timer_deadline()
+            .map_or(false, |deadline| deadline <= now)
+    }
+
+    /// external interrupt of imsic file of vcpu is asserted,a guest file is only seen from its hart
+    fn imsic_pending(&self, vcpu_id: usize) -> bool {
+        let imsic = match self.devices.imsic.as_ref() {
+            Some(imsic) => imsic,
+            None => return false,
+        };
+        match imsic.guest_file(vcpu_id) {
+            Some(file) => {
+                file.hart == percpu::hart_id() && pending_guest_irqs() & 1 << file.index != 0
+            }
+            None => imsic.software_pending(vcpu_id),
+        }
+    }
+
+    /// interrupt is pending for vcpu,virtual one from hypervisor,its timer or external one on
+    /// irqchip or imsic
+    fn vcpu_has_interrupt(&self, vcpu_id: usize) -> bool {
+        self.vcpus[vcpu_id].has_virtual_irq()
+            || self.vcpu_timer_due(vcpu_id)
+            || self.devices.irqchip.vcpu_pending(vcpu_id)
+            || self.imsic_pending(vcpu_id)
+    }
+
+    /// external interrupt of vcpu follows irqchip delivering to it directly and its emulated imsic
+    /// file,a guest file raises it by itself
+    fn sync_external_irq(&mut self, vcpu_id: usize) {
+        let pending = self.devices.irqchip.vcpu_pending(vcpu_id)
+            || self
+                .devices
+                .imsic
+                .as_ref()
+                .map_or(false, |imsic| imsic.software_pending(vcpu_id));
+        self.vcpus[vcpu_id].set_external_pending(pending);
+    }
+
+    /// vcpu of ctx is about to enter guest again,interrupts it sees are brought up to date
+    pub fn sync_vcpu_irqs(&mut self, ctx: *mut TrapContext) {
+        if let Some(vcpu_id) = self.vcpu_id_of(ctx) {
+            self.sync_external_irq(vcpu_id);
+            self.vcpus[vcpu_id].deliver_irqs();
+        }
+    }
+
+    /// irq is pending for vcpu until cleared,return false if there is no such vcpu
+    ///
+    /// a vcpu running on this hart sees it at its next entry,one running on another hart is kicked
+    /// to exit and pick it up. a blocked vcpu wakes
+    pub fn inject_irq(&mut self, vcpu_id: usize, irq: VirtIrq) -> bool {
+        let vcpu = match self.vcpus.get_mut(vcpu_id) {
+            Some(vcpu) => vcpu,
+            None => return false,
+        };
+        vcpu.inject_irq(irq);
+        match vcpu.hart() {
+            Some(hart) if hart == percpu::hart_id() => vcpu.deliver_irqs(),
+            Some(hart) => kick(hart),
+            None => {
+                vcpu.wake();
+                self.queue_runnable_vcpus();
+            }
+        }
+        true
+    }
+
+    /// irq injected before is no longer pending for vcpu,return false if there is no such vcpu
+    pub fn clear_irq(&mut self, vcpu_id: usize, irq: VirtIrq) -> bool {
+        let vcpu = match self.vcpus.get_mut(vcpu_id) {
+            Some(vcpu) => vcpu,
+            None => return false,
+        };
+        vcpu.clear_irq(irq);
+        match vcpu.hart() {
+            Some(hart) if hart == percpu::hart_id() => vcpu.deliver_irqs(),
+            Some(hart) => kick(hart),
+            None => {}
+        }
+        true
+    }
+
+    /// vcpus an msi landed in emulated imsic file of notice it,a running one is kicked to exit and
+    /// a blocked one wakes
+    fn deliver_msis(&mut self) {
+        let notified = match self.devices.imsic.as_mut() {
+            Some(imsic) => imsic.take_notified(),
+            None => return,
+        };
+        let this = percpu::hart_id();
+        for vcpu_id in (0..self.vcpus.len()).filter(|vcpu_id| notified & 1 << vcpu_id != 0) {
+            match self.vcpus[vcpu_id].hart() {
+                Some(hart) if hart != this => kick(hart),
+                _ => {}
+            }
+            if self.vcpus[vcpu_id].state() == RunState::Blocked && self.imsic_pending(vcpu_id) {
+                self.vcpus[vcpu_id].wake();
+            }
+        }
+        self.queue_runnable_vcpus();
+    }
+
+    /// supervisor guest external interrupt on hart for guest files of mask,their blocked vcpus
+    /// wake
+    pub fn wake_guest_files(&mut self, hart: usize, mask: usize) {
+        for vcpu in self.vcpus.iter_mut() {
+            let raised = vcpu.guest_file().map_or(false, |file| {
+                file.hart == hart && mask & 1 << file.index != 0
+            });
+            if raised && vcpu.state() == RunState::Blocked {
+                vcpu.wake();
+            }
+        }
+        self.queue_runnable_vcpus();
+    }
+
+    /// emulate sireg or stopei access of vcpu of ctx to its emulated imsic file,return false if it's
+    /// not one
+    pub fn emulate_imsic_csr(&mut self, ctx: *mut TrapContext, insn: usize) -> bool {
+        let csr = match decode_csr_insn(insn) {
+            Some(csr) => csr,
+            None => return false,
+        };
+        let vcpu_id = match self.vcpu_id_of(ctx) {
+            Some(vcpu_id) => vcpu_id,
+            None => return false,
+        };
+        let file = match self
+            .devices
+            .imsic
+            .as_mut()
+            .and_then(|imsic| imsic.software(vcpu_id))
+        {
+            Some(file) => file,
+            None => return false,
+        };
+        let ctx = unsafe { &mut *ctx };
+        let operand = if csr.imm { csr.src } else { ctx.regs[csr.src] };
+        let old = match csr.csr {
+            CSR_SIREG => {
+                let select = read_vsiselect();
+                let old = match file.read(select) {
+                    Some(old) => old,
+                    None => return false,
+                };
+                if let Some(value) = csr.new_value(old, operand) {
+                    file.write(select, value);
+                }
+                old
+            }
+            // interrupt id in both halves,priority is the id
+            CSR_STOPEI => {
+                let old = file.top().map_or(0, |id| id << 16 | id);
+                if csr.new_value(old, operand).is_some() {
+                    file.claim();
+                }
+                old
+            }
+            _ => return false,
+        };
+        if csr.rd != 0 {
+            ctx.regs[csr.rd] = old;
+        }
+        // csr instructions are never compressed
+        ctx.sepc += 4;
+        true
+    }
+
+    /// guest set timer of vcpu of ctx to guest time deadline,hypervisor fires it unless hart has
+    /// sstc
+    pub fn set_vcpu_timer(&mut self, ctx: *mut TrapContext, deadline: usize) {
+        let host_deadline = self.clock.to_host(deadline);
+        if let Some(vcpu) = self.vcpu_of_ctx(ctx) {
+            vcpu.set_timer(deadline);
+            if vcpu.sbi_timer().is_some() {
+                schedule::set_vcpu_timer(host_deadline);
+            }
+        }
+    }
+
+    /// fire sbi timer of running vcpu of ctx if it's due
+    pub fn check_vcpu_timer(&mut self, ctx: *mut TrapContext) {
+        let now = self.clock.now();
+        if let Some(vcpu) = self.vcpu_of_ctx(ctx) {
+            if vcpu.sbi_timer().map_or(false, |deadline| deadline <= now) {
+                vcpu.fire_timer();
+                schedule::set_vcpu_timer(usize::MAX);
+            }
+        }
+    }
+
+    /// vcpu of ctx executed wfi,block it unless an interrupt is pending already
+    ///
+    /// return true if it should leave its hart,it's blocked or was paused or stopped meanwhile
+    pub fn block_vcpu(&mut self, ctx: *mut TrapContext) -> bool {
+        let vcpu_id = match self.vcpu_id_of(ctx) {
+            Some(vcpu_id) => vcpu_id,
+            None => return false,
+        };
+        if self.vcpus[vcpu_id].state() != RunState::Running {
+            return true;
+        }
+        self.vcpus[vcpu_id].sync_timer();
+        if self.vcpu_has_interrupt(vcpu_id) {
+            self.fire_due_timer(vcpu_id);
+            return false;
+        }
+        self.vcpus[vcpu_id].block();
+        self.wake_at_timer(vcpu_id);
+        true
+    }
+
+    /// sbi timer interrupt of vcpu becomes pending once it's due
+    fn fire_due_timer(&mut self, vcpu_id: usize) {
+        if self.vcpu_timer_due(vcpu_id) {
+            self.vcpus[vcpu_id].fire_timer();
+        }
+    }
+
+    /// some hart wakes blocked vcpu when its timer fires
+    fn wake_at_timer(&self, vcpu_id: usize) {
+        if let Some(deadline) = self.vcpus[vcpu_id].timer_deadline() {
+            schedule::wake_at(self.clock.to_host(deadline));
+        }
+    }
+
+    /// interrupt of host plic came for a passthrough device of guest,return false if it has none
+    ///
+    /// blocked vcpus it's pending for wake,running ones on other harts are kicked to see it
+    pub fn forward_host_irq(&mut self, host_irq: u32) -> bool {
+        if !self.devices.forward_irq(host_irq) {
+            return false;
+        }
+        self.wake_vcpus();
+        let this = percpu::hart_id();
+        for (vcpu_id, vcpu) in self.vcpus.iter().enumerate() {
+            match vcpu.hart() {
+                Some(hart) if hart != this && self.devices.irqchip.vcpu_pending(vcpu_id) => {
+                    kick(hart)
+                }
+                _ => {}
+            }
+        }
+        true
+    }
+
+    /// wake blocked vcpus an interrupt is pending for and queue them,device lines are synced first
+    ///
+    /// timers of vcpus still blocked are registered again,see `schedule::clear_wakeups`
+    pub fn wake_vcpus(&mut self) {
+        self.devices.sync_irqs();
+        self.deliver_msis();
+        for vcpu_id in 0..self.vcpus.len() {
+            if self.vcpus[vcpu_id].state() != RunState::Blocked {
+                continue;
+            }
+            if self.vcpu_has_interrupt(vcpu_id) {
+                self.fire_due_timer(vcpu_id);
+                self.vcpus[vcpu_id].wake();
+            } else {
+                self.wake_at_timer(vcpu_id);
+            }
+        }
+        self.queue_runnable_vcpus();
+    }
+
+    /// burn credits of running vcpu of ctx,return true if guest went over its cap
+    pub fn charge_vcpu(&mut self, ctx: *mut TrapContext) -> bool {
+        if let Some(vcpu) = self.vcpu_of_ctx(ctx) {
+            let ran = vcpu.charge();
+            self.share.add_used(ran);
+        }
+        self.share.is_capped(schedule::period())
+    }
+
+    /// guest wants cpu time,some vcpu of it is started
+    pub fn is_active(&self) -> bool {
+        self.may_run()
+            && self
+                .vcpus
+                .iter()
+                .any(|vcpu| vcpu.hsm_state() == HsmState::Started)
+    }
+
+    #[inline]
+    pub fn share(&self) -> &GuestShare {
+        &self.share
+    }
+
+    /// credits of a new period out of total,split evenly between started vcpus
+    pub fn refill_credits(&mut self, total: usize, total_weight: usize) {
+        let credits = self.share.credits(total, total_weight, schedule::period());
+        let started = self
+            .vcpus
+            .iter()
+            .filter(|vcpu| vcpu.hsm_state() == HsmState::Started)
+            .count();
+        for vcpu in self
+            .vcpus
+            .iter_mut()
+            .filter(|vcpu| vcpu.hsm_state() == HsmState::Started)
+        {
+            vcpu.refill(credits / started);
+        }
+    }
+
+    pub fn vcpus(&self) -> &[VCpu] {
+        &self.vcpus
+    }
+
+    /// ticks of time csr a vcpu runs before others waiting for its hart get a turn
+    #[inline]
+    pub fn time_slice(&self) -> usize {
+        self.time_slice
+    }
+
+    /// harts running vcpus of this guest
+    pub fn running_harts(&self) -> Vec<usize> {
+        self.vcpus.iter().filter_map(|vcpu| vcpu.hart()).collect()
+    }
+
+    fn vcpu_id_of(&self, ctx: *mut TrapContext) -> Option<usize> {
+        self.vcpus.iter().position(|vcpu| vcpu.get_ctx_ptr() == ctx)
+    }
+
+    pub fn vcpu_of_ctx(&mut self, ctx: *mut TrapContext) -> Option<&mut VCpu> {
+        self.vcpus.iter_mut().find(|vcpu| vcpu.get_ctx_ptr() == ctx)
+    }
+
+    /// vcpus of targets fence as sbi rfence asks,return false if host sbi fails to reach harts
+    ///
+    /// vcpus on harts are fenced there now,they can't leave while guest queue is locked. others do
+    /// it when they run next,vs stage tlb is flushed anyway if they run on another hart
+    pub fn remote_fence(&mut self, targets: &[usize], fence: Fence) -> bool {
+        let mut harts = 0;
+        for &vcpu_id in targets {
+            let vcpu = &mut self.vcpus[vcpu_id];
+            match vcpu.hart() {
+                Some(hart) => harts |= 1 << hart,
+                None => vcpu.fence_on_entry(fence),
+            }
+        }
+        if harts == 0 {
+            return true;
+        }
+        let ret = match fence {
+            Fence::Tlb => sbi_remote_hfence_vvma(harts, 0),
+            Fence::Icache => sbi_remote_fence_i(harts, 0),
+        };
+        ret == SBI_SUCCESS
+    }
+
+    pub fn vcpu_hsm_state(&self, vcpu_id: usize) -> Option<HsmState> {
+        self.vcpus.get(vcpu_id).map(|vcpu| vcpu.hsm_state())
+    }
+
+    /// start stopped vcpu at entry as sbi hsm says: a0 = hartid,a1 = opaque,bare vsatp
+    pub fn start_vcpu(&mut self, vcpu_id: usize, entry: usize, opaque: usize) {
+        let mut vcpu = VCpu::new(
+            TrapContext::init_context(
+                entry,
+                self.resources.hart_stack_top(vcpu_id),
+                self.address_space.token(),
+                vm_exit as usize,
+            ),
+            self.vector,
+        );
+        vcpu.set_args(&[vcpu_id, opaque]);
+        vcpu.set_guest_file(self.guest_file_of(vcpu_id));
+        vcpu.start();
+        vcpu.set_affinity(self.vcpus[vcpu_id].affinity());
+        self.vcpus[vcpu_id] = vcpu;
+    }
+
+    /// guest memory banks viewed from hypervisor address space
+    pub fn guest_ram(&mut self) -> GuestRam {
+        let banks = self
+            .address_space
+            .regions
+            .iter()
+            .zip(self.resources.normal_mem.iter())
+            .map(|(guest, host)| unsafe {
+                (
+                    guest.start_vpn().page_base_va().0,
+                    core::slice::from_raw_parts_mut(
+                        host.start_vpn().page_base_va().0 as *mut u8,
+                        host.get_size(),
+                    ),
+                )
+            })
+            .collect();
+        GuestRam { banks }
+    }
+
+    /// load flat binary,elf or linux image,boot vcpu starts at its entry. image must be in format
+    /// kind if it's given
+    ///
+    /// follow riscv boot protocol: a0 = hartid,a1 = gpa of device tree
+    pub fn load_guest_image(
+        &mut self,
+        guest_data: &[u8],
+        kind: Option<ImageKind>,
+    ) -> Result<LoadedImage, LoadError> {
+        let loaded = load_image(&mut self.guest_ram(), guest_data, kind)?;
+        if !self
+            .address_space
+            .reserve(loaded.start, loaded.end - loaded.start)
+        {
+            return Err(LoadError::OutOfGuestMemory {
+                start: loaded.start,
+                end: loaded.end,
+            });
+        }
+        let boot_vcpu = &mut self.vcpus[0];
+        boot_vcpu.set_entry(loaded.entry);
+        boot_vcpu.set_args(&[0, self.dtb_gpa.unwrap_or(0)]);
+        Ok(loaded)
+    }
 
-            dst[..guest_data.len()].copy_from_slice(guest_data)
+    /// device tree describing memory,vcpus and emulated devices of this guest
+    pub fn generate_device_tree(&self, bootargs: &str) -> Vec<u8> {
+        let memory: Vec<(usize, usize)> = self
+            .banks
+            .iter()
+            .map(|bank| (bank.gpa, bank.size))
+            .collect();
+        let reserved: Vec<(usize, usize)> = self
+            .banks
+            .iter()
+            .filter(|bank| bank.kind == MemoryKind::Reserved)
+            .map(|bank| (bank.gpa, bank.size))
+            .collect();
+        build_guest_fdt(&GuestFdtInfo {
+            memory: &memory,
+            reserved: &reserved,
+            vcpu_nums: self.vcpus.len(),
+            devices: &self.devices,
+            bootargs,
+            initrd: self.initrd,
+            passthrough: &self.passthrough,
+            vector: self.vector,
+        })
+    }
+
+    /// load initrd to page aligned free guest ram above kernel_end,compressed initrd is decompressed
+    /// once straight into it
+    ///
+    /// a compressed initrd whose size its header doesn't record goes to the largest free gap and
+    /// takes what decoding produced
+    pub fn load_initrd(
+        &mut self,
+        initrd: &[u8],
+        kernel_end: usize,
+    ) -> Result<(usize, usize), LoadError> {
+        let compression = Compression::detect(initrd);
+        let recorded = match compression {
+            Some(compression) => compression.recorded_size(initrd),
+            None => Some(initrd.len()),
+        };
+        let (start, room) = match recorded {
+            Some(size) => {
+                let start = self
+                    .address_space
+                    .find_free(size, PAGE_SIZE, kernel_end)
+                    .ok_or(LoadError::NoSpace { size })?;
+                (start, size)
+            }
+            None => self
+                .address_space
+                .largest_free(PAGE_SIZE, kernel_end)
+                .ok_or(LoadError::NoSpace { size: initrd.len() })?,
+        };
+        let mut ram = self.guest_ram();
+        let dst = ram.slice_mut(start, room)?;
+        let size = match compression {
+            Some(compression) => {
+                let size = compression.decompress_into(initrd, start, dst)?;
+                // size in header is not trusted
+                if recorded.map_or(false, |recorded| recorded != size) {
+                    return Err(LoadError::BadCompressedImage);
+                }
+                size
+            }
+            None => {
+                dst.copy_from_slice(initrd);
+                initrd.len()
+            }
+        };
+        if !self.address_space.reserve(start, size) {
+            return Err(LoadError::NoSpace { size });
+        }
+        self.initrd = Some((start, start + size));
+        println!(
+            "[hypervisor] load initrd at [{:#x},{:#x})",
+            start,
+            start + size
+        );
+        Ok((start, start + size))
+    }
+
+    /// place device tree at the top of guest ram,or in any free gap if that's taken,and pass it
+    /// to boot vcpu
+    pub fn place_device_tree(&mut self, dtb: &[u8]) -> Result<usize, LoadError> {
+        let err = LoadError::NoSpace { size: dtb.len() };
+        let end = self.guest_ram().end_gpa();
+        let top = end.saturating_sub(dtb.len()) / DTB_ALIGN * DTB_ALIGN;
+        let gpa = if self.address_space.reserve(top, dtb.len()) {
+            top
+        } else {
+            let gpa = self
+                .address_space
+                .find_free(dtb.len(), DTB_ALIGN, 0)
+                .ok_or(err)?;
+            if !self.address_space.reserve(gpa, dtb.len()) {
+                return Err(err);
+            }
+            gpa
+        };
+        self.guest_ram()
+            .slice_mut(gpa, dtb.len())?
+            .copy_from_slice(dtb);
+        self.dtb_gpa = Some(gpa);
+        self.vcpus[0].set_args(&[0, gpa]);
+        Ok(gpa)
+    }
+
+    /// append digest of data to measurement log,refuse image not in allowlist
+    pub fn measure(&mut self, event: MeasureEvent, data: &[u8]) -> Result<(), LoadError> {
+        let digest = self.measurements.extend(event, data);
+        println!(
+            "[hypervisor] guest {} measure {:?} {:?}",
+            self.guest_id, event, digest
+        );
+        if event.is_external() && !is_allowed(&digest) {
+            return Err(LoadError::NotAllowed(event));
+        }
+        Ok(())
+    }
+
+    #[inline]
+    pub fn measurements(&self) -> &[Measurement] {
+        self.measurements.entries()
+    }
+
+    /// measure and load kernel,initrd and device tree,then give boot vcpu a stack
+    ///
+    /// device tree is generated after initrd is loaded,so it can record where initrd is
+    pub fn load_boot_images(&mut self, images: &BootImages) -> Result<(), LoadError> {
+        self.measure(MeasureEvent::Kernel, images.kernel)?;
+        let kernel = self.load_guest_image(images.kernel, images.kernel_kind)?;
+        if let Some(initrd) = images.initrd {
+            self.measure(MeasureEvent::Initrd, initrd)?;
+            self.load_initrd(initrd, kernel.end)?;
+        }
+        match images.dtb {
+            Some(dtb) => {
+                self.measure(MeasureEvent::Dtb, dtb)?;
+                self.place_device_tree(dtb)?
+            }
+            None => {
+                let dtb = self.generate_device_tree(images.bootargs);
+                self.measure(MeasureEvent::GeneratedDtb, &dtb)?;
+                self.place_device_tree(&dtb)?
+            }
+        };
+        self.setup_boot_stack()?;
+        Ok(())
+    }
+
+    /// reserve boot stack right below device tree for boot vcpu,or in any free gap if that's taken
+    pub fn setup_boot_stack(&mut self) -> Result<usize, LoadError> {
+        let err = LoadError::NoSpace {
+            size: GUEST_BOOT_STACK_SIZE,
+        };
+        let top = self.dtb_gpa.unwrap_or(self.guest_ram().end_gpa());
+        let top = match top.checked_sub(GUEST_BOOT_STACK_SIZE) {
+            Some(bottom) if self.address_space.reserve(bottom, GUEST_BOOT_STACK_SIZE) => top,
+            _ => {
+                let bottom = self
+                    .address_space
+                    .find_free(GUEST_BOOT_STACK_SIZE, PAGE_SIZE, 0)
+                    .ok_or(err)?;
+                if !self.address_space.reserve(bottom, GUEST_BOOT_STACK_SIZE) {
+                    return Err(err);
+                }
+                bottom + GUEST_BOOT_STACK_SIZE
+            }
+        };
+        self.vcpus[0].set_sp(top);
+        Ok(top)
+    }
+
+    /// emulate load/store of vcpu on emulated device,return false if no device covers gpa
+    pub fn emulate_mmio(&mut self, ctx: &mut TrapContext, gpa: usize, insn: MmioInsn) -> bool {
+        let dev = match self.devices.find(gpa) {
+            Some(dev) => dev,
+            None => return false,
+        };
+        let offset = gpa - dev.base();
+        let mask = if insn.width == 8 {
+            usize::MAX
+        } else {
+            (1 << (insn.width * 8)) - 1
+        };
+        if insn.is_store {
+            dev.write(offset, insn.width, ctx.regs[insn.reg] & mask);
+        } else {
+            let value = dev.read(offset, insn.width) & mask;
+            let shift = 64 - insn.width * 8;
+            let value = if insn.signed {
+                ((value << shift) as isize >> shift) as usize
+            } else {
+                value
+            };
+            if insn.reg != 0 {
+                ctx.regs[insn.reg] = value;
+            }
         }
+        ctx.sepc += insn.len;
+        self.devices.sync_irqs();
+        self.deliver_msis();
+        true
+    }
+
+    /// start boot vcpu at entry instead of where image says
+    pub fn set_boot_entry(&mut self, entry: usize) {
+        self.vcpus[0].set_entry(entry);
     }
 
     pub fn vcpu_ctx_ptr(&mut self, vcpu_id: usize) -> *mut TrapContext {
         self.vcpus[vcpu_id].get_ctx_ptr()
     }
 
+    /// whether ctx is the context of one of our vcpus
+    pub fn owns_ctx(&mut self, ctx: *mut TrapContext) -> bool {
+        self.vcpus.iter_mut().any(|vcpu| vcpu.get_ctx_ptr() == ctx)
+    }
+
+    /// idx-th guest physical page backed by memory banks
+    pub fn ram_gppn(&self, idx: usize) -> Option<PhysPageNum> {
+        let mut idx = idx;
+        for region in self.address_space.regions.iter() {
+            if idx < region.page_nums {
+                return Some(PhysPageNum(region.start_vpn().0 + idx));
+            }
+            idx -= region.page_nums;
+        }
+        None
+    }
+
+    /// bank holding gppn and vpn in hypervisor address space mapped to the same frame
+    #[inline]
+    fn gppn_to_hvpn(&self, gppn: PhysPageNum) -> Option<(usize, VirtPageNum)> {
+        let bank = self
+            .address_space
+            .regions
+            .iter()
+            .position(|region| region.start_vpn().0 <= gppn.0 && gppn.0 < region.end_vpn().0)?;
+        let offset = gppn.0 - self.address_space.regions[bank].start_vpn().0;
+        Some((
+            bank,
+            VirtPageNum(self.resources.normal_mem[bank].start_vpn().0 + offset),
+        ))
+    }
+
+    /// frame backing guest physical page
+    pub fn ram_frame(&self, gppn: PhysPageNum) -> Option<&Arc<FrameTracker>> {
+        let (bank, hvpn) = self.gppn_to_hvpn(gppn)?;
+        self.resources.normal_mem[bank].frame(hvpn)
+    }
+
+    fn gstage_pte(&self, gppn: PhysPageNum) -> Option<&mut PageTableEntry> {
+        self.address_space
+            .page_table
+            .find_pte(VirtPageNum(gppn.0))
+            .filter(|pte| pte.is_valid())
+    }
+
+    /// clear W of guest physical page in g stage, next store will trap to hypervisor
+    ///
+    /// return false if page was not writable
+    pub fn protect_page(&mut self, gppn: PhysPageNum) -> bool {
+        match self.gstage_pte(gppn) {
+            Some(pte) if pte.writable() => {
+                pte.set_flags(pte.pte_flags() - PTEFlags::W);
+                flush_guest_tlb_all();
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// back guest physical page with a frame shared read only between guests
+    ///
+    /// old frame is released when it has no other owner
+    pub fn share_page(&mut self, gppn: PhysPageNum, frame: Arc<FrameTracker>) {
+        let (bank, hvpn) = self.gppn_to_hvpn(gppn).unwrap();
+        let pte = self.gstage_pte(gppn).unwrap();
+        *pte = PageTableEntry::new(
+            frame.ppn,
+            PTEFlags::R | PTEFlags::X | PTEFlags::V | PTEFlags::U,
+        );
+        flush_guest_tlb_all();
+        // hypervisor should never write to shared frame
+        hpm_guard().remap_page(hvpn, frame.ppn, MapPermission::R);
+        self.resources.normal_mem[bank].replace_frame(hvpn, frame);
+    }
+
+    /// handle store guest page fault on write protected normal memory
+    ///
+    /// copy the page if it's still shared with others,or just make it writable again. page stays
+    /// shared if there is no frame to copy it to
+    pub fn handle_cow_fault(&mut self, gpa: usize) -> CowFault {
+        let gppn = PhysAddress(gpa).current_page_number();
+        let (bank, hvpn) = match self.gppn_to_hvpn(gppn) {
+            Some(page) => page,
+            None => return CowFault::NotCow,
+        };
+        let shared = match (self.ram_frame(gppn), self.gstage_pte(gppn)) {
+            (Some(frame), Some(pte)) if !pte.writable() => Arc::strong_count(frame) > 1,
+            _ => return CowFault::NotCow,
+        };
+
+        let ppn = if shared {
+            let frame = match frame_alloc() {
+                Some(frame) => frame,
+                None => return CowFault::OutOfMemory,
+            };
+            let old_ppn = self.ram_frame(gppn).unwrap().ppn;
+            frame
+                .ppn
+                .get_bytes_array()
+                .copy_from_slice(old_ppn.get_bytes_array());
+            let ppn = frame.ppn;
+            self.resources.normal_mem[bank].replace_frame(hvpn, Arc::new(frame));
+            ppn
+        } else {
+            self.ram_frame(gppn).unwrap().ppn
+        };
+
+        *self.gstage_pte(gppn).unwrap() = PageTableEntry::new(
+            ppn,
+            PTEFlags::R | PTEFlags::W | PTEFlags::X | PTEFlags::V | PTEFlags::U,
+        );
+        flush_guest_tlb_all();
+        hpm_guard().remap_page(hvpn, ppn, MapPermission::R | MapPermission::W);
+        CowFault::Handled
+    }
+
+    /// copy data to guest physical memory,return false if range isn't inside one memory bank or
+    /// host is out of frames
+    ///
+    /// sharing of pages in range is broken first,hypervisor never writes to shared frames
+    pub fn write_guest_memory(&mut self, gpa: usize, data: &[u8]) -> bool {
+        if self.guest_ram().slice_mut(gpa, data.len()).is_err() {
+            return false;
+        }
+        let start = PhysAddress(gpa).current_page_number();
+        let end = PhysAddress(gpa + data.len()).next_page_number();
+        for gppn in start.0..end.0 {
+            let protected = self
+                .gstage_pte(PhysPageNum(gppn))
+                .map_or(false, |pte| !pte.writable());
+            if protected && self.handle_cow_fault(gppn * PAGE_SIZE) == CowFault::OutOfMemory {
+                return false;
+            }
+        }
+        self.guest_ram()
+            .slice_mut(gpa, data.len())
+            .unwrap()
+            .copy_from_slice(data);
+        true
+    }
+
+    /// (shared pages,saved bytes) of memory banks
+    ///
+    /// a frame shared by n owners saves (n - 1) / n page for each of them
+    pub fn merge_stats(&self) -> (usize, usize) {
+        self.resources
+            .normal_mem
+            .iter()
+            .flat_map(|region| region.data_frames.values())
+            .map(Arc::strong_count)
+            .filter(|&owners| owners > 1)
+            .fold((0, 0), |(pages, saved), owners| {
+                (pages + 1, saved + PAGE_SIZE - PAGE_SIZE / owners)
+            })
+    }
+
     #[inline(always)]
     pub fn get_id(&self) -> usize {
         self.guest_id
     }
+
+    #[inline]
+    pub fn get_name(&self) -> &str {
+        &self.name
+    }
+
+    #[inline]
+    pub fn console(&self) -> ConsoleRoute {
+        self.console
+    }
+}
+
+/// nothing is loaded into reserved banks
+fn reserve_banks<G: GStagePageTable>(gpm: &mut GuestAddressSpace<G>, banks: &[MemoryBank]) {
+    for bank in banks
+        .iter()
+        .filter(|bank| bank.kind == MemoryKind::Reserved)
+    {
+        assert!(gpm.reserve(bank.gpa, bank.size));
+    }
 }
@@ -0,0 +1,151 @@
+//! external interrupts of host
+//!
+//! a host plic source is routed to a driver of hypervisor or to the guest its passthrough device is
+//! assigned to,other sources stay masked. a guest source stays claimed on host plic while line of
+//! the device on guest irqchip is up,it's completed once guest is done with it,see
+//! `VirtDevices::sync_irqs`. a source firing more than `STORM_LIMIT` times in `STORM_WINDOW_MS` is
+//! masked for `STORM_BACKOFF_MS`
+
+use super::queue_guard;
+use crate::arch::intc::plic::host_plic;
+use crate::device_tree::host_timebase_frequency;
+use crate::println;
+use crate::schedule::now;
+use alloc::collections::BTreeMap;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use spin::Mutex;
+
+const STORM_LIMIT: usize = 1000;
+const STORM_WINDOW_MS: usize = 10;
+const STORM_BACKOFF_MS: usize = 100;
+
+/// who handles a host source
+#[derive(Clone, Copy)]
+pub enum Route {
+    /// driver of hypervisor,source is completed once it returns
+    // hypervisor console is polled,no driver of it takes interrupts yet
+    #[allow(dead_code)]
+    Host(fn(u32)),
+    /// guest of guest_id,as interrupt of its passthrough device
+    Guest(usize),
+}
+
+struct Source {
+    route: Route,
+    // interrupts since window started
+    count: usize,
+    window_start: usize,
+    // masked for storming until then
+    masked_until: Option<usize>,
+}
+
+static SOURCES: Mutex<BTreeMap<u32, Source>> = Mutex::new(BTreeMap::new());
+// earliest time a masked source is let through again
+static NEXT_UNMASK: AtomicUsize = AtomicUsize::new(usize::MAX);
+
+#[inline]
+fn ms_to_ticks(ms: usize) -> usize {
+    host_timebase_frequency() * ms / 1000
+}
+
+/// send host source to route and let it interrupt,return false if host has no such source
+pub fn route_irq(source: u32, route: Route) -> bool {
+    let plic = match host_plic() {
+        Some(plic) if plic.has_source(source) => plic,
+        _ => return false,
+    };
+    SOURCES.lock().insert(
+        source,
+        Source {
+            route,
+            count: 0,
+            window_start: now(),
+            masked_until: None,
+        },
+    );
+    plic.set_priority(source, 1);
+    plic.set_enabled(source, true);
+    true
+}
+
+/// one more interrupt of source,return its route unless it's storming and was masked now
+fn account(source: u32) -> Option<Route> {
+    let mut sources = SOURCES.lock();
+    let state = sources.get_mut(&source)?;
+    let now = now();
+    if now - state.window_start >= ms_to_ticks(STORM_WINDOW_MS) {
+        state.window_start = now;
+        state.count = 0;
+    }
+    state.count += 1;
+    if state.count <= STORM_LIMIT {
+        return Some(state.route);
+    }
+    let until = now + ms_to_ticks(STORM_BACKOFF_MS);
+    state.masked_until = Some(until);
+    NEXT_UNMASK.fetch_min(until, Ordering::AcqRel);
+    println!(
+        "[hypervisor] irq {} storms,masked for {}ms",
+        source, STORM_BACKOFF_MS
+    );
+    None
+}
+
+/// take external interrupts pending for this hart until none is left
+pub fn handle_external_irqs() {
+    let plic = match host_plic() {
+        Some(plic) => plic,
+        None => return,
+    };
+    while let Some(source) = plic.claim() {
+        match account(source) {
+            Some(Route::Host(handler)) => {
+                handler(source);
+                plic.complete(source);
+            }
+            Some(Route::Guest(guest_id)) => {
+                let forwarded = queue_guard()
+                    .iter_mut()
+                    .find(|guest| guest.get_id() == guest_id)
+                    .map_or(false, |guest| guest.forward_host_irq(source));
+                // guest completes it later
+                if !forwarded {
+                    plic.complete(source);
+                }
+            }
+            // storming or unrouted,completed before masking as plic ignores completion of a
+            // masked source
+            None => {
+                plic.complete(source);
+                plic.set_enabled(source, false);
+            }
+        }
+    }
+}
+
+/// let sources masked for storming interrupt again once their backoff is over
+pub fn unmask_quiet_irqs() {
+    let now = now();
+    if now < NEXT_UNMASK.load(Ordering::Acquire) {
+        return;
+    }
+    let plic = match host_plic() {
+        Some(plic) => plic,
+        None => return,
+    };
+    let mut sources = SOURCES.lock();
+    let mut next = usize::MAX;
+    for (&source, state) in sources.iter_mut() {
+        match state.masked_until {
+            Some(until) if until <= now => {
+                state.masked_until = None;
+                state.count = 0;
+                state.window_start = now;
+                plic.set_enabled(source, true);
+            }
+            Some(until) => next = next.min(until),
+            None => {}
+        }
+    }
+    NEXT_UNMASK.store(next, Ordering::Release);
+}
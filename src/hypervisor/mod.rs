@@ -1,11 +1,25 @@
+use self::irq::Route;
+use self::smp::{kick, leave_hart, start_secondary_harts};
+use crate::arch::intc::imsic::{host_imsic, take_guest_irqs};
+use crate::arch::mmio::decode_trapped_insn;
 use crate::arch::page_table::PageTableAdapter;
-use crate::arch::{vm_entry, TrapContext};
-use crate::guest::Guest;
-use crate::mm::{HostAddressSpace, HOST_ADDRESS_SPACE};
+use crate::arch::{vm_entry, TrapContext, VirtIrq};
+use crate::config::VmConfig;
+use crate::guest::{CowFault, Guest, LoadError, RunState, StateError, StopReason};
+use crate::monitor;
+use crate::percpu;
+use crate::println;
+use crate::sbi::sbi_shutdown;
+use crate::schedule;
 use alloc::collections::LinkedList;
 use core::sync::atomic::{AtomicUsize, Ordering};
 use spin::{Mutex, MutexGuard, Once};
 
+pub mod irq;
+pub mod page_merge;
+pub mod sbi;
+pub mod smp;
+
 pub static mut GUESTS_QUEUE: Once<Mutex<LinkedList<Guest<PageTableAdapter, PageTableAdapter>>>> =
     Once::new();
 pub static GUEST_ID: AtomicUsize = AtomicUsize::new(0);
@@ -24,32 +38,324 @@ pub fn queue_guard() -> MutexGuard<'static, LinkedList<Guest<PageTableAdapter, P
     unsafe { GUESTS_QUEUE.get().unwrap().lock() }
 }
 
-/// create guest and add guest to queue
-pub fn create_guest(cpu_nums: usize, mem_size: usize, guest_data: &[u8]) -> usize {
+/// create guest from validated config and add guest to queue
+pub fn create_guest(config: &VmConfig<'static>) -> Result<usize, LoadError> {
     let guest_id = alloc_guest_id();
-    let mut guest = Guest::new(guest_id, cpu_nums, mem_size);
-    guest.load_guest_image(guest_data);
-    unsafe {
-        HOST_ADDRESS_SPACE
-            .get()
-            .unwrap()
-            .lock()
-            .unmap_embedded_guest();
-    }
-    queue_guard().push_back(guest);
-    guest_id
-}
-
-pub fn run_guest(guest_id: usize) -> ! {
-    let mut ctx: *mut TrapContext = core::ptr::null_mut();
-    let mut queue_guard = queue_guard();
-    for guest in queue_guard.iter_mut() {
-        if guest.get_id() == guest_id {
-            ctx = guest.vcpu_ctx_ptr(0);
-            break;
+    let mut guest = Guest::new(guest_id, config)?;
+    guest.boot()?;
+    for (host_irq, _) in config.passthrough.iter().filter_map(|pt| pt.irq) {
+        if !irq::route_irq(host_irq, Route::Guest(guest_id)) {
+            println!(
+                "[hypervisor] host has no irq {},guest {} doesn't get it",
+                host_irq, guest_id
+            );
+        }
+    }
+    let mut queue = queue_guard();
+    queue.push_back(guest);
+    queue.back_mut().unwrap().queue_runnable_vcpus();
+    Ok(guest_id)
+}
+
+/// background work done between vm exit and next vm entry
+pub fn housekeeping() {
+    page_merge::merge_tick();
+    monitor::poll();
+    irq::unmask_quiet_irqs();
+}
+
+/// handle store guest page fault on write protected guest memory,return false if it isn't one
+///
+/// guest crashes if host has no frame to break sharing,other guests keep running
+pub fn handle_cow_fault(ctx: *mut TrapContext, gpa: usize) -> bool {
+    let fault = queue_guard()
+        .iter_mut()
+        .find(|guest| guest.owns_ctx(ctx))
+        .map_or(CowFault::NotCow, |guest| guest.handle_cow_fault(gpa));
+    match fault {
+        CowFault::NotCow => false,
+        CowFault::Handled => true,
+        CowFault::OutOfMemory => {
+            println!("[hypervisor] no free frame to copy shared page {:#x}", gpa);
+            stop_guest(ctx, StopReason::Crash)
+        }
+    }
+}
+
+/// emulate guest access to device window,return false if no emulated device covers gpa
+pub fn handle_mmio(ctx: *mut TrapContext, gpa: usize) -> bool {
+    let mut queue = queue_guard();
+    let guest = match queue.iter_mut().find(|guest| guest.owns_ctx(ctx)) {
+        Some(guest) => guest,
+        None => return false,
+    };
+    let ctx = unsafe { &mut *ctx };
+    match unsafe { decode_trapped_insn(ctx.sepc) } {
+        Some(insn) => guest.emulate_mmio(ctx, gpa, insn),
+        None => false,
+    }
+}
+
+/// emulate sireg or stopei access of guest to emulated imsic file of vcpu of ctx,return false if
+/// it isn't one
+pub fn handle_imsic_csr(ctx: *mut TrapContext, insn: usize) -> bool {
+    queue_guard()
+        .iter_mut()
+        .find(|guest| guest.owns_ctx(ctx))
+        .map_or(false, |guest| guest.emulate_imsic_csr(ctx, insn))
+}
+
+/// guest files of this hart raised supervisor guest external interrupt,their blocked vcpus wake
+pub fn take_guest_interrupts() {
+    if host_imsic().is_none() {
+        return;
+    }
+    let taken = take_guest_irqs();
+    if taken == 0 {
+        return;
+    }
+    let hart = percpu::hart_id();
+    for guest in queue_guard().iter_mut() {
+        guest.wake_guest_files(hart, taken);
+    }
+}
+
+/// vcpu for hart to run next and its time slice,it is marked as running on hart
+pub fn next_vcpu(hart_id: usize) -> Option<(*mut TrapContext, usize)> {
+    while let Some(next) = schedule::pick_next() {
+        let mut queue = queue_guard();
+        let guest = match queue
+            .iter_mut()
+            .find(|guest| guest.get_id() == next.guest_id)
+        {
+            Some(guest) => guest,
+            None => continue,
+        };
+        if let Some(ctx) = guest.take_vcpu(next.vcpu_id, hart_id) {
+            return Some((ctx, guest.time_slice()));
+        }
+    }
+    None
+}
+
+#[inline]
+pub fn all_halted() -> bool {
+    queue_guard().iter().all(|guest| guest.is_halted())
+}
+
+/// vcpu of ctx has left its hart,it queues again if it's still runnable
+pub fn release_vcpu(ctx: *mut TrapContext) {
+    if let Some(guest) = queue_guard().iter_mut().find(|guest| guest.owns_ctx(ctx)) {
+        guest.release_vcpu(ctx);
+    }
+}
+
+/// set harts vcpu of guest may run on,return false if there is no such vcpu or none of harts is
+/// online
+pub fn set_vcpu_affinity(guest_id: usize, vcpu_id: usize, affinity: usize) -> bool {
+    // vcpu would wait for a hart which never comes up
+    if !percpu::any_online(affinity) {
+        return false;
+    }
+    queue_guard()
+        .iter_mut()
+        .find(|guest| guest.get_id() == guest_id)
+        .map_or(false, |guest| guest.set_affinity(vcpu_id, affinity))
+}
+
+/// virtual interrupt irq is pending for vcpu of guest until cleared,return false if there is no such
+/// vcpu
+pub fn inject_irq(guest_id: usize, vcpu_id: usize, irq: VirtIrq) -> bool {
+    queue_guard()
+        .iter_mut()
+        .find(|guest| guest.get_id() == guest_id)
+        .map_or(false, |guest| guest.inject_irq(vcpu_id, irq))
+}
+
+/// virtual interrupt irq injected before is no longer pending for vcpu of guest
+pub fn clear_irq(guest_id: usize, vcpu_id: usize, irq: VirtIrq) -> bool {
+    queue_guard()
+        .iter_mut()
+        .find(|guest| guest.get_id() == guest_id)
+        .map_or(false, |guest| guest.clear_irq(vcpu_id, irq))
+}
+
+/// wake blocked vcpus an interrupt came for since they blocked or whose timer fired
+pub fn wake_blocked_vcpus() {
+    let mut queue = queue_guard();
+    // vcpus still blocked register their timers again
+    schedule::clear_wakeups();
+    for guest in queue.iter_mut() {
+        guest.wake_vcpus();
+    }
+}
+
+/// hand out credits of a new period to active guests by weight,parked vcpus queue again
+pub fn refill_credits() {
+    let total = schedule::period() * percpu::online_harts().count();
+    let mut queue = queue_guard();
+    let total_weight = queue
+        .iter()
+        .filter(|guest| guest.is_active())
+        .map(|guest| guest.share().weight)
+        .sum();
+    for guest in queue.iter_mut().filter(|guest| guest.is_active()) {
+        guest.refill_credits(total, total_weight);
+        guest.queue_runnable_vcpus();
+    }
+}
+
+/// reset or halt guests asked to stop,once none of their vcpus is on a hart
+pub fn finish_stops() {
+    for guest in queue_guard().iter_mut() {
+        guest.finish_stop();
+        // boot vcpu of a restarted guest
+        guest.queue_runnable_vcpus();
+    }
+}
+
+/// go back to vcpu once its exit is handled,or give up this hart if vcpu has to stop
+pub fn resume_vcpu(ctx: *mut TrapContext) -> ! {
+    housekeeping();
+    let mut queue = queue_guard();
+    let guest = match queue.iter_mut().find(|guest| guest.owns_ctx(ctx)) {
+        Some(guest) => guest,
+        None => sbi_shutdown(),
+    };
+    let may_run = guest.may_run();
+    guest.sync_vcpu_irqs(ctx);
+    let vcpu = guest.vcpu_of_ctx(ctx).unwrap();
+    // a vcpu repinned elsewhere moves once it's off this hart,with its guest file if it has one
+    let may_run = may_run
+        && vcpu.state() == RunState::Running
+        && vcpu.allows(percpu::hart_id())
+        && !vcpu.is_moving_file();
+    drop(queue);
+    if may_run {
+        unsafe { vm_entry(ctx) }
+    }
+    leave_hart(ctx)
+}
+
+/// host timer fired while vcpu of ctx runs,it gives up the hart if its slice is used up and other
+/// vcpus wait,or its guest went over its cap
+///
+/// timers of guests fire here too,sbi timer of vcpu of ctx and those of blocked vcpus
+pub fn handle_timer_tick(ctx: *mut TrapContext) -> ! {
+    if schedule::refill_due() {
+        refill_credits();
+    }
+    if schedule::wakeup_due() {
+        wake_blocked_vcpus();
+    }
+    let capped = queue_guard()
+        .iter_mut()
+        .find(|guest| guest.owns_ctx(ctx))
+        .map_or(false, |guest| {
+            guest.check_vcpu_timer(ctx);
+            guest.charge_vcpu(ctx)
+        });
+    // vcpu of a capped guest is parked till next period
+    if capped || schedule::tick() {
+        leave_hart(ctx)
+    }
+    resume_vcpu(ctx)
+}
+
+/// guest executed wfi,vcpu of ctx gives up its hart until an interrupt is pending for it
+pub fn handle_wfi(ctx: *mut TrapContext) -> ! {
+    // wfi is never compressed
+    unsafe { (*ctx).sepc += 4 };
+    let blocked = queue_guard()
+        .iter_mut()
+        .find(|guest| guest.owns_ctx(ctx))
+        .map_or(false, |guest| guest.block_vcpu(ctx));
+    if blocked {
+        leave_hart(ctx)
+    }
+    resume_vcpu(ctx)
+}
+
+/// guest owning ctx stops for reason,never return
+///
+/// other vcpus of guest are kicked out of their harts. once all of them are off,guest boots again
+/// if its restart policy says so or halts,see `Guest::finish_stop`
+pub fn stop_guest(ctx: *mut TrapContext, reason: StopReason) -> ! {
+    let mut queue = queue_guard();
+    let guest = match queue.iter_mut().find(|guest| guest.owns_ctx(ctx)) {
+        Some(guest) => guest,
+        None => sbi_shutdown(),
+    };
+    let hart = percpu::hart_id();
+    if reason == StopReason::Crash {
+        guest.crash_vcpu(ctx);
+    }
+    // guest may be stopping already for another vcpu
+    if guest.request_stop(reason) {
+        for other in guest.running_harts() {
+            if other != hart {
+                kick(other);
+            }
+        }
+    }
+    drop(queue);
+    leave_hart(ctx)
+}
+
+/// run op on guest of guest_id
+fn change_state(
+    guest_id: usize,
+    op: impl FnOnce(&mut Guest<PageTableAdapter, PageTableAdapter>) -> Result<(), StateError>,
+) -> Result<(), StateError> {
+    let mut queue = queue_guard();
+    let guest = queue
+        .iter_mut()
+        .find(|guest| guest.get_id() == guest_id)
+        .ok_or(StateError::NoGuest(guest_id))?;
+    op(guest)
+}
+
+/// freeze guest,its vcpus running on any hart are kicked out by ipi and stay off until resumed
+///
+/// return once guest is frozen,see `wait_off_harts`
+pub fn pause_guest(guest_id: usize) -> Result<(), StateError> {
+    change_state(guest_id, |guest| guest.pause())?;
+    wait_off_harts(guest_id)
+}
+
+/// wait until no vcpu of guest is on another hart
+///
+/// a vcpu of guest on this hart is left,it's in hypervisor already and leaves once caller is done.
+/// guest queue is free while waiting,kicked vcpus need it to leave
+fn wait_off_harts(guest_id: usize) -> Result<(), StateError> {
+    let this = percpu::hart_id();
+    loop {
+        let off = queue_guard()
+            .iter()
+            .find(|guest| guest.get_id() == guest_id)
+            .ok_or(StateError::NoGuest(guest_id))?
+            .running_harts()
+            .into_iter()
+            .all(|hart| hart == this);
+        if off {
+            return Ok(());
         }
+        core::hint::spin_loop();
     }
-    drop(queue_guard);
+}
+
+/// paused guest runs again
+pub fn resume_guest(guest_id: usize) -> Result<(), StateError> {
+    change_state(guest_id, |guest| guest.resume())
+}
+
+/// guest stops for good whatever its restart policy says,its running vcpus are kicked out by ipi
+pub fn halt_guest(guest_id: usize) -> Result<(), StateError> {
+    change_state(guest_id, |guest| guest.halt())
+}
 
-    unsafe { vm_entry(ctx) }
+/// start other harts and run vcpus on every hart,called by boot hart once guests are created
+pub fn run_guests(boot_hart: usize) -> ! {
+    start_secondary_harts(boot_hart);
+    leave_hart(core::ptr::null_mut())
 }
@@ -0,0 +1,243 @@
+//! same page merging across guests
+//!
+//! memory bank pages of guests are hashed in background. a page whose checksum holds from one scan
+//! to the next is write protected and compared with stable pages of the same checksum,identical
+//! pages end up backed by one read only frame in every g stage page table. pages written between
+//! scans are never protected,like unstable tree of ksm. a store to a merged page traps as store
+//! guest page fault and `Guest::handle_cow_fault` breaks the sharing by copying
+
+use crate::arch::page_table::{PageTableAdapter, PhysPageNum};
+use crate::device_tree::host_timebase_frequency;
+use crate::guest::Guest;
+use crate::hypervisor::queue_guard;
+use crate::mm::FrameTracker;
+use crate::println;
+use crate::schedule::now;
+use alloc::collections::BTreeMap;
+use alloc::sync::{Arc, Weak};
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use spin::{Mutex, Once};
+
+type HostGuest = Guest<PageTableAdapter, PageTableAdapter>;
+
+/// guest pages scanned in one round
+const SCAN_BUDGET: usize = 64;
+/// a round runs at most once in this many ms,whichever hart is in housekeeping then runs it
+const SCAN_INTERVAL_MS: usize = 10;
+
+static PAGE_MERGER: Mutex<PageMerger> = Mutex::new(PageMerger::new());
+// next scan round is due
+static NEXT_SCAN: AtomicUsize = AtomicUsize::new(0);
+static SCAN_INTERVAL: Once<usize> = Once::new();
+
+/// a write protected page whose content may be shared by others
+struct StablePage {
+    frame: Weak<FrameTracker>,
+    guest_id: usize,
+    gppn: PhysPageNum,
+}
+
+pub struct PageMerger {
+    // content checksum -> stable pages with that checksum. a guest page is in the bucket of the
+    // checksum it had when it was last scanned,at most once
+    stable: BTreeMap<u32, Vec<StablePage>>,
+    // guest id -> checksum of every memory bank page at last scan,0 for not scanned yet
+    checksums: BTreeMap<usize, Vec<u32>>,
+    // scan position: index of guest in guest queue and page index in its memory banks
+    guest_idx: usize,
+    page_idx: usize,
+    // guest id -> saved bytes reported last time
+    reported: BTreeMap<usize, usize>,
+}
+
+/// fnv-1a checksum of a physical page
+fn page_checksum(ppn: PhysPageNum) -> u32 {
+    ppn.get_bytes_array()
+        .iter()
+        .fold(0x811c_9dc5, |hash, &byte| {
+            (hash ^ byte as u32).wrapping_mul(0x0100_0193)
+        })
+}
+
+/// a scan round is due,only one hart gets true for an interval
+fn scan_due() -> bool {
+    let interval = *SCAN_INTERVAL.call_once(|| host_timebase_frequency() * SCAN_INTERVAL_MS / 1000);
+    let now = now();
+    let next = NEXT_SCAN.load(Ordering::Acquire);
+    now >= next
+        && NEXT_SCAN
+            .compare_exchange(next, now + interval, Ordering::AcqRel, Ordering::Acquire)
+            .is_ok()
+}
+
+impl PageMerger {
+    pub const fn new() -> Self {
+        Self {
+            stable: BTreeMap::new(),
+            checksums: BTreeMap::new(),
+            guest_idx: 0,
+            page_idx: 0,
+            reported: BTreeMap::new(),
+        }
+    }
+
+    /// scan at most budget pages from where last scan stopped
+    pub fn scan(&mut self, guests: &mut [&mut HostGuest], budget: usize) {
+        if guests.is_empty() {
+            return;
+        }
+        for _ in 0..budget {
+            if self.guest_idx >= guests.len() {
+                self.guest_idx = 0;
+                self.prune(guests);
+                self.report(guests);
+            }
+            let gppn = match guests[self.guest_idx].ram_gppn(self.page_idx) {
+                Some(gppn) => gppn,
+                None => {
+                    self.guest_idx += 1;
+                    self.page_idx = 0;
+                    continue;
+                }
+            };
+            self.merge_one(guests, self.guest_idx, self.page_idx, gppn);
+            self.page_idx += 1;
+        }
+    }
+
+    /// record checksum of page_idx-th page of guest,return the one of last scan
+    fn swap_checksum(&mut self, guest_id: usize, page_idx: usize, checksum: u32) -> u32 {
+        let checksums = self.checksums.entry(guest_id).or_default();
+        if checksums.len() <= page_idx {
+            checksums.resize(page_idx + 1, 0);
+        }
+        core::mem::replace(&mut checksums[page_idx], checksum)
+    }
+
+    /// drop stable entry of guest page in bucket of checksum
+    fn forget(&mut self, checksum: u32, guest_id: usize, gppn: PhysPageNum) {
+        if let Some(pages) = self.stable.get_mut(&checksum) {
+            pages.retain(|page| page.guest_id != guest_id || page.gppn != gppn);
+            if pages.is_empty() {
+                self.stable.remove(&checksum);
+            }
+        }
+    }
+
+    fn merge_one(
+        &mut self,
+        guests: &mut [&mut HostGuest],
+        idx: usize,
+        page_idx: usize,
+        gppn: PhysPageNum,
+    ) {
+        let guest_id = guests[idx].get_id();
+        let frame = match guests[idx].ram_frame(gppn) {
+            // already merged
+            Some(frame) if Arc::strong_count(frame) > 1 => return,
+            Some(frame) => frame.clone(),
+            None => return,
+        };
+        let checksum = page_checksum(frame.ppn);
+        let last = self.swap_checksum(guest_id, page_idx, checksum);
+        if last != checksum {
+            // page is being written,leave it writable. its stable entry is stale if it has one
+            self.forget(last, guest_id, gppn);
+            return;
+        }
+        // protect before comparing,so content can't change behind our back. a store may have
+        // slipped in between checksum and protection,check again
+        if guests[idx].protect_page(gppn) && page_checksum(frame.ppn) != checksum {
+            self.swap_checksum(guest_id, page_idx, 0);
+            self.forget(checksum, guest_id, gppn);
+            return;
+        }
+
+        let candidates = self.stable.entry(checksum).or_default();
+        candidates.retain(|page| page.frame.strong_count() > 0);
+        for page in candidates.iter() {
+            let shared = page.frame.upgrade().unwrap();
+            if Arc::ptr_eq(&shared, &frame) {
+                return;
+            }
+            // a frame with single owner may be written again after its cow fault,protect it again
+            // before comparing. frames with more owners are read only everywhere
+            if Arc::strong_count(&shared) == 2 {
+                let owner = guests
+                    .iter_mut()
+                    .find(|guest| guest.get_id() == page.guest_id);
+                match owner {
+                    Some(owner)
+                        if owner
+                            .ram_frame(page.gppn)
+                            .map_or(false, |f| Arc::ptr_eq(f, &shared)) =>
+                    {
+                        owner.protect_page(page.gppn);
+                    }
+                    _ => continue,
+                }
+            }
+            if shared.ppn.get_bytes_array() == frame.ppn.get_bytes_array() {
+                drop(frame);
+                guests[idx].share_page(gppn, shared);
+                return;
+            }
+        }
+        // page may still be here with the frame it had before a cow fault
+        candidates.retain(|page| page.guest_id != guest_id || page.gppn != gppn);
+        candidates.push(StablePage {
+            frame: Arc::downgrade(&frame),
+            guest_id,
+            gppn,
+        });
+    }
+
+    /// forget guests gone from guest queue and pages whose frames are released,once a round
+    fn prune(&mut self, guests: &[&mut HostGuest]) {
+        let alive = |guest_id: &usize| guests.iter().any(|guest| guest.get_id() == *guest_id);
+        self.checksums.retain(|guest_id, _| alive(guest_id));
+        self.reported.retain(|guest_id, _| alive(guest_id));
+        self.stable.retain(|_, pages| {
+            pages.retain(|page| page.frame.strong_count() > 0 && alive(&page.guest_id));
+            !pages.is_empty()
+        });
+    }
+
+    /// report saved memory of guests whenever it changes
+    fn report(&mut self, guests: &[&mut HostGuest]) {
+        for guest in guests.iter() {
+            let (pages, saved) = guest.merge_stats();
+            let last = self.reported.insert(guest.get_id(), saved);
+            if last != Some(saved) {
+                println!(
+                    "[hypervisor] guest {} shares {} pages,saves {} KiB",
+                    guest.get_id(),
+                    pages,
+                    saved / 1024
+                );
+            }
+        }
+    }
+}
+
+/// merge a bounded number of pages if a round is due,called between vm exit and vm entry and by
+/// idle harts
+pub fn merge_tick() {
+    if !scan_due() {
+        return;
+    }
+    let mut queue = queue_guard();
+    let mut guests: Vec<&mut HostGuest> = queue.iter_mut().collect();
+    PAGE_MERGER.lock().scan(&mut guests, SCAN_BUDGET);
+}
+
+/// saved bytes of guest reported in last full scan
+pub fn saved_memory(guest_id: usize) -> usize {
+    PAGE_MERGER
+        .lock()
+        .reported
+        .get(&guest_id)
+        .copied()
+        .unwrap_or(0)
+}
@@ -0,0 +1,281 @@
+//! sbi seen by guests
+//!
+//! base extension and legacy console are served here,system reset stops or reboots calling guest
+//! only. hsm starts and stops vcpus of calling guest,hart ids are vcpu ids. time sets timer of
+//! calling vcpu in guest time,hypervisor fires it by vstip unless hart has sstc. ipi injects a
+//! software interrupt into vcpus of calling guest. rfence does sfence.vma or fence.i for vcpus of
+//! calling guest,as hfence.vvma on harts running them. vendor
+//! extension `HYPERCRAB_EXTENSION` hands the measurement log of calling guest to it:
+//! - `MEASUREMENT_COUNT` () -> number of entries
+//! - `MEASUREMENT_READ` (index,gpa) -> write entry index to guest physical address gpa,entry layout
+//!   is `Measurement::to_bytes`
+//!
+//! other extensions fail with `SBI_ERR_NOT_SUPPORTED`
+
+use crate::arch::{TrapContext, VirtIrq};
+use crate::config::ConsoleRoute;
+use crate::guest::{Fence, HsmState, StopReason};
+use crate::hypervisor::queue_guard;
+use crate::monitor;
+use crate::sbi::{
+    sbi_call_ret, sbi_put_char, COLD_REBOOT, HART_GET_STATUS, HART_START, HART_STARTED, HART_STOP,
+    HART_STOPPED, REMOTE_FENCE_I, REMOTE_SFENCE_VMA, REMOTE_SFENCE_VMA_ASID,
+    RUSTSBI_GET_CHAR_EXTENSION, RUSTSBI_PUT_CHAR_EXTENSION, SBI_BASE_EXTENSION,
+    SBI_ERR_ALREADY_AVAILABLE, SBI_ERR_FAILED, SBI_ERR_INVALID_ADDRESS, SBI_ERR_INVALID_PARAM,
+    SBI_ERR_NOT_SUPPORTED, SBI_HSM_EXTENSION, SBI_IPI_EXTENSION, SBI_RESET_EXTENSION,
+    SBI_RFENCE_EXTENSION, SBI_SUCCESS, SBI_TIME_EXTENSION, SEND_IPI, SET_TIMER, SHUTDOWN,
+    SYSTEM_RESET, WARM_REBOOT,
+};
+use alloc::vec::Vec;
+
+// vendor extension space is 0x0900_0000..0x0a00_0000,low bytes are "HCR"
+pub const HYPERCRAB_EXTENSION: usize = 0x0948_4352;
+pub const MEASUREMENT_COUNT: usize = 0;
+pub const MEASUREMENT_READ: usize = 1;
+
+// sbi spec 1.0
+const SBI_SPEC_VERSION: usize = 1 << 24;
+
+// base extension functions
+const GET_SPEC_VERSION: usize = 0;
+const GET_IMPL_ID: usize = 1;
+const GET_IMPL_VERSION: usize = 2;
+const PROBE_EXTENSION: usize = 3;
+const GET_MVENDORID: usize = 4;
+const GET_MARCHID: usize = 5;
+const GET_MIMPID: usize = 6;
+
+const SUPPORTED_EXTENSIONS: &[usize] = &[
+    RUSTSBI_PUT_CHAR_EXTENSION,
+    RUSTSBI_GET_CHAR_EXTENSION,
+    SBI_BASE_EXTENSION,
+    SBI_RESET_EXTENSION,
+    SBI_HSM_EXTENSION,
+    SBI_TIME_EXTENSION,
+    SBI_IPI_EXTENSION,
+    SBI_RFENCE_EXTENSION,
+    HYPERCRAB_EXTENSION,
+];
+
+const A0: usize = 10;
+const A1: usize = 11;
+const A2: usize = 12;
+const A6: usize = 16;
+const A7: usize = 17;
+
+/// (error,value) returned in a0 and a1
+type SbiRet = (isize, usize);
+
+fn base_call(fid: usize, args: [usize; 3]) -> SbiRet {
+    match fid {
+        GET_SPEC_VERSION => (0, SBI_SPEC_VERSION),
+        GET_IMPL_ID | GET_IMPL_VERSION | GET_MVENDORID | GET_MARCHID | GET_MIMPID => {
+            let (error, value) = sbi_call_ret(SBI_BASE_EXTENSION, fid, [0; 3]);
+            (error as isize, value)
+        }
+        PROBE_EXTENSION => (0, SUPPORTED_EXTENSIONS.contains(&args[0]) as usize),
+        _ => (SBI_ERR_NOT_SUPPORTED, 0),
+    }
+}
+
+fn hypercrab_call(ctx: *mut TrapContext, fid: usize, args: [usize; 3]) -> SbiRet {
+    let mut queue = queue_guard();
+    let guest = match queue.iter_mut().find(|guest| guest.owns_ctx(ctx)) {
+        Some(guest) => guest,
+        None => return (SBI_ERR_NOT_SUPPORTED, 0),
+    };
+    match fid {
+        MEASUREMENT_COUNT => (0, guest.measurements().len()),
+        MEASUREMENT_READ => {
+            let raw = match guest.measurements().get(args[0]) {
+                Some(measurement) => measurement.to_bytes(),
+                None => return (SBI_ERR_INVALID_PARAM, 0),
+            };
+            if guest.write_guest_memory(args[1], &raw) {
+                (0, raw.len())
+            } else {
+                (SBI_ERR_INVALID_ADDRESS, 0)
+            }
+        }
+        _ => (SBI_ERR_NOT_SUPPORTED, 0),
+    }
+}
+
+/// hart state management of calling guest,a stopped vcpu leaves its hart after this call
+fn hsm_call(ctx: *mut TrapContext, fid: usize, args: [usize; 3]) -> SbiRet {
+    let mut queue = queue_guard();
+    let guest = match queue.iter_mut().find(|guest| guest.owns_ctx(ctx)) {
+        Some(guest) => guest,
+        None => return (SBI_ERR_NOT_SUPPORTED, 0),
+    };
+    match fid {
+        HART_START => match guest.vcpu_hsm_state(args[0]) {
+            None => (SBI_ERR_INVALID_PARAM, 0),
+            Some(HsmState::Started) => (SBI_ERR_ALREADY_AVAILABLE, 0),
+            // a stopped vcpu is still on its hart until it switches to hart stack,its context
+            // can't be replaced before
+            Some(HsmState::Stopped) if guest.vcpus()[args[0]].hart().is_some() => {
+                (SBI_ERR_ALREADY_AVAILABLE, 0)
+            }
+            Some(HsmState::Stopped) => {
+                guest.start_vcpu(args[0], args[1], args[2]);
+                guest.queue_runnable_vcpus();
+                (0, 0)
+            }
+        },
+        HART_STOP => {
+            let vcpu = guest.vcpu_of_ctx(ctx).unwrap();
+            vcpu.stop();
+            (0, 0)
+        }
+        HART_GET_STATUS => match guest.vcpu_hsm_state(args[0]) {
+            None => (SBI_ERR_INVALID_PARAM, 0),
+            Some(HsmState::Started) => (0, HART_STARTED),
+            Some(HsmState::Stopped) => (0, HART_STOPPED),
+        },
+        _ => (SBI_ERR_NOT_SUPPORTED, 0),
+    }
+}
+
+/// timer of calling vcpu fires at guest time args[0],pending timer interrupt is cleared
+fn time_call(ctx: *mut TrapContext, fid: usize, args: [usize; 3]) -> SbiRet {
+    if fid != SET_TIMER {
+        return (SBI_ERR_NOT_SUPPORTED, 0);
+    }
+    let mut queue = queue_guard();
+    match queue.iter_mut().find(|guest| guest.owns_ctx(ctx)) {
+        Some(guest) => {
+            guest.set_vcpu_timer(ctx, args[0]);
+            (0, 0)
+        }
+        None => (SBI_ERR_NOT_SUPPORTED, 0),
+    }
+}
+
+/// vcpu ids in hart mask counted from hart id base,every vcpu if base is usize::MAX
+fn target_vcpus(vcpu_nums: usize, mask: usize, base: usize) -> Result<Vec<usize>, SbiRet> {
+    let targets: Vec<usize> = match base {
+        usize::MAX => (0..vcpu_nums).collect(),
+        base => (0..usize::BITS as usize)
+            .filter(|bit| mask & 1 << bit != 0)
+            .map(|bit| base.saturating_add(bit))
+            .collect(),
+    };
+    if targets.iter().any(|&vcpu_id| vcpu_id >= vcpu_nums) {
+        return Err((SBI_ERR_INVALID_PARAM, 0));
+    }
+    Ok(targets)
+}
+
+/// software interrupt to vcpus of calling guest in hart mask args[0] counted from hart id args[1],
+/// to every vcpu if args[1] is usize::MAX
+fn ipi_call(ctx: *mut TrapContext, fid: usize, args: [usize; 3]) -> SbiRet {
+    if fid != SEND_IPI {
+        return (SBI_ERR_NOT_SUPPORTED, 0);
+    }
+    let mut queue = queue_guard();
+    let guest = match queue.iter_mut().find(|guest| guest.owns_ctx(ctx)) {
+        Some(guest) => guest,
+        None => return (SBI_ERR_NOT_SUPPORTED, 0),
+    };
+    let targets = match target_vcpus(guest.vcpus().len(), args[0], args[1]) {
+        Ok(targets) => targets,
+        Err(ret) => return ret,
+    };
+    for vcpu_id in targets {
+        guest.inject_irq(vcpu_id, VirtIrq::Software);
+    }
+    (0, 0)
+}
+
+/// fence.i or sfence.vma for vcpus of calling guest in hart mask args[0] counted from hart id
+/// args[1],address range and asid are ignored and whole vs stage tlb is flushed
+///
+/// hfence calls are for guests running their own guests,which vcpus can't
+fn rfence_call(ctx: *mut TrapContext, fid: usize, args: [usize; 3]) -> SbiRet {
+    let fence = match fid {
+        REMOTE_FENCE_I => Fence::Icache,
+        REMOTE_SFENCE_VMA | REMOTE_SFENCE_VMA_ASID => Fence::Tlb,
+        _ => return (SBI_ERR_NOT_SUPPORTED, 0),
+    };
+    let mut queue = queue_guard();
+    let guest = match queue.iter_mut().find(|guest| guest.owns_ctx(ctx)) {
+        Some(guest) => guest,
+        None => return (SBI_ERR_NOT_SUPPORTED, 0),
+    };
+    let targets = match target_vcpus(guest.vcpus().len(), args[0], args[1]) {
+        Ok(targets) => targets,
+        Err(ret) => return ret,
+    };
+    if guest.remote_fence(&targets, fence) {
+        (0, 0)
+    } else {
+        (SBI_ERR_FAILED, 0)
+    }
+}
+
+/// system reset asked by guest,invalid request fails and guest goes on
+fn reset_call(fid: usize, args: [usize; 3]) -> Result<StopReason, SbiRet> {
+    if fid != SYSTEM_RESET {
+        return Err((SBI_ERR_NOT_SUPPORTED, 0));
+    }
+    match args[0] {
+        SHUTDOWN => Ok(StopReason::Shutdown),
+        COLD_REBOOT | WARM_REBOOT => Ok(StopReason::Reboot),
+        _ => Err((SBI_ERR_INVALID_PARAM, 0)),
+    }
+}
+
+fn console_route(ctx: *mut TrapContext) -> ConsoleRoute {
+    queue_guard()
+        .iter_mut()
+        .find(|guest| guest.owns_ctx(ctx))
+        .map_or(ConsoleRoute::Null, |guest| guest.console())
+}
+
+/// handle ecall from vs mode,return why guest stops if it asked for system reset
+///
+/// other calls are answered and guest goes on
+pub fn handle_sbi_call(ctx: *mut TrapContext) -> Option<StopReason> {
+    let (eid, fid, args) = {
+        let regs = unsafe { &(*ctx).regs };
+        (regs[A7], regs[A6], [regs[A0], regs[A1], regs[A2]])
+    };
+    let ret = match eid {
+        // legacy calls return value in a0 only
+        RUSTSBI_PUT_CHAR_EXTENSION => {
+            if console_route(ctx) == ConsoleRoute::Host {
+                sbi_put_char(args[0]);
+            }
+            unsafe { (*ctx).regs[A0] = SBI_SUCCESS };
+            None
+        }
+        RUSTSBI_GET_CHAR_EXTENSION => {
+            let c = match console_route(ctx) {
+                ConsoleRoute::Host => monitor::getchar().map_or(usize::MAX, |c| c as usize),
+                ConsoleRoute::Null => usize::MAX,
+            };
+            unsafe { (*ctx).regs[A0] = c };
+            None
+        }
+        SBI_BASE_EXTENSION => Some(base_call(fid, args)),
+        SBI_RESET_EXTENSION => match reset_call(fid, args) {
+            Ok(reason) => return Some(reason),
+            Err(ret) => Some(ret),
+        },
+        SBI_HSM_EXTENSION => Some(hsm_call(ctx, fid, args)),
+        SBI_TIME_EXTENSION => Some(time_call(ctx, fid, args)),
+        SBI_IPI_EXTENSION => Some(ipi_call(ctx, fid, args)),
+        SBI_RFENCE_EXTENSION => Some(rfence_call(ctx, fid, args)),
+        HYPERCRAB_EXTENSION => Some(hypercrab_call(ctx, fid, args)),
+        _ => Some((SBI_ERR_NOT_SUPPORTED, 0)),
+    };
+    let ctx = unsafe { &mut *ctx };
+    if let Some((error, value)) = ret {
+        ctx.regs[A0] = error as usize;
+        ctx.regs[A1] = value;
+    }
+    // ecall is never compressed
+    ctx.sepc += 4;
+    None
+}
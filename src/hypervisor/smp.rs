@@ -0,0 +1,151 @@
+//! physical harts running vcpus
+//!
+//! boot hart starts other harts by sbi hsm after guests are created. every hart then runs
+//! `hart_loop` on its own stack,which takes the next vcpu from `schedule` and enters it. a vcpu
+//! stays on its hart until it stops,blocks in wfi or is preempted,other harts kick it out with an
+//! ipi when they need it to exit. a hart with nothing to run waits in wfi itself
+
+use crate::arch::intc::imsic::{enable_sgei, host_imsic};
+use crate::arch::intc::plic::host_plic;
+use crate::arch::page_table::flush_guest_tlb;
+use crate::arch::{set_hyp_trap_handler, vm_entry, TrapContext};
+use crate::constants::MAX_HARTS;
+use crate::device_tree::host_fdt;
+use crate::hypervisor::irq::handle_external_irqs;
+use crate::hypervisor::{
+    all_halted, finish_stops, housekeeping, next_vcpu, refill_credits, release_vcpu,
+    take_guest_interrupts, wake_blocked_vcpus,
+};
+use crate::mm::hpm_guard;
+use crate::percpu;
+use crate::println;
+use crate::sbi::{sbi_hart_start, sbi_remote_hfence_gvma, sbi_send_ipi, sbi_shutdown, SBI_SUCCESS};
+use crate::schedule;
+use riscv::register::sie;
+
+extern "C" {
+    fn _secondary_start();
+    fn __hart_loop(hart_id: usize, stack_top: usize, left: *mut TrapContext) -> !;
+}
+
+/// bit mask of online harts
+fn online_mask() -> usize {
+    percpu::online_harts().fold(0, |mask, cpu| mask | 1 << cpu.hart_id())
+}
+
+/// let ipi from other harts wake this hart or force its vcpu to exit
+fn enable_kick() {
+    unsafe {
+        sie::set_ssoft();
+    }
+}
+
+/// acknowledge ipi
+#[inline]
+pub fn clear_kick() {
+    unsafe {
+        core::arch::asm!("csrci sip, 2");
+    }
+}
+
+/// force vcpu running on hart to exit to hypervisor,or wake hart if it's idle
+pub fn kick(hart_id: usize) {
+    sbi_send_ipi(1 << hart_id, 0);
+}
+
+/// flush g stage tlb of every hart,after g stage page table of a running guest changes
+pub fn flush_guest_tlb_all() {
+    unsafe {
+        flush_guest_tlb();
+    }
+    let mask = online_mask();
+    if sbi_remote_hfence_gvma(mask, 0) != SBI_SUCCESS {
+        // g stage tlb is flushed on every vm entry
+        for hart in (0..MAX_HARTS).filter(|hart| mask & 1 << hart != 0) {
+            kick(hart);
+        }
+    }
+}
+
+/// start every other hart host device tree lists,called by boot hart once guests are created
+pub fn start_secondary_harts(boot_hart: usize) {
+    for cpu in host_fdt().cpus() {
+        let hart = cpu.ids().first();
+        if hart == boot_hart {
+            continue;
+        }
+        if hart >= MAX_HARTS {
+            println!("[hypervisor] hart {} has no stack,leave it stopped", hart);
+            continue;
+        }
+        if sbi_hart_start(hart, _secondary_start as usize, 0) != SBI_SUCCESS {
+            println!("[hypervisor] fail to start hart {}", hart);
+        }
+    }
+}
+
+/// rust entry of secondary harts,see `_secondary_start` in entry.S
+#[no_mangle]
+pub extern "C" fn secondary_entry(hart_id: usize) -> ! {
+    percpu::init(hart_id);
+    hpm_guard().activate();
+    set_hyp_trap_handler();
+    println!("[hypervisor] hart {} online", hart_id);
+    hart_loop(hart_id, core::ptr::null_mut())
+}
+
+/// run vcpus on this hart forever,wait for ipi when there is nothing to run
+///
+/// left is context of vcpu which just left this hart,or null
+#[no_mangle]
+pub extern "C" fn hart_loop(hart_id: usize, left: *mut TrapContext) -> ! {
+    percpu::with(|cpu| cpu.set_current(core::ptr::null_mut()));
+    // we are on hart stack now,other harts may take the vcpu from here on
+    if !left.is_null() {
+        release_vcpu(left);
+    }
+    enable_kick();
+    schedule::enable_tick();
+    // an msi to a guest file of a blocked vcpu wakes this hart from wfi
+    if host_imsic().is_some() {
+        enable_sgei();
+    }
+    if host_plic().is_some() {
+        unsafe { sie::set_sext() };
+    }
+    loop {
+        if schedule::refill_due() {
+            refill_credits();
+        }
+        finish_stops();
+        // nothing else polls console and devices while every vcpu is blocked
+        housekeeping();
+        wake_blocked_vcpus();
+        take_guest_interrupts();
+        handle_external_irqs();
+        if let Some((ctx, time_slice)) = next_vcpu(hart_id) {
+            percpu::with(|cpu| cpu.set_current(ctx));
+            schedule::start_slice(time_slice);
+            unsafe { vm_entry(ctx) }
+        }
+        schedule::stop_tick();
+        if all_halted() {
+            println!("[hypervisor] every guest is halted,shutdown");
+            sbi_shutdown()
+        }
+        unsafe {
+            riscv::asm::wfi();
+        }
+        clear_kick();
+        percpu::count(|stats| &stats.wakeups);
+    }
+}
+
+/// vcpu of ctx leaves this hart,go back to `hart_loop` on trap stack of this hart
+///
+/// we are still on hypervisor stack of the vcpu,which another hart uses once it runs the vcpu. so
+/// vcpu is released only after stack is switched
+pub fn leave_hart(ctx: *mut TrapContext) -> ! {
+    let (hart_id, stack_top) = percpu::with(|cpu| (cpu.hart_id(), cpu.trap_stack_top()));
+    unsafe { __hart_loop(hart_id, stack_top, ctx) }
+}
@@ -4,19 +4,27 @@
 
 use crate::arch::page_table::PageTableAdapter;
 use crate::arch::set_hyp_trap_handler;
-use crate::constants::GUEST_MEM_SIZE;
-use crate::hypervisor::{create_guest, init_guest_queue, run_guest};
-use crate::mm::{mm_init, HostAddressSpace};
+use crate::bundle::{find_host_bundle, Bundle};
+use crate::config::{bundle_configs, VmConfig};
+use crate::hypervisor::{create_guest, init_guest_queue, run_guests};
+use crate::mm::{hpm_guard, mm_init, HostAddressSpace, MapPermission};
+use alloc::vec;
 use core::arch::global_asm;
 use core::ptr::NonNull;
 
 mod arch;
+mod bundle;
+mod config;
 mod console;
 mod constants;
+mod device_tree;
 mod guest;
 mod hypervisor;
 mod lang_items;
+mod measure;
 mod mm;
+mod monitor;
+mod percpu;
 mod sbi;
 mod schedule;
 mod iommu;
@@ -30,24 +38,58 @@ global_asm!(include_str!("arch/riscv/entry.S"));
 static GUEST_IMAGE: [u8; include_bytes!("../guest.bin").len()] = *include_bytes!("../guest.bin");
 
 #[no_mangle]
-pub fn hypervisor_entry(hart_id: usize, dtb_paddress: usize) {
+pub fn hypervisor_entry(hart_id: usize, dtb_paddress: usize) -> ! {
+    percpu::init(hart_id);
     if arch::is_cup_support_virtualization() {
         println!("current cpu support hardware virtualization!");
         // before_start_check();
     }
-    walk_fdt(dtb_paddress);
+    // dtb may lie beyond identity map,read its size before paging is on
+    let dtb_size = walk_fdt(dtb_paddress);
     mm_init();
+    device_tree::init_host_fdt(dtb_paddress, dtb_size);
+    arch::intc::imsic::init_imsic();
+    arch::intc::plic::init_plic();
     init_guest_queue();
     println!("[hypervisor] init host address space success!");
     set_hyp_trap_handler();
     println!("[hypervisor]set hyp trap handler");
-    unsafe {
-        let guest_id = create_guest(1, GUEST_MEM_SIZE, &GUEST_IMAGE);
-        println!("load guest bin!");
-        run_guest(guest_id);
-    }
+    create_guests();
+    println!("load guest bin!");
+    println!("[hypervisor] press ctrl-a h for monitor help");
+    run_guests(hart_id)
+}
+
+/// create guests from bundle loaded by qemu or embedded one
+///
+/// an embedded image which is not a bundle boots as the only guest with default config
+pub fn create_guests() {
+    let data: &'static [u8] = match find_host_bundle() {
+        Some((start, end)) => {
+            hpm_guard().map_physical(start, end - start, MapPermission::R);
+            unsafe { core::slice::from_raw_parts(start as *const u8, end - start) }
+        }
+        None => &GUEST_IMAGE,
+    };
 
-    sbi::sbi_shutdown()
+    let configs = if Bundle::is_bundle(data) {
+        bundle_configs(&Bundle::parse(data).expect("[hypervisor] broken guest bundle"))
+    } else {
+        vec![VmConfig::new("guest", data)]
+    };
+
+    let mut created = 0;
+    for config in configs.iter() {
+        match create_guest(config) {
+            Ok(guest_id) => {
+                println!("[hypervisor] create guest {} from {}", guest_id, config.name);
+                created += 1;
+            }
+            Err(err) => println!("[hypervisor] fail to create guest {}: {:?}", config.name, err),
+        }
+    }
+    // images stay mapped,guests load them again when they restart
+    assert!(created > 0, "[hypervisor] no guest to run");
 }
 
 pub fn before_start_check() {
@@ -55,9 +97,11 @@ pub fn before_start_check() {
     {}
 }
 
-pub fn walk_fdt(address: usize) {
+/// print nodes of host device tree,return its total size
+pub fn walk_fdt(address: usize) -> usize {
     let mut fdt = unsafe { fdt::Fdt::from_ptr(address as *const u8).unwrap() };
     for node in fdt.all_nodes() {
         println!("[INFO] find device node {:?}", node.name);
     }
+    fdt.total_size()
 }
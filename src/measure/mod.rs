@@ -0,0 +1,110 @@
+//! measured boot of guests
+//!
+//! every kernel,initrd and device tree loaded into a guest is hashed with sha-256 and appended to the
+//! measurement log of that guest. with feature `allowlist`,images from outside whose digest is not in
+//! `allowlist.txt` are refused. generated device trees are measured but never refused
+//!
+//! log lives as long as its guest. a reset appends a `Reset` entry and images measured again after
+//! it,so what ran before a reboot can still be attested
+
+mod sha256;
+
+use alloc::vec::Vec;
+use core::fmt;
+pub use sha256::sha256;
+
+#[cfg(feature = "allowlist")]
+const ALLOWLIST: &str = include_str!("../../allowlist.txt");
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct Digest(pub [u8; 32]);
+
+impl fmt::Display for Digest {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for byte in self.0.iter() {
+            write!(f, "{:02x}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for Digest {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "sha256:{}", self)
+    }
+}
+
+/// what was measured,value is reported to guest by vendor sbi call
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u32)]
+pub enum MeasureEvent {
+    Kernel = 1,
+    Initrd = 2,
+    Dtb = 3,
+    // device tree hypervisor generated for guest
+    GeneratedDtb = 4,
+    // guest was reset,entries after it belong to the next boot. digest is that of no data
+    Reset = 5,
+}
+
+impl MeasureEvent {
+    /// image comes from outside hypervisor,so allowlist applies to it
+    #[inline]
+    pub fn is_external(&self) -> bool {
+        !matches!(self, Self::GeneratedDtb | Self::Reset)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Measurement {
+    pub event: MeasureEvent,
+    pub digest: Digest,
+}
+
+impl Measurement {
+    /// size of entry handed to guest: event u32 | reserved u32 | digest [u8; 32]
+    pub const RAW_SIZE: usize = 40;
+
+    pub fn to_bytes(&self) -> [u8; Self::RAW_SIZE] {
+        let mut raw = [0; Self::RAW_SIZE];
+        raw[..4].copy_from_slice(&(self.event as u32).to_le_bytes());
+        raw[8..].copy_from_slice(&self.digest.0);
+        raw
+    }
+}
+
+/// measurements of one guest,entries are only ever appended
+#[derive(Default)]
+pub struct MeasurementLog {
+    entries: Vec<Measurement>,
+}
+
+impl MeasurementLog {
+    /// hash data and append it to log
+    pub fn extend(&mut self, event: MeasureEvent, data: &[u8]) -> Digest {
+        let digest = sha256(data);
+        self.entries.push(Measurement { event, digest });
+        digest
+    }
+
+    #[inline]
+    pub fn entries(&self) -> &[Measurement] {
+        &self.entries
+    }
+}
+
+/// whether digest is in allowlist compiled into hypervisor,always true without feature `allowlist`
+#[cfg(feature = "allowlist")]
+pub fn is_allowed(digest: &Digest) -> bool {
+    let hex = alloc::format!("{}", digest);
+    ALLOWLIST
+        .lines()
+        .map(|line| line.split('#').next().unwrap().trim())
+        .any(|line| line.eq_ignore_ascii_case(&hex))
+}
+
+#[cfg(not(feature = "allowlist"))]
+#[inline]
+pub fn is_allowed(_digest: &Digest) -> bool {
+    true
+}
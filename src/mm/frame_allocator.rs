@@ -46,6 +46,11 @@ impl StackFrameAllocator {
         self.current_ppn = start.0;
         self.end = end.0;
     }
+
+    /// frames never handed out plus recycled ones
+    pub fn free_frames(&self) -> usize {
+        self.end - self.current_ppn + self.recycled.len()
+    }
 }
 
 impl FrameAllocator for StackFrameAllocator {
@@ -120,6 +125,15 @@ pub fn frame_alloc() -> Option<FrameTracker> {
     }
 }
 
+/// frames left to allocate,0 before allocator is initialized
+pub fn free_frames() -> usize {
+    unsafe {
+        FRAME_ALLOCATOR
+            .get()
+            .map_or(0, |frame_allocator| frame_allocator.lock().free_frames())
+    }
+}
+
 pub fn n_frames_alloc(order: usize) -> Option<Vec<FrameTracker>> {
     unsafe {
         let frame_allocator_ref = FRAME_ALLOCATOR.get_mut();
@@ -6,7 +6,7 @@ mod vm_space;
 use crate::arch::page_table::PageTableAdapter;
 use crate::mm::frame_allocator::init_frame_allocator;
 use crate::mm::heap_allocator::init_heap;
-pub use frame_allocator::{frame_alloc, n_frames_alloc, FrameTracker};
+pub use frame_allocator::{frame_alloc, free_frames, n_frames_alloc, FrameTracker};
 pub use page_table::{GStagePageTable, PageTable};
 use spin::{Mutex, MutexGuard, Once};
 pub use vm_space::{
@@ -1,7 +1,7 @@
-use crate::arch::mm::{GUEST_START_VA, KERNEL_START_PA};
+use crate::arch::mm::GUEST_START_VA;
 use crate::arch::page_table::{
-    active_page_table, PPNRange, PTEFlags, PhysAddress, PhysPageNum, VPNRange, VirtAddress,
-    VirtPageNum,
+    active_page_table, flush_host_tlb, PPNRange, PTEFlags, PageTableEntry, PhysAddress,
+    PhysPageNum, VPNRange, VirtAddress, VirtPageNum,
 };
 use crate::constants::{GUEST_STACK_SIZE, GUEST_STACK_TOP, MEMORY_END, PAGE_SIZE, TRAMPOLINE};
 use crate::mm::frame_allocator::FrameTracker;
@@ -9,6 +9,7 @@ use crate::mm::page_table::{fill_guest_page_table, CombinedWalker};
 use crate::mm::{frame_alloc, GStagePageTable, PageTable};
 use crate::GUEST_IMAGE;
 use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
 use alloc::vec;
 use alloc::vec::Vec;
 use bitflags::bitflags;
@@ -41,7 +42,8 @@ pub struct MemRegion<P: PageTable> {
     pub start_vpn: VirtPageNum, //must be page boundary align
     pub page_nums: usize,
     pub map_type: MapType,
-    pub data_frames: BTreeMap<VirtPageNum, FrameTracker>,
+    // frames may be shared with other regions after same page merging
+    pub data_frames: BTreeMap<VirtPageNum, Arc<FrameTracker>>,
     pub permission: MapPermission,
     _marker: PhantomData<P>,
 }
@@ -91,7 +93,7 @@ impl<P: PageTable> MemRegion<P> {
             MapType::Framed => {
                 let frame_tracker = frame_alloc().unwrap();
                 let ppn = frame_tracker.ppn;
-                self.data_frames.insert(vpn, frame_tracker);
+                self.data_frames.insert(vpn, Arc::new(frame_tracker));
                 ppn
             }
         };
@@ -132,6 +134,21 @@ impl<P: PageTable> MemRegion<P> {
     pub fn get_size(&self) -> usize {
         self.page_nums * PAGE_SIZE
     }
+
+    #[inline]
+    pub fn frame(&self, vpn: VirtPageNum) -> Option<&Arc<FrameTracker>> {
+        self.data_frames.get(&vpn)
+    }
+
+    /// replace frame backing vpn,return the old one
+    pub fn replace_frame(
+        &mut self,
+        vpn: VirtPageNum,
+        frame: Arc<FrameTracker>,
+    ) -> Option<Arc<FrameTracker>> {
+        assert_eq!(self.map_type, MapType::Framed);
+        self.data_frames.insert(vpn, frame)
+    }
 }
 
 extern "C" {
@@ -166,6 +183,10 @@ pub struct HostAddressSpace<P: PageTable> {
 pub struct GuestAddressSpace<G: GStagePageTable> {
     pub guest_id: usize,
     pub regions: Vec<MemRegion<G>>,
+    // pieces of guest ram used by kernel,device tree,initrd and boot stack,only for bookkeeping
+    pub reserved: Vec<MemRegion<G>>,
+    // host device windows mapped to guest directly
+    pub passthrough: Vec<MemRegion<G>>,
     pub page_table: G,
 }
 
@@ -209,14 +230,6 @@ impl<P: PageTable> HostAddressSpace<P> {
         self.regions.push(guest_image_region);
     }
 
-    pub fn unmap_embedded_guest(&mut self) {
-        let start_addr = GUEST_IMAGE.as_ptr() as usize;
-        let guest_image_region = self.regions.last().unwrap();
-        assert_eq!(guest_image_region.start_vpn.page_base_va().0, start_addr);
-        let mut region = self.regions.pop().unwrap();
-        region.unmap(&mut self.page_table);
-    }
-
     pub fn new_host_space() -> Self {
         let mut host_vm_space = Self::new_bare();
 
@@ -272,11 +285,15 @@ impl<P: PageTable> HostAddressSpace<P> {
         host_vm_space
     }
 
-    pub fn alloc_gpm<G: GStagePageTable>(
+    /// back guest physical memory [gpa,gpa + size) with new frames
+    ///
+    /// frames are mapped to hypervisor address space too,return the host region of them
+    pub fn alloc_guest_ram<G: GStagePageTable>(
         &mut self,
-        guest_id: usize,
+        gpm: &mut GuestAddressSpace<G>,
+        gpa: usize,
         size: usize,
-    ) -> (GuestAddressSpace<G>, MemRegion<P>) {
+    ) -> MemRegion<P> {
         let mut host_map_region = MemRegion::<P>::new(
             self.gpm_base.into(),
             size,
@@ -292,13 +309,12 @@ impl<P: PageTable> HostAddressSpace<P> {
         let host_end_vpn = host_map_region.end_vpn();
         let page_nums = host_map_region.page_nums;
 
-        let mut gpm = GuestAddressSpace::<G>::new_bare(guest_id);
-        let guest_start_ppn = PhysPageNum::from(PhysAddress(KERNEL_START_PA));
+        let guest_start_ppn = PhysPageNum::from(PhysAddress(gpa));
         let guest_end_ppn = PhysPageNum(guest_start_ppn.0 + page_nums);
 
         // todo 因为现在的FrameTracker实现了drop又没有做引用计数，所以暂时用没有携带任何页面的mem region 填充 guest pm space
         let guest_mem_region = MemRegion::<G>::new(
-            VirtAddress(KERNEL_START_PA),
+            VirtAddress(gpa),
             size,
             MapType::Framed,
             MapPermission::R | MapPermission::W | MapPermission::X,
@@ -314,7 +330,7 @@ impl<P: PageTable> HostAddressSpace<P> {
         fill_guest_page_table(combined_walker);
         gpm.regions.push(guest_mem_region);
 
-        (gpm, host_map_region)
+        host_map_region
     }
 
     /// alloc stack regions and map to hyp address space
@@ -331,6 +347,48 @@ impl<P: PageTable> HostAddressSpace<P> {
         stack_region
     }
 
+    /// identical map physical memory outside hypervisor image,such as device tree from sbi
+    ///
+    /// pages mapped already are skipped
+    pub fn map_physical(&mut self, pa: usize, size: usize, permission: MapPermission) {
+        let start = PhysAddress(pa).current_page_number();
+        let end = PhysAddress(pa + size).next_page_number();
+        let pte_flags = PTEFlags::from_bits(permission.bits).unwrap();
+        for ppn in PPNRange::new(start, end) {
+            let vpn = VirtPageNum(ppn.0);
+            if self
+                .page_table
+                .find_pte(vpn)
+                .map_or(false, |pte| pte.is_valid())
+            {
+                continue;
+            }
+            self.page_table.map(vpn, ppn, pte_flags);
+        }
+        unsafe {
+            flush_host_tlb();
+        }
+    }
+
+    /// let a mapped vpn point to another frame
+    ///
+    /// used when guest memory is merged or copied on write
+    pub fn remap_page(&mut self, vpn: VirtPageNum, ppn: PhysPageNum, permission: MapPermission) {
+        let pte = self.page_table.find_pte(vpn).unwrap();
+        assert!(
+            pte.is_valid(),
+            "vpn {:?} is not mapped before remapping",
+            vpn
+        );
+        *pte = PageTableEntry::new(
+            ppn,
+            PTEFlags::from_bits(permission.bits).unwrap() | PTEFlags::V,
+        );
+        unsafe {
+            flush_host_tlb();
+        }
+    }
+
     /// active page based virtual address space
     pub fn activate(&self) {
         let token = self.page_table.token();
@@ -345,9 +403,120 @@ impl<G: GStagePageTable> GuestAddressSpace<G> {
         Self {
             guest_id,
             regions: vec![],
+            reserved: vec![],
+            passthrough: vec![],
             page_table: G::new_guest_stage(),
         }
     }
+
+    /// map host physical window [hpa,hpa + size) to gpa,guest accesses it without trapping
+    pub fn map_passthrough(&mut self, gpa: usize, hpa: usize, size: usize) {
+        let mut region = MemRegion::<G>::new(
+            VirtAddress(gpa),
+            size,
+            MapType::new_linear(PhysAddress(hpa)),
+            MapPermission::R | MapPermission::W | MapPermission::U,
+        );
+        region.map(&mut self.page_table);
+        self.passthrough.push(region);
+    }
+
+    /// drop passthrough window mapped at gpa,guest accesses to it trap again
+    pub fn unmap_passthrough(&mut self, gpa: usize) {
+        if let Some(i) = self
+            .passthrough
+            .iter()
+            .position(|region| region.start_vpn().page_base_va().0 == gpa)
+        {
+            let mut region = self.passthrough.remove(i);
+            region.unmap(&mut self.page_table);
+        }
+    }
+
+    /// record [gpa,gpa + size) as used
+    ///
+    /// fail if it's not inside guest memory or overlaps something reserved before
+    pub fn reserve(&mut self, gpa: usize, size: usize) -> bool {
+        let region = MemRegion::<G>::new(
+            VirtAddress(gpa),
+            size,
+            MapType::new_linear(PhysAddress(gpa)),
+            MapPermission::R | MapPermission::W | MapPermission::X,
+        );
+        let inside = self
+            .regions
+            .iter()
+            .any(|ram| ram.start_vpn() <= region.start_vpn() && region.end_vpn() <= ram.end_vpn());
+        let overlapped = self
+            .reserved
+            .iter()
+            .any(|used| used.start_vpn() < region.end_vpn() && region.start_vpn() < used.end_vpn());
+        if !inside || overlapped {
+            return false;
+        }
+        self.reserved.push(region);
+        true
+    }
+
+    /// forget everything reserved,guest ram is about to be loaded again
+    pub fn release_all(&mut self) {
+        self.reserved.clear();
+    }
+
+    /// [start,end) of everything reserved,sorted
+    fn reserved_ranges(&self) -> Vec<(usize, usize)> {
+        let mut used: Vec<(usize, usize)> = self
+            .reserved
+            .iter()
+            .map(|r| (r.start_vpn().page_base_va().0, r.end_vpn().page_base_va().0))
+            .collect();
+        used.sort_unstable();
+        used
+    }
+
+    /// lowest align aligned gpa not below from where size bytes are not reserved yet
+    pub fn find_free(&self, size: usize, align: usize, from: usize) -> Option<usize> {
+        let align_up = |addr: usize| (addr + align - 1) / align * align;
+        let used = self.reserved_ranges();
+        for ram in self.regions.iter() {
+            let end = ram.end_vpn().page_base_va().0;
+            let mut candidate = align_up(ram.start_vpn().page_base_va().0.max(from));
+            for &(used_start, used_end) in used.iter() {
+                if candidate + size <= used_start {
+                    break;
+                }
+                candidate = candidate.max(align_up(used_end));
+            }
+            if candidate + size <= end {
+                return Some(candidate);
+            }
+        }
+        None
+    }
+
+    /// (start,size) of largest align aligned gap not below from where nothing is reserved
+    pub fn largest_free(&self, align: usize, from: usize) -> Option<(usize, usize)> {
+        let align_up = |addr: usize| (addr + align - 1) / align * align;
+        let used = self.reserved_ranges();
+        let mut largest: Option<(usize, usize)> = None;
+        for ram in self.regions.iter() {
+            let end = ram.end_vpn().page_base_va().0;
+            let mut candidate = align_up(ram.start_vpn().page_base_va().0.max(from));
+            for &(used_start, used_end) in used.iter().chain(core::iter::once(&(end, end))) {
+                let gap_end = used_start.min(end);
+                if gap_end > candidate
+                    && largest.map_or(true, |(_, size)| gap_end - candidate > size)
+                {
+                    largest = Some((candidate, gap_end - candidate));
+                }
+                candidate = candidate.max(align_up(used_end));
+                if candidate >= end {
+                    break;
+                }
+            }
+        }
+        largest
+    }
 }
 
 impl<S: GStagePageTable> AddressSpace<S> for GuestAddressSpace<S> {
@@ -0,0 +1,286 @@
+//! hypervisor console monitor
+//!
+//! host console input goes to guests,except what follows escape key ctrl-a:
+//! `ctrl-a h` prints help,`ctrl-a m` prints measurement logs,`ctrl-a c` prints counters of harts,
+//! `ctrl-a s` prints cpu time and interrupt latency of vcpus,`ctrl-a k` prints memory same page
+//! merging saves,`ctrl-a ctrl-a` sends ctrl-a to guest.
+//! `ctrl-a a` reads a line `guest vcpu harts` and repins the vcpu. `ctrl-a p`,`ctrl-a u` and
+//! `ctrl-a x` read a guest id and pause,resume or halt the guest.
+//! commands run in `poll` between vm exit and vm entry,when guest queue is not locked
+
+use crate::config::parse_harts;
+use crate::device_tree::host_timebase_frequency;
+use crate::guest::StateError;
+use crate::hypervisor::page_merge::saved_memory;
+use crate::hypervisor::{halt_guest, pause_guest, queue_guard, resume_guest, set_vcpu_affinity};
+use crate::percpu;
+use crate::sbi::{sbi_get_char, sbi_put_char};
+use crate::{print, println};
+use alloc::collections::VecDeque;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::sync::atomic::Ordering;
+use spin::Mutex;
+
+const ESCAPE: u8 = 0x01;
+// commands which read a line of arguments first
+const LINE_COMMANDS: &[u8] = b"apux";
+
+static MONITOR: Mutex<Monitor> = Mutex::new(Monitor::new());
+
+struct Monitor {
+    // last char was escape key
+    escaped: bool,
+    // input for guests
+    rx: VecDeque<u8>,
+    // monitor commands not run yet,with their arguments
+    commands: VecDeque<(u8, String)>,
+    // command whose arguments are being typed
+    typing: Option<(u8, String)>,
+}
+
+impl Monitor {
+    const fn new() -> Self {
+        Self {
+            escaped: false,
+            rx: VecDeque::new(),
+            commands: VecDeque::new(),
+            typing: None,
+        }
+    }
+
+    /// take c as part of arguments being typed,echo it
+    fn type_char(&mut self, c: u8) {
+        let (_, line) = self.typing.as_mut().unwrap();
+        match c {
+            b'\r' | b'\n' => {
+                println!("");
+                let command = self.typing.take().unwrap();
+                self.commands.push_back(command);
+            }
+            // backspace or delete
+            0x08 | 0x7f => {
+                if line.pop().is_some() {
+                    print!("\x08 \x08");
+                }
+            }
+            _ => {
+                line.push(c as char);
+                sbi_put_char(c as usize);
+            }
+        }
+    }
+
+    /// move everything host console has into rx or commands
+    fn pump(&mut self) {
+        loop {
+            let c = sbi_get_char();
+            if c == usize::MAX {
+                break;
+            }
+            let c = c as u8;
+            if self.typing.is_some() {
+                self.type_char(c);
+            } else if self.escaped {
+                self.escaped = false;
+                if c == ESCAPE {
+                    self.rx.push_back(c);
+                } else if LINE_COMMANDS.contains(&c) {
+                    print!("[monitor] {}: ", prompt(c));
+                    self.typing = Some((c, String::new()));
+                } else {
+                    self.commands.push_back((c, String::new()));
+                }
+            } else if c == ESCAPE {
+                self.escaped = true;
+            } else {
+                self.rx.push_back(c);
+            }
+        }
+    }
+}
+
+/// arguments line command c reads
+fn prompt(c: u8) -> &'static str {
+    match c {
+        b'a' => "guest vcpu harts",
+        _ => "guest",
+    }
+}
+
+/// next char of host console meant for guests
+pub fn getchar() -> Option<u8> {
+    let mut monitor = MONITOR.lock();
+    monitor.pump();
+    monitor.rx.pop_front()
+}
+
+/// run pending monitor commands,guest queue must not be locked by caller
+pub fn poll() {
+    let commands: Vec<(u8, String)> = {
+        let mut monitor = MONITOR.lock();
+        monitor.pump();
+        monitor.commands.drain(..).collect()
+    };
+    for (command, args) in commands {
+        match command {
+            b'h' => print_help(),
+            b'm' => print_measurements(),
+            b'c' => print_hart_counters(),
+            b's' => print_vcpu_stats(),
+            b'k' => print_saved_memory(),
+            b'a' => repin_vcpu(&args),
+            b'p' => change_guest(&args, pause_guest, "paused"),
+            b'u' => change_guest(&args, resume_guest, "resumed"),
+            b'x' => change_guest(&args, halt_guest, "halted"),
+            _ => println!("[monitor] unknown command,ctrl-a h for help"),
+        }
+    }
+}
+
+fn print_help() {
+    println!("[monitor] ctrl-a h    this help");
+    println!("[monitor] ctrl-a m    measurement logs of guests");
+    println!("[monitor] ctrl-a c    counters of harts");
+    println!("[monitor] ctrl-a s    cpu time and interrupt latency of vcpus");
+    println!("[monitor] ctrl-a k    memory saved by same page merging");
+    println!("[monitor] ctrl-a a    repin vcpu,then type guest vcpu harts like 0 1 2-3");
+    println!("[monitor] ctrl-a p    pause guest,then type its id");
+    println!("[monitor] ctrl-a u    resume paused guest,then type its id");
+    println!("[monitor] ctrl-a x    halt guest,then type its id");
+    println!("[monitor] ctrl-a ctrl-a  send ctrl-a to guest");
+}
+
+fn print_measurements() {
+    for guest in queue_guard().iter() {
+        println!("[monitor] guest {} {}", guest.get_id(), guest.get_name());
+        for (i, measurement) in guest.measurements().iter().enumerate() {
+            println!(
+                "[monitor]   {} {:?} {}",
+                i, measurement.event, measurement.digest
+            );
+        }
+    }
+}
+
+fn print_hart_counters() {
+    for cpu in percpu::online_harts() {
+        let stats = &cpu.stats;
+        println!(
+            "[monitor] hart {} vm exits {} kicks {} wakeups {}",
+            cpu.hart_id(),
+            stats.vm_exits.load(Ordering::Relaxed),
+            stats.kicks.load(Ordering::Relaxed),
+            stats.wakeups.load(Ordering::Relaxed)
+        );
+    }
+}
+
+/// runtime and steal time of every vcpu,steal is time it waited on run queues while runnable,and
+/// how long interrupts injected into it took to reach it
+fn print_vcpu_stats() {
+    let ticks_per_ms = (host_timebase_frequency() / 1000).max(1);
+    let ms = |ticks: usize| ticks / ticks_per_ms;
+    let us = |ticks: usize| ticks * 1000 / ticks_per_ms;
+    for guest in queue_guard().iter() {
+        let share = guest.share();
+        match share.cap {
+            Some(cap) => println!(
+                "[monitor] guest {} {} {:?} weight {} cap {}%",
+                guest.get_id(),
+                guest.get_name(),
+                guest.state(),
+                share.weight,
+                cap
+            ),
+            None => println!(
+                "[monitor] guest {} {} {:?} weight {}",
+                guest.get_id(),
+                guest.get_name(),
+                guest.state(),
+                share.weight
+            ),
+        }
+        for (vcpu_id, vcpu) in guest.vcpus().iter().enumerate() {
+            let account = vcpu.account();
+            println!(
+                "[monitor]   vcpu {} {:?} hart {:?} affinity {:#x} runtime {}ms steal {}ms idle {}ms credit {}ms",
+                vcpu_id,
+                vcpu.state(),
+                vcpu.hart(),
+                vcpu.affinity(),
+                ms(account.runtime()),
+                ms(account.steal()),
+                ms(account.idle()),
+                account.credit() / ticks_per_ms as isize
+            );
+            let irqs = vcpu.irq_latency();
+            println!(
+                "[monitor]     irqs injected {} delivered {} latency avg {}us max {}us",
+                irqs.injected,
+                irqs.delivered,
+                us(irqs.average()),
+                us(irqs.max)
+            );
+        }
+    }
+}
+
+/// saved bytes of every guest as of last full merge scan
+fn print_saved_memory() {
+    for guest in queue_guard().iter() {
+        println!(
+            "[monitor] guest {} {} saves {} KiB",
+            guest.get_id(),
+            guest.get_name(),
+            saved_memory(guest.get_id()) / 1024
+        );
+    }
+}
+
+/// args are `guest vcpu harts`,harts like `0,2-3`
+fn repin_vcpu(args: &str) {
+    let mut args = args.split_whitespace();
+    let mut next_id = || args.next().and_then(|arg| arg.parse::<usize>().ok());
+    let (guest_id, vcpu_id) = match (next_id(), next_id()) {
+        (Some(guest_id), Some(vcpu_id)) => (guest_id, vcpu_id),
+        _ => {
+            println!("[monitor] usage: guest vcpu harts,like 0 1 2-3");
+            return;
+        }
+    };
+    let affinity = match args.next().and_then(parse_harts) {
+        Some(affinity) => affinity,
+        None => {
+            println!("[monitor] bad hart set");
+            return;
+        }
+    };
+    if !percpu::any_online(affinity) {
+        println!("[monitor] none of harts {:#x} is online", affinity);
+        return;
+    }
+    if set_vcpu_affinity(guest_id, vcpu_id, affinity) {
+        println!(
+            "[monitor] guest {} vcpu {} runs on harts {:#x}",
+            guest_id, vcpu_id, affinity
+        );
+    } else {
+        println!("[monitor] guest {} has no vcpu {}", guest_id, vcpu_id);
+    }
+}
+
+/// args are `guest`,run op on it
+fn change_guest(args: &str, op: fn(usize) -> Result<(), StateError>, done: &str) {
+    let guest_id = match args.trim().parse::<usize>() {
+        Ok(guest_id) => guest_id,
+        Err(_) => {
+            println!("[monitor] usage: guest,like 0");
+            return;
+        }
+    };
+    match op(guest_id) {
+        Ok(()) => println!("[monitor] guest {} {}", guest_id, done),
+        Err(err) => println!("[monitor] guest {}: {:?}", guest_id, err),
+    }
+}
@@ -0,0 +1,196 @@
+//! per hart data of hypervisor,addressed by tp in hs mode
+//!
+//! tp of a hart points to its `PerCpu` whenever hypervisor code runs there. guest owns tp while it
+//! runs,so `__vm_entry` saves tp of hypervisor into vcpu context and `__vm_exit` loads it back.
+//! more per hart statics are declared by `percpu!`. accessors only lend a reference to a closure,so
+//! it never outlives the code running on this hart,a vcpu may be on another hart next time it exits
+
+use crate::arch::TrapContext;
+use crate::constants::{HART_STACK_SIZE, MAX_HARTS};
+use core::sync::atomic::{AtomicBool, AtomicPtr, AtomicUsize, Ordering};
+
+extern "C" {
+    fn boot_stack_top();
+}
+
+/// per hart counters,other harts may read them
+pub struct HartStats {
+    pub vm_exits: AtomicUsize,
+    // ipi taken from other harts
+    pub kicks: AtomicUsize,
+    // hart woken up while idle
+    pub wakeups: AtomicUsize,
+}
+
+impl HartStats {
+    const fn new() -> Self {
+        Self {
+            vm_exits: AtomicUsize::new(0),
+            kicks: AtomicUsize::new(0),
+            wakeups: AtomicUsize::new(0),
+        }
+    }
+}
+
+pub struct PerCpu {
+    hart_id: usize,
+    online: AtomicBool,
+    // context of vcpu running on hart,null when idle
+    current: AtomicPtr<TrapContext>,
+    // vcpu which ran last,vs stage tlb of hart may hold its translations
+    last: AtomicPtr<TrapContext>,
+    pub stats: HartStats,
+}
+
+impl PerCpu {
+    const fn new(hart_id: usize) -> Self {
+        Self {
+            hart_id,
+            online: AtomicBool::new(false),
+            current: AtomicPtr::new(core::ptr::null_mut()),
+            last: AtomicPtr::new(core::ptr::null_mut()),
+            stats: HartStats::new(),
+        }
+    }
+
+    #[inline]
+    pub fn hart_id(&self) -> usize {
+        self.hart_id
+    }
+
+    #[inline]
+    pub fn is_online(&self) -> bool {
+        self.online.load(Ordering::Acquire)
+    }
+
+    #[inline]
+    pub fn current(&self) -> *mut TrapContext {
+        self.current.load(Ordering::Acquire)
+    }
+
+    #[inline]
+    pub fn set_current(&self, ctx: *mut TrapContext) {
+        self.current.store(ctx, Ordering::Release);
+        if !ctx.is_null() {
+            self.last.store(ctx, Ordering::Release);
+        }
+    }
+
+    #[inline]
+    pub fn last(&self) -> *mut TrapContext {
+        self.last.load(Ordering::Acquire)
+    }
+
+    /// top of stack hart traps and idles on,entry.S reserves it
+    #[inline]
+    pub fn trap_stack_top(&self) -> usize {
+        boot_stack_top as usize - self.hart_id * HART_STACK_SIZE
+    }
+}
+
+static AREAS: [PerCpu; MAX_HARTS] = {
+    let mut areas = [const { PerCpu::new(0) }; MAX_HARTS];
+    let mut hart = 0;
+    while hart < MAX_HARTS {
+        areas[hart].hart_id = hart;
+        hart += 1;
+    }
+    areas
+};
+
+/// point tp of this hart to its area and mark hart online,first thing a hart does
+pub fn init(hart_id: usize) {
+    let area = &AREAS[hart_id];
+    unsafe {
+        core::arch::asm!("mv tp, {}", in(reg) area as *const PerCpu);
+    }
+    area.online.store(true, Ordering::Release);
+}
+
+#[inline(always)]
+fn this() -> &'static PerCpu {
+    let tp: usize;
+    unsafe {
+        core::arch::asm!("mv {}, tp", out(reg) tp);
+        &*(tp as *const PerCpu)
+    }
+}
+
+/// run f with area of this hart
+#[inline]
+pub fn with<R>(f: impl FnOnce(&PerCpu) -> R) -> R {
+    f(this())
+}
+
+/// bump a counter of this hart,`percpu::count(|stats| &stats.kicks)`
+#[inline]
+pub fn count(counter: impl FnOnce(&HartStats) -> &AtomicUsize) {
+    counter(&this().stats).fetch_add(1, Ordering::Relaxed);
+}
+
+#[inline]
+pub fn hart_id() -> usize {
+    this().hart_id
+}
+
+/// area of another hart,only its atomics are meant to be touched
+#[inline]
+pub fn of(hart_id: usize) -> &'static PerCpu {
+    &AREAS[hart_id]
+}
+
+pub fn online_harts() -> impl Iterator<Item = &'static PerCpu> {
+    AREAS.iter().filter(|area| area.is_online())
+}
+
+/// some hart of mask is online
+pub fn any_online(mask: usize) -> bool {
+    online_harts().any(|cpu| mask & 1 << cpu.hart_id() != 0)
+}
+
+/// static with a copy for every hart,declared by `percpu!`
+pub struct PerCpuVar<T> {
+    vars: [T; MAX_HARTS],
+}
+
+// every hart only touches its own copy,except through `remote` which needs T: Sync
+unsafe impl<T> Sync for PerCpuVar<T> {}
+
+impl<T> PerCpuVar<T> {
+    pub const fn new(vars: [T; MAX_HARTS]) -> Self {
+        Self { vars }
+    }
+
+    /// run f with copy of this hart
+    #[inline]
+    pub fn with<R>(&self, f: impl FnOnce(&T) -> R) -> R {
+        f(&self.vars[hart_id()])
+    }
+}
+
+impl<T: Sync> PerCpuVar<T> {
+    /// copy of another hart
+    #[inline]
+    pub fn remote(&self, hart_id: usize) -> &T {
+        &self.vars[hart_id]
+    }
+}
+
+/// declare statics with a copy for every hart,init must be const
+///
+/// ```ignore
+/// percpu! {
+///     static TICKS: Cell<usize> = Cell::new(0);
+/// }
+/// TICKS.with(|ticks| ticks.set(ticks.get() + 1));
+/// ```
+#[macro_export]
+macro_rules! percpu {
+    ($($(#[$attr:meta])* $vis:vis static $name:ident: $ty:ty = $init:expr;)+) => {
+        $(
+            $(#[$attr])*
+            $vis static $name: $crate::percpu::PerCpuVar<$ty> =
+                $crate::percpu::PerCpuVar::new([const { $init }; $crate::constants::MAX_HARTS]);
+        )+
+    };
+}
@@ -25,9 +25,44 @@ pub const SBI_RESET_EXTENSION: usize = 0x53525354;
 pub const SYSTEM_RESET: usize = 0x0;
 // system reset types
 pub const SHUTDOWN: usize = 0;
+pub const COLD_REBOOT: usize = 1;
+pub const WARM_REBOOT: usize = 2;
 // system reset reason
 pub const NO_REASON: usize = 0;
 
+// ascii represent of "HSM"
+pub const SBI_HSM_EXTENSION: usize = 0x48534D;
+// sbi_hart_start(hartid,start_addr,opaque);
+pub const HART_START: usize = 0;
+pub const HART_STOP: usize = 1;
+pub const HART_GET_STATUS: usize = 2;
+// hart states
+pub const HART_STARTED: usize = 0;
+pub const HART_STOPPED: usize = 1;
+
+// ascii represent of "TIME"
+pub const SBI_TIME_EXTENSION: usize = 0x54494D45;
+// sbi_set_timer(stime_value);
+pub const SET_TIMER: usize = 0;
+
+// ascii represent of "sPI"
+pub const SBI_IPI_EXTENSION: usize = 0x735049;
+// sbi_send_ipi(hart_mask,hart_mask_base);
+pub const SEND_IPI: usize = 0;
+
+// ascii represent of "RFNC"
+pub const SBI_RFENCE_EXTENSION: usize = 0x52464E43;
+// sbi_remote_fence_i(hart_mask,hart_mask_base);
+pub const REMOTE_FENCE_I: usize = 0;
+// sbi_remote_sfence_vma(hart_mask,hart_mask_base,start_addr,size);
+pub const REMOTE_SFENCE_VMA: usize = 1;
+// sbi_remote_sfence_vma_asid(hart_mask,hart_mask_base,start_addr,size,asid);
+pub const REMOTE_SFENCE_VMA_ASID: usize = 2;
+// sbi_remote_hfence_gvma(hart_mask,hart_mask_base,start_addr,size);
+pub const REMOTE_HFENCE_GVMA: usize = 4;
+// sbi_remote_hfence_vvma(hart_mask,hart_mask_base,start_addr,size);
+pub const REMOTE_HFENCE_VVMA: usize = 6;
+
 
 #[inline(always)]
 pub fn sbi_call(sbi_extension: usize, function_id: usize, args: [usize; 3]) -> usize {
@@ -45,10 +80,82 @@ pub fn sbi_call(sbi_extension: usize, function_id: usize, args: [usize; 3]) -> u
     ret
 }
 
+/// sbi call returning both error in a0 and value in a1
+#[inline(always)]
+pub fn sbi_call_ret(sbi_extension: usize, function_id: usize, args: [usize; 3]) -> (usize, usize) {
+    let (error, value): (usize, usize);
+    unsafe {
+        asm!(
+        "ecall",
+        in("a7") sbi_extension,
+        in("a6") function_id,
+        inlateout("a0") args[0] => error,
+        inlateout("a1") args[1] => value,
+        in("a2") args[2]
+        );
+    }
+    (error, value)
+}
+
 pub fn sbi_probe_extension(extension_id: usize) -> usize {
     sbi_call(SBI_BASE_EXTENSION, PROBE_CPU_EXTENSION, [extension_id, 0, 0])
 }
 
+/// start hart at physical address start_addr,with a0 = hart_id and a1 = opaque
+pub fn sbi_hart_start(hart_id: usize, start_addr: usize, opaque: usize) -> usize {
+    sbi_call(SBI_HSM_EXTENSION, HART_START, [hart_id, start_addr, opaque])
+}
+
+/// raise supervisor software interrupt on harts of hart_mask
+pub fn sbi_send_ipi(hart_mask: usize, hart_mask_base: usize) -> usize {
+    sbi_call(SBI_IPI_EXTENSION, SEND_IPI, [hart_mask, hart_mask_base, 0])
+}
+
+/// raise supervisor timer interrupt once time reaches stime_value,clear pending one
+pub fn sbi_set_timer(stime_value: usize) -> usize {
+    sbi_call(SBI_TIME_EXTENSION, SET_TIMER, [stime_value, 0, 0])
+}
+
+/// execute fence.i on harts of hart_mask
+pub fn sbi_remote_fence_i(hart_mask: usize, hart_mask_base: usize) -> usize {
+    sbi_call(SBI_RFENCE_EXTENSION, REMOTE_FENCE_I, [hart_mask, hart_mask_base, 0])
+}
+
+/// flush whole g stage tlb on harts of hart_mask
+pub fn sbi_remote_hfence_gvma(hart_mask: usize, hart_mask_base: usize) -> usize {
+    let mut ret: usize;
+    unsafe {
+        asm!(
+        "ecall",
+        in("a7") SBI_RFENCE_EXTENSION,
+        in("a6") REMOTE_HFENCE_GVMA,
+        inlateout("a0") hart_mask => ret,
+        in("a1") hart_mask_base,
+        in("a2") 0,
+        // size of all ones means the whole address space
+        in("a3") usize::MAX
+        );
+    }
+    ret
+}
+
+/// flush whole vs stage tlb of current vmid on harts of hart_mask
+pub fn sbi_remote_hfence_vvma(hart_mask: usize, hart_mask_base: usize) -> usize {
+    let mut ret: usize;
+    unsafe {
+        asm!(
+        "ecall",
+        in("a7") SBI_RFENCE_EXTENSION,
+        in("a6") REMOTE_HFENCE_VVMA,
+        inlateout("a0") hart_mask => ret,
+        in("a1") hart_mask_base,
+        in("a2") 0,
+        in("a3") usize::MAX
+        );
+    }
+    ret
+}
+
 pub fn sbi_shutdown() -> ! {
     sbi_call(SBI_RESET_EXTENSION, SYSTEM_RESET, [SHUTDOWN, NO_REASON, 0]);
     unreachable!()
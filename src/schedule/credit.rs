@@ -0,0 +1,142 @@
+//! credit accounting of vcpus
+//!
+//! every period harts' time is handed out to active guests as credits in proportion to their
+//! weight,a cap limits what a guest gets to a percent of one hart. running burns credits,measured
+//! by time csr. vcpus with credit left run before those which used theirs up,a guest over its cap
+//! is parked until next period
+
+use riscv::register::time;
+
+pub const DEFAULT_WEIGHT: usize = 256;
+pub const MAX_WEIGHT: usize = 65535;
+/// credits are handed out this often
+pub const PERIOD_MS: usize = 30;
+
+/// now in ticks of time csr
+#[inline]
+pub fn now() -> usize {
+    time::read()
+}
+
+/// cpu time of a vcpu,in ticks of time csr
+#[derive(Debug, Clone, Copy, Default)]
+pub struct VcpuAccount {
+    // time run on harts
+    runtime: usize,
+    // time waited on run queues while runnable
+    steal: usize,
+    // time blocked in wfi
+    idle: usize,
+    credit: isize,
+    // joined a run queue at
+    ready_since: usize,
+    // started running on hart,or charged last,at
+    run_since: usize,
+    // blocked in wfi at
+    idle_since: usize,
+}
+
+impl VcpuAccount {
+    #[inline]
+    pub fn runtime(&self) -> usize {
+        self.runtime
+    }
+
+    #[inline]
+    pub fn steal(&self) -> usize {
+        self.steal
+    }
+
+    #[inline]
+    pub fn idle(&self) -> usize {
+        self.idle
+    }
+
+    #[inline]
+    pub fn credit(&self) -> isize {
+        self.credit
+    }
+
+    /// vcpu has credit left,it goes before vcpus which don't
+    #[inline]
+    pub fn is_under(&self) -> bool {
+        self.credit > 0
+    }
+
+    /// vcpu joins a run queue
+    pub fn ready(&mut self, now: usize) {
+        self.ready_since = now;
+    }
+
+    /// vcpu starts running on a hart
+    pub fn run(&mut self, now: usize) {
+        self.steal += now.saturating_sub(self.ready_since);
+        self.run_since = now;
+    }
+
+    /// vcpu waits for an interrupt
+    pub fn block(&mut self, now: usize) {
+        self.idle_since = now;
+    }
+
+    /// interrupt came for blocked vcpu
+    pub fn wake(&mut self, now: usize) {
+        self.idle += now.saturating_sub(self.idle_since);
+    }
+
+    /// burn time run since last charge,return it
+    pub fn charge(&mut self, now: usize) -> usize {
+        let ran = now.saturating_sub(self.run_since);
+        self.run_since = now;
+        self.runtime += ran;
+        self.credit -= ran as isize;
+        ran
+    }
+
+    /// credits of a new period,unused ones are kept up to one period worth
+    pub fn refill(&mut self, share: usize) {
+        let share = share as isize;
+        self.credit = (self.credit + share).min(share);
+    }
+}
+
+/// weight and cap of a guest,cap is percent of one hart
+#[derive(Debug, Clone, Copy)]
+pub struct GuestShare {
+    pub weight: usize,
+    pub cap: Option<usize>,
+    // time vcpus of guest ran in this period
+    used: usize,
+}
+
+impl GuestShare {
+    pub fn new(weight: usize, cap: Option<usize>) -> Self {
+        Self {
+            weight,
+            cap,
+            used: 0,
+        }
+    }
+
+    #[inline]
+    pub fn add_used(&mut self, ran: usize) {
+        self.used += ran;
+    }
+
+    /// guest used up its cap of this period
+    #[inline]
+    pub fn is_capped(&self, period: usize) -> bool {
+        self.cap
+            .map_or(false, |cap| self.used >= period * cap / 100)
+    }
+
+    /// credits guest gets of total handed out in a period,out of weight of all active guests
+    pub fn credits(&mut self, total: usize, total_weight: usize, period: usize) -> usize {
+        self.used = 0;
+        let share = total * self.weight / total_weight.max(1);
+        match self.cap {
+            Some(cap) => share.min(period * cap / 100),
+            None => share,
+        }
+    }
+}
@@ -0,0 +1,262 @@
+//! vcpu scheduler
+//!
+//! every hart has a run queue of vcpus ready to run,from all guests. a vcpu joins the least loaded
+//! hart of its affinity when it becomes runnable,an idle hart steals from the busiest one what it
+//! may run. vcpu runs for the time slice of its guest,then host timer programmed by sbi time kicks
+//! it off its hart if other vcpus wait there. its context is saved by `__vm_exit` and the next one
+//! is entered by `__vm_entry`
+//!
+//! which vcpu goes first is up to `credit`,vcpus with credit left are taken before the others.
+//! run queue entries are only hints,`Guest::take_vcpu` checks vcpu is still runnable. lock order is
+//! guest queue,then run queues
+//!
+//! host timer also fires for guest timers: sbi timer of the vcpu running on a hart,and the earliest
+//! timer of blocked vcpus,whichever hart sees that one due wakes them
+
+mod credit;
+
+use self::credit::PERIOD_MS;
+pub use self::credit::{now, GuestShare, VcpuAccount, DEFAULT_WEIGHT, MAX_WEIGHT};
+use crate::device_tree::{host_harts, host_timebase_frequency};
+use crate::hypervisor::smp::kick;
+use crate::percpu;
+use crate::sbi::sbi_set_timer;
+use alloc::collections::VecDeque;
+use alloc::vec::Vec;
+use core::cell::Cell;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use riscv::register::sie;
+use spin::{Mutex, Once};
+
+/// vcpu of a guest in run queues
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VcpuRef {
+    pub guest_id: usize,
+    pub vcpu_id: usize,
+}
+
+/// time slice of vcpu running on hart,in ticks of time csr
+#[derive(Clone, Copy)]
+struct Slice {
+    len: usize,
+    end: usize,
+}
+
+/// vcpu waiting on a run queue with harts it may run on,see `VCpu::placement`
+#[derive(Clone, Copy)]
+struct Waiting {
+    vcpu: VcpuRef,
+    affinity: usize,
+}
+
+/// vcpus waiting for a hart,those with credit left go first
+struct RunQueue {
+    under: VecDeque<Waiting>,
+    over: VecDeque<Waiting>,
+}
+
+impl RunQueue {
+    const fn new() -> Self {
+        Self {
+            under: VecDeque::new(),
+            over: VecDeque::new(),
+        }
+    }
+
+    #[inline]
+    fn len(&self) -> usize {
+        self.under.len() + self.over.len()
+    }
+
+    #[inline]
+    fn is_empty(&self) -> bool {
+        self.under.is_empty() && self.over.is_empty()
+    }
+
+    fn push(&mut self, waiting: Waiting, under: bool) {
+        if under {
+            self.under.push_back(waiting);
+        } else {
+            self.over.push_back(waiting);
+        }
+    }
+
+    fn pop_front(&mut self) -> Option<VcpuRef> {
+        self.under
+            .pop_front()
+            .or_else(|| self.over.pop_front())
+            .map(|waiting| waiting.vcpu)
+    }
+
+    /// the one waiting shortest which may run on hart,taken by hart stealing work
+    fn steal(&mut self, hart: usize) -> Option<VcpuRef> {
+        for queue in [&mut self.under, &mut self.over] {
+            if let Some(i) = queue
+                .iter()
+                .rposition(|waiting| waiting.affinity & 1 << hart != 0)
+            {
+                return queue.remove(i).map(|waiting| waiting.vcpu);
+            }
+        }
+        None
+    }
+}
+
+percpu! {
+    static RUN_QUEUES: Mutex<RunQueue> = Mutex::new(RunQueue::new());
+    static SLICE: Cell<Slice> = Cell::new(Slice { len: 0, end: usize::MAX });
+    // sbi timer of vcpu running on hart,in host time
+    static VCPU_TIMER: Cell<usize> = Cell::new(usize::MAX);
+}
+
+// time credits are handed out next
+static NEXT_REFILL: AtomicUsize = AtomicUsize::new(0);
+static PERIOD: Once<usize> = Once::new();
+// earliest timer of blocked vcpus,in host time
+static NEXT_WAKEUP: AtomicUsize = AtomicUsize::new(usize::MAX);
+
+/// credit period in ticks of time csr
+pub fn period() -> usize {
+    *PERIOD.call_once(|| host_timebase_frequency() * PERIOD_MS / 1000)
+}
+
+/// credits of a new period are due,only one hart gets true for a period
+pub fn refill_due() -> bool {
+    let now = now();
+    let next = NEXT_REFILL.load(Ordering::Acquire);
+    now >= next
+        && NEXT_REFILL
+            .compare_exchange(next, now + period(), Ordering::AcqRel, Ordering::Acquire)
+            .is_ok()
+}
+
+/// a blocked vcpu has to wake at time
+#[inline]
+pub fn wake_at(time: usize) {
+    NEXT_WAKEUP.fetch_min(time, Ordering::AcqRel);
+}
+
+/// timer of some blocked vcpu is due
+#[inline]
+pub fn wakeup_due() -> bool {
+    now() >= NEXT_WAKEUP.load(Ordering::Acquire)
+}
+
+/// forget timers of blocked vcpus,caller registers those of vcpus still blocked again
+#[inline]
+pub fn clear_wakeups() {
+    NEXT_WAKEUP.store(usize::MAX, Ordering::Release);
+}
+
+/// vcpus waiting on hart plus the one running there
+fn load(hart_id: usize) -> usize {
+    let running = !percpu::of(hart_id).current().is_null();
+    RUN_QUEUES.remote(hart_id).lock().len() + running as usize
+}
+
+/// put runnable vcpu on run queue of least loaded hart of its affinity,this hart wins a tie
+///
+/// affinity is harts vcpu may run on now,only hart of its guest file if it owns one. under says
+/// vcpu has credit left. if no hart of affinity is online yet,vcpu waits on the first
+/// one host has until it comes up,config and monitor never let affinity leave out every such hart
+pub fn enqueue(vcpu: VcpuRef, under: bool, affinity: usize) {
+    let this = percpu::hart_id();
+    let hart = percpu::online_harts()
+        .map(|cpu| cpu.hart_id())
+        .filter(|&hart| affinity & 1 << hart != 0)
+        .min_by_key(|&hart| (load(hart), hart != this))
+        .unwrap_or_else(|| match affinity & host_harts() {
+            0 => this,
+            listed => listed.trailing_zeros() as usize,
+        });
+    RUN_QUEUES
+        .remote(hart)
+        .lock()
+        .push(Waiting { vcpu, affinity }, under);
+    let target = percpu::of(hart);
+    if hart != this && target.is_online() && target.current().is_null() {
+        kick(hart);
+    }
+}
+
+/// next vcpu for this hart,from its own queue or stolen from other harts,busiest first
+pub fn pick_next() -> Option<VcpuRef> {
+    if let Some(vcpu) = RUN_QUEUES.with(|queue| queue.lock().pop_front()) {
+        return Some(vcpu);
+    }
+    let this = percpu::hart_id();
+    let mut others: Vec<usize> = percpu::online_harts()
+        .map(|cpu| cpu.hart_id())
+        .filter(|&hart| hart != this)
+        .collect();
+    others.sort_by_key(|&hart| core::cmp::Reverse(RUN_QUEUES.remote(hart).lock().len()));
+    others
+        .into_iter()
+        .find_map(|hart| RUN_QUEUES.remote(hart).lock().steal(this))
+}
+
+/// other vcpus wait for this hart
+#[inline]
+pub fn has_waiting() -> bool {
+    RUN_QUEUES.with(|queue| !queue.lock().is_empty())
+}
+
+/// let host timer interrupt this hart
+pub fn enable_tick() {
+    unsafe {
+        sie::set_stimer();
+    }
+}
+
+/// fire at end,when credits are handed out or when a guest timer fires,whichever comes first
+#[inline]
+fn set_timer(end: usize) {
+    let vcpu_timer = VCPU_TIMER.with(|timer| timer.get());
+    sbi_set_timer(
+        end.min(NEXT_REFILL.load(Ordering::Acquire))
+            .min(NEXT_WAKEUP.load(Ordering::Acquire))
+            .min(vcpu_timer),
+    );
+}
+
+/// sbi timer of vcpu running on this hart fires at deadline in host time,usize::MAX for none
+pub fn set_vcpu_timer(deadline: usize) {
+    VCPU_TIMER.with(|timer| timer.set(deadline));
+    set_timer(SLICE.with(|slice| slice.get().end));
+}
+
+/// vcpu entering this hart runs for len ticks before it may be preempted
+pub fn start_slice(len: usize) {
+    let end = now().saturating_add(len);
+    SLICE.with(|slice| slice.set(Slice { len, end }));
+    set_timer(end);
+}
+
+/// hart goes idle,only credit refill and timers of blocked vcpus tick until a vcpu runs again
+pub fn stop_tick() {
+    VCPU_TIMER.with(|timer| timer.set(usize::MAX));
+    SLICE.with(|slice| {
+        slice.set(Slice {
+            len: 0,
+            end: usize::MAX,
+        })
+    });
+    set_timer(usize::MAX);
+}
+
+/// host timer fired on this hart,return true if running vcpu should give up the hart
+///
+/// vcpu goes on with a new slice if nobody waits
+pub fn tick() -> bool {
+    let now = now();
+    let current = SLICE.with(|slice| slice.get());
+    if now < current.end {
+        set_timer(current.end);
+        return false;
+    }
+    if has_waiting() {
+        return true;
+    }
+    start_slice(current.len);
+    false
+}